@@ -1,6 +1,7 @@
 use std::io;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
+use std::time::Duration;
 
 use url::{
     Url, SchemeType, Host,
@@ -18,7 +19,19 @@ use irc::legacy::State;
 
 use irc_mio::IrcMsgRingBuf;
 use irc_mio::PopError as IrcRingPopError;
-use command_mapper::PluginContainer;
+use command_mapper::{
+    PluginContainer,
+    PluginConfig,
+    ExternalPlugin,
+    ExternalPluginConfig,
+    WasmPlugin,
+    WasmPluginConfig,
+    ChannelConfig,
+    Storage,
+    stop_deadline,
+    CapNegotiation,
+};
+use signal;
 
 use plugins::{
     DeerPlugin,
@@ -36,6 +49,10 @@ use plugins::{
     EightBallPlugin,
     PickPlugin,
     IrcColorsPlugin,
+    TellPlugin,
+    RemindPlugin,
+    EconomyPlugin,
+    DicePlugin,
 };
 
 
@@ -48,6 +65,27 @@ pub struct BotConfig {
     pub nickname: String,
     pub channels: Vec<String>,
     pub enabled_plugins: HashSet<String>,
+    /// Per-channel plugin settings; `"*"` applies everywhere
+    pub channel_plugins: Option<BTreeMap<String, ChannelConfig>>,
+    /// Who may use administrative commands: services accounts, or
+    /// `nick!user@host` masks
+    pub admins: Option<Vec<String>>,
+    /// Command aliases, from alias name to expansion
+    pub aliases: Option<BTreeMap<String, String>>,
+    /// How many times a plugin may panic before it is disabled
+    pub plugin_panic_limit: Option<u32>,
+    /// Sent with QUIT when the bot is asked to shut down
+    pub quit_message: Option<String>,
+    /// Plugins run as separate processes; see `command_mapper::external`
+    pub external_plugins: Option<Vec<ExternalPluginConfig>>,
+    /// Paths of shared libraries to load plugins from
+    pub plugin_libraries: Option<Vec<String>>,
+    /// WebAssembly modules to run as plugins; see `command_mapper::wasm`
+    pub wasm_plugins: Option<Vec<WasmPluginConfig>>,
+    /// Where plugins' persistent storage is kept
+    pub data_dir: Option<String>,
+    /// How often plugins' storage is written to disk
+    pub snapshot_interval_secs: Option<u64>,
 }
 
 pub fn irc_scheme_type_mapper(scheme: &str) -> SchemeType {
@@ -82,6 +120,13 @@ impl BotConfig {
 
 const CLIENT: Token = Token(1);
 
+/// How often the event loop wakes up to check on pings and signals
+const TICK_MS: u64 = 500;
+
+const DEFAULT_QUIT_MESSAGE: &'static str = "Shutting down";
+
+const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 300;
+
 mod ping {
     use time::{Duration, SteadyTime};
 
@@ -219,6 +264,10 @@ impl Bot2Session {
 
         if should_upgrade {
             self.upgrade();
+            if let Bot2Session::Connected(ref mut conn) = *self {
+                let state = Arc::new(conn.state.clone_frozen());
+                conn.plugins.connected(state, &eloop.channel());
+            }
         }
 
         while try!(self.dispatch_msg(eloop)) {}
@@ -234,6 +283,32 @@ impl Bot2Session {
         }
     }
 
+    fn plugins(&mut self) -> &mut PluginContainer {
+        match *self {
+            Bot2Session::Connecting(ref mut conn) => &mut conn.plugins,
+            Bot2Session::Connected(ref mut conn) => &mut conn.plugins,
+        }
+    }
+
+    /// Queues a QUIT; `shutdown` stops the plugins.
+    fn quit(&mut self, message: &str) {
+        let quit = cli2::QuitBuf::new(message.as_bytes()).unwrap();
+        let (_, _, write_buffer) = self.operate();
+        write_buffer.push_msg(&quit).ok().unwrap();
+    }
+
+    /// Tells the plugins the connection is gone, then stops them and the
+    /// event loop.  Stopped plugins hear nothing more, so this order matters.
+    /// Does nothing more once the plugins have stopped, so that every
+    /// path giving up on the connection may call it.
+    fn shutdown(&mut self, eloop: &mut EventLoop<BotHandler>) {
+        if !self.plugins().is_stopped() {
+            self.plugins().disconnected();
+            self.plugins().stop(stop_deadline());
+        }
+        eloop.shutdown();
+    }
+
     fn client_try_io(&mut self, eset: EventSet) -> io::Result<EventSet> {
         use ::mio::{TryRead, TryWrite};
 
@@ -282,7 +357,7 @@ impl Bot2Session {
     fn client_ready(&mut self, eloop: &mut EventLoop<BotHandler>, eset: EventSet) {
         if eset.is_error() {
             warn!("client_ready: eset with error: {:?}", eset);
-            eloop.shutdown();
+            self.shutdown(eloop);
             return;
         }
 
@@ -290,14 +365,14 @@ impl Bot2Session {
             Ok(_) => (),
             Err(err) => {
                 warn!("client_readable: error in client_try_io: {:?}", err);
-                eloop.shutdown();
+                self.shutdown(eloop);
                 return;
             }
         }
 
         if let Err(err) = self.dispatch_read(eloop) {
             warn!("ready/is_readable: error in dispatch_read: {:?}", err);
-            eloop.shutdown();
+            self.shutdown(eloop);
             return;
         }
 
@@ -308,7 +383,7 @@ impl Bot2Session {
             },
             Err(err) => {
                 warn!("client_readable: error in client_try_io: {:?}", err);
-                eloop.shutdown();
+                self.shutdown(eloop);
                 return;
             }
         }
@@ -324,13 +399,26 @@ struct BotConnector {
 
     state_builder: StatePlugin,
     state: Option<State>,
+    caps: CapNegotiation,
     read_buffer: IrcMsgRingBuf,
     write_buffer: IrcMsgRingBuf,
 }
 
 impl BotConnector {
-    fn configured(connection: TcpStream, conf: &BotConfig) -> BotConnector {
-        let mut plugins = PluginContainer::new(conf.command_prefixes.clone());
+    fn configured(connection: TcpStream, conf: &BotConfig, plugin_config: PluginConfig) -> BotConnector {
+        let storage = match conf.data_dir {
+            Some(ref data_dir) => Storage::open(data_dir)
+                .ok().expect("failed to open data directory"),
+            None => {
+                warn!("no data_dir configured: plugin storage will not persist");
+                Storage::in_memory()
+            }
+        };
+        storage.start_snapshots(Duration::from_secs(
+            conf.snapshot_interval_secs.unwrap_or(DEFAULT_SNAPSHOT_INTERVAL_SECS)));
+
+        let mut plugins = PluginContainer::new(conf.command_prefixes.clone(), storage);
+        plugins.set_plugin_config(plugin_config);
         if conf.enabled_plugins.contains(PingPlugin::get_plugin_name()) {
             plugins.register(PingPlugin::new());
         }
@@ -376,12 +464,64 @@ impl BotConnector {
         if conf.enabled_plugins.contains(IrcColorsPlugin::get_plugin_name()) {
             plugins.register(IrcColorsPlugin::new());
         }
+        if conf.enabled_plugins.contains(TellPlugin::get_plugin_name()) {
+            plugins.register(TellPlugin::new());
+        }
+        if conf.enabled_plugins.contains(RemindPlugin::get_plugin_name()) {
+            plugins.register(RemindPlugin::new());
+        }
+        if conf.enabled_plugins.contains(EconomyPlugin::get_plugin_name()) {
+            plugins.register(EconomyPlugin::new());
+        }
+        if conf.enabled_plugins.contains(DicePlugin::get_plugin_name()) {
+            plugins.register(DicePlugin::new());
+        }
+        for external in conf.external_plugins.iter().flat_map(|x| x.iter()) {
+            if let Err(err) = plugins.try_register(ExternalPlugin::new(external.clone())) {
+                warn!("failed to start plugin {}: {:?}", external.name, err);
+            }
+        }
+        for path in conf.plugin_libraries.iter().flat_map(|x| x.iter()) {
+            match plugins.register_library(path) {
+                Ok(name) => info!("loaded plugin {} from {}", name, path),
+                Err(err) => warn!("failed to load plugin from {}: {:?}", path, err),
+            }
+        }
+        for wasm in conf.wasm_plugins.iter().flat_map(|x| x.iter()) {
+            let loaded = WasmPlugin::load(wasm)
+                .and_then(|plugin| plugins.try_register(plugin).map_err(|err| format!("{:?}", err)));
+            match loaded {
+                Ok(name) => info!("loaded plugin {} from {}", name, wasm.path),
+                Err(err) => warn!("failed to load plugin from {}: {}", wasm.path, err),
+            }
+        }
+
+        plugins.set_admins(conf.admins.clone().unwrap_or(Vec::new()));
+        for (channel, config) in conf.channel_plugins.iter().flat_map(|x| x.iter()) {
+            plugins.set_channel_config(channel, config.clone());
+        }
+        if let Some(panic_limit) = conf.plugin_panic_limit {
+            plugins.set_panic_limit(panic_limit);
+        }
+        if let Some(ref aliases) = conf.aliases {
+            for (name, expansion) in aliases.iter() {
+                if let Err(err) = plugins.set_alias(name, expansion) {
+                    warn!("ignoring alias {:?} => {:?}: {:?}", name, expansion, err);
+                }
+            }
+        }
+        plugins.load_aliases();
 
         let autojoin_on_invite: HashSet<String> = conf.channels.iter().cloned().collect();
         let autojoin_on_connect: Vec<String> = conf.channels.iter().cloned().collect();
 
         let mut wbuf = IrcMsgRingBuf::new(1 << 16);
 
+        // registration waits for the capabilities to be negotiated
+        let caps = CapNegotiation::new();
+        let cap_ls = IrcMsg::new(caps.start().as_bytes().to_vec()).unwrap();
+        wbuf.push_msg(&IrcMsgBuf::from_legacy(cap_ls)).ok().unwrap();
+
         // FIXME: legacy
         let user_msg = client::User::new(&conf.username, "8", "*", &conf.realname).into_irc_msg();
         wbuf.push_msg(&IrcMsgBuf::from_legacy(user_msg)).ok().unwrap();
@@ -398,6 +538,7 @@ impl BotConnector {
             nick: conf.nickname.clone(),
             state_builder: StatePlugin::new(),
             state: None,
+            caps: caps,
 
             read_buffer: IrcMsgRingBuf::new(1 << 16),
             write_buffer: wbuf,
@@ -457,6 +598,14 @@ impl BotConnector {
             self.write_buffer.push_msg(&ping.response()).ok().unwrap();
         }
 
+        if msg.get_command() == "CAP" {
+            let raw = String::from_utf8_lossy(msg.as_bytes()).into_owned();
+            if let Some(reply) = self.caps.observe(&raw) {
+                let reply = IrcMsg::new(reply.into_bytes()).unwrap();
+                self.write_buffer.push_msg(&IrcMsgBuf::from_legacy(reply)).ok().unwrap();
+            }
+        }
+
         let legacy = msg.clone().into_legacy();
         if let Some(state) = self.state_builder.on_irc_msg(&legacy) {
             self.state = Some(state);
@@ -516,18 +665,23 @@ impl BotSession {
             let who = IrcMsgBuf::from_legacy(who);
             self.write_buffer.push_msg(&who).ok().unwrap();
         }
-        for event in self.bundler_man.on_irc_msg(&legacy).into_iter() {
-            self.state.on_event(&event);
+        let events = self.bundler_man.on_irc_msg(&legacy);
+        let before = self.state.clone_frozen();
+        for event in events.iter() {
+            self.state.on_event(event);
         }
-        self.plugins.dispatch(Arc::new(self.state.clone_frozen()), &eloop.channel(), &msg);
+        let after = Arc::new(self.state.clone_frozen());
+        self.plugins.dispatch(after.clone(), &eloop.channel(), &msg);
+        self.plugins.dispatch_hooks(&before, &after, &eloop.channel(), &events);
         Ok(true)
     }
 
-    fn dispatch_timeout(&mut self, _eloop: &mut EventLoop<BotHandler>) -> Result<bool, ()> {
+    fn dispatch_timeout(&mut self, eloop: &mut EventLoop<BotHandler>) -> Result<bool, ()> {
         if self.ping_man.should_terminate() {
             let quit = cli2::QuitBuf::new(b"Server not responding to PING").unwrap();
             self.write_buffer.push_msg(&quit).ok().unwrap();
-            return Ok(false);
+            // the caller flushes the QUIT and shuts down
+            return Err(());
         }
 
         if self.ping_man.next_ping().is_now() {
@@ -538,6 +692,7 @@ impl BotSession {
             self.ping_man.ping_sent();
         }
 
+        self.plugins.tick(&eloop.channel());
         Ok(false)
     }
 }
@@ -545,12 +700,14 @@ impl BotSession {
 
 struct BotHandler {
     session: Bot2Session,
+    quit_message: String,
 }
 
 impl BotHandler {
-    fn new(connector: BotConnector) -> BotHandler {
+    fn new(connector: BotConnector, quit_message: String) -> BotHandler {
         BotHandler {
             session: Bot2Session::Connecting(connector),
+            quit_message: quit_message,
         }
     }
 }
@@ -576,28 +733,42 @@ impl ::mio::Handler for BotHandler {
 
     fn timeout(&mut self, eloop: &mut EventLoop<BotHandler>, token: Token) {
         if token == CLIENT {
+            if signal::shutdown_requested() {
+                info!("shutdown requested: quitting");
+                self.session.quit(&self.quit_message);
+                self.session.client_ready(eloop, EventSet::writable());
+                self.session.shutdown(eloop);
+                return;
+            }
             if let Err(err) = self.session.dispatch_timeout(eloop) {
                 warn!("Error during dispatch_timeout: {:?}", err);
-                eloop.shutdown();
+                self.session.client_ready(eloop, EventSet::writable());
+                self.session.shutdown(eloop);
+                return;
             }
+            eloop.timeout_ms(CLIENT, TICK_MS).unwrap();
         }
     }
 }
 
 
-pub fn run_loop(conf: &BotConfig) -> Result<(), ()> {
+pub fn run_loop(conf: &BotConfig, plugin_config: PluginConfig) -> Result<(), ()> {
     let config = EventLoopConfig::default();
     let mut event_loop = EventLoop::configured(config).unwrap();
 
     let addr: ::std::net::SocketAddr =
         format!("{}:{}", conf.get_host(), conf.get_port()).parse().unwrap();
     let conn = TcpStream::connect(&addr).unwrap();
-    let connector = BotConnector::configured(conn, conf);
+    let connector = BotConnector::configured(conn, conf, plugin_config);
 
     event_loop.register(&connector.connection, CLIENT,
         EventSet::readable() | EventSet::writable(), PollOpt::edge()).unwrap();
-    event_loop.timeout_ms(CLIENT, 2500).unwrap();
-    event_loop.run(&mut BotHandler::new(connector)).unwrap();
+    event_loop.timeout_ms(CLIENT, TICK_MS).unwrap();
+    signal::install();
+
+    let quit_message = conf.quit_message.clone()
+        .unwrap_or(DEFAULT_QUIT_MESSAGE.to_string());
+    event_loop.run(&mut BotHandler::new(connector, quit_message)).unwrap();
 
     Ok(())
 }
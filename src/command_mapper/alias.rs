@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+const MAX_EXPANSIONS: usize = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AliasError {
+    /// The alias name is empty or contains whitespace
+    InvalidName,
+    /// The alias name is already the name of a command
+    ShadowsCommand,
+    /// Expanding the alias leads back to itself
+    Loop(Vec<String>),
+    /// More than `MAX_EXPANSIONS` aliases were expanded
+    TooDeep,
+}
+
+/// User-defined command aliases, expanded before formats are parsed.
+///
+/// An expansion may refer to the words following the alias with `$1`
+/// through `$9`, to all of them with `$*`, and to a literal `$` with
+/// `$$`.  If it refers to none of them, the words are appended.
+pub struct AliasTable {
+    aliases: BTreeMap<String, String>,
+}
+
+fn split_command(message_body: &str) -> (&str, &str) {
+    match message_body.find(' ') {
+        Some(idx) => (&message_body[..idx], message_body[idx..].trim_left_matches(' ')),
+        None => (message_body, ""),
+    }
+}
+
+fn substitute(expansion: &str, args: &str) -> String {
+    let words: Vec<&str> = args.split(' ').filter(|x| x.len() > 0).collect();
+    let mut out = String::new();
+    let mut substituted = false;
+
+    let mut chars = expansion.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != '$' {
+            out.push(ch);
+            continue;
+        }
+        match chars.peek().cloned() {
+            Some('*') => {
+                chars.next();
+                substituted = true;
+                out.push_str(args);
+            },
+            Some('$') => {
+                chars.next();
+                out.push('$');
+            },
+            Some(digit @ '1'...'9') => {
+                chars.next();
+                substituted = true;
+                let idx = digit as usize - '1' as usize;
+                out.push_str(words.get(idx).map(|x| *x).unwrap_or(""));
+            },
+            _ => out.push('$'),
+        }
+    }
+
+    if !substituted && args.len() > 0 {
+        out.push(' ');
+        out.push_str(args);
+    }
+    out
+}
+
+impl AliasTable {
+    pub fn new() -> AliasTable {
+        AliasTable {
+            aliases: BTreeMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, name: &str, expansion: &str) -> Result<(), AliasError> {
+        if name.len() == 0 || name.contains(' ') {
+            return Err(AliasError::InvalidName);
+        }
+        self.aliases.insert(name.to_lowercase(), expansion.trim().to_string());
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.aliases.get(&name.to_lowercase()).map(|x| &x[..])
+    }
+
+    /// Removes an alias, returning its expansion if it existed.
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.aliases.remove(&name.to_lowercase())
+    }
+
+    pub fn iter(&self) -> btree_map::Iter<String, String> {
+        self.aliases.iter()
+    }
+
+    /// Expands aliases at the start of `message_body` until the first word
+    /// is no longer an alias.  Returns `None` if no alias applied.
+    pub fn expand(&self, message_body: &str) -> Result<Option<String>, AliasError> {
+        let mut chain: Vec<String> = Vec::new();
+        let mut current = message_body.to_string();
+
+        loop {
+            let next = {
+                let (command, args) = split_command(&current);
+                let command = command.to_lowercase();
+                let expansion = match self.aliases.get(&command) {
+                    Some(expansion) => expansion,
+                    None => break,
+                };
+                if chain.contains(&command) {
+                    chain.push(command);
+                    return Err(AliasError::Loop(chain));
+                }
+                if chain.len() == MAX_EXPANSIONS {
+                    return Err(AliasError::TooDeep);
+                }
+                chain.push(command);
+                substitute(expansion, args)
+            };
+            current = next;
+        }
+
+        if chain.len() == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(current))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AliasTable, AliasError};
+
+    #[test]
+    fn test_expand() {
+        let mut aliases = AliasTable::new();
+        aliases.set("np", "dj").unwrap();
+        aliases.set("8", "8ball").unwrap();
+        aliases.set("Slap", "me slaps $1 with $2$$").unwrap();
+        aliases.set("ask", "8 $*?").unwrap();
+
+        assert_eq!(aliases.expand("np"), Ok(Some("dj".to_string())));
+        assert_eq!(aliases.expand("NP  "), Ok(Some("dj".to_string())));
+        assert_eq!(aliases.expand("8 will it rain"),
+            Ok(Some("8ball will it rain".to_string())));
+        assert_eq!(aliases.expand("slap bob a trout"),
+            Ok(Some("me slaps bob with a$".to_string())));
+        assert_eq!(aliases.expand("slap bob"),
+            Ok(Some("me slaps bob with $".to_string())));
+        assert_eq!(aliases.expand("ask will it rain"),
+            Ok(Some("8ball will it rain?".to_string())));
+        assert_eq!(aliases.expand("dj"), Ok(None));
+        assert_eq!(aliases.expand("npc"), Ok(None));
+
+        assert_eq!(aliases.remove("np"), Some("dj".to_string()));
+        assert_eq!(aliases.expand("np"), Ok(None));
+        assert_eq!(aliases.set("two words", "dj"), Err(AliasError::InvalidName));
+    }
+
+    #[test]
+    fn test_loops() {
+        let mut aliases = AliasTable::new();
+        aliases.set("a", "b $*").unwrap();
+        aliases.set("b", "c").unwrap();
+        aliases.set("c", "a x").unwrap();
+        aliases.set("self", "self").unwrap();
+
+        assert_eq!(aliases.expand("a"), Err(AliasError::Loop(vec![
+            "a".to_string(), "b".to_string(), "c".to_string(), "a".to_string(),
+        ])));
+        assert_eq!(aliases.expand("self"), Err(AliasError::Loop(vec![
+            "self".to_string(), "self".to_string(),
+        ])));
+
+        let mut deep = AliasTable::new();
+        for idx in 0..20 {
+            deep.set(&format!("d{}", idx), &format!("d{}", idx + 1)).unwrap();
+        }
+        assert_eq!(deep.expand("d0"), Err(AliasError::TooDeep));
+        assert_eq!(deep.expand("d15"), Ok(Some("d20".to_string())));
+    }
+}
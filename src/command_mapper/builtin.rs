@@ -0,0 +1,197 @@
+use super::{
+    Token,
+    Format,
+    Quoting,
+    MappedFormat,
+    PluginContainer,
+    CommandMapperDispatch,
+    DispatchBuilder,
+};
+use super::alias::AliasError;
+use super::dynamic::LoadError;
+
+const CMD_HELP: Token = Token(0);
+const CMD_ALIAS: Token = Token(1);
+const CMD_UNALIAS: Token = Token(2);
+const CMD_ALIASES: Token = Token(3);
+const CMD_PLUGIN_UNLOAD: Token = Token(4);
+const CMD_PLUGIN_RELOAD: Token = Token(5);
+
+fn mapped(token: Token, format: Format, description: &str) -> MappedFormat {
+    MappedFormat {
+        token: token,
+        format: format,
+        description: description.to_string(),
+    }
+}
+
+/// Commands handled by the `PluginContainer` itself.
+pub fn builtin_formats() -> Vec<MappedFormat> {
+    vec![
+        mapped(CMD_HELP, Format::from_str("help {command?}").unwrap(),
+            "List commands, or show the usage of one"),
+        mapped(CMD_ALIAS, Format::from_str("alias {name} {*expansion}").unwrap()
+            .with_quoting(Quoting::Shell),
+            "Define a command alias, using $1..$9 and $* for arguments (admins only)"),
+        mapped(CMD_UNALIAS, Format::from_str("unalias {name}").unwrap(),
+            "Remove a command alias (admins only)"),
+        mapped(CMD_ALIASES, Format::from_str("aliases").unwrap(),
+            "List command aliases"),
+        mapped(CMD_PLUGIN_UNLOAD, Format::from_str("plugin-unload {name:s}").unwrap(),
+            "Stop and remove a plugin (admins only)"),
+        mapped(CMD_PLUGIN_RELOAD, Format::from_str("plugin-reload {name:s}").unwrap(),
+            "Reload a plugin from its shared library (admins only)"),
+    ]
+}
+
+/// Whether only admins may use the built-in command mapped under `token`.
+fn is_admin_only(token: Token) -> bool {
+    match token {
+        CMD_ALIAS | CMD_UNALIAS | CMD_PLUGIN_UNLOAD | CMD_PLUGIN_RELOAD => true,
+        _ => false,
+    }
+}
+
+pub fn describe_alias_error(err: &AliasError) -> String {
+    match *err {
+        AliasError::InvalidName => "Alias names must be a single word".to_string(),
+        AliasError::ShadowsCommand => "Aliases may not replace existing commands".to_string(),
+        AliasError::Loop(ref chain) => format!("Alias loop: {}", chain.join(" -> ")),
+        AliasError::TooDeep => "Aliases are nested too deeply".to_string(),
+    }
+}
+
+pub fn describe_load_error(err: &LoadError) -> String {
+    match *err {
+        LoadError::Open(ref msg) => format!("Failed to load library: {}", msg),
+        LoadError::MissingEntryPoint(ref msg) => format!("Not a plugin library: {}", msg),
+        LoadError::AbiMismatch(version) => format!("Plugin ABI version {} is not supported", version),
+        LoadError::BadDeclaration => "The plugin declaration is invalid".to_string(),
+        LoadError::NameTaken(ref name) => format!("A plugin named {} is already loaded", name),
+        LoadError::NullPlugin => "The plugin library failed to create its plugin".to_string(),
+    }
+}
+
+impl PluginContainer {
+    pub fn dispatch_builtin(&mut self, m: &CommandMapperDispatch, builder: &DispatchBuilder, prefix: &str) {
+        let source_nick = &builder.source_nick;
+        match m.command().token {
+            CMD_HELP => self.dispatch_help(m, builder, prefix),
+            CMD_ALIAS | CMD_UNALIAS if !builder.is_admin => {
+                m.reply(&format!("{}: only admins may edit aliases", source_nick));
+            },
+            CMD_PLUGIN_UNLOAD | CMD_PLUGIN_RELOAD if !builder.is_admin => {
+                m.reply(&format!("{}: only admins may manage plugins", source_nick));
+            },
+            CMD_PLUGIN_UNLOAD => {
+                let name = m.command().get::<String>("name").unwrap();
+                match self.unregister(&name) {
+                    Some(_) => m.reply(&format!("Unloaded {}", name)),
+                    None => m.reply(&format!("No such plugin: {}", name)),
+                }
+            },
+            CMD_PLUGIN_RELOAD => {
+                let name = m.command().get::<String>("name").unwrap();
+                let is_library = self.plugins.iter()
+                    .any(|registered| registered.name == name && registered.library.is_some());
+                if !is_library {
+                    m.reply(&format!("{} was not loaded from a library", name));
+                    return;
+                }
+                let path = self.unregister(&name).unwrap().unwrap();
+                match self.register_library(&path) {
+                    Ok(name) => m.reply(&format!("Reloaded {} from {}", name, path)),
+                    Err(err) => m.reply(&describe_load_error(&err)),
+                }
+            },
+            CMD_ALIAS => {
+                let name = m.command().get::<String>("name").unwrap();
+                let expansion = m.command().get::<String>("expansion").unwrap();
+                match self.set_alias(&name, &expansion) {
+                    Ok(()) => {
+                        self.store_alias(&name, Some(expansion.clone()));
+                        m.reply(&format!("{}{} is now an alias for {}{}",
+                            prefix, name.to_lowercase(), prefix, expansion));
+                    },
+                    Err(err) => m.reply(&describe_alias_error(&err)),
+                }
+            },
+            CMD_UNALIAS => {
+                let name = m.command().get::<String>("name").unwrap();
+                match self.aliases.remove(&name) {
+                    Some(_) => {
+                        self.store_alias(&name, None);
+                        m.reply(&format!("Removed alias {}{}", prefix, name));
+                    },
+                    None => m.reply(&format!("No such alias: {}", name)),
+                }
+            },
+            CMD_ALIASES => {
+                let aliases: Vec<String> = self.aliases.iter()
+                    .map(|(name, expansion)| format!("{} -> {}", name, expansion))
+                    .collect();
+                if aliases.len() == 0 {
+                    m.reply("No aliases are defined");
+                } else {
+                    m.reply(&format!("Aliases: {}", aliases.join(", ")));
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// The built-in `help [command]`: lists the commands of each plugin,
+    /// or the usage of every format mapped under a single command.  Only
+    /// commands the caller may use where they asked are shown.
+    fn dispatch_help(&self, m: &CommandMapperDispatch, builder: &DispatchBuilder, prefix: &str) {
+        let builtins: Vec<&MappedFormat> = self.builtins.iter()
+            .filter(|mapped| builder.is_admin || !is_admin_only(mapped.token))
+            .collect();
+        let plugins: Vec<_> = self.plugins.iter()
+            .filter(|registered| self.permits(builder, &registered.name))
+            .collect();
+
+        let command = match m.command().get::<String>("command") {
+            Some(command) => command,
+            None => {
+                // a single line, so as not to flood the channel
+                let builtins = builtins.iter().map(|mapped| mapped.format.command_name());
+                let mut groups = vec![format!("built-in: {}", builtins.collect::<Vec<_>>().join(", "))];
+                for registered in plugins.iter() {
+                    let mut names: Vec<&str> = Vec::new();
+                    for mapped in registered.formats.iter() {
+                        let name = mapped.format.command_name();
+                        if !names.contains(&name) {
+                            names.push(name);
+                        }
+                    }
+                    if names.len() > 0 {
+                        groups.push(format!("{}: {}", registered.name, names.join(", ")));
+                    }
+                }
+                m.reply(&format!("Commands (see {}help <command>): {}", prefix, groups.join("; ")));
+                return;
+            }
+        };
+
+        let command = command.to_lowercase();
+        let command = command.trim_left_matches(prefix);
+        if let Some(expansion) = self.aliases.get(command) {
+            m.reply(&format!("{}{} is an alias for {}{}", prefix, command, prefix, expansion));
+            return;
+        }
+
+        let mut found = false;
+        let plugin_formats = plugins.iter().flat_map(|r| r.formats.iter());
+        for mapped in builtins.into_iter().chain(plugin_formats) {
+            if mapped.format.command_name() == command {
+                found = true;
+                m.reply(&format!("{}{} \u{2014} {}",
+                    prefix, mapped.format.usage(), mapped.description));
+            }
+        }
+        if !found {
+            m.reply(&format!("No such command: {}", command));
+        }
+    }
+}
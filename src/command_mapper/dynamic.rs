@@ -0,0 +1,350 @@
+//! Plugins loaded from shared libraries.
+//!
+//! A library exports `ircbot_plugin_declaration`, an `extern "C"` function
+//! returning a pointer to a static `PluginDeclaration`.  Its `create`
+//! function returns a `Box<Box<RustBotPlugin>>` turned into a raw pointer.
+//! Since `RustBotPlugin` is a Rust trait, the library must be built by the
+//! same compiler, against the same revision of the bot; `abi_version`
+//! only catches changes to the declaration itself.
+//!
+//! The plugin's commands are those in the declaration; its `configure`
+//! is still called, but should not map any formats.
+//!
+//! A plugin's threads must have exited before its library is unloaded,
+//! so plugins should wait for them in `stop`.
+
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::time::Instant;
+
+use irc::{IrcMsg, server};
+use irc::legacy::FrozenState;
+
+use super::{
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Replier,
+    RustBotPlugin,
+    TimerToken,
+    Token,
+};
+
+pub const ABI_VERSION: u32 = 1;
+
+const ENTRY_POINT: &'static str = "ircbot_plugin_declaration";
+
+#[link(name = "dl")]
+extern "C" {
+    fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlclose(handle: *mut c_void) -> c_int;
+    fn dlerror() -> *mut c_char;
+}
+
+const RTLD_NOW: c_int = 2;
+
+#[repr(C)]
+pub struct FormatDeclaration {
+    pub token: u64,
+    pub format: *const c_char,
+    pub description: *const c_char,
+}
+
+#[repr(C)]
+pub struct PluginDeclaration {
+    pub abi_version: u32,
+    pub name: *const c_char,
+    pub formats: *const FormatDeclaration,
+    pub format_count: usize,
+    pub create: extern "C" fn() -> *mut c_void,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Open(String),
+    MissingEntryPoint(String),
+    AbiMismatch(u32),
+    /// A string in the declaration is null or not UTF-8
+    BadDeclaration,
+    /// A plugin with the same name is already registered
+    NameTaken(String),
+    /// The declaration's `create` returned null
+    NullPlugin,
+}
+
+struct Library(*mut c_void);
+
+fn last_error() -> String {
+    unsafe {
+        let err = dlerror();
+        if err.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    }
+}
+
+impl Library {
+    fn open(path: &str) -> Result<Library, LoadError> {
+        let c_path = try!(CString::new(path).map_err(|_| LoadError::Open(path.to_string())));
+        let handle = unsafe { dlopen(c_path.as_ptr(), RTLD_NOW) };
+        if handle.is_null() {
+            return Err(LoadError::Open(last_error()));
+        }
+        Ok(Library(handle))
+    }
+
+    fn symbol(&self, name: &str) -> Result<*mut c_void, LoadError> {
+        let c_name = CString::new(name).unwrap();
+        let symbol = unsafe { dlsym(self.0, c_name.as_ptr()) };
+        if symbol.is_null() {
+            return Err(LoadError::MissingEntryPoint(last_error()));
+        }
+        Ok(symbol)
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        unsafe { dlclose(self.0); }
+    }
+}
+
+unsafe fn declared_str(ptr: *const c_char) -> Result<String, LoadError> {
+    if ptr.is_null() {
+        return Err(LoadError::BadDeclaration);
+    }
+    match CStr::from_ptr(ptr).to_str() {
+        Ok(string) => Ok(string.to_string()),
+        Err(_) => Err(LoadError::BadDeclaration),
+    }
+}
+
+/// What a declaration says, copied out of the library.
+#[derive(Debug)]
+struct Manifest {
+    name: String,
+    formats: Vec<(Token, String, String)>,
+}
+
+/// Checks and copies out a library's declaration.
+unsafe fn read_manifest(declaration: *const PluginDeclaration) -> Result<Manifest, LoadError> {
+    if declaration.is_null() {
+        return Err(LoadError::BadDeclaration);
+    }
+    let declaration = &*declaration;
+    if declaration.abi_version != ABI_VERSION {
+        return Err(LoadError::AbiMismatch(declaration.abi_version));
+    }
+
+    let name = try!(declared_str(declaration.name));
+    let mut formats = Vec::new();
+    if 0 < declaration.format_count && declaration.formats.is_null() {
+        return Err(LoadError::BadDeclaration);
+    }
+    for idx in 0..declaration.format_count {
+        let format = &*declaration.formats.offset(idx as isize);
+        formats.push((
+            Token(format.token),
+            try!(declared_str(format.format)),
+            try!(declared_str(format.description)),
+        ));
+    }
+    Ok(Manifest { name: name, formats: formats })
+}
+
+/// Calls the declaration's `create`, taking ownership of the plugin.
+unsafe fn create_plugin(declaration: &PluginDeclaration) -> Result<Box<RustBotPlugin>, LoadError> {
+    let plugin = (declaration.create)() as *mut Box<RustBotPlugin>;
+    if plugin.is_null() {
+        return Err(LoadError::NullPlugin);
+    }
+    Ok(*Box::from_raw(plugin))
+}
+
+/// A plugin from a shared library, and the library holding its code.
+pub struct DynamicPlugin {
+    name: String,
+    // declared before the library, so that it is dropped first
+    plugin: Box<RustBotPlugin>,
+    formats: Vec<(Token, String, String)>,
+    _library: Library,
+}
+
+/// Loads the plugin in the library at `path`.
+pub fn load(path: &str) -> Result<DynamicPlugin, LoadError> {
+    let library = try!(Library::open(path));
+    let entry = try!(library.symbol(ENTRY_POINT));
+
+    unsafe {
+        let entry: extern "C" fn() -> *const PluginDeclaration = mem::transmute(entry);
+        let declaration = entry();
+        let manifest = try!(read_manifest(declaration));
+
+        let plugin = try!(create_plugin(&*declaration));
+        Ok(DynamicPlugin {
+            name: manifest.name,
+            plugin: plugin,
+            formats: manifest.formats,
+            _library: library,
+        })
+    }
+}
+
+impl RustBotPlugin for DynamicPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        // for storage, configuration and timers: the declaration alone
+        // says which commands the plugin has
+        self.plugin.configure(conf);
+        if !conf.mapped.is_empty() {
+            warn!("{}: ignoring formats mapped in configure rather than declared", self.name);
+            conf.mapped.clear();
+        }
+        for &(token, ref format, ref description) in self.formats.iter() {
+            match Format::from_str(format) {
+                Ok(parsed) => conf.map_format(token, parsed, description),
+                Err(err) => warn!("ignoring declared format {:?}: {:?}", format, err),
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        self.plugin.start();
+    }
+
+    fn on_message(&mut self, replier: &mut Replier, msg: &IrcMsg) {
+        self.plugin.on_message(replier, msg);
+    }
+
+    fn on_timer(&mut self, replier: &mut Replier, token: TimerToken) {
+        self.plugin.on_timer(replier, token);
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        self.plugin.dispatch_cmd(m, msg);
+    }
+
+    fn on_join(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Join) {
+        self.plugin.on_join(r, state, msg);
+    }
+
+    fn on_part(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Part) {
+        self.plugin.on_part(r, state, msg);
+    }
+
+    fn on_quit(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Quit) {
+        self.plugin.on_quit(r, state, msg);
+    }
+
+    fn on_kick(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Kick) {
+        self.plugin.on_kick(r, state, msg);
+    }
+
+    fn on_nick(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Nick) {
+        self.plugin.on_nick(r, state, msg);
+    }
+
+    fn on_mode(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Mode) {
+        self.plugin.on_mode(r, state, msg);
+    }
+
+    fn on_topic(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Topic) {
+        self.plugin.on_topic(r, state, msg);
+    }
+
+    fn on_notice(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Notice) {
+        self.plugin.on_notice(r, state, msg);
+    }
+
+    fn on_invite(&mut self, r: &mut Replier, state: &FrozenState, msg: &server::Invite) {
+        self.plugin.on_invite(r, state, msg);
+    }
+
+    fn on_connected(&mut self, r: &mut Replier, state: &FrozenState) {
+        self.plugin.on_connected(r, state);
+    }
+
+    fn on_disconnected(&mut self) {
+        self.plugin.on_disconnected();
+    }
+
+    fn stop(&mut self, deadline: Instant) {
+        self.plugin.stop(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::raw::{c_char, c_void};
+    use std::ptr;
+
+    use super::{read_manifest, create_plugin, FormatDeclaration, PluginDeclaration, LoadError, ABI_VERSION};
+    use super::super::Token;
+
+    extern "C" fn create() -> *mut c_void {
+        ptr::null_mut()
+    }
+
+    fn c_str(bytes: &'static [u8]) -> *const c_char {
+        bytes.as_ptr() as *const c_char
+    }
+
+    #[test]
+    fn test_read_manifest() {
+        let formats = [
+            FormatDeclaration { token: 0, format: c_str(b"hello\0"), description: c_str(b"Say hello\0") },
+            FormatDeclaration { token: 3, format: c_str(b"hello {nick}\0"), description: c_str(b"\0") },
+        ];
+        let mut declaration = PluginDeclaration {
+            abi_version: ABI_VERSION,
+            name: c_str(b"greeter\0"),
+            formats: formats.as_ptr(),
+            format_count: formats.len(),
+            create: create,
+        };
+        let manifest = unsafe { read_manifest(&declaration) }.unwrap();
+        assert_eq!(manifest.name, "greeter");
+        assert_eq!(manifest.formats, vec![
+            (Token(0), "hello".to_string(), "Say hello".to_string()),
+            (Token(3), "hello {nick}".to_string(), "".to_string()),
+        ]);
+
+        declaration.abi_version = ABI_VERSION + 1;
+        match unsafe { read_manifest(&declaration) } {
+            Err(LoadError::AbiMismatch(version)) => assert_eq!(version, ABI_VERSION + 1),
+            other => panic!("expected an ABI mismatch, got {:?}", other),
+        }
+
+        declaration.abi_version = ABI_VERSION;
+        declaration.name = ptr::null();
+        match unsafe { read_manifest(&declaration) } {
+            Err(LoadError::BadDeclaration) => (),
+            other => panic!("expected a bad declaration, got {:?}", other),
+        }
+
+        declaration.name = c_str(b"greeter\0");
+        declaration.formats = ptr::null();
+        match unsafe { read_manifest(&declaration) } {
+            Err(LoadError::BadDeclaration) => (),
+            other => panic!("expected a bad declaration, got {:?}", other),
+        }
+
+        match unsafe { read_manifest(ptr::null()) } {
+            Err(LoadError::BadDeclaration) => (),
+            other => panic!("expected a bad declaration, got {:?}", other),
+        }
+
+        match unsafe { create_plugin(&declaration) } {
+            Err(LoadError::NullPlugin) => (),
+            Err(other) => panic!("expected a null plugin, got {:?}", other),
+            Ok(_) => panic!("expected a null plugin"),
+        }
+    }
+}
@@ -0,0 +1,525 @@
+//! Plugins running as separate processes, speaking JSON lines.
+//!
+//! When started, the process writes a line declaring its commands, within
+//! ten seconds or it is killed:
+//!
+//! ```text
+//! {"type": "hello", "formats": [{"format": "weather {*place}", "description": "Show the weather"}]}
+//! ```
+//!
+//! The bot then writes a line to its stdin for every IRC message, and for
+//! every command dispatched to it, where `format` is the index of the
+//! format in the hello line:
+//!
+//! ```text
+//! {"type": "message", "command": "PRIVMSG", "raw": ":a!b@c PRIVMSG #x :hi"}
+//! {"type": "dispatch", "id": 7, "format": 0, "args": {"place": "Oslo"}, "source": "a", "target": "#x"}
+//! ```
+//!
+//! and the process may write back, at any time:
+//!
+//! ```text
+//! {"type": "reply", "id": 7, "text": "Sunny"}
+//! {"type": "done", "id": 7}
+//! {"type": "action", "target": "#x", "text": "waves"}
+//! {"type": "raw", "line": "TOPIC #x :hello"}
+//! ```
+//!
+//! A process which exits is restarted, waiting longer after each
+//! failure.  The formats of the first hello line stay registered.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Command, Stdio, Child, ChildStdin, ChildStdout};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::channel;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::Sender;
+use rustc_serialize::json::Json;
+
+use irc::{IrcMsg, IrcMsgBuf, server};
+use irc::legacy::IrcMsg as LegacyIrcMsg;
+
+use super::{
+    send_privmsg,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Replier,
+    RustBotPlugin,
+    SubmitError,
+    Token,
+    Worker,
+    WorkerConfig,
+    WorkerPool,
+};
+
+const MIN_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF_MS: u64 = 60000;
+
+/// A process which ran for this long is considered to have started
+/// successfully, resetting the backoff.
+const HEALTHY_AFTER_MS: u64 = 60000;
+
+/// Dispatches never marked done are forgotten after this long.
+const PENDING_TIMEOUT_MS: u64 = 300000;
+
+/// A process which has not written its hello line by then is killed.
+const HELLO_TIMEOUT_MS: u64 = 10000;
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct ExternalPluginConfig {
+    pub name: String,
+    pub command: String,
+    pub args: Option<Vec<String>>,
+}
+
+type Reader = BufReader<ChildStdout>;
+
+struct Shared {
+    name: String,
+    stopping: AtomicBool,
+    child: Mutex<Option<Child>>,
+    stdin: Mutex<Option<ChildStdin>>,
+    raw_tx: Mutex<Option<Sender<IrcMsgBuf>>>,
+    pending: Mutex<HashMap<u64, (Instant, CommandMapperDispatch)>>,
+}
+
+fn json_object(fields: Vec<(&str, Json)>) -> Json {
+    let mut object = BTreeMap::new();
+    for (key, value) in fields.into_iter() {
+        object.insert(key.to_string(), value);
+    }
+    Json::Object(object)
+}
+
+fn spawn(config: &ExternalPluginConfig) -> io::Result<(Child, ChildStdin, Reader)> {
+    let mut command = Command::new(&config.command);
+    if let Some(ref args) = config.args {
+        command.args(args);
+    }
+    let mut child = try!(command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn());
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    Ok((child, stdin, BufReader::new(stdout)))
+}
+
+/// Reads the hello line, returning the declared formats and descriptions.
+fn read_hello<R: BufRead>(reader: &mut R) -> Result<Vec<(String, String)>, String> {
+    let mut line = String::new();
+    if let Err(err) = reader.read_line(&mut line) {
+        return Err(format!("reading hello: {:?}", err));
+    }
+    let hello = match Json::from_str(&line) {
+        Ok(hello) => hello,
+        Err(err) => return Err(format!("bad hello {:?}: {:?}", line, err)),
+    };
+    if hello.find("type").and_then(|x| x.as_string()) != Some("hello") {
+        return Err(format!("expected hello, got {:?}", line));
+    }
+
+    let mut formats = Vec::new();
+    for declared in hello.find("formats").and_then(|x| x.as_array()).unwrap_or(&Vec::new()).iter() {
+        let format = declared.find("format").and_then(|x| x.as_string()).unwrap_or("");
+        let description = declared.find("description").and_then(|x| x.as_string()).unwrap_or("");
+        formats.push((format.to_string(), description.to_string()));
+    }
+    Ok(formats)
+}
+
+/// Reads the hello line on a thread of its own, so that a process which
+/// never writes one cannot hang the bot.  Such a process is killed, and
+/// its reader is not returned.
+fn await_hello(child: &mut Child, reader: Reader) -> (Option<Reader>, Result<Vec<(String, String)>, String>) {
+    let (tx, rx) = channel();
+    let spawned = thread::Builder::new().name("external-hello".to_string()).spawn(move || {
+        let mut reader = reader;
+        let hello = read_hello(&mut reader);
+        let _ = tx.send((reader, hello));
+    });
+    if let Err(err) = spawned {
+        return (None, Err(format!("failed to spawn hello reader: {:?}", err)));
+    }
+    match rx.recv_timeout(Duration::from_millis(HELLO_TIMEOUT_MS)) {
+        Ok((reader, hello)) => (Some(reader), hello),
+        Err(_) => {
+            // the reader thread sees stdout close and exits
+            let _ = child.kill();
+            let _ = child.wait();
+            (None, Err(format!("no hello within {}ms", HELLO_TIMEOUT_MS)))
+        }
+    }
+}
+
+/// A line written by the process.
+#[derive(Debug, PartialEq, Eq)]
+enum ProcessLine {
+    Reply(u64, String),
+    Done(u64),
+    Action(String, String),
+    Raw(String),
+}
+
+fn parse_process_line(line: &str) -> Result<ProcessLine, String> {
+    let json = match Json::from_str(line) {
+        Ok(json) => json,
+        Err(err) => return Err(format!("bad line {:?}: {:?}", line, err)),
+    };
+    let string = |key: &str| json.find(key).and_then(|x| x.as_string()).map(|x| x.to_string());
+    let id = json.find("id").and_then(|x| x.as_u64());
+
+    let parsed = match json.find("type").and_then(|x| x.as_string()).unwrap_or("") {
+        "reply" => match (id, string("text")) {
+            (Some(id), Some(text)) => Some(ProcessLine::Reply(id, text)),
+            _ => None,
+        },
+        "done" => id.map(ProcessLine::Done),
+        "action" => match (string("target"), string("text")) {
+            (Some(target), Some(text)) => Some(ProcessLine::Action(target, text)),
+            _ => None,
+        },
+        "raw" => string("line").map(ProcessLine::Raw),
+        other => return Err(format!("unknown line type {:?}", other)),
+    };
+    parsed.ok_or_else(|| format!("line missing fields: {:?}", line))
+}
+
+impl Shared {
+    fn raw_tx(&self) -> Option<Sender<IrcMsgBuf>> {
+        self.raw_tx.lock().unwrap().clone()
+    }
+
+    fn handle_line(&self, line: &str) {
+        let parsed = match parse_process_line(line) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                warn!("{}: ignoring {}", self.name, err);
+                return;
+            }
+        };
+
+        match parsed {
+            ProcessLine::Reply(id, text) => {
+                if let Some(&(_, ref m)) = self.pending.lock().unwrap().get(&id) {
+                    m.reply(&text);
+                }
+            },
+            ProcessLine::Done(id) => {
+                self.pending.lock().unwrap().remove(&id);
+            },
+            ProcessLine::Action(target, text) => {
+                if let Some(tx) = self.raw_tx() {
+                    send_privmsg(&tx, &target, &format!("\x01ACTION {}\x01", text));
+                }
+            },
+            ProcessLine::Raw(raw) => {
+                if let Some(tx) = self.raw_tx() {
+                    match LegacyIrcMsg::new(raw.as_bytes().to_vec()) {
+                        Ok(msg) => { let _ = tx.send(IrcMsgBuf::from_legacy(msg)); },
+                        Err(err) => warn!("{}: bad raw line {:?}: {:?}", self.name, raw, err),
+                    }
+                }
+            },
+        }
+    }
+
+    fn serve(&self, child: Child, stdin: ChildStdin, reader: Reader) {
+        *self.child.lock().unwrap() = Some(child);
+        *self.stdin.lock().unwrap() = Some(stdin);
+        for line in reader.lines() {
+            match line {
+                Ok(line) => self.handle_line(&line),
+                Err(err) => {
+                    warn!("{}: error reading: {:?}", self.name, err);
+                    break;
+                }
+            }
+        }
+        *self.stdin.lock().unwrap() = None;
+        if let Some(mut child) = self.child.lock().unwrap().take() {
+            let _ = child.kill();
+            let status = child.wait();
+            warn!("{}: process exited: {:?}", self.name, status);
+        }
+    }
+}
+
+/// Serves the process, restarting it whenever it exits.
+fn supervise(config: ExternalPluginConfig, shared: Arc<Shared>, first: (Child, ChildStdin, Reader)) {
+    let mut process = Some(first);
+    let mut backoff_ms = MIN_BACKOFF_MS;
+    while !shared.stopping.load(Ordering::SeqCst) {
+        // the first process's hello was read by `configure`
+        let (child, stdin, reader) = match process.take() {
+            Some(process) => process,
+            None => {
+                let started = match spawn(&config) {
+                    Ok((mut child, stdin, reader)) => {
+                        let (reader, hello) = await_hello(&mut child, reader);
+                        if let Err(err) = hello {
+                            warn!("{}: {}", shared.name, err);
+                        }
+                        reader.map(|reader| (child, stdin, reader))
+                    },
+                    Err(err) => {
+                        warn!("{}: failed to start {:?}: {:?}", shared.name, config.command, err);
+                        None
+                    }
+                };
+                match started {
+                    Some(process) => process,
+                    None => {
+                        thread::sleep(Duration::from_millis(backoff_ms));
+                        backoff_ms = ::std::cmp::min(2 * backoff_ms, MAX_BACKOFF_MS);
+                        continue;
+                    }
+                }
+            },
+        };
+
+        let started = Instant::now();
+        shared.serve(child, stdin, reader);
+        if shared.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+
+        if Duration::from_millis(HEALTHY_AFTER_MS) < started.elapsed() {
+            backoff_ms = MIN_BACKOFF_MS;
+        }
+        warn!("{}: restarting in {}ms", shared.name, backoff_ms);
+        thread::sleep(Duration::from_millis(backoff_ms));
+        backoff_ms = ::std::cmp::min(2 * backoff_ms, MAX_BACKOFF_MS);
+    }
+}
+
+struct StdinWriter {
+    shared: Arc<Shared>,
+}
+
+impl Worker<String> for StdinWriter {
+    fn handle(&mut self, line: String) {
+        // written outside the lock, so that `stop` is not held up by a
+        // process which has stopped reading
+        let mut stdin = match self.shared.stdin.lock().unwrap().take() {
+            Some(stdin) => stdin,
+            // not running; the line is lost
+            None => return,
+        };
+        let written = stdin.write_all(line.as_bytes())
+            .and_then(|()| stdin.write_all(b"\n"))
+            .and_then(|()| stdin.flush());
+        if written.is_err() || self.shared.stopping.load(Ordering::SeqCst) {
+            return;
+        }
+        let mut slot = self.shared.stdin.lock().unwrap();
+        // unless a restarted process has taken its place
+        if slot.is_none() {
+            *slot = Some(stdin);
+        }
+    }
+}
+
+/// Adapts an external process to `RustBotPlugin`.
+pub struct ExternalPlugin {
+    config: ExternalPluginConfig,
+    shared: Arc<Shared>,
+    process: Option<(Child, ChildStdin, Reader)>,
+    writer: Option<WorkerPool<String>>,
+    next_id: u64,
+}
+
+impl ExternalPlugin {
+    pub fn new(config: ExternalPluginConfig) -> ExternalPlugin {
+        let shared = Shared {
+            name: config.name.clone(),
+            stopping: AtomicBool::new(false),
+            child: Mutex::new(None),
+            stdin: Mutex::new(None),
+            raw_tx: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        };
+        ExternalPlugin {
+            config: config,
+            shared: Arc::new(shared),
+            process: None,
+            writer: None,
+            next_id: 0,
+        }
+    }
+
+    fn send(&self, line: Json) -> Result<(), SubmitError> {
+        match self.writer {
+            Some(ref writer) => writer.submit(line.to_string()),
+            None => Err(SubmitError::Unavailable),
+        }
+    }
+}
+
+impl RustBotPlugin for ExternalPlugin {
+    fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        let (mut child, stdin, reader) = match spawn(&self.config) {
+            Ok(process) => process,
+            Err(err) => {
+                warn!("{}: failed to start {:?}: {:?}", self.config.name, self.config.command, err);
+                return;
+            }
+        };
+        let (reader, hello) = await_hello(&mut child, reader);
+        match hello {
+            Ok(formats) => {
+                for (idx, (format, description)) in formats.into_iter().enumerate() {
+                    match Format::from_str(&format) {
+                        Ok(parsed) => conf.map_format(Token(idx as u64), parsed, &description),
+                        Err(err) => warn!("{}: bad format {:?}: {:?}", self.config.name, format, err),
+                    }
+                }
+            },
+            Err(err) => warn!("{}: {}", self.config.name, err),
+        }
+        if let Some(reader) = reader {
+            self.process = Some((child, stdin, reader));
+        }
+    }
+
+    fn start(&mut self) {
+        let process = match self.process.take() {
+            Some(process) => process,
+            None => return,
+        };
+        let shared = self.shared.clone();
+        self.writer = Some(WorkerPool::start(&self.config.name,
+            WorkerConfig::new().queue_size(100),
+            move || StdinWriter { shared: shared.clone() }));
+
+        let config = self.config.clone();
+        let shared = self.shared.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("plugin-{}-supervisor", self.config.name))
+            .spawn(move || supervise(config, shared, process));
+        if let Err(err) = spawned {
+            warn!("{}: failed to spawn supervisor: {:?}", self.config.name, err);
+        }
+    }
+
+    fn on_message(&mut self, replier: &mut Replier, msg: &IrcMsg) {
+        {
+            let mut raw_tx = self.shared.raw_tx.lock().unwrap();
+            if raw_tx.is_none() {
+                *raw_tx = Some(replier.0.clone());
+            }
+        }
+        let line = json_object(vec![
+            ("type", Json::String("message".to_string())),
+            ("command", Json::String(msg.get_command().to_string())),
+            ("raw", Json::String(String::from_utf8_lossy(msg.as_bytes()).into_owned())),
+        ]);
+        // events are dropped while the process is behind
+        let _ = self.send(line);
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let source = match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(privmsg) => privmsg.source_nick().to_string(),
+            Err(_) => return,
+        };
+        let args = m.command().args().into_iter()
+            .map(|(key, value)| (key, Json::String(value)))
+            .collect();
+
+        self.next_id += 1;
+        let id = self.next_id;
+        let line = json_object(vec![
+            ("type", Json::String("dispatch".to_string())),
+            ("id", Json::U64(id)),
+            ("format", Json::U64(m.command().token.0)),
+            ("args", Json::Object(args)),
+            ("source", Json::String(source)),
+            ("target", Json::String(m.reply_target.clone())),
+        ]);
+
+        {
+            let mut pending = self.shared.pending.lock().unwrap();
+            let timeout = Duration::from_millis(PENDING_TIMEOUT_MS);
+            let expired: Vec<u64> = pending.iter()
+                .filter(|&(_, &(when, _))| timeout < when.elapsed())
+                .map(|(&id, _)| id)
+                .collect();
+            for id in expired.iter() {
+                pending.remove(id);
+            }
+            pending.insert(id, (Instant::now(), m.clone()));
+        }
+
+        match self.send(line) {
+            Ok(()) => (),
+            Err(SubmitError::QueueFull) => {
+                self.shared.pending.lock().unwrap().remove(&id);
+                m.reply(&format!("Service ``{}'' is busy; try again later", self.config.name));
+            },
+            Err(SubmitError::Unavailable) => {
+                self.shared.pending.lock().unwrap().remove(&id);
+                m.reply(&format!("Service ``{}'' unavailable", self.config.name));
+            },
+        }
+    }
+
+    fn stop(&mut self, deadline: Instant) {
+        self.shared.stopping.store(true, Ordering::SeqCst);
+        if let Some(writer) = self.writer.take() {
+            writer.shutdown(deadline);
+        }
+        // the supervisor sees stdout close, and returns as we are stopping
+        *self.shared.stdin.lock().unwrap() = None;
+        if let Some(mut child) = self.shared.child.lock().unwrap().take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_hello, parse_process_line, ProcessLine};
+
+    #[test]
+    fn test_read_hello() {
+        let mut hello: &[u8] = b"{\"type\": \"hello\", \"formats\": [{\"format\": \"weather {*place}\", \"description\": \"Weather\"}, {\"format\": \"w\"}]}\n{}\n";
+        assert_eq!(read_hello(&mut hello), Ok(vec![
+            ("weather {*place}".to_string(), "Weather".to_string()),
+            ("w".to_string(), "".to_string()),
+        ]));
+        assert_eq!(hello, b"{}\n");
+
+        let mut no_formats: &[u8] = b"{\"type\": \"hello\"}";
+        assert_eq!(read_hello(&mut no_formats), Ok(vec![]));
+        let mut wrong_type: &[u8] = b"{\"type\": \"reply\"}\n";
+        assert!(read_hello(&mut wrong_type).is_err());
+        let mut garbage: &[u8] = b"hello\n";
+        assert!(read_hello(&mut garbage).is_err());
+        let mut empty: &[u8] = b"";
+        assert!(read_hello(&mut empty).is_err());
+    }
+
+    #[test]
+    fn test_parse_process_line() {
+        assert_eq!(parse_process_line("{\"type\": \"reply\", \"id\": 7, \"text\": \"Sunny\"}"),
+            Ok(ProcessLine::Reply(7, "Sunny".to_string())));
+        assert_eq!(parse_process_line("{\"type\": \"done\", \"id\": 7}"), Ok(ProcessLine::Done(7)));
+        assert_eq!(parse_process_line("{\"type\": \"action\", \"target\": \"#x\", \"text\": \"waves\"}"),
+            Ok(ProcessLine::Action("#x".to_string(), "waves".to_string())));
+        assert_eq!(parse_process_line("{\"type\": \"raw\", \"line\": \"TOPIC #x :hi\"}"),
+            Ok(ProcessLine::Raw("TOPIC #x :hi".to_string())));
+
+        assert!(parse_process_line("{\"type\": \"reply\", \"text\": \"no id\"}").is_err());
+        assert!(parse_process_line("{\"type\": \"done\", \"id\": \"7\"}").is_err());
+        assert!(parse_process_line("{\"type\": \"teleport\"}").is_err());
+        assert!(parse_process_line("{\"type\":").is_err());
+    }
+}
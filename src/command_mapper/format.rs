@@ -15,9 +15,23 @@ pub enum ValueParseError {
     Mismatch(&'static str),
     MessageTooShort,
     MessageTooLong,
+    /// A quoted argument was opened but never closed
+    UnterminatedQuote,
+    /// The input ended with a backslash that escapes nothing
+    TrailingEscape,
 }
 pub type ValueResult<T> = Result<T, ValueParseError>;
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Quoting {
+    /// Arguments end at the first space.
+    None,
+    /// Arguments may contain spaces if wrapped in double or single quotes,
+    /// and a backslash escapes the following character outside of single
+    /// quotes.  Rest atoms are always taken verbatim.
+    Shell,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum AtomType {
     String,
@@ -34,6 +48,8 @@ pub enum Atom {
     // Rest(name)
     Rest(String),
     Whitespace,
+    // Optional(atoms, defaults)
+    Optional(Vec<Atom>, Vec<(String, Value)>),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -48,8 +64,13 @@ impl Value {
         match kind {
             AtomType::String => Ok(Value::String(input.to_string())),
             AtomType::WholeNumeric => {
-                // TODO: check if it is a numberish thing
-                Ok(Value::WholeNumeric(input.to_string()))
+                if input.is_empty() || !input.chars().all(|ch| ch.is_digit(10)) {
+                    return Err(ValueParseError::Mismatch("expected a whole number"));
+                }
+                match input.parse::<u64>() {
+                    Ok(_) => Ok(Value::WholeNumeric(input.to_string())),
+                    Err(_) => Err(ValueParseError::Mismatch("number too large")),
+                }
             }
         }
     }
@@ -62,6 +83,70 @@ fn consume_token<'a>(from: &'a str) -> ValueResult<(&'a str, &'a str)> {
     }
 }
 
+fn consume_quoted_token<'a>(from: &'a str) -> ValueResult<(String, &'a str)> {
+    let mut token = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = from.char_indices();
+
+    while let Some((idx, ch)) = chars.next() {
+        match (quote, ch) {
+            (None, ' ') => return Ok((token, &from[idx..])),
+            (None, '"') | (None, '\'') => quote = Some(ch),
+            (Some(open), ch) if open == ch => quote = None,
+            (Some('\''), ch) => token.push(ch),
+            (_, '\\') => match chars.next() {
+                Some((_, escaped)) => token.push(escaped),
+                None => return Err(ValueParseError::TrailingEscape),
+            },
+            (_, ch) => token.push(ch),
+        }
+    }
+    match quote {
+        Some(_) => Err(ValueParseError::UnterminatedQuote),
+        None => Ok((token, "")),
+    }
+}
+
+/// Splits `from` at each `separator` outside quotes and not escaped, by
+/// the rules of `consume_quoted_token`.  The pieces are left as they
+/// were.  A quote which is never closed is taken literally, so that an
+/// apostrophe does not hide the separators after it.
+pub fn split_unquoted<'a>(from: &'a str, separator: &str) -> Vec<&'a str> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut idx = 0;
+    // the open quote, and where it is
+    let mut quote: Option<(char, usize)> = None;
+
+    while idx < from.len() {
+        let ch = from[idx..].chars().next().unwrap();
+        let mut next = idx + ch.len_utf8();
+        match (quote, ch) {
+            (None, _) if from[idx..].starts_with(separator) => {
+                pieces.push(&from[start..idx]);
+                next = idx + separator.len();
+                start = next;
+            },
+            (None, '"') | (None, '\'') => quote = Some((ch, idx)),
+            (Some((open, _)), ch) if open == ch => quote = None,
+            (Some(('\'', _)), _) => (),
+            (_, '\\') => {
+                next += from[next..].chars().next().map(|escaped| escaped.len_utf8()).unwrap_or(0);
+            },
+            _ => (),
+        }
+        idx = next;
+        if from.len() <= idx {
+            if let Some((_, open_idx)) = quote.take() {
+                // never closed: scan again from just after it
+                idx = open_idx + 1;
+            }
+        }
+    }
+    pieces.push(&from[start..]);
+    pieces
+}
+
 fn consume_literal<'a>(from: &'a str, literal: &str) -> ValueResult<(&'a str, &'a str)> {
     let from_s = from.to_lowercase();
     if from_s.starts_with(literal) {
@@ -82,13 +167,18 @@ fn consume_whitespace<'a>(from: &'a str) -> (&'a str, &'a str) {
 
 
 impl Atom {
-    fn consume<'a>(&self, input: &'a str) -> ValueResult<(Option<Value>, &'a str)> {
+    fn consume<'a>(&self, input: &'a str, quoting: Quoting) -> ValueResult<(Option<Value>, &'a str)> {
         match *self {
             Atom::Literal(ref val) => {
                 let (lit, rest) = try!(consume_literal(input, &val));
                 let value = Value::Literal(lit.to_string());
                 Ok((Some(value), rest))
             },
+            Atom::Formatted(_, kind) if quoting == Quoting::Shell => {
+                let (token, rest) = try!(consume_quoted_token(input));
+                let value = try!(Value::parse(kind, &token));
+                Ok((Some(value), rest))
+            },
             Atom::Formatted(_, kind) => {
                 let (lit, rest) = try!(consume_token(input));
                 let value = try!(Value::parse(kind, lit));
@@ -104,14 +194,152 @@ impl Atom {
                     return Err(ValueParseError::Mismatch("Missing whitespace"));
                 }
                 Ok((None, rest))
+            },
+            Atom::Optional(_, _) => unreachable!("optional atoms are handled by consume_atoms"),
+        }
+    }
+}
+
+/// Inserts the defaults of an optional atom which was not present in the
+/// input, including the defaults of any optional atoms nested within it.
+fn apply_defaults(atoms: &[Atom], defaults: &[(String, Value)], args_map: &mut BTreeMap<String, Value>) {
+    for &(ref name, ref value) in defaults.iter() {
+        args_map.insert(name.clone(), value.clone());
+    }
+    for atom in atoms.iter() {
+        if let Atom::Optional(ref inner, ref inner_defaults) = *atom {
+            apply_defaults(inner, inner_defaults, args_map);
+        }
+    }
+}
+
+fn value_str(value: &Value) -> &str {
+    match *value {
+        Value::Literal(ref val) => val,
+        Value::String(ref val) => val,
+        Value::WholeNumeric(ref val) => val,
+    }
+}
+
+fn render_placeholder(out: &mut String, name: &str, suffix: &str, defaults: &[(String, Value)]) {
+    out.push('<');
+    out.push_str(name);
+    out.push_str(suffix);
+    for &(ref default_name, ref value) in defaults.iter() {
+        if default_name == name {
+            out.push('=');
+            out.push_str(value_str(value));
+        }
+    }
+    out.push('>');
+}
+
+fn render_usage(out: &mut String, atoms: &[Atom], defaults: &[(String, Value)]) {
+    for atom in atoms.iter() {
+        match *atom {
+            Atom::Literal(ref val) => out.push_str(val),
+            Atom::Whitespace => out.push(' '),
+            Atom::Formatted(ref name, _) => render_placeholder(out, name, "", defaults),
+            Atom::Rest(ref name) => render_placeholder(out, name, "...", defaults),
+            Atom::Optional(ref inner, ref inner_defaults) => {
+                // keep the leading whitespace outside of the brackets
+                let inner = match inner.first() {
+                    Some(&Atom::Whitespace) => {
+                        out.push(' ');
+                        &inner[1..]
+                    },
+                    _ => &inner[..],
+                };
+                out.push('[');
+                render_usage(out, inner, inner_defaults);
+                out.push(']');
             }
         }
     }
 }
 
+/// Consumes `atoms` from the start of `input`, returning what is left.
+/// With `whole`, only a match which leaves nothing but spaces will do.
+fn consume_atoms<'a>(atoms: &[Atom], quoting: Quoting, input: &'a str,
+                     args_map: &mut BTreeMap<String, Value>, whole: bool) -> ValueResult<&'a str> {
+    let mut remaining = input;
+
+    for (idx, atom) in atoms.iter().enumerate() {
+        // Optional atoms are greedy, but if the atoms after them fail to
+        // match with them taken, they are tried again without them.
+        if let Atom::Optional(ref inner, ref defaults) = *atom {
+            let mut inner_args = BTreeMap::new();
+            match consume_atoms(inner, quoting, remaining, &mut inner_args, false) {
+                Ok(tmp) => {
+                    let mut taken_args = args_map.clone();
+                    taken_args.extend(inner_args.into_iter());
+                    match consume_atoms(&atoms[idx + 1..], quoting, tmp, &mut taken_args, whole) {
+                        Ok(rest) => {
+                            *args_map = taken_args;
+                            return Ok(rest);
+                        },
+                        Err(ValueParseError::UnterminatedQuote) => {
+                            return Err(ValueParseError::UnterminatedQuote)
+                        },
+                        Err(ValueParseError::TrailingEscape) => {
+                            return Err(ValueParseError::TrailingEscape)
+                        },
+                        Err(_) => (),
+                    }
+                },
+                // A broken quote is an error even if the argument is optional
+                Err(ValueParseError::UnterminatedQuote) => {
+                    return Err(ValueParseError::UnterminatedQuote)
+                },
+                Err(ValueParseError::TrailingEscape) => {
+                    return Err(ValueParseError::TrailingEscape)
+                },
+                Err(_) => (),
+            }
+            apply_defaults(inner, defaults, args_map);
+            continue;
+        }
+
+        if remaining == "" {
+            return Err(ValueParseError::MessageTooShort)
+        }
+
+        let value = match atom.consume(remaining, quoting) {
+            Ok((Some(value), tmp)) => {
+                remaining = tmp;
+                value
+            },
+            Ok((None, tmp)) => {
+                remaining = tmp;
+                continue;
+            },
+            Err(err) => return Err(err)
+        };
+        let name = match *atom {
+            Atom::Literal(_) => continue,
+            Atom::Whitespace => continue,
+            Atom::Optional(_, _) => continue,
+            Atom::Formatted(ref name, _) => name.clone(),
+            Atom::Rest(ref name) => name.clone(),
+        };
+        match value {
+            Value::Literal(_) => (),
+            Value::String(_) |  Value::WholeNumeric(_) => {
+                args_map.insert(name, value);
+            },
+        };
+    }
+
+    if whole && !remaining.bytes().all(|x| x == b' ') {
+        return Err(ValueParseError::MessageTooLong)
+    }
+    Ok(remaining)
+}
+
 #[derive(Debug)]
 pub struct Format {
-    atoms: Vec<Atom>
+    atoms: Vec<Atom>,
+    quoting: Quoting,
 }
 
 #[derive(Debug, Clone)]
@@ -129,6 +357,20 @@ impl CommandPhrase {
             None => None
         }
     }
+
+    /// Every argument that was given or defaulted, as text.
+    pub fn args(&self) -> BTreeMap<String, String> {
+        let mut args = BTreeMap::new();
+        for (key, value) in self.args.iter() {
+            match *value {
+                Value::String(ref text) | Value::WholeNumeric(ref text) => {
+                    args.insert(key.clone(), text.clone());
+                },
+                Value::Literal(_) => (),
+            }
+        }
+        args
+    }
 }
 
 pub trait ValueExtract: Sized {
@@ -158,7 +400,10 @@ impl Format {
         match atom_parser::parse_atoms(definition) {
             Ok(atoms) => {
                 match atoms[0] {
-                    Atom::Literal(_) => Ok(Format { atoms: atoms }),
+                    Atom::Literal(_) => Ok(Format {
+                        atoms: atoms,
+                        quoting: Quoting::None,
+                    }),
                     _ => return Err(FormatParseError::InvalidAtom(
                         "first atom must be literal".to_string()))
                 }
@@ -167,6 +412,55 @@ impl Format {
         }
     }
 
+    /// The leading literal of the format, which names the command.
+    pub fn command_name(&self) -> &str {
+        match self.atoms[0] {
+            Atom::Literal(ref literal) => literal,
+            _ => unreachable!("first atom must be literal"),
+        }
+    }
+
+    /// A human readable rendering of the format, e.g. `seen <nick>`
+    /// or `top [<count=5>] [<query...>]`.
+    pub fn usage(&self) -> String {
+        let mut out = String::new();
+        render_usage(&mut out, &self.atoms, &[]);
+        out
+    }
+
+    /// Whether the command name is always followed by whitespace or the
+    /// end of input, so the format can be found by the input's first word.
+    pub fn is_indexable(&self) -> bool {
+        match self.atoms.get(1) {
+            None => true,
+            Some(&Atom::Whitespace) => true,
+            Some(&Atom::Optional(ref inner, _)) => inner.first() == Some(&Atom::Whitespace),
+            Some(_) => false,
+        }
+    }
+
+    /// Orders formats which accept the same input: more literal atoms win,
+    /// then more required arguments.
+    pub fn specificity(&self) -> (usize, usize) {
+        let mut literals = 0;
+        let mut required = 0;
+        for atom in self.atoms.iter() {
+            match *atom {
+                Atom::Literal(_) => literals += 1,
+                Atom::Formatted(_, _) | Atom::Rest(_) => required += 1,
+                Atom::Whitespace | Atom::Optional(_, _) => (),
+            }
+        }
+        (literals, required)
+    }
+
+    /// Sets how arguments are split from the input.  The default is
+    /// `Quoting::None`.
+    pub fn with_quoting(mut self, quoting: Quoting) -> Format {
+        self.quoting = quoting;
+        self
+    }
+
     pub fn parse(&self, token: Token, input: &str) -> ValueResult<CommandPhrase> {
         let original_input: &str = input;
         let input: &str = input;
@@ -176,48 +470,13 @@ impl Format {
             Atom::Literal(ref literal) => literal.to_string(),
             _ => return Err(ValueParseError::Mismatch("first atom must be literal"))
         };
-        let mut remaining = input;
-
-        for atom in self.atoms.iter() {
-            if remaining == "" {
-                return Err(ValueParseError::MessageTooShort)
-            }
-
-            let value = match atom.consume(remaining) {
-                Ok((Some(value), tmp)) => {
-                    remaining = tmp;
-                    value
-                },
-                Ok((None, tmp)) => {
-                    remaining = tmp;
-                    continue;
-                },
-                Err(err) => return Err(err)
-            };
-            let name = match *atom {
-                Atom::Literal(_) => continue,
-                Atom::Whitespace => continue,
-                Atom::Formatted(ref name, _) => name.clone(),
-                Atom::Rest(ref name) => name.clone(),
-            };
-            match value {
-                Value::Literal(_) => (),
-                Value::String(_) |  Value::WholeNumeric(_) => {
-                    args_map.insert(name, value);
-                },
-            };
-        }
-
-        if !remaining.bytes().all(|x| x == b' ') {
-            return Err(ValueParseError::MessageTooLong)
-        }
+        try!(consume_atoms(&self.atoms, self.quoting, input, &mut args_map, true));
         let cmd_phrase = CommandPhrase {
             token: token,
             command: command.trim_right_matches(' ').to_lowercase(),
             original_command: original_input.to_string(),
             args: args_map,
         };
-        println!("{:?} - {:?} is consuming on {:?}", token, input, cmd_phrase);
         Ok(cmd_phrase)
     }
 }
@@ -225,7 +484,7 @@ impl Format {
 // use self::atom_parser::parse_atom;
 
 pub mod atom_parser {
-    use super::{Atom, AtomType, FormatResult, FormatParseError};
+    use super::{Atom, AtomType, Value, FormatResult, FormatParseError};
 
     static ASCII_ALPHANUMERIC: [u8; 62] = [
         b'0', b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', b'9',
@@ -249,7 +508,53 @@ pub mod atom_parser {
         false
     }
 
+    fn split_default(atom: &str) -> (&str, Option<&str>) {
+        match atom.find('=') {
+            Some(idx) => (&atom[..idx], Some(&atom[1 + idx ..])),
+            None => (atom, None)
+        }
+    }
+
+    fn split_optional(atom: &str) -> (&str, bool) {
+        if atom.ends_with('?') {
+            (&atom[..atom.len() - 1], true)
+        } else {
+            (atom, false)
+        }
+    }
+
+    fn parse_default(kind: AtomType, default: &str) -> FormatResult<Value> {
+        match kind {
+            AtomType::String => Ok(Value::String(default.to_string())),
+            AtomType::WholeNumeric => match default.parse::<u64>() {
+                Ok(_) => Ok(Value::WholeNumeric(default.to_string())),
+                Err(_) => Err(FormatParseError::InvalidAtom(
+                    format!("default `{}' is not a whole number", default))),
+            }
+        }
+    }
+
+    /// Wraps `atom` in an `Atom::Optional` if it was marked optional with
+    /// a `?` or given a default value with `=`.
+    fn wrap_optional(atom: Atom, name: &str, kind: AtomType,
+                     optional: bool, default: Option<&str>) -> FormatResult<Atom> {
+        if name.len() == 0 || name.contains('?') {
+            return Err(FormatParseError::InvalidAtom(
+                format!("atom has invalid name `{}'", name)));
+        }
+        match default {
+            Some(default) => {
+                let value = try!(parse_default(kind, default));
+                Ok(Atom::Optional(vec![atom], vec![(name.to_string(), value)]))
+            },
+            None if optional => Ok(Atom::Optional(vec![atom], Vec::new())),
+            None => Ok(atom),
+        }
+    }
+
     fn parse_var_atom(atom: &str) -> FormatResult<Atom> {
+        let (atom, default) = split_default(atom);
+        let (atom, optional) = split_optional(atom);
         let (name, format_spec) = match atom.find(':') {
             Some(idx) => (&atom[..idx], Some(&atom[1 + idx ..])),
             None => (atom, None)
@@ -263,7 +568,15 @@ pub mod atom_parser {
                 format!("atom has unknown format specifier `{}'", spec))),
             None => AtomType::String
         };
-        Ok(Atom::Formatted(name.to_string(), format_kind))
+        let formatted = Atom::Formatted(name.to_string(), format_kind);
+        wrap_optional(formatted, name, format_kind, optional, default)
+    }
+
+    fn parse_rest_atom(atom: &str) -> FormatResult<Atom> {
+        let (name, default) = split_default(atom);
+        let (name, optional) = split_optional(name);
+        let rest = Atom::Rest(name.to_string());
+        wrap_optional(rest, name, AtomType::String, optional, default)
     }
 
     #[derive(Clone, Copy)]
@@ -272,7 +585,9 @@ pub mod atom_parser {
         InLiteral,
         InWhitespace,
         InVariable,
+        InVariableDefault,
         InRestVariable,
+        InRestVariableDefault,
         ForceEnd,
         Errored,
     }
@@ -280,6 +595,8 @@ pub mod atom_parser {
     struct AtomParser {
         byte_idx: usize,
         atoms: Vec<Atom>,
+        // start indices into `atoms' of the currently open `[...]' groups
+        groups: Vec<usize>,
         state: State,
         cur_atom: Vec<u8>,
         error: Option<FormatParseError>
@@ -290,52 +607,103 @@ pub mod atom_parser {
             AtomParser {
                 byte_idx: 0,
                 atoms: Vec::new(),
+                groups: Vec::new(),
                 state: State::Zero,
                 cur_atom: Vec::new(),
                 error: None,
             }
         }
 
+        fn push_atom(&mut self, atom: Atom) {
+            match atom {
+                Atom::Optional(mut inner, defaults) => {
+                    // An optional atom takes the whitespace leading up to it,
+                    // so the whitespace is only required if the atom is present.
+                    let group_start = self.groups.last().map(|x| *x).unwrap_or(0);
+                    if group_start < self.atoms.len() {
+                        if let Some(&Atom::Whitespace) = self.atoms.last() {
+                            self.atoms.pop();
+                            inner.insert(0, Atom::Whitespace);
+                        }
+                    }
+                    self.atoms.push(Atom::Optional(inner, defaults));
+                },
+                atom => self.atoms.push(atom),
+            }
+        }
+
         fn finalize_literal(&mut self) {
             {
                 // These should be fine unless we break parse_atom ...
                 let string = String::from_utf8_lossy(self.cur_atom.as_slice());
-                self.atoms.push(Atom::Rest(string.into_owned()));
+                self.atoms.push(Atom::Literal(string.into_owned()));
             }
             self.cur_atom.clear();
         }
 
+        fn finalize_variable(&mut self, next_state: State) -> State {
+            let atom_res = {
+                // These should be fine unless we break parse_atom ...
+                let string = String::from_utf8_lossy(self.cur_atom.as_slice());
+                match next_state {
+                    State::ForceEnd => parse_rest_atom(&string),
+                    _ => parse_var_atom(&string),
+                }
+            };
+            match atom_res {
+                Ok(atom) => {
+                    self.push_atom(atom);
+                    self.cur_atom.clear();
+                    next_state
+                },
+                Err(err) => {
+                    self.error = Some(err);
+                    State::Errored
+                }
+            }
+        }
+
+        fn open_group(&mut self) -> State {
+            let start = self.atoms.len();
+            self.groups.push(start);
+            State::Zero
+        }
+
+        fn close_group(&mut self, next_state: State) -> State {
+            let start = match self.groups.pop() {
+                Some(start) => start,
+                None => {
+                    self.error = Some(FormatParseError::BrokenFormat);
+                    return State::Errored;
+                }
+            };
+            let inner: Vec<Atom> = self.atoms.drain(start..).collect();
+            if inner.len() == 0 {
+                self.error = Some(FormatParseError::InvalidAtom(
+                    "optional group is empty".to_string()));
+                return State::Errored;
+            }
+            self.push_atom(Atom::Optional(inner, Vec::new()));
+            next_state
+        }
+
         fn push_byte(&mut self, byte: u8) {
             use self::State::{
-                Zero, InLiteral, InVariable, InRestVariable, ForceEnd, Errored, InWhitespace
+                Zero, InLiteral, InVariable, InVariableDefault, InRestVariable,
+                InRestVariableDefault, ForceEnd, Errored, InWhitespace
             };
 
             let new_state = match (self.state, byte) {
                 (Zero, b'{') => InVariable,
                 (Zero, b' ') => InWhitespace,
+                (Zero, b'[') => self.open_group(),
+                (Zero, b']') => self.close_group(Zero),
                 (Zero, cur_byte) => {
                     self.cur_atom.push(cur_byte);
                     InLiteral
                 }
 
-                (InVariable, b'}') => {
-                    let atom_res = {
-                        // These should be fine unless we break parse_atom ...
-                        let string = String::from_utf8_lossy(self.cur_atom.as_slice());
-                        parse_var_atom(&string)
-                    };
-                    match atom_res {
-                        Ok(atom) => {
-                            self.atoms.push(atom);
-                            self.cur_atom.clear();
-                            Zero
-                        },
-                        Err(err) => {
-                            self.error = Some(err);
-                            Errored
-                        }
-                    }
-                },
+                (InVariable, b'}') => self.finalize_variable(Zero),
                 (InVariable, b'*') if self.cur_atom.len() == 0 => {
                     InRestVariable
                 },
@@ -345,6 +713,14 @@ pub mod atom_parser {
                     InVariable
                 },
                 (InVariable, b':') => Errored,
+                (InVariable, b'?') if self.cur_atom.len() > 0 => {
+                    self.cur_atom.push(b'?');
+                    InVariable
+                },
+                (InVariable, b'=') if self.cur_atom.len() > 0 => {
+                    self.cur_atom.push(b'=');
+                    InVariableDefault
+                },
                 (InVariable, cur_byte) if is_ascii_alphanumeric(cur_byte) => {
                     self.cur_atom.push(cur_byte);
                     InVariable
@@ -354,9 +730,20 @@ pub mod atom_parser {
                     Errored
                 },
 
-                (InRestVariable, b'}') => {
-                    self.finalize_literal();
-                    ForceEnd
+                (InVariableDefault, b'}') => self.finalize_variable(Zero),
+                (InVariableDefault, cur_byte) => {
+                    self.cur_atom.push(cur_byte);
+                    InVariableDefault
+                },
+
+                (InRestVariable, b'}') => self.finalize_variable(ForceEnd),
+                (InRestVariable, b'?') if self.cur_atom.len() > 0 => {
+                    self.cur_atom.push(b'?');
+                    InRestVariable
+                },
+                (InRestVariable, b'=') if self.cur_atom.len() > 0 => {
+                    self.cur_atom.push(b'=');
+                    InRestVariableDefault
                 },
                 (InRestVariable, cur_byte) if is_ascii_alphanumeric(cur_byte) => {
                     self.cur_atom.push(cur_byte);
@@ -367,6 +754,12 @@ pub mod atom_parser {
                     Errored
                 },
 
+                (InRestVariableDefault, b'}') => self.finalize_variable(ForceEnd),
+                (InRestVariableDefault, cur_byte) => {
+                    self.cur_atom.push(cur_byte);
+                    InRestVariableDefault
+                },
+
                 (InWhitespace, b' ') => {
                     InWhitespace
                 },
@@ -375,6 +768,16 @@ pub mod atom_parser {
                     self.atoms.push(Atom::Whitespace);
                     InVariable
                 },
+                (InWhitespace, b'[') => {
+                    assert_eq!(self.cur_atom.len(), 0);
+                    self.atoms.push(Atom::Whitespace);
+                    self.open_group()
+                },
+                (InWhitespace, b']') => {
+                    assert_eq!(self.cur_atom.len(), 0);
+                    self.atoms.push(Atom::Whitespace);
+                    self.close_group(Zero)
+                },
                 (InWhitespace, cur_byte) => {
                     assert_eq!(self.cur_atom.len(), 0);
                     self.atoms.push(Atom::Whitespace);
@@ -383,29 +786,28 @@ pub mod atom_parser {
                 },
 
                 (InLiteral, b' ') => {
-                    {
-                        // These should be fine unless we break parse_atom ...
-                        let string = String::from_utf8_lossy(self.cur_atom.as_slice());
-                        self.atoms.push(Atom::Literal(string.into_owned()));
-                    }
-                    self.cur_atom.clear();
+                    self.finalize_literal();
                     InWhitespace
                 },
                 (InLiteral, b'{') => {
-                    {
-                        // These should be fine unless we break parse_atom ...
-                        let string = String::from_utf8_lossy(self.cur_atom.as_slice());
-                        self.atoms.push(Atom::Literal(string.into_owned()));
-                    }
-                    self.cur_atom.clear();
+                    self.finalize_literal();
                     InVariable
                 },
+                (InLiteral, b'[') => {
+                    self.finalize_literal();
+                    self.open_group()
+                },
+                (InLiteral, b']') => {
+                    self.finalize_literal();
+                    self.close_group(Zero)
+                },
                 (InLiteral, cur_byte)  => {
                     self.cur_atom.push(cur_byte);
                     InLiteral
                 },
 
                 (Errored, _) => Errored,
+                (ForceEnd, b']') => self.close_group(ForceEnd),
                 (ForceEnd, _) => {
                     self.error = Some(FormatParseError::BrokenFormat);
                     Errored
@@ -416,10 +818,12 @@ pub mod atom_parser {
         }
 
         fn finish(mut self) -> FormatResult<Vec<Atom>> {
+            if self.groups.len() > 0 {
+                return Err(FormatParseError::BrokenFormat);
+            }
             match self.state {
                 State::InLiteral => {
-                    let string = String::from_utf8_lossy(self.cur_atom.as_slice());
-                    self.atoms.push(Atom::Literal(string.into_owned()));
+                    self.finalize_literal();
                     Ok(self.atoms)
                 },
                 State::InWhitespace => {
@@ -429,7 +833,9 @@ pub mod atom_parser {
                 State::Zero | State::ForceEnd => Ok(self.atoms),
                 State::Errored => Err(self.error.unwrap()),
                 State::InVariable => Err(FormatParseError::BrokenFormat),
+                State::InVariableDefault => Err(FormatParseError::BrokenFormat),
                 State::InRestVariable => Err(FormatParseError::BrokenFormat),
+                State::InRestVariableDefault => Err(FormatParseError::BrokenFormat),
             }
         }
     }
@@ -453,7 +859,7 @@ pub mod atom_parser {
     #[cfg(test)]
     mod tests {
         use super::parse_atoms;
-        use super::super::{Atom, AtomType};
+        use super::super::{Atom, AtomType, Value};
 
         #[test]
         fn test_basics() {
@@ -489,6 +895,47 @@ pub mod atom_parser {
                 Err(err) => assert!(false, format!("{:?}", err))
             };
         }
+
+        #[test]
+        fn test_optional() {
+            let atoms = parse_atoms("deer {a?}").ok().unwrap();
+            assert_eq!(atoms, vec!(
+                Atom::Literal("deer".to_string()),
+                Atom::Optional(vec!(
+                    Atom::Whitespace,
+                    Atom::Formatted("a".to_string(), AtomType::String),
+                ), vec!()),
+            ));
+
+            let atoms = parse_atoms("deer {a:d=5}").ok().unwrap();
+            assert_eq!(atoms, vec!(
+                Atom::Literal("deer".to_string()),
+                Atom::Optional(vec!(
+                    Atom::Whitespace,
+                    Atom::Formatted("a".to_string(), AtomType::WholeNumeric),
+                ), vec!(("a".to_string(), Value::WholeNumeric("5".to_string())))),
+            ));
+
+            let atoms = parse_atoms("deer [{a:d}] {*b?}").ok().unwrap();
+            assert_eq!(atoms, vec!(
+                Atom::Literal("deer".to_string()),
+                Atom::Optional(vec!(
+                    Atom::Whitespace,
+                    Atom::Formatted("a".to_string(), AtomType::WholeNumeric),
+                ), vec!()),
+                Atom::Optional(vec!(
+                    Atom::Whitespace,
+                    Atom::Rest("b".to_string()),
+                ), vec!()),
+            ));
+
+            assert!(parse_atoms("deer {a:d=x}").is_err());
+            assert!(parse_atoms("deer {a?b}").is_err());
+            assert!(parse_atoms("deer [{a}").is_err());
+            assert!(parse_atoms("deer {a}]").is_err());
+            assert!(parse_atoms("deer []").is_err());
+            assert!(parse_atoms("deer {*b?} {c}").is_err());
+        }
     }
 }
 
@@ -632,3 +1079,173 @@ fn simple005() {
     assert_eq!(&cmdlet.command, "irc-colors");
 }
 
+#[test]
+fn optional001() {
+    let fmt = match Format::from_str("8ball {*query?}") {
+        Ok(fmt) => fmt,
+        Err(err) => panic!("parse failure: {:?}", err)
+    };
+
+    for cmd_str in ["8ball", "8ball "].iter() {
+        let cmdlet = match fmt.parse(Token(0), cmd_str) {
+            Ok(cmdlet) => cmdlet,
+            Err(err) => panic!("parse failure on {:?}: {:?}", cmd_str, err)
+        };
+        assert_eq!(&cmdlet.command, "8ball");
+        assert_eq!(cmdlet.get::<String>("query"), None);
+    }
+
+    let cmdlet = match fmt.parse(Token(0), "8ball will it rain?") {
+        Ok(cmdlet) => cmdlet,
+        Err(err) => panic!("parse failure: {:?}", err)
+    };
+    assert_eq!(
+        cmdlet.get::<String>("query"),
+        Some("will it rain?".to_string()));
+
+    match fmt.parse(Token(0), "8balls") {
+        Err(ValueParseError::MessageTooLong) => (),
+        p @ _ => panic!("8balls should not parse. Got {:?}", p),
+    };
+}
+
+#[test]
+fn optional002() {
+    let fmt = match Format::from_str("top {count:d=5} [in {channel}]") {
+        Ok(fmt) => fmt,
+        Err(err) => panic!("parse failure: {:?}", err)
+    };
+
+    let cmdlet = fmt.parse(Token(0), "top").unwrap();
+    assert_eq!(cmdlet.get::<u64>("count"), Some(5));
+    assert_eq!(cmdlet.get::<String>("channel"), None);
+
+    let cmdlet = fmt.parse(Token(0), "top 10").unwrap();
+    assert_eq!(cmdlet.get::<u64>("count"), Some(10));
+
+    let cmdlet = fmt.parse(Token(0), "top 10 in #sample").unwrap();
+    assert_eq!(cmdlet.get::<u64>("count"), Some(10));
+    assert_eq!(cmdlet.get::<String>("channel"), Some("#sample".to_string()));
+
+    assert!(fmt.parse(Token(0), "top 10 in").is_err());
+}
+
+#[test]
+fn optional003() {
+    let fmt = Format::from_str("top {count:d=5} [in {channel}]").unwrap();
+
+    // a word which is not a number is not taken as the count
+    let cmdlet = fmt.parse(Token(0), "top in #sample").unwrap();
+    assert_eq!(cmdlet.get::<u64>("count"), Some(5));
+    assert_eq!(cmdlet.get::<String>("channel"), Some("#sample".to_string()));
+    match fmt.parse(Token(0), "top abc") {
+        Err(ValueParseError::MessageTooLong) => (),
+        p @ _ => panic!("top abc should not parse. Got {:?}", p),
+    };
+    assert!(fmt.parse(Token(0), "top -3").is_err());
+    assert!(fmt.parse(Token(0), "top 99999999999999999999").is_err());
+
+    // an optional atom is given up if the rest cannot match without it
+    let fmt = Format::from_str("pick {count:d=1} {*options}").unwrap();
+    let cmdlet = fmt.parse(Token(0), "pick 3").unwrap();
+    assert_eq!(cmdlet.get::<u64>("count"), Some(1));
+    assert_eq!(cmdlet.get::<String>("options"), Some("3".to_string()));
+    let cmdlet = fmt.parse(Token(0), "pick 3 a, b").unwrap();
+    assert_eq!(cmdlet.get::<u64>("count"), Some(3));
+    assert_eq!(cmdlet.get::<String>("options"), Some("a, b".to_string()));
+}
+
+#[test]
+fn quoting001() {
+    let fmt = Format::from_str("seen {nick} {*rest?}").unwrap()
+        .with_quoting(Quoting::Shell);
+
+    let cmdlet = fmt.parse(Token(0), "seen \"foo bar\" baz").unwrap();
+    assert_eq!(cmdlet.get::<String>("nick"), Some("foo bar".to_string()));
+    assert_eq!(cmdlet.get::<String>("rest"), Some("baz".to_string()));
+
+    let cmdlet = fmt.parse(Token(0), "seen 'it\\s' \"a \\\"b\\\"\"").unwrap();
+    assert_eq!(cmdlet.get::<String>("nick"), Some("it\\s".to_string()));
+    assert_eq!(cmdlet.get::<String>("rest"), Some("\"a \\\"b\\\"\"".to_string()));
+
+    let cmdlet = fmt.parse(Token(0), "seen foo\\ bar").unwrap();
+    assert_eq!(cmdlet.get::<String>("nick"), Some("foo bar".to_string()));
+
+    let cmdlet = fmt.parse(Token(0), "seen a\"b c\"d").unwrap();
+    assert_eq!(cmdlet.get::<String>("nick"), Some("ab cd".to_string()));
+
+    let cmdlet = fmt.parse(Token(0), "seen \"\"").unwrap();
+    assert_eq!(cmdlet.get::<String>("nick"), Some("".to_string()));
+}
+
+#[test]
+fn quoting002() {
+    let fmt = Format::from_str("seen {nick} {other?}").unwrap()
+        .with_quoting(Quoting::Shell);
+
+    match fmt.parse(Token(0), "seen \"foo bar") {
+        Err(ValueParseError::UnterminatedQuote) => (),
+        p @ _ => panic!("should not parse. Got {:?}", p),
+    };
+    match fmt.parse(Token(0), "seen foo 'bar") {
+        Err(ValueParseError::UnterminatedQuote) => (),
+        p @ _ => panic!("should not parse. Got {:?}", p),
+    };
+    match fmt.parse(Token(0), "seen foo\\") {
+        Err(ValueParseError::TrailingEscape) => (),
+        p @ _ => panic!("should not parse. Got {:?}", p),
+    };
+
+    let unquoted = Format::from_str("seen {nick}").unwrap();
+    match unquoted.parse(Token(0), "seen \"foo bar\"") {
+        Err(ValueParseError::MessageTooLong) => (),
+        p @ _ => panic!("should not parse. Got {:?}", p),
+    };
+}
+
+#[test]
+fn quoting003() {
+    let cases: &[(&str, &[&str])] = &[
+        ("pick a, b | u", &["pick a, b", "u"]),
+        ("pick \"a | b\", c | u", &["pick \"a | b\", c", "u"]),
+        ("pick 'a | b', c", &["pick 'a | b', c"]),
+        ("pick a \\| b", &["pick a \\| b"]),
+        ("pick don't | u", &["pick don't", "u"]),
+        ("say \"it's | fine\" | u", &["say \"it's | fine\"", "u"]),
+        ("say é | ü\\", &["say é", "ü\\"]),
+        ("a|b", &["a|b"]),
+    ];
+    for &(input, pieces) in cases.iter() {
+        assert_eq!(split_unquoted(input, " | "), pieces);
+    }
+}
+
+#[test]
+fn usage001() {
+    let cases = [
+        ("ping", "ping"),
+        ("seen {nick:s}", "seen <nick>"),
+        ("8ball {*query?}", "8ball [<query...>]"),
+        ("top {count:d=5} [in {channel}]", "top [<count=5>] [in <channel>]"),
+    ];
+    for &(fmt_str, usage) in cases.iter() {
+        let fmt = Format::from_str(fmt_str).unwrap();
+        assert_eq!(fmt.usage(), usage);
+    }
+    assert_eq!(Format::from_str("seen {nick}").unwrap().command_name(), "seen");
+}
+
+#[test]
+fn specificity001() {
+    let colors = Format::from_str("irc-colors").unwrap();
+    let colors_more = Format::from_str("irc-colors more").unwrap();
+    let seen = Format::from_str("seen {nick} {*rest?}").unwrap();
+    assert!(colors.specificity() < colors_more.specificity());
+    assert_eq!(seen.specificity(), (1, 1));
+
+    assert!(colors.is_indexable());
+    assert!(seen.is_indexable());
+    assert!(Format::from_str("8ball {*query?}").unwrap().is_indexable());
+    assert!(!Format::from_str("deer{a}").unwrap().is_indexable());
+    assert!(!Format::from_str("deer[{a}]").unwrap().is_indexable());
+}
@@ -0,0 +1,118 @@
+use irc::{IrcMsg, server};
+use irc::legacy::FrozenState;
+
+use super::{Replier, RustBotPlugin};
+
+/// A message which has its own hook on `RustBotPlugin`
+pub enum Hook<'a> {
+    Join(&'a server::Join),
+    Part(&'a server::Part),
+    Quit(&'a server::Quit),
+    Kick(&'a server::Kick),
+    Nick(&'a server::Nick),
+    Mode(&'a server::Mode),
+    Topic(&'a server::Topic),
+    Notice(&'a server::Notice),
+    Invite(&'a server::Invite),
+}
+
+impl<'a> Hook<'a> {
+    pub fn from_msg(msg: &'a IrcMsg) -> Option<Hook<'a>> {
+        if let Ok(join) = msg.as_tymsg::<&server::Join>() {
+            return Some(Hook::Join(join));
+        }
+        if let Ok(part) = msg.as_tymsg::<&server::Part>() {
+            return Some(Hook::Part(part));
+        }
+        if let Ok(quit) = msg.as_tymsg::<&server::Quit>() {
+            return Some(Hook::Quit(quit));
+        }
+        if let Ok(kick) = msg.as_tymsg::<&server::Kick>() {
+            return Some(Hook::Kick(kick));
+        }
+        if let Ok(nick) = msg.as_tymsg::<&server::Nick>() {
+            return Some(Hook::Nick(nick));
+        }
+        if let Ok(mode) = msg.as_tymsg::<&server::Mode>() {
+            return Some(Hook::Mode(mode));
+        }
+        if let Ok(topic) = msg.as_tymsg::<&server::Topic>() {
+            return Some(Hook::Topic(topic));
+        }
+        if let Ok(notice) = msg.as_tymsg::<&server::Notice>() {
+            return Some(Hook::Notice(notice));
+        }
+        if let Ok(invite) = msg.as_tymsg::<&server::Invite>() {
+            return Some(Hook::Invite(invite));
+        }
+        None
+    }
+
+    /// The state a hook should see.  A departing user has already been
+    /// removed from `after`, so PART, KICK and QUIT see `before` instead.
+    pub fn snapshot<'s>(&self, before: &'s FrozenState, after: &'s FrozenState) -> &'s FrozenState {
+        match *self {
+            Hook::Part(_) | Hook::Quit(_) | Hook::Kick(_) => before,
+            _ => after,
+        }
+    }
+
+    pub fn call(&self, plugin: &mut RustBotPlugin, replier: &mut Replier, state: &FrozenState) {
+        match *self {
+            Hook::Join(join) => plugin.on_join(replier, state, join),
+            Hook::Part(part) => plugin.on_part(replier, state, part),
+            Hook::Quit(quit) => plugin.on_quit(replier, state, quit),
+            Hook::Kick(kick) => plugin.on_kick(replier, state, kick),
+            Hook::Nick(nick) => plugin.on_nick(replier, state, nick),
+            Hook::Mode(mode) => plugin.on_mode(replier, state, mode),
+            Hook::Topic(topic) => plugin.on_topic(replier, state, topic),
+            Hook::Notice(notice) => plugin.on_notice(replier, state, notice),
+            Hook::Invite(invite) => plugin.on_invite(replier, state, invite),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use irc::IrcMsgBuf;
+    use irc::legacy::{BundlerManager, IrcMsg, JoinBundlerTrigger, State};
+
+    use super::Hook;
+
+    fn feed(bundler: &mut BundlerManager, state: &mut State, line: &str) {
+        let msg = IrcMsg::new(line.as_bytes().to_vec()).unwrap();
+        for event in bundler.on_irc_msg(&msg).into_iter() {
+            state.on_event(&event);
+        }
+    }
+
+    #[test]
+    fn test_quit_hook_resolves_user() {
+        let mut state = State::new();
+        state.set_self_nick("rustbot");
+        let mut bundler = BundlerManager::with_defaults();
+        bundler.add_bundler_trigger(Box::new(JoinBundlerTrigger::new(b"rustbot")));
+
+        feed(&mut bundler, &mut state, ":rustbot!bot@host JOIN #sample");
+        feed(&mut bundler, &mut state, ":server 353 rustbot = #sample :rustbot bob");
+        feed(&mut bundler, &mut state, ":server 366 rustbot #sample :End of /NAMES list.");
+        let before = state.clone_frozen();
+        assert!(before.identify_nick("bob").is_some());
+
+        let quit = ":bob!b@host QUIT :bye";
+        feed(&mut bundler, &mut state, quit);
+        let after = state.clone_frozen();
+        assert!(after.identify_nick("bob").is_none());
+
+        let quit = IrcMsgBuf::from_legacy(IrcMsg::new(quit.as_bytes().to_vec()).unwrap());
+        let hook = Hook::from_msg(&quit).unwrap();
+        assert!(hook.snapshot(&before, &after).identify_nick("bob").is_some());
+
+        let join = ":carol!c@host JOIN #sample";
+        feed(&mut bundler, &mut state, join);
+        let later = state.clone_frozen();
+        let join = IrcMsgBuf::from_legacy(IrcMsg::new(join.as_bytes().to_vec()).unwrap());
+        let hook = Hook::from_msg(&join).unwrap();
+        assert!(hook.snapshot(&after, &later).identify_nick("carol").is_some());
+    }
+}
@@ -0,0 +1,361 @@
+//! Who is behind a nick: their `user@host`, and the services account they
+//! are logged in to, as far as the server has told us.
+//!
+//! The bot requests the `account-notify`, `extended-join` and `chghost`
+//! capabilities, and sends a WHOX query when it joins a channel.  So it
+//! learns the accounts of those already there from the query, of those
+//! joining later from their JOIN, and whenever anyone logs in or out.
+//! Users are forgotten once they share no channel with the bot, and an
+//! account is only believed for messages from the `user@host` it was
+//! learned for.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use utils::glob::glob_match;
+
+/// Marks the replies to our own WHOX queries.
+const WHOX_TOKEN: &'static str = "745";
+
+/// The capabilities requested before registering, of those the server
+/// offers.
+const CAPABILITIES: &'static [&'static str] = &["account-notify", "extended-join", "chghost"];
+
+/// A WHOX query for a channel or nick, the replies to which
+/// `Identities::observe` understands.
+pub fn whox_query(target: &str) -> String {
+    format!("WHO {} %tcnuha,{}", target, WHOX_TOKEN)
+}
+
+/// Capability negotiation while registering: `CAP LS`, a `CAP REQ` for
+/// those of `CAPABILITIES` the server lists, then `CAP END` once it has
+/// answered.  Servers without CAP ignore `CAP LS` and register as usual.
+pub struct CapNegotiation {
+    // as listed so far, for replies split over several lines
+    offered: Vec<String>,
+}
+
+impl CapNegotiation {
+    pub fn new() -> CapNegotiation {
+        CapNegotiation { offered: Vec::new() }
+    }
+
+    /// Sent before `USER` and `NICK`; registration then waits for `CAP END`.
+    pub fn start(&self) -> &'static str {
+        "CAP LS 302"
+    }
+
+    /// What to send in reply to a raw message from the server, if anything.
+    pub fn observe(&mut self, raw: &str) -> Option<String> {
+        let line = match parse_line(raw) {
+            Some(ref line) if line.command == "CAP" => line.params.clone(),
+            _ => return None,
+        };
+        // <nick> <subcommand> [*] :<capabilities>
+        match (line.get(1).cloned(), line.len()) {
+            (Some("LS"), 4) if line[2] == "*" => {
+                self.offered.extend(line[3].split(' ').map(|cap| cap.to_string()));
+                None
+            },
+            (Some("LS"), 3) => {
+                self.offered.extend(line[2].split(' ').map(|cap| cap.to_string()));
+                let wanted: Vec<&str> = CAPABILITIES.iter()
+                    .filter(|&&wanted| self.offered.iter()
+                        .any(|offered| offered.split('=').next() == Some(wanted)))
+                    .cloned()
+                    .collect();
+                if wanted.is_empty() {
+                    Some("CAP END".to_string())
+                } else {
+                    Some(format!("CAP REQ :{}", wanted.join(" ")))
+                }
+            },
+            (Some("ACK"), _) | (Some("NAK"), _) => Some("CAP END".to_string()),
+            _ => None,
+        }
+    }
+}
+
+struct Line<'a> {
+    // nick and `user@host`
+    source: Option<(&'a str, &'a str)>,
+    command: &'a str,
+    params: Vec<&'a str>,
+}
+
+fn parse_line(raw: &str) -> Option<Line> {
+    let mut rest = raw.trim_right_matches(|ch: char| ch == '\r' || ch == '\n');
+    let mut source = None;
+    if rest.starts_with(':') {
+        let end = match rest.find(' ') {
+            Some(end) => end,
+            None => return None,
+        };
+        let prefix = &rest[1..end];
+        if let Some(bang) = prefix.find('!') {
+            source = Some((&prefix[..bang], &prefix[bang + 1..]));
+        }
+        rest = &rest[end + 1..];
+    }
+
+    let mut command = None;
+    let mut params = Vec::new();
+    while rest.len() > 0 {
+        if command.is_some() && rest.starts_with(':') {
+            params.push(&rest[1..]);
+            break;
+        }
+        let (word, remainder) = match rest.find(' ') {
+            Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+            None => (rest, ""),
+        };
+        rest = remainder;
+        if word.len() == 0 {
+            continue;
+        }
+        match command {
+            Some(_) => params.push(word),
+            None => command = Some(word),
+        }
+    }
+    command.map(|command| Line { source: source, command: command, params: params })
+}
+
+/// The `user@host` of whoever sent a raw message, if a user did.
+pub fn source_mask(raw: &str) -> Option<&str> {
+    parse_line(raw).and_then(|line| line.source).map(|(_, mask)| mask)
+}
+
+/// Whether `entry`, from the `admins` setting, names this user.  Entries
+/// containing `!` are `nick!user@host` masks, in which `*` and `?` are
+/// wildcards; anything else is a services account.
+pub fn admin_matches(entry: &str, nick: &str, mask: Option<&str>, account: Option<&str>) -> bool {
+    if entry.contains('!') {
+        match mask {
+            Some(mask) => glob_match(entry, &format!("{}!{}", nick, mask)),
+            None => false,
+        }
+    } else {
+        match account {
+            Some(account) => account.to_lowercase() == entry.to_lowercase(),
+            None => false,
+        }
+    }
+}
+
+struct KnownUser {
+    mask: String,
+    account: Option<String>,
+    // lowercased
+    channels: BTreeSet<String>,
+}
+
+type Users = HashMap<String, KnownUser>;
+
+/// The user behind `nick` as of a message from `mask`.  If they have
+/// changed `user@host` without our noticing, their account is forgotten.
+fn known<'a>(users: &'a mut Users, nick: &str, mask: &str) -> &'a mut KnownUser {
+    let user = users.entry(nick.to_lowercase()).or_insert_with(|| KnownUser {
+        mask: mask.to_string(),
+        account: None,
+        channels: BTreeSet::new(),
+    });
+    if user.mask != mask {
+        user.mask = mask.to_string();
+        user.account = None;
+    }
+    user
+}
+
+/// `nick` has left `channel`; if it was us, everyone has.
+fn leave(users: &mut Users, self_nick: &str, nick: &str, channel: &str) {
+    let channel = channel.to_lowercase();
+    if nick.to_lowercase() == self_nick.to_lowercase() {
+        for user in users.values_mut() {
+            user.channels.remove(&channel);
+        }
+    } else if let Some(user) = users.get_mut(&nick.to_lowercase()) {
+        user.channels.remove(&channel);
+    }
+    let gone: Vec<String> = users.iter()
+        .filter(|&(_, user)| user.channels.len() == 0)
+        .map(|(nick, _)| nick.clone())
+        .collect();
+    for nick in gone.iter() {
+        users.remove(nick);
+    }
+}
+
+/// What is known of the users the bot shares channels with; a handle
+/// cheap to clone.
+#[derive(Clone)]
+pub struct Identities {
+    // by lowercased nick
+    users: Arc<Mutex<Users>>,
+}
+
+impl Identities {
+    pub fn new() -> Identities {
+        Identities { users: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// The account `nick` is logged in to, provided the message came from
+    /// the `user@host` the account was learned for.
+    pub fn account(&self, nick: &str, mask: &str) -> Option<String> {
+        let users = self.users.lock().unwrap();
+        match users.get(&nick.to_lowercase()) {
+            Some(user) if user.mask == mask => user.account.clone(),
+            _ => None,
+        }
+    }
+
+    /// The account `nick` is logged in to, as last seen from whatever
+    /// `user@host` they are using now.
+    pub fn account_of(&self, nick: &str) -> Option<String> {
+        let users = self.users.lock().unwrap();
+        users.get(&nick.to_lowercase()).and_then(|user| user.account.clone())
+    }
+
+    /// Learns from a raw message from the server, returning a WHOX query
+    /// to send if more should be asked.
+    pub fn observe(&self, self_nick: &str, raw: &str) -> Option<String> {
+        let line = match parse_line(raw) {
+            Some(line) => line,
+            None => return None,
+        };
+        let mut users = self.users.lock().unwrap();
+
+        if line.command == "354" {
+            // <self> <token> <channel> <user> <host> <nick> <account>
+            if line.params.len() == 7 && line.params[1] == WHOX_TOKEN {
+                let params = &line.params;
+                let user = known(&mut users, params[5], &format!("{}@{}", params[3], params[4]));
+                user.account = match params[6] {
+                    "0" => None,
+                    account => Some(account.to_string()),
+                };
+                if params[2] != "*" {
+                    user.channels.insert(params[2].to_lowercase());
+                }
+            }
+            return None;
+        }
+
+        let (nick, mask) = match line.source {
+            Some(source) => source,
+            None => return None,
+        };
+        let is_self = nick.to_lowercase() == self_nick.to_lowercase();
+        if !is_self && users.contains_key(&nick.to_lowercase()) {
+            known(&mut users, nick, mask);
+        }
+
+        match (line.command, line.params.get(0).cloned(), line.params.get(1).cloned()) {
+            ("JOIN", Some(channel), _) if is_self => return Some(whox_query(channel)),
+            // with `extended-join`: <channel> <account> :<realname>
+            ("JOIN", Some(channel), account) => {
+                let user = known(&mut users, nick, mask);
+                user.channels.insert(channel.to_lowercase());
+                match account {
+                    Some("*") => user.account = None,
+                    Some(account) => user.account = Some(account.to_string()),
+                    None => (),
+                }
+            },
+            ("PART", Some(channel), _) => leave(&mut users, self_nick, nick, channel),
+            ("KICK", Some(channel), Some(kicked)) => leave(&mut users, self_nick, kicked, channel),
+            ("QUIT", _, _) => {
+                users.remove(&nick.to_lowercase());
+            },
+            ("NICK", Some(new_nick), _) => {
+                if let Some(user) = users.remove(&nick.to_lowercase()) {
+                    users.insert(new_nick.to_lowercase(), user);
+                }
+            },
+            ("ACCOUNT", Some(account), _) => {
+                if let Some(user) = users.get_mut(&nick.to_lowercase()) {
+                    user.account = match account {
+                        "*" => None,
+                        account => Some(account.to_string()),
+                    };
+                }
+            },
+            ("CHGHOST", Some(new_user), Some(new_host)) => {
+                if let Some(user) = users.get_mut(&nick.to_lowercase()) {
+                    user.mask = format!("{}@{}", new_user, new_host);
+                }
+            },
+            _ => (),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CapNegotiation, Identities, admin_matches, whox_query};
+
+    #[test]
+    fn test_cap_negotiation() {
+        let mut caps = CapNegotiation::new();
+        assert_eq!(caps.observe(":srv NOTICE * :*** Looking up your hostname"), None);
+        assert_eq!(caps.observe(":srv CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL chghost"), None);
+        assert_eq!(caps.observe(":srv CAP * LS :account-notify away-notify"),
+            Some("CAP REQ :account-notify chghost".to_string()));
+        assert_eq!(caps.observe(":srv CAP * ACK :account-notify chghost"), Some("CAP END".to_string()));
+
+        let mut caps = CapNegotiation::new();
+        assert_eq!(caps.observe(":srv CAP * LS :multi-prefix"), Some("CAP END".to_string()));
+        let mut caps = CapNegotiation::new();
+        assert_eq!(caps.observe(":srv CAP * LS :extended-join"),
+            Some("CAP REQ :extended-join".to_string()));
+        assert_eq!(caps.observe(":srv CAP * NAK :extended-join"), Some("CAP END".to_string()));
+    }
+
+    #[test]
+    fn test_observe() {
+        let ids = Identities::new();
+        assert_eq!(ids.observe("bot", ":bot!b@bot.host JOIN #x"), Some(whox_query("#x")));
+        ids.observe("bot", ":srv 354 bot 745 #x ~a alice.host Alice alice\r\n");
+        ids.observe("bot", ":srv 354 bot 745 #x ~c carol.host carol 0");
+        assert_eq!(ids.account("alice", "~a@alice.host"), Some("alice".to_string()));
+        assert_eq!(ids.account("alice", "~a@elsewhere"), None);
+        assert_eq!(ids.account("carol", "~c@carol.host"), None);
+        assert_eq!(ids.account_of("ALICE"), Some("alice".to_string()));
+        assert_eq!(ids.account_of("carol"), None);
+        assert_eq!(ids.account_of("nobody"), None);
+
+        // someone taking a nick we know cannot take its account
+        assert_eq!(ids.observe("bot", ":Bob!~b@bob.host JOIN #x bobby :Bob B."), None);
+        assert_eq!(ids.observe("bot", ":Dave!~d@d.host JOIN #x * :Dave"), None);
+        assert_eq!(ids.account_of("dave"), None);
+        assert_eq!(ids.observe("bot", ":Erin!~e@e.host JOIN #x"), None);
+        ids.observe("bot", ":Bob!~b@bob.host NICK :robert");
+        assert_eq!(ids.account("robert", "~b@bob.host"), Some("bobby".to_string()));
+        ids.observe("bot", ":robert!~b@other.host PRIVMSG #x :hi");
+        assert_eq!(ids.account("robert", "~b@other.host"), None);
+
+        ids.observe("bot", ":carol!~c@carol.host ACCOUNT carol");
+        assert_eq!(ids.account("carol", "~c@carol.host"), Some("carol".to_string()));
+        ids.observe("bot", ":carol!~c@carol.host CHGHOST ~c carol.cloak");
+        assert_eq!(ids.account("carol", "~c@carol.cloak"), Some("carol".to_string()));
+        ids.observe("bot", ":carol!~c@carol.cloak ACCOUNT *");
+        assert_eq!(ids.account("carol", "~c@carol.cloak"), None);
+
+        ids.observe("bot", ":alice!~a@alice.host PART #x :bye");
+        assert_eq!(ids.account("alice", "~a@alice.host"), None);
+        ids.observe("bot", ":srv 354 bot 745 #x ~c carol.cloak carol carol");
+        ids.observe("bot", ":op!o@op.host KICK #x bot :out");
+        assert_eq!(ids.account("carol", "~c@carol.cloak"), None);
+    }
+
+    #[test]
+    fn test_admin_matches() {
+        assert!(admin_matches("Alice", "alice", Some("~a@host"), Some("alice")));
+        assert!(!admin_matches("alice", "alice", Some("~a@host"), None));
+        assert!(!admin_matches("alice", "mallory", Some("~m@host"), Some("mallory")));
+        assert!(admin_matches("*!*@staff.example", "bob", Some("~b@staff.example"), None));
+        assert!(!admin_matches("*!*@staff.example", "bob", Some("~b@evil.example"), Some("bob")));
+        assert!(!admin_matches("*!*@staff.example", "bob", None, None));
+    }
+}
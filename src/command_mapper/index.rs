@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use super::{Token, Format, CommandPhrase};
+
+/// Locates a mapped format: the index of the plugin which mapped it, and
+/// the index of the format amongst those the plugin mapped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FormatRef {
+    pub plugin: usize,
+    pub format: usize,
+}
+
+/// Finds the formats which might accept a message without trying all
+/// of them.  Formats are keyed by their command name, case-folded.
+pub struct CommandIndex {
+    by_command: HashMap<String, Vec<FormatRef>>,
+    // formats where the command name may run into an argument, such as
+    // `deer{a}`, and must be tried against every message
+    unindexed: Vec<FormatRef>,
+}
+
+fn first_word(message_body: &str) -> &str {
+    match message_body.find(' ') {
+        Some(idx) => &message_body[..idx],
+        None => message_body,
+    }
+}
+
+impl CommandIndex {
+    pub fn new() -> CommandIndex {
+        CommandIndex {
+            by_command: HashMap::new(),
+            unindexed: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, format_ref: FormatRef, format: &Format) {
+        if format.is_indexable() {
+            let key = format.command_name().to_lowercase();
+            self.by_command.entry(key).or_insert(Vec::new()).push(format_ref);
+        } else {
+            self.unindexed.push(format_ref);
+        }
+    }
+
+    /// Whether any indexed format uses `name` as its command name.
+    pub fn contains_command(&self, name: &str) -> bool {
+        self.by_command.contains_key(&name.to_lowercase())
+    }
+
+    /// The formats which might accept `message_body`, in registration order.
+    pub fn candidates(&self, message_body: &str) -> Vec<FormatRef> {
+        let key = first_word(message_body).to_lowercase();
+        let mut candidates = self.unindexed.clone();
+        if let Some(refs) = self.by_command.get(&key) {
+            candidates.extend(refs.iter().cloned());
+        }
+        candidates.sort();
+        candidates
+    }
+
+    /// Parses `message_body` with each candidate format.  If several accept
+    /// it, the most specific format wins (see `Format::specificity`), with
+    /// ties going to the format registered first.
+    pub fn best_match<'a, F>(&self, message_body: &str, lookup: F) -> Option<(FormatRef, CommandPhrase)>
+        where F: Fn(FormatRef) -> (Token, &'a Format)
+    {
+        let mut best: Option<((usize, usize), FormatRef, CommandPhrase)> = None;
+        for format_ref in self.candidates(message_body).into_iter() {
+            let (token, format) = lookup(format_ref);
+            let phrase = match format.parse(token, message_body) {
+                Ok(phrase) => phrase,
+                Err(_) => continue,
+            };
+            let specificity = format.specificity();
+            let better = match best {
+                Some((best_specificity, _, _)) => best_specificity < specificity,
+                None => true,
+            };
+            if better {
+                best = Some((specificity, format_ref, phrase));
+            }
+        }
+        best.map(|(_, format_ref, phrase)| (format_ref, phrase))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CommandIndex, FormatRef};
+    use super::super::{Token, Format};
+
+    fn build(definitions: &[&str]) -> (CommandIndex, Vec<Format>) {
+        let mut index = CommandIndex::new();
+        let mut formats = Vec::new();
+        for (idx, definition) in definitions.iter().enumerate() {
+            let format = Format::from_str(definition).unwrap();
+            index.insert(FormatRef { plugin: 0, format: idx }, &format);
+            formats.push(format);
+        }
+        (index, formats)
+    }
+
+    #[test]
+    fn test_candidates() {
+        let (index, _) = build(&["seen {nick}", "deer", "deer{a}", "deer-stats", "seen"]);
+        assert_eq!(index.candidates("SEEN foo"), vec![
+            FormatRef { plugin: 0, format: 0 },
+            FormatRef { plugin: 0, format: 2 },
+            FormatRef { plugin: 0, format: 4 },
+        ]);
+        assert_eq!(index.candidates("deer-stats"), vec![
+            FormatRef { plugin: 0, format: 2 },
+            FormatRef { plugin: 0, format: 3 },
+        ]);
+        assert_eq!(index.candidates("unknown"), vec![
+            FormatRef { plugin: 0, format: 2 },
+        ]);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let (index, formats) = build(&[
+            "colors {*rest?}", "colors more", "colors {which}", "colors more"]);
+        let lookup = |r: FormatRef| (Token(r.format as u64), &formats[r.format]);
+
+        let (format_ref, phrase) = index.best_match("colors more", &lookup).unwrap();
+        assert_eq!(format_ref, FormatRef { plugin: 0, format: 1 });
+        assert_eq!(phrase.token, Token(1));
+
+        let (format_ref, _) = index.best_match("colors less", &lookup).unwrap();
+        assert_eq!(format_ref, FormatRef { plugin: 0, format: 2 });
+
+        let (format_ref, _) = index.best_match("colors", &lookup).unwrap();
+        assert_eq!(format_ref, FormatRef { plugin: 0, format: 0 });
+
+        assert!(index.best_match("colours", &lookup).is_none());
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    use test::Bencher;
+
+    use super::{CommandIndex, FormatRef};
+    use super::super::{Token, Format};
+
+    const FORMAT_COUNT: usize = 400;
+
+    fn build() -> (CommandIndex, Vec<Format>) {
+        let mut index = CommandIndex::new();
+        let mut formats = Vec::new();
+        for idx in 0..FORMAT_COUNT {
+            let definition = match idx % 4 {
+                0 => format!("command{}", idx),
+                1 => format!("command{} {{nick}}", idx),
+                2 => format!("command{} {{count:d=5}} {{*rest?}}", idx),
+                _ => format!("command{} more {{*rest}}", idx),
+            };
+            let format = Format::from_str(&definition).unwrap();
+            index.insert(FormatRef { plugin: idx / 8, format: idx % 8 }, &format);
+            formats.push(format);
+        }
+        (index, formats)
+    }
+
+    #[bench]
+    fn bench_indexed(b: &mut Bencher) {
+        let (index, formats) = build();
+        let lookup = |r: FormatRef| (Token(0), &formats[8 * r.plugin + r.format]);
+        b.iter(|| {
+            assert!(index.best_match("command398 10 some trailing words", &lookup).is_some());
+            assert!(index.best_match("unknown command", &lookup).is_none());
+        });
+    }
+
+    #[bench]
+    fn bench_linear(b: &mut Bencher) {
+        let (_, formats) = build();
+        b.iter(|| {
+            let mut matched = 0;
+            for message_body in ["command398 10 some trailing words", "unknown command"].iter() {
+                for format in formats.iter() {
+                    if format.parse(Token(0), message_body).is_ok() {
+                        matched += 1;
+                    }
+                }
+            }
+            assert_eq!(matched, 1);
+        });
+    }
+}
@@ -0,0 +1,374 @@
+//! The bot's shared points ledger.
+//!
+//! Every plugin sees the same balances through `IrcBotConfigurator::ledger`.
+//! Points only move by transfers, each of which is journalled, and a
+//! `Transaction` applies all of its transfers or none of them, storing
+//! the accounts and journal entries together.  The journal is never
+//! trimmed: once the current page is full it is kept under its number and
+//! a new one begun.
+//!
+//! A committed transaction is written to disk before `transaction`
+//! returns, so a transfer which has been announced survives a crash.  If
+//! the write fails it is logged, and the transfer reaches disk with the
+//! next snapshot instead.  Plugins which keep their own records of points,
+//! such as stakes held for a game, keep them as notes on the transaction
+//! (`Transaction::set_note`), so the two are written together.
+//!
+//! Accounts are named by services account, so a nick change takes no
+//! points with it; names starting with `*` belong to the bot, and `MINT`
+//! may go into debt, so it is where new points come from.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use rustc_serialize::{Decodable, Encodable};
+use time::get_time;
+
+use super::storage::{Batch, Namespace};
+
+/// The source of new points
+pub static MINT: &'static str = "*mint";
+
+/// How many journal entries are kept under one key
+static JOURNAL_PAGE: usize = 1000;
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+struct Account {
+    // as first seen, for display
+    name: String,
+    balance: u64,
+    opened: i64,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug, PartialEq)]
+pub struct Transfer {
+    pub id: u64,
+    pub when: i64,
+    pub from: String,
+    pub to: String,
+    pub amount: u64,
+    pub reason: String,
+}
+
+impl Transfer {
+    pub fn involves(&self, account: &str) -> bool {
+        let account = account.to_lowercase();
+        self.from.to_lowercase() == account || self.to.to_lowercase() == account
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LedgerError {
+    ZeroAmount,
+    SameAccount,
+    InsufficientFunds { account: String, balance: u64 },
+}
+
+/// A handle to the ledger, cheap to clone.
+#[derive(Clone)]
+pub struct Ledger {
+    // `Account`s under `account:<lowercased name>`, plus `next_id`, the
+    // current `journal` page, `journal_pages`, the full pages under
+    // `journal:<number>` and plugins' notes under `note:<key>`
+    storage: Namespace,
+    // held for the whole of a transaction
+    lock: Arc<Mutex<()>>,
+}
+
+/// Transfers staged against the ledger, applied when the closure given to
+/// `Ledger::transaction` returns `Ok`.
+pub struct Transaction<'a> {
+    ledger: &'a Ledger,
+    accounts: BTreeMap<String, Account>,
+    entries: Vec<Transfer>,
+    next_id: u64,
+    notes: Batch,
+}
+
+fn account_key(name: &str) -> String {
+    format!("account:{}", name.to_lowercase())
+}
+
+fn note_key(key: &str) -> String {
+    format!("note:{}", key)
+}
+
+fn is_mint(name: &str) -> bool {
+    name.to_lowercase() == MINT
+}
+
+impl Ledger {
+    pub fn new(storage: Namespace) -> Ledger {
+        Ledger {
+            storage: storage,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn get_account(&self, name: &str) -> Option<Account> {
+        self.storage.get(&account_key(name))
+    }
+
+    pub fn has_account(&self, name: &str) -> bool {
+        self.get_account(name).is_some()
+    }
+
+    pub fn balance(&self, name: &str) -> u64 {
+        self.get_account(name).map(|account| account.balance).unwrap_or(0)
+    }
+
+    /// When the account received its first points.
+    pub fn opened(&self, name: &str) -> Option<i64> {
+        self.get_account(name).map(|account| account.opened)
+    }
+
+    /// The name the account was opened under.
+    pub fn display_name(&self, name: &str) -> Option<String> {
+        self.get_account(name).map(|account| account.name)
+    }
+
+    /// A note stored by `Transaction::set_note`, as last committed.
+    pub fn note<T: Decodable>(&self, key: &str) -> Option<T> {
+        self.storage.get(&note_key(key))
+    }
+
+    fn journal_page(&self, page: Option<u64>) -> Vec<Transfer> {
+        let key = match page {
+            Some(page) => format!("journal:{}", page),
+            None => "journal".to_string(),
+        };
+        self.storage.get(&key).unwrap_or(Vec::new())
+    }
+
+    /// The latest `count` journal entries involving `name`, newest first.
+    pub fn entries_for(&self, name: &str, count: usize) -> Vec<Transfer> {
+        let pages: u64 = self.storage.get("journal_pages").unwrap_or(0);
+        let mut entries = Vec::new();
+        let mut page = None;
+        loop {
+            for entry in self.journal_page(page).into_iter().rev() {
+                if entries.len() == count {
+                    return entries;
+                }
+                if entry.involves(name) {
+                    entries.push(entry);
+                }
+            }
+            page = match page {
+                None if 0 < pages => Some(pages - 1),
+                Some(older) if 0 < older => Some(older - 1),
+                _ => return entries,
+            };
+        }
+    }
+
+    /// Runs `func` with the ledger locked, keeping its transfers only if it
+    /// returns `Ok`.
+    pub fn transaction<F, T>(&self, func: F) -> Result<T, LedgerError>
+        where F: FnOnce(&mut Transaction) -> Result<T, LedgerError>
+    {
+        let _guard = self.lock.lock().unwrap();
+        let mut tx = Transaction {
+            ledger: self,
+            accounts: BTreeMap::new(),
+            entries: Vec::new(),
+            next_id: self.storage.get("next_id").unwrap_or(1),
+            notes: Batch::new(),
+        };
+        let result = try!(func(&mut tx));
+        tx.commit();
+        Ok(result)
+    }
+
+    /// A transaction of a single transfer.
+    pub fn transfer(&self, from: &str, to: &str, amount: u64, reason: &str) -> Result<Transfer, LedgerError> {
+        self.transaction(|tx| tx.transfer(from, to, amount, reason))
+    }
+}
+
+impl<'a> Transaction<'a> {
+    fn account(&mut self, name: &str) -> Option<&mut Account> {
+        let key = name.to_lowercase();
+        if !self.accounts.contains_key(&key) {
+            match self.ledger.get_account(name) {
+                Some(account) => { self.accounts.insert(key.clone(), account); },
+                None => return None,
+            }
+        }
+        self.accounts.get_mut(&key)
+    }
+
+    pub fn balance(&mut self, name: &str) -> u64 {
+        self.account(name).map(|account| account.balance).unwrap_or(0)
+    }
+
+    /// Stores `value` under `key`, written with the transaction's transfers
+    /// if it commits.
+    pub fn set_note<T: Encodable>(&mut self, key: &str, value: &T) {
+        self.notes.set(&note_key(key), value);
+    }
+
+    pub fn transfer(&mut self, from: &str, to: &str, amount: u64, reason: &str) -> Result<Transfer, LedgerError> {
+        if amount == 0 {
+            return Err(LedgerError::ZeroAmount);
+        }
+        if from.to_lowercase() == to.to_lowercase() {
+            return Err(LedgerError::SameAccount);
+        }
+        if !is_mint(from) {
+            let balance = self.balance(from);
+            if balance < amount {
+                return Err(LedgerError::InsufficientFunds {
+                    account: from.to_string(),
+                    balance: balance,
+                });
+            }
+            self.account(from).unwrap().balance -= amount;
+        }
+        let now = get_time().sec;
+        if !is_mint(to) {
+            if self.account(to).is_none() {
+                self.accounts.insert(to.to_lowercase(), Account {
+                    name: to.to_string(),
+                    balance: 0,
+                    opened: now,
+                });
+            }
+            let account = self.account(to).unwrap();
+            account.balance = account.balance.saturating_add(amount);
+        }
+
+        let entry = Transfer {
+            id: self.next_id,
+            when: now,
+            from: from.to_string(),
+            to: to.to_string(),
+            amount: amount,
+            reason: reason.to_string(),
+        };
+        self.next_id += 1;
+        self.entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    fn commit(self) {
+        if self.entries.is_empty() && self.notes.is_empty() {
+            return;
+        }
+        let storage = &self.ledger.storage;
+        let mut batch = self.notes;
+        for (key, account) in self.accounts.iter() {
+            batch.set(&account_key(key), account);
+        }
+        let mut journal = self.ledger.journal_page(None);
+        journal.extend(self.entries.into_iter());
+        if JOURNAL_PAGE <= journal.len() {
+            let pages: u64 = storage.get("journal_pages").unwrap_or(0);
+            batch.set(&format!("journal:{}", pages), &journal);
+            batch.set("journal_pages", &(pages + 1));
+            journal = Vec::new();
+        }
+        batch.set("journal", &journal);
+        batch.set("next_id", &self.next_id);
+        storage.apply(batch);
+        if let Err(err) = storage.flush() {
+            warn!("ledger: failed to write a committed transaction: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{Ledger, LedgerError, MINT, JOURNAL_PAGE};
+    use super::super::storage::Storage;
+
+    #[test]
+    fn test_transaction() {
+        let ledger = Ledger::new(Storage::in_memory().namespace("*ledger"));
+        assert!(!ledger.has_account("alice"));
+        ledger.transfer(MINT, "Alice", 100, "daily").unwrap();
+        assert_eq!(ledger.balance("alice"), 100);
+        assert_eq!(ledger.display_name("ALICE"), Some("Alice".to_string()));
+
+        assert_eq!(ledger.transfer("alice", "bob", 0, "give"), Err(LedgerError::ZeroAmount));
+        assert_eq!(ledger.transfer("alice", "ALICE", 5, "give"), Err(LedgerError::SameAccount));
+
+        // the second transfer fails, so neither happens
+        let result = ledger.transaction(|tx| {
+            try!(tx.transfer("alice", "bob", 60, "give"));
+            tx.transfer("alice", "carol", 60, "give")
+        });
+        assert_eq!(result.unwrap_err(),
+            LedgerError::InsufficientFunds { account: "alice".to_string(), balance: 40 });
+        assert_eq!(ledger.balance("alice"), 100);
+        assert!(!ledger.has_account("bob"));
+
+        ledger.transaction(|tx| {
+            try!(tx.transfer("alice", "bob", 60, "give"));
+            tx.transfer("bob", "carol", 10, "give")
+        }).unwrap();
+        assert_eq!(ledger.balance("alice"), 40);
+        assert_eq!(ledger.balance("bob"), 50);
+        assert_eq!(ledger.balance("carol"), 10);
+
+        let entries = ledger.entries_for("bob", 5);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, &entries[0].to[..]), (3, "carol"));
+        assert_eq!((entries[1].id, &entries[1].from[..]), (2, "alice"));
+    }
+
+    #[test]
+    fn test_journal_pages() {
+        let storage = Storage::in_memory().namespace("*ledger");
+        let ledger = Ledger::new(storage.clone());
+        ledger.transfer(MINT, "alice", 5, "first").unwrap();
+        ledger.transaction(|tx| {
+            for _ in 0..JOURNAL_PAGE {
+                try!(tx.transfer(MINT, "bob", 1, "filler"));
+            }
+            Ok(())
+        }).unwrap();
+        ledger.transfer("alice", "carol", 2, "gift").unwrap();
+        assert_eq!(storage.get::<u64>("journal_pages"), Some(1));
+        assert_eq!(storage.get::<Vec<super::Transfer>>("journal").unwrap().len(), 1);
+
+        // nothing is dropped: the first entry is still found, on the full page
+        let entries = ledger.entries_for("alice", 5);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, &entries[0].reason[..]), (JOURNAL_PAGE as u64 + 2, "gift"));
+        assert_eq!((entries[1].id, &entries[1].reason[..]), (1, "first"));
+        assert_eq!(ledger.entries_for("bob", 2000).len(), JOURNAL_PAGE);
+        assert_eq!(ledger.entries_for("bob", 3).len(), 3);
+    }
+
+    #[test]
+    fn test_notes_and_durability() {
+        let dir = env::temp_dir().join(format!("ircbot-ledger-test-{}", ::time::precise_time_ns()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let ledger = Ledger::new(Storage::open(&dir).unwrap().namespace("*ledger"));
+        ledger.transaction(|tx| {
+            try!(tx.transfer(MINT, "alice", 10, "daily"));
+            tx.set_note("held", &vec![("alice".to_string(), 10u64)]);
+            Ok(())
+        }).unwrap();
+
+        // a failed transaction keeps neither its transfers nor its notes
+        let result = ledger.transaction(|tx| {
+            tx.set_note("held", &Vec::<(String, u64)>::new());
+            tx.transfer("alice", "bob", 50, "give")
+        });
+        assert!(result.is_err());
+
+        // committed without waiting for a snapshot
+        let reopened = Ledger::new(Storage::open(&dir).unwrap().namespace("*ledger"));
+        assert_eq!(reopened.balance("alice"), 10);
+        assert_eq!(reopened.note::<Vec<(String, u64)>>("held"), Some(vec![("alice".to_string(), 10)]));
+        assert_eq!(reopened.note::<u64>("missing"), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,18 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
 use mio::Sender;
 use irc::{IrcMsg, IrcMsgBuf, client, server};
-use irc::legacy::FrozenState;
+use irc::legacy::{FrozenState, IrcEvent};
+use irc::legacy::IrcMsg as LegacyIrcMsg;
+use rustc_serialize::Decodable;
+use toml;
 use irc::legacy::MessageEndpoint::{
     self,
     KnownUser,
@@ -14,11 +24,44 @@ use irc::legacy::MessageEndpoint::{
 pub use self::format::{
     Format,
     FormatResult,
-    CommandPhrase
+    CommandPhrase,
+    Quoting,
 };
 pub use self::format::FormatParseError::EmptyFormat;
-
+pub use self::worker::{Worker, WorkerPool, WorkerConfig, SubmitError};
+pub use self::external::{ExternalPlugin, ExternalPluginConfig};
+pub use self::wasm::{WasmPlugin, WasmPluginConfig};
+pub use self::dynamic::LoadError;
+pub use self::storage::{Storage, Namespace};
+pub use self::ledger::{Ledger, LedgerError, Transaction, Transfer, MINT};
+pub use self::timer::{Timers, TimerToken};
+pub use self::policy::ChannelConfig;
+pub use self::identity::CapNegotiation;
+
+use self::alias::{AliasTable, AliasError};
+use self::hooks::Hook;
+use self::identity::{Identities, admin_matches, source_mask};
+use self::builtin::{builtin_formats, describe_alias_error};
+use self::index::{CommandIndex, FormatRef};
+use self::pipeline::{PipeError, Poll, StageOutput};
+use self::policy::{ChannelPolicy, Access};
+
+mod alias;
+mod builtin;
+mod dynamic;
+mod external;
 mod format;
+mod hooks;
+mod identity;
+mod index;
+mod ledger;
+mod pipeline;
+mod policy;
+mod storage;
+mod suggest;
+mod timer;
+mod wasm;
+mod worker;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct Token(pub u64);
@@ -42,49 +85,239 @@ impl Replier {
 /// Defines the API a plugin implements
 // TODO: move to `plugin' module
 pub trait RustBotPlugin {
+    /// The name the plugin is registered, configured and stored under.
+    fn name(&self) -> &str;
     fn configure(&mut self, _: &mut IrcBotConfigurator) {}
     fn start(&mut self) {}
     fn on_message(&mut self, _: &mut Replier, _: &IrcMsg) {}
-    // fn on_timer(&mut self, _: &mut Replier, _: TimerToken) {}
+    /// Called once a timer scheduled through `IrcBotConfigurator::timers` is due.
+    fn on_timer(&mut self, _: &mut Replier, _: TimerToken) {}
     fn dispatch_cmd(&mut self, _: &CommandMapperDispatch, _: &IrcMsg) {}
+
+    // Typed hooks, called after `on_message` with the state as updated
+    // by the message; `on_part`, `on_quit` and `on_kick` get the state from
+    // before, so the departing user can still be looked up.
+    fn on_join(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Join) {}
+    fn on_part(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Part) {}
+    fn on_quit(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Quit) {}
+    fn on_kick(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Kick) {}
+    fn on_nick(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Nick) {}
+    fn on_mode(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Mode) {}
+    fn on_topic(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Topic) {}
+    fn on_notice(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Notice) {}
+    fn on_invite(&mut self, _: &mut Replier, _: &FrozenState, _: &server::Invite) {}
+
+    /// Called once registration with the server has completed.
+    fn on_connected(&mut self, _: &mut Replier, _: &FrozenState) {}
+    /// Called when the connection to the server is lost or closed.
+    fn on_disconnected(&mut self) {}
+
+    /// Called when the bot quits or the plugin is disabled.  Flush any
+    /// buffered work, waiting no later than `deadline`.
+    fn stop(&mut self, _deadline: Instant) {}
 }
 
+/// How long plugins are given to stop.
+pub const STOP_TIMEOUT_MS: u64 = 5000;
+
+/// The deadline for plugins stopping now.
+pub fn stop_deadline() -> Instant {
+    Instant::now() + Duration::from_millis(STOP_TIMEOUT_MS)
+}
+
+
+/// Each plugin's `[plugins.<name>]` table from the configuration file
+pub type PluginConfig = BTreeMap<String, toml::Value>;
+
+struct MappedFormat {
+    token: Token,
+    format: Format,
+    description: String,
+}
 
 pub struct IrcBotConfigurator {
-    mapped: Vec<(Token, Format)>
+    mapped: Vec<MappedFormat>,
+    storage: Namespace,
+    ledger: Ledger,
+    timers: Timers,
+    config: Option<toml::Value>,
 }
 
 /// Defines the public API the bot exposes to plugins for configuration
 // TODO: move to `plugin' module
 impl IrcBotConfigurator {
-    pub fn new() -> IrcBotConfigurator {
+    pub fn new(storage: Namespace, ledger: Ledger, config: Option<toml::Value>) -> IrcBotConfigurator {
         IrcBotConfigurator {
             mapped: Vec::new(),
+            storage: storage,
+            ledger: ledger,
+            timers: Timers::new(),
+            config: config,
+        }
+    }
+
+    /// The plugin's `[plugins.<name>]` table, if there is one and it
+    /// decodes as a `T`.
+    pub fn config<T: Decodable>(&self) -> Option<T> {
+        let config = match self.config {
+            Some(ref config) => config.clone(),
+            None => return None,
+        };
+        let decoded = toml::decode(config);
+        if decoded.is_none() {
+            warn!("plugin configuration did not decode");
+        }
+        decoded
+    }
+
+    /// Persistent storage for the plugin, in a namespace of its own.
+    pub fn storage(&self) -> Namespace {
+        self.storage.clone()
+    }
+
+    /// The points ledger shared by all plugins.
+    pub fn ledger(&self) -> Ledger {
+        self.ledger.clone()
+    }
+
+    /// The plugin's timers, which may be kept to schedule more later.
+    pub fn timers(&self) -> Timers {
+        self.timers.clone()
+    }
+
+    /// Dispatch commands matching `format` to the plugin under `token`.
+    /// `description` is a short sentence shown by the built-in `help`.
+    pub fn map_format(&mut self, token: Token, format: Format, description: &str) {
+        self.mapped.push(MappedFormat {
+            token: token,
+            format: format,
+            description: description.to_string(),
+        });
+    }
+}
+
+/// Where a dispatched command's replies go
+#[derive(Clone)]
+enum ReplySink {
+    Irc(Sender<IrcMsgBuf>),
+    // The command is part of a pipeline: its replies become the
+    // arguments of the next command.
+    Pipe(mpsc::Sender<String>),
+}
+
+impl ReplySink {
+    fn reply(&self, target: &str, message: &str) {
+        match *self {
+            ReplySink::Irc(ref sender) => send_privmsg(sender, target, message),
+            ReplySink::Pipe(ref sender) => {
+                // the pipeline may have timed out and gone away
+                let _ = sender.send(message.to_string());
+            }
         }
     }
 
-    pub fn map_format(&mut self, token: Token, format: Format) {
-        self.mapped.push((token, format));
+    fn notice(&self, target: &str, message: &str) {
+        match *self {
+            ReplySink::Irc(ref sender) => send_raw(sender, &format!("NOTICE {} :{}", target, message)),
+            // piped output is text either way
+            ReplySink::Pipe(_) => self.reply(target, message),
+        }
     }
 }
 
 struct DispatchBuilder {
     state: Arc<FrozenState>,
     sender: Sender<IrcMsgBuf>,
+    sink: ReplySink,
     reply_target: String,
     source: MessageEndpoint,
     target: MessageEndpoint,
+    source_nick: String,
+    // the services account of the sender, if they are identified
+    account: Option<String>,
+    identities: Identities,
+    // the channel the message was sent to, if it was
+    channel: Option<String>,
+    is_admin: bool,
+}
+
+fn send_privmsg(sender: &Sender<IrcMsgBuf>, target: &str, message: &str) {
+    let privmsg = client::PrivmsgBuf::new(
+        target.as_bytes(),
+        message.as_bytes()).unwrap();
+
+    println!("CommandMapperDispatch::reply EMITTING: {:?}", ::botcore::MaybeString::new(privmsg.as_bytes()));
+    sender.send(privmsg.into_inner())
+        .ok().expect("Failed to send to IRC socket");
+}
+
+/// The lines a finished pipeline stage replied, or `None` after telling
+/// the user why the pipeline stopped.
+fn piped_output(builder: &DispatchBuilder, prefix: &str, stage: &str,
+                polled: Result<Poll, PipeError>) -> Option<Vec<String>> {
+    match polled {
+        Ok(Poll::Done(ref lines)) if lines.len() == 0 => {
+            builder.reply(&format!("{}{} gave no output to pipe", prefix, stage));
+            None
+        },
+        Ok(Poll::Done(lines)) => Some(lines),
+        Ok(Poll::Pending) => None,
+        Err(PipeError::TimedOut) => {
+            builder.reply(&format!("{}{} timed out", prefix, stage));
+            None
+        },
+        Err(PipeError::TooManyStages) => unreachable!(),
+    }
+}
+
+fn send_raw(sender: &Sender<IrcMsgBuf>, line: &str) {
+    match LegacyIrcMsg::new(line.as_bytes().to_vec()) {
+        Ok(msg) => {
+            sender.send(IrcMsgBuf::from_legacy(msg))
+                .ok().expect("Failed to send to IRC socket");
+        },
+        Err(err) => warn!("bad raw line {:?}: {:?}", line, err),
+    }
 }
 
 impl DispatchBuilder {
+    /// Reply without dispatching to a plugin, for messages the
+    /// container answers itself.  These always go to IRC, even
+    /// from within a pipeline.
+    fn reply(&self, message: &str) {
+        send_privmsg(&self.sender, &self.reply_target, message);
+    }
+
+    fn with_sink(&self, sink: ReplySink) -> DispatchBuilder {
+        DispatchBuilder {
+            state: self.state.clone(),
+            sender: self.sender.clone(),
+            sink: sink,
+            reply_target: self.reply_target.clone(),
+            source: self.source.clone(),
+            target: self.target.clone(),
+            source_nick: self.source_nick.clone(),
+            account: self.account.clone(),
+            identities: self.identities.clone(),
+            channel: self.channel.clone(),
+            is_admin: self.is_admin,
+        }
+    }
+
+    fn channel(&self) -> Option<&str> {
+        self.channel.as_ref().map(|channel| &channel[..])
+    }
+
     fn build(&self, phrase: CommandPhrase) -> CommandMapperDispatch {
         CommandMapperDispatch {
             state: self.state.clone(),
             command: phrase,
-            sender: self.sender.clone(),
+            sink: self.sink.clone(),
             reply_target: self.reply_target.clone(),
             source: self.source.clone(),
             target: self.target.clone(),
+            account: self.account.clone(),
+            identities: self.identities.clone(),
         }
     }
 }
@@ -96,10 +329,12 @@ impl DispatchBuilder {
 pub struct CommandMapperDispatch {
     state: Arc<FrozenState>,
     command: CommandPhrase,
-    sender: Sender<IrcMsgBuf>,
+    sink: ReplySink,
     reply_target: String,
     pub source: MessageEndpoint,
     pub target: MessageEndpoint,
+    account: Option<String>,
+    identities: Identities,
 }
 
 
@@ -113,54 +348,455 @@ impl CommandMapperDispatch {
         self.state.get_self_nick()
     }
 
+    /// The services account the sender is identified to, if any.
+    pub fn source_account(&self) -> Option<&str> {
+        self.account.as_ref().map(|account| &account[..])
+    }
+
+    /// The services account `nick` is identified to, if they share a
+    /// channel with the bot and are identified.
+    pub fn account_of(&self, nick: &str) -> Option<String> {
+        self.identities.account_of(nick)
+    }
+
     /// The current command name, as set by the call to `map` when
     /// `configure` is called on the `RustBotPlugin`.
     pub fn command(&self) -> &CommandPhrase {
         &self.command
     }
 
-    /// Reply with a message to the channel/nick which sent the message being dispatched
+    /// Reply with a message to the channel/nick which sent the message being dispatched,
+    /// or to the next command if this one is part of a pipeline.
     pub fn reply(&self, message: &str) {
-        let privmsg = client::PrivmsgBuf::new(
-            self.reply_target.as_bytes(),
-            message.as_bytes()).unwrap();
+        self.sink.reply(&self.reply_target, message);
+    }
+
+    /// Reply with a notice rather than a message.
+    pub fn reply_notice(&self, message: &str) {
+        self.sink.notice(&self.reply_target, message);
+    }
+}
+
+
+/// How many times a plugin may panic before it is disabled, by default.
+const DEFAULT_PANIC_LIMIT: u32 = 3;
+
+/// The ledger's storage; no plugin name starts with `*`.
+const LEDGER_NAMESPACE: &'static str = "*ledger";
+
+/// Alias edits made at runtime, as `Some(expansion)` or `None` if removed.
+const ALIAS_NAMESPACE: &'static str = "*aliases";
+
+struct RegisteredPlugin {
+    name: String,
+    plugin: Box<RustBotPlugin+'static>,
+    formats: Vec<MappedFormat>,
+    panics: u32,
+    disabled: bool,
+    // the shared library the plugin was loaded from, if any
+    library: Option<String>,
+    timers: Timers,
+}
+
+fn panic_message(payload: &Box<Any + Send>) -> &str {
+    match payload.downcast_ref::<&'static str>() {
+        Some(message) => *message,
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => &message[..],
+            None => "Box<Any>",
+        }
+    }
+}
+
+impl RegisteredPlugin {
+    /// Runs a plugin callback, catching a panic so that it takes down
+    /// only this plugin, and only once it has panicked `panic_limit` times.
+    fn guard<F>(&mut self, panic_limit: u32, context: &fmt::Debug, callback: F)
+        where F: FnOnce(&mut (RustBotPlugin+'static))
+    {
+        if self.disabled {
+            return;
+        }
+        let plugin = &mut *self.plugin;
+        let payload = match panic::catch_unwind(AssertUnwindSafe(|| callback(plugin))) {
+            Ok(()) => return,
+            Err(payload) => payload,
+        };
+
+        self.panics += 1;
+        warn!("plugin {} panicked ({} of {}): {} -- while handling {:?}",
+            self.name, self.panics, panic_limit, panic_message(&payload), context);
+        if panic_limit <= self.panics {
+            warn!("plugin {} disabled", self.name);
+            self.stop(stop_deadline());
+        }
+    }
 
-        println!("CommandMapperDispatch::reply EMITTING: {:?}", ::botcore::MaybeString::new(privmsg.as_bytes()));
-        self.sender.send(privmsg.into_inner())
-            .ok().expect("Failed to send to IRC socket");
+    /// Stops and disables the plugin.
+    fn stop(&mut self, deadline: Instant) {
+        if self.disabled {
+            return;
+        }
+        self.disabled = true;
+        let plugin = &mut *self.plugin;
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| plugin.stop(deadline))) {
+            warn!("plugin {} panicked while stopping: {}", self.name, panic_message(&payload));
+        }
     }
 }
 
 
+/// A pipeline waiting on a stage whose plugin replies from another thread.
+struct PendingPipeline {
+    msg: IrcMsgBuf,
+    builder: DispatchBuilder,
+    prefix: String,
+    suggest: bool,
+    // the stage being waited on, and the ones after it
+    stage: String,
+    output: StageOutput,
+    rest: Vec<String>,
+}
+
+
 pub struct PluginContainer {
     cmd_prefixes: Vec<String>,
-    plugins: Vec<(Box<RustBotPlugin+'static>, Vec<(Token, Format)>)>,
+    admins: Vec<String>,
+    builtins: Vec<MappedFormat>,
+    plugins: Vec<RegisteredPlugin>,
+    index: CommandIndex,
+    aliases: AliasTable,
+    panic_limit: u32,
+    policy: ChannelPolicy,
+    identities: Identities,
+    storage: Storage,
+    ledger: Ledger,
+    plugin_config: PluginConfig,
+    pipelines: Vec<PendingPipeline>,
+    // set by `stop`
+    stopped: bool,
 }
 
 
 impl PluginContainer {
-    pub fn new(prefixes: Vec<String>) -> PluginContainer {
+    pub fn new(prefixes: Vec<String>, storage: Storage) -> PluginContainer {
         PluginContainer {
             cmd_prefixes: prefixes,
-            plugins: Vec::new()
+            admins: Vec::new(),
+            builtins: builtin_formats(),
+            plugins: Vec::new(),
+            index: CommandIndex::new(),
+            aliases: AliasTable::new(),
+            panic_limit: DEFAULT_PANIC_LIMIT,
+            policy: ChannelPolicy::new(),
+            identities: Identities::new(),
+            ledger: Ledger::new(storage.namespace(LEDGER_NAMESPACE)),
+            storage: storage,
+            plugin_config: PluginConfig::new(),
+            pipelines: Vec::new(),
+            stopped: false,
+        }
+    }
+
+    /// Sets the configuration handed to plugins registered after this.
+    pub fn set_plugin_config(&mut self, plugin_config: PluginConfig) {
+        self.plugin_config = plugin_config;
+    }
+
+    /// Sets how many times a plugin may panic before it is disabled.
+    pub fn set_panic_limit(&mut self, panic_limit: u32) {
+        self.panic_limit = panic_limit;
+    }
+
+    /// Sets who may use administrative commands: services accounts, or
+    /// `nick!user@host` masks.
+    pub fn set_admins(&mut self, admins: Vec<String>) {
+        self.admins = admins;
+    }
+
+    fn is_admin(&self, nick: &str, mask: Option<&str>, account: Option<&str>) -> bool {
+        self.admins.iter().any(|admin| admin_matches(admin, nick, mask, account))
+    }
+
+    /// Sets which plugins may be used in `channel`; see `ChannelConfig`.
+    pub fn set_channel_config(&mut self, channel: &str, config: ChannelConfig) {
+        self.policy.set(channel, config);
+    }
+
+    /// Whether whoever sent the message being dispatched may use
+    /// `plugin`'s commands where they sent it.
+    fn permits(&self, builder: &DispatchBuilder, plugin: &str) -> bool {
+        self.policy.permits(builder.channel(), plugin, builder.is_admin)
+    }
+
+    /// Stops every plugin, for shutdown.  Plugins are stopped in turn
+    /// and all must finish by `deadline`.
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stopped = true;
+        for registered in self.plugins.iter_mut() {
+            registered.stop(deadline);
+        }
+        if let Err(err) = self.storage.flush() {
+            warn!("failed to flush storage: {:?}", err);
+        }
+    }
+
+    /// Whether `stop` has been called.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Tells plugins that registration with the server has completed.
+    pub fn connected(&mut self, state: Arc<FrozenState>, raw_tx: &Sender<IrcMsgBuf>) {
+        let mut replier = Replier(raw_tx.clone());
+        let panic_limit = self.panic_limit;
+        for registered in self.plugins.iter_mut() {
+            registered.guard(panic_limit, &"on_connected",
+                |plugin| plugin.on_connected(&mut replier, &state));
         }
     }
 
-    /// Register a plugin instance.  This will configure and start the plugin.
+    /// Fires plugins' due timers and carries on pipelines whose output
+    /// has arrived.  Called every bot tick while connected.
+    pub fn tick(&mut self, raw_tx: &Sender<IrcMsgBuf>) {
+        let mut replier = Replier(raw_tx.clone());
+        let panic_limit = self.panic_limit;
+        let now = ::time::get_time();
+        for registered in self.plugins.iter_mut() {
+            for token in registered.timers.take_due(now).into_iter() {
+                registered.guard(panic_limit, &token,
+                    |plugin| plugin.on_timer(&mut replier, token));
+            }
+        }
+
+        let pipelines = mem::replace(&mut self.pipelines, Vec::new());
+        for mut pending in pipelines.into_iter() {
+            let polled = pending.output.poll();
+            if let Ok(Poll::Pending) = polled {
+                self.pipelines.push(pending);
+                continue;
+            }
+            let PendingPipeline { msg, builder, prefix, suggest, stage, output, rest } = pending;
+            if let Some(lines) = piped_output(&builder, &prefix, &stage, polled) {
+                self.run_stages(&msg, builder, prefix, suggest, rest, lines, output.deadline());
+            }
+        }
+    }
+
+    /// Tells plugins that the connection has gone away.
+    pub fn disconnected(&mut self) {
+        self.pipelines.clear();
+        let panic_limit = self.panic_limit;
+        for registered in self.plugins.iter_mut() {
+            registered.guard(panic_limit, &"on_disconnected", |plugin| plugin.on_disconnected());
+        }
+    }
+
+    /// Define or replace a command alias.  Plugins should be registered
+    /// first, so aliases which would hide one of their commands are refused.
+    pub fn set_alias(&mut self, name: &str, expansion: &str) -> Result<(), AliasError> {
+        let is_builtin = self.builtins.iter()
+            .any(|mapped| mapped.format.command_name() == name.to_lowercase());
+        if is_builtin || self.index.contains_command(name) {
+            return Err(AliasError::ShadowsCommand);
+        }
+        self.aliases.set(name, expansion)
+    }
+
+    /// Applies the alias edits made with the built-in `alias` and `unalias`,
+    /// which are kept in storage.  Call after setting configured aliases.
+    pub fn load_aliases(&mut self) {
+        let storage = self.storage.namespace(ALIAS_NAMESPACE);
+        for name in storage.keys().iter() {
+            match storage.get::<Option<String>>(name) {
+                Some(Some(expansion)) => {
+                    if let Err(err) = self.set_alias(name, &expansion) {
+                        warn!("ignoring stored alias {:?} => {:?}: {:?}", name, expansion, err);
+                    }
+                },
+                Some(None) => {
+                    self.aliases.remove(name);
+                },
+                None => (),
+            }
+        }
+    }
+
+    /// Keeps an alias edit, so that it survives a restart.
+    fn store_alias(&self, name: &str, expansion: Option<String>) {
+        self.storage.namespace(ALIAS_NAMESPACE).set(&name.to_lowercase(), &expansion);
+    }
+
+    /// Register a plugin instance under its name.  This will configure and
+    /// start the plugin.  A plugin whose name is taken is dropped with a
+    /// warning; see `try_register`.
     pub fn register<P>(&mut self, plugin: P) where P: RustBotPlugin+'static {
-        let mut plugin = Box::new(plugin) as Box<RustBotPlugin+'static>;
-        let mut configurator = IrcBotConfigurator::new();
+        if let Err(err) = self.try_register(plugin) {
+            warn!("not registering plugin: {:?}", err);
+        }
+    }
+
+    /// Register a plugin instance under its name, unless another plugin
+    /// already has it.  Returns the name.
+    pub fn try_register<P>(&mut self, plugin: P) -> Result<String, LoadError>
+        where P: RustBotPlugin+'static
+    {
+        self.register_boxed(Box::new(plugin), None)
+    }
+
+    fn register_boxed(&mut self, mut plugin: Box<RustBotPlugin+'static>, library: Option<String>)
+        -> Result<String, LoadError>
+    {
+        let name = plugin.name().to_string();
+        if self.plugins.iter().any(|registered| registered.name == name) {
+            return Err(LoadError::NameTaken(name));
+        }
+        let mut configurator = IrcBotConfigurator::new(
+            self.storage.namespace(&name), self.ledger.clone(), self.plugin_config.get(&name).cloned());
         plugin.configure(&mut configurator);
         plugin.start();
-        self.plugins.push((plugin, configurator.mapped));
+
+        // the built-in commands are matched first, so these could never run
+        let builtins = &self.builtins;
+        let (shadowed, mapped): (Vec<MappedFormat>, Vec<MappedFormat>) = configurator.mapped.into_iter()
+            .partition(|mapped| builtins.iter()
+                .any(|builtin| builtin.format.command_name() == mapped.format.command_name()));
+        for mapped in shadowed.iter() {
+            warn!("plugin {}: ignoring `{}', which a built-in command hides",
+                name, mapped.format.usage());
+        }
+
+        let plugin_idx = self.plugins.len();
+        for (format_idx, mapped) in mapped.iter().enumerate() {
+            let format_ref = FormatRef { plugin: plugin_idx, format: format_idx };
+            self.index.insert(format_ref, &mapped.format);
+        }
+        self.plugins.push(RegisteredPlugin {
+            name: name.clone(),
+            plugin: plugin,
+            formats: mapped,
+            panics: 0,
+            disabled: false,
+            library: library,
+            timers: configurator.timers,
+        });
+        Ok(name)
+    }
+
+    /// Load, configure and start the plugin in the shared library at `path`.
+    pub fn register_library(&mut self, path: &str) -> Result<String, LoadError> {
+        let plugin = try!(dynamic::load(path));
+        self.register_boxed(Box::new(plugin), Some(path.to_string()))
+    }
+
+    /// Stops and removes a plugin, unloading its library if it has one.
+    /// Returns the library path, or `None` if there was no such plugin.
+    fn unregister(&mut self, name: &str) -> Option<Option<String>> {
+        let idx = match self.plugins.iter().position(|registered| registered.name == name) {
+            Some(idx) => idx,
+            None => return None,
+        };
+        let mut registered = self.plugins.remove(idx);
+        registered.stop(stop_deadline());
+
+        // the indices of later plugins have shifted
+        self.index = CommandIndex::new();
+        for (plugin_idx, registered) in self.plugins.iter().enumerate() {
+            for (format_idx, mapped) in registered.formats.iter().enumerate() {
+                let format_ref = FormatRef { plugin: plugin_idx, format: format_idx };
+                self.index.insert(format_ref, &mapped.format);
+            }
+        }
+        Some(registered.library.take())
+    }
+
+    /// Called when no format accepted a prefixed message.  If the first word
+    /// is exactly a command name, reply with the usage of each format mapped
+    /// under it.  Otherwise suggest a command name a single edit away, if
+    /// there is one; anything else is taken to be conversation.
+    fn dispatch_unmatched(&self, builder: &DispatchBuilder, prefix: &str,
+                          suggest: bool, message_body: &str) {
+        let command = match message_body.split(' ').next() {
+            Some(command) if command.len() > 0 => command.to_lowercase(),
+            _ => return,
+        };
+
+        let mut formats: Vec<&Format> = Vec::new();
+        for mapped in self.builtins.iter() {
+            formats.push(&mapped.format);
+        }
+        for registered in self.plugins.iter() {
+            if !self.permits(builder, &registered.name) {
+                continue;
+            }
+            for mapped in registered.formats.iter() {
+                formats.push(&mapped.format);
+            }
+        }
+
+        let mut usages: Vec<String> = Vec::new();
+        for format in formats.iter() {
+            if format.command_name() == command {
+                let usage = format!("{}{}", prefix, format.usage());
+                if !usages.contains(&usage) {
+                    usages.push(usage);
+                }
+            }
+        }
+        if usages.len() > 0 {
+            builder.reply(&format!("Usage: {}", usages.join(" | ")));
+            return;
+        }
+
+        if !suggest {
+            return;
+        }
+        let names = formats.iter().map(|f| f.command_name())
+            .chain(self.aliases.iter().map(|(name, _)| &name[..]));
+        if let Some(name) = suggest::closest(&command, names) {
+            builder.reply(&format!("Unknown command {}{}; did you mean {}{}?",
+                prefix, command, prefix, name));
+        }
+    }
+
+    /// Runs the typed hooks (`on_join`, `on_quit`, ...) for the events the
+    /// bundler produced from one message.  `before` is the state prior to
+    /// applying those events and `after` the state once they were applied.
+    pub fn dispatch_hooks(&mut self, before: &FrozenState, after: &FrozenState,
+                          raw_tx: &Sender<IrcMsgBuf>, events: &[IrcEvent]) {
+        let mut replier = Replier(raw_tx.clone());
+        let panic_limit = self.panic_limit;
+        for event in events.iter() {
+            let msg = match *event {
+                IrcEvent::IrcMsg(ref legacy) => IrcMsgBuf::from_legacy(legacy.clone()),
+                _ => continue,
+            };
+            let hook = match Hook::from_msg(&msg) {
+                Some(hook) => hook,
+                None => continue,
+            };
+            let state = hook.snapshot(before, after);
+            let context = ::botcore::MaybeString::new(msg.as_bytes());
+            for registered in self.plugins.iter_mut() {
+                registered.guard(panic_limit, &context, |plugin| hook.call(plugin, &mut replier, state));
+            }
+        }
     }
 
     /// Dispatches messages to plugins, if they have expressed interest in the message.
     /// Interest is expressed via calling map during the configuration phase.
+    /// At most one format is dispatched per message; see `CommandIndex::best_match`.
     pub fn dispatch(&mut self, state: Arc<FrozenState>, raw_tx: &Sender<IrcMsgBuf>, msg: &IrcMsg) {
+        let raw = String::from_utf8_lossy(msg.as_bytes()).into_owned();
+        if let Some(query) = self.identities.observe(state.get_self_nick(), &raw) {
+            send_raw(raw_tx, &query);
+        }
+
         let mut replier = Replier(raw_tx.clone());
-        for &mut (ref mut plugin, _) in self.plugins.iter_mut() {
-            plugin.on_message(&mut replier, msg);
+        let panic_limit = self.panic_limit;
+        let context = ::botcore::MaybeString::new(msg.as_bytes());
+        for registered in self.plugins.iter_mut() {
+            registered.guard(panic_limit, &context, |plugin| plugin.on_message(&mut replier, msg));
         }
         
         let privmsg;
@@ -186,27 +822,39 @@ impl PluginContainer {
         };
 
         let ptarget = ::std::str::from_utf8(privmsg.get_target()).unwrap();
-        let target = match state.identify_channel(ptarget) {
-            Some(channel_id) => KnownChannel(channel_id),
+        let (target, channel) = match state.identify_channel(ptarget) {
+            Some(channel_id) => (KnownChannel(channel_id), Some(ptarget.to_string())),
             None => match state.identify_nick(ptarget) {
-                Some(user_id) => KnownUser(user_id),
-                None => AnonymousUser
+                Some(user_id) => (KnownUser(user_id), None),
+                None => (AnonymousUser, None)
             }
         };
 
+        let mask = source_mask(&raw);
+        let account = mask.and_then(|mask| self.identities.account(privmsg.source_nick(), mask));
+        let is_admin = self.is_admin(privmsg.source_nick(), mask, account.as_ref().map(|x| &x[..]));
         let builder = DispatchBuilder {
             state: state.clone(),
             sender: raw_tx.clone(),
+            sink: ReplySink::Irc(raw_tx.clone()),
             reply_target: reply_target,
             source: source.clone(),
             target: target.clone(),
+            source_nick: privmsg.source_nick().to_string(),
+            account: account,
+            identities: self.identities.clone(),
+            is_admin: is_admin,
+            channel: channel,
         };
 
         let nick_cmd = format!("{}: ", state.get_self_nick());
-        let mut prefix = get_prefix(privmsg, &self.cmd_prefixes);
+        let mut prefix = get_prefix(privmsg, &self.cmd_prefixes).map(|x| x.to_string());
+        // Only suggest commands for typos after an explicit command prefix:
+        // addressing the bot by nick is often just conversation.
+        let suggest = prefix.is_some();
 
         if privmsg.get_body_raw().starts_with(nick_cmd.as_bytes()) {
-            prefix = prefix.or(Some(&nick_cmd));
+            prefix = prefix.or(Some(nick_cmd));
         }
         
         if let Some(prefix) = prefix {
@@ -220,13 +868,126 @@ impl PluginContainer {
                 Err(_) => return,
             };
 
-            for &mut (ref mut plugin, ref mappers) in self.plugins.iter_mut() {
-                for &(token, ref mapper_format) in mappers.iter() {
-                    if let Ok(command_phrase) = mapper_format.parse(token, &message_body) {
-                        let dispatch = builder.build(command_phrase);
-                        plugin.dispatch_cmd(&dispatch, privmsg);
-                    }
+            let stages = match pipeline::split_stages(&message_body) {
+                Ok(stages) => stages,
+                Err(_) => {
+                    builder.reply(&format!("At most {} commands may be chained", pipeline::MAX_STAGES));
+                    return;
+                }
+            };
+
+            let stages = stages.into_iter().map(|x| x.to_string()).collect();
+            self.run_stages(msg, builder, prefix, suggest, stages, Vec::new(), pipeline::deadline());
+        }
+    }
+
+    /// Runs the stages of a pipeline in turn, the first fed `input`.  If
+    /// a stage's plugin has yet to finish replying, the rest are left
+    /// for `tick`.
+    fn run_stages(&mut self, msg: &IrcMsg, builder: DispatchBuilder, prefix: String, suggest: bool,
+                  mut stages: Vec<String>, mut input: Vec<String>, deadline: Instant) {
+        let privmsg;
+        match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(p) => privmsg = p,
+            Err(_) => return,
+        }
+
+        while stages.len() > 0 {
+            let stage = stages.remove(0);
+            let stage_body = pipeline::feed_stage(&stage, &input);
+            if stages.len() == 0 {
+                self.dispatch_command(&builder, &prefix, suggest, stage_body, privmsg);
+                return;
+            }
+
+            let (tx, rx) = mpsc::channel();
+            let dispatched = {
+                let piped = builder.with_sink(ReplySink::Pipe(tx));
+                self.dispatch_command(&piped, &prefix, suggest, stage_body, privmsg)
+            };
+            if !dispatched {
+                return;
+            }
+            let mut output = StageOutput::new(rx, deadline);
+            let polled = output.poll();
+            if let Ok(Poll::Pending) = polled {
+                self.pipelines.push(PendingPipeline {
+                    msg: msg.to_owned(),
+                    builder: builder,
+                    prefix: prefix,
+                    suggest: suggest,
+                    stage: stage,
+                    output: output,
+                    rest: stages,
+                });
+                return;
+            }
+            input = match piped_output(&builder, &prefix, &stage, polled) {
+                Some(lines) => lines,
+                None => return,
+            };
+        }
+    }
+
+    /// Expands aliases in, and dispatches, a single command.  Returns
+    /// whether a built-in or plugin accepted the command.
+    fn dispatch_command(&mut self, builder: &DispatchBuilder, prefix: &str, suggest: bool,
+                        message_body: String, privmsg: &server::Privmsg) -> bool {
+        let message_body = match self.aliases.expand(&message_body) {
+            Ok(Some(expanded)) => expanded,
+            Ok(None) => message_body,
+            Err(err) => {
+                builder.reply(&describe_alias_error(&err));
+                return false;
+            }
+        };
+
+        let mut builtin_match = None;
+        for mapped in self.builtins.iter() {
+            if let Ok(command_phrase) = mapped.format.parse(mapped.token, &message_body) {
+                builtin_match = Some(command_phrase);
+                break;
+            }
+        }
+        if let Some(command_phrase) = builtin_match {
+            let help_prefix = self.cmd_prefixes.first().cloned().unwrap_or(prefix.to_string());
+            self.dispatch_builtin(&builder.build(command_phrase), builder, &help_prefix);
+            return true;
+        }
+
+        let best_match = {
+            let plugins = &self.plugins;
+            self.index.best_match(&message_body, |r: FormatRef| {
+                let mapped = &plugins[r.plugin].formats[r.format];
+                (mapped.token, &mapped.format)
+            })
+        };
+
+        match best_match {
+            Some((format_ref, command_phrase)) => {
+                match self.policy.access(builder.channel(), &self.plugins[format_ref.plugin].name) {
+                    Access::Disabled => return false,
+                    Access::AdminsOnly if !builder.is_admin => {
+                        builder.reply(&format!("{}: only admins may use {} here",
+                            builder.source_nick, self.plugins[format_ref.plugin].name));
+                        return false;
+                    },
+                    _ => (),
+                }
+                let dispatch = builder.build(command_phrase);
+                let panic_limit = self.panic_limit;
+                let registered = &mut self.plugins[format_ref.plugin];
+                if registered.disabled {
+                    builder.reply(&format!("{} is disabled after repeated errors", registered.name));
+                    return false;
                 }
+                let context = ::botcore::MaybeString::new(privmsg.as_bytes());
+                registered.guard(panic_limit, &context, |plugin| plugin.dispatch_cmd(&dispatch, privmsg));
+                true
+            },
+            None => {
+                self.dispatch_unmatched(builder, prefix, suggest, &message_body);
+                false
             }
         }
     }
@@ -0,0 +1,138 @@
+use std::mem;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use super::format::split_unquoted;
+
+/// The most commands one message may chain together.
+pub const MAX_STAGES: usize = 4;
+
+/// How long a whole pipeline may take, including waiting for plugins
+/// which reply from their own threads.
+pub const TIMEOUT_MS: u64 = 5000;
+
+const SEPARATOR: &'static str = " | ";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PipeError {
+    TooManyStages,
+    TimedOut,
+}
+
+/// Splits a message into the commands of a pipeline, e.g.
+/// `pick a, b | u` into `["pick a, b", "u"]`.  Quoted or escaped
+/// separators are left alone.
+pub fn split_stages(message_body: &str) -> Result<Vec<&str>, PipeError> {
+    let stages: Vec<&str> = split_unquoted(message_body, SEPARATOR).into_iter()
+        .map(|x| x.trim())
+        .collect();
+    if MAX_STAGES < stages.len() {
+        return Err(PipeError::TooManyStages);
+    }
+    Ok(stages)
+}
+
+/// The message body for the next stage: its own command with the
+/// output of the previous stage appended as the rest-argument.
+pub fn feed_stage(stage: &str, output: &[String]) -> String {
+    let mut body = stage.to_string();
+    for line in output.iter() {
+        body.push(' ');
+        body.push_str(line);
+    }
+    body
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Poll {
+    /// Some copy of the dispatch holding the sender is still alive.
+    Pending,
+    /// Every copy has been dropped; these are all the lines replied.
+    Done(Vec<String>),
+}
+
+/// The lines one stage replies into a pipe, gathered without blocking.
+pub struct StageOutput {
+    rx: Receiver<String>,
+    lines: Vec<String>,
+    deadline: Instant,
+}
+
+impl StageOutput {
+    pub fn new(rx: Receiver<String>, deadline: Instant) -> StageOutput {
+        StageOutput {
+            rx: rx,
+            lines: Vec::new(),
+            deadline: deadline,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Takes whatever has been replied so far.
+    pub fn poll(&mut self) -> Result<Poll, PipeError> {
+        loop {
+            match self.rx.try_recv() {
+                Ok(line) => self.lines.push(line),
+                Err(TryRecvError::Disconnected) => {
+                    return Ok(Poll::Done(mem::replace(&mut self.lines, Vec::new())));
+                },
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+        if self.deadline <= Instant::now() {
+            return Err(PipeError::TimedOut);
+        }
+        Ok(Poll::Pending)
+    }
+}
+
+/// The deadline for a pipeline starting now.
+pub fn deadline() -> Instant {
+    Instant::now() + Duration::from_millis(TIMEOUT_MS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::time::{Duration, Instant};
+
+    use super::{split_stages, feed_stage, StageOutput, Poll, PipeError};
+
+    #[test]
+    fn test_split_stages() {
+        assert_eq!(split_stages("pick a, b, c | u"), Ok(vec!["pick a, b, c", "u"]));
+        assert_eq!(split_stages("8ball a|b"), Ok(vec!["8ball a|b"]));
+        assert_eq!(split_stages("pick \"a | b\", c | u"), Ok(vec!["pick \"a | b\", c", "u"]));
+        assert_eq!(split_stages("a | b | c | d | e"), Err(PipeError::TooManyStages));
+        assert_eq!(split_stages("a | b | c | d '| e'"), Ok(vec!["a", "b", "c", "d '| e'"]));
+    }
+
+    #[test]
+    fn test_feed_stage() {
+        let output = vec!["b".to_string(), "c".to_string()];
+        assert_eq!(feed_stage("u", &output), "u b c");
+        assert_eq!(feed_stage("u", &[]), "u");
+    }
+
+    #[test]
+    fn test_stage_output() {
+        let (tx, rx) = channel();
+        let worker_tx = tx.clone();
+        let mut output = StageOutput::new(rx, Instant::now() + Duration::from_millis(1000));
+        tx.send("first".to_string()).unwrap();
+        drop(tx);
+        assert_eq!(output.poll(), Ok(Poll::Pending));
+        worker_tx.send("second".to_string()).unwrap();
+        drop(worker_tx);
+        assert_eq!(output.poll(),
+            Ok(Poll::Done(vec!["first".to_string(), "second".to_string()])));
+
+        let (tx, rx) = channel::<String>();
+        let mut output = StageOutput::new(rx, Instant::now());
+        assert_eq!(output.poll(), Err(PipeError::TimedOut));
+        drop(tx);
+    }
+}
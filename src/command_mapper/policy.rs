@@ -0,0 +1,94 @@
+//! Which plugins' commands may be used in which channels, and by whom.
+
+use std::collections::BTreeMap;
+
+/// Applies to every channel, and to private messages.
+pub const ANY_CHANNEL: &'static str = "*";
+
+/// A `[core.channel_plugins."<channel>"]` table in the configuration file.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone, Default)]
+pub struct ChannelConfig {
+    /// Plugins whose commands are ignored
+    pub disabled: Option<Vec<String>>,
+    /// Plugins whose commands only admins may use
+    pub admins_only: Option<Vec<String>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Allowed,
+    AdminsOnly,
+    Disabled,
+}
+
+fn lists(names: &Option<Vec<String>>, plugin: &str) -> bool {
+    names.iter().flat_map(|names| names.iter()).any(|name| name == plugin)
+}
+
+pub struct ChannelPolicy {
+    // by lowercased channel name
+    channels: BTreeMap<String, ChannelConfig>,
+}
+
+impl ChannelPolicy {
+    pub fn new() -> ChannelPolicy {
+        ChannelPolicy { channels: BTreeMap::new() }
+    }
+
+    pub fn set(&mut self, channel: &str, config: ChannelConfig) {
+        self.channels.insert(channel.to_lowercase(), config);
+    }
+
+    /// How `plugin`'s commands may be used in `channel`, or in private
+    /// messages if `None`.  Settings for `ANY_CHANNEL` and for the channel
+    /// itself both apply.
+    pub fn access(&self, channel: Option<&str>, plugin: &str) -> Access {
+        let specific = channel.and_then(|channel| self.channels.get(&channel.to_lowercase()));
+        let mut access = Access::Allowed;
+        for config in self.channels.get(ANY_CHANNEL).into_iter().chain(specific.into_iter()) {
+            if lists(&config.disabled, plugin) {
+                return Access::Disabled;
+            }
+            if lists(&config.admins_only, plugin) {
+                access = Access::AdminsOnly;
+            }
+        }
+        access
+    }
+
+    /// Whether someone may use `plugin`'s commands in `channel`.
+    pub fn permits(&self, channel: Option<&str>, plugin: &str, is_admin: bool) -> bool {
+        match self.access(channel, plugin) {
+            Access::Allowed => true,
+            Access::AdminsOnly => is_admin,
+            Access::Disabled => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelPolicy, ChannelConfig, Access};
+
+    fn names(names: &[&str]) -> Option<Vec<String>> {
+        Some(names.iter().map(|name| name.to_string()).collect())
+    }
+
+    #[test]
+    fn test_access() {
+        let mut policy = ChannelPolicy::new();
+        policy.set("*", ChannelConfig { disabled: None, admins_only: names(&["economy"]) });
+        policy.set("#Quiet", ChannelConfig { disabled: names(&["greed", "economy"]), admins_only: None });
+
+        assert_eq!(policy.access(Some("#quiet"), "greed"), Access::Disabled);
+        assert_eq!(policy.access(Some("#quiet"), "economy"), Access::Disabled);
+        assert_eq!(policy.access(Some("#quiet"), "dice"), Access::Allowed);
+        assert_eq!(policy.access(Some("#other"), "greed"), Access::Allowed);
+        assert_eq!(policy.access(Some("#other"), "economy"), Access::AdminsOnly);
+        assert_eq!(policy.access(None, "economy"), Access::AdminsOnly);
+
+        assert!(policy.permits(Some("#other"), "economy", true));
+        assert!(!policy.permits(Some("#other"), "economy", false));
+        assert!(!policy.permits(Some("#quiet"), "greed", true));
+    }
+}
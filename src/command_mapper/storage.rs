@@ -0,0 +1,389 @@
+//! Persistent key-value storage for plugins.
+//!
+//! Each plugin gets a namespace, kept as a JSON object in
+//! `<data_dir>/<namespace>.json`, the name escaped by `file_stem`.
+//! Changes are held in memory until `Storage::flush`, which the snapshot
+//! thread calls periodically and the `PluginContainer` calls when
+//! stopping, or until `Namespace::flush` writes one namespace at once.
+//! A file is replaced atomically by writing a temporary file and renaming
+//! it over the old one.  Flushing writes copies of the changed tables, so
+//! plugins are not held up while the files are written.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::{Decodable, Encodable};
+use rustc_serialize::json::{self, Json};
+
+struct Table {
+    values: BTreeMap<String, Json>,
+    dirty: bool,
+    // bumped on every change, so that a flush can tell whether the table
+    // changed while its copy was being written
+    generation: u64,
+}
+
+impl Table {
+    fn changed(&mut self) {
+        self.dirty = true;
+        self.generation += 1;
+    }
+}
+
+struct Inner {
+    // `None` for storage which is never written out
+    dir: Option<PathBuf>,
+    tables: Mutex<HashMap<String, Table>>,
+    // held while flushing, so that an older copy of a table is never
+    // written over a newer one
+    writing: Mutex<()>,
+}
+
+#[derive(Clone)]
+pub struct Storage {
+    inner: Arc<Inner>,
+}
+
+/// One plugin's view of the storage.
+#[derive(Clone)]
+pub struct Namespace {
+    name: String,
+    inner: Arc<Inner>,
+}
+
+/// Changes to a namespace made together by `Namespace::apply`.
+pub struct Batch {
+    values: Vec<(String, Result<Json, json::EncoderError>)>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch { values: Vec::new() }
+    }
+
+    pub fn set<T: Encodable>(&mut self, key: &str, value: &T) {
+        let encoded = json::encode(value).map(|encoded| Json::from_str(&encoded).unwrap());
+        self.values.push((key.to_string(), encoded));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// Plugin names such as `r/a/dio` are not all valid file names.  Bytes
+/// other than ASCII letters, digits and `-` are escaped as `_` and two hex
+/// digits, so that no two namespaces share a file.
+fn file_stem(namespace: &str) -> String {
+    let mut stem = String::new();
+    for &byte in namespace.as_bytes().iter() {
+        match byte {
+            b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' => stem.push(byte as char),
+            _ => stem.push_str(&format!("_{:02x}", byte)),
+        }
+    }
+    stem
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("json.tmp");
+    {
+        let mut file = try!(File::create(&tmp_path));
+        try!(file.write_all(contents));
+        try!(file.sync_all());
+    }
+    fs::rename(&tmp_path, path)
+}
+
+fn read_table(path: &Path) -> BTreeMap<String, Json> {
+    let mut contents = String::new();
+    match File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => (),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return BTreeMap::new(),
+        Err(err) => {
+            warn!("storage: failed to read {:?}: {:?}", path, err);
+            return BTreeMap::new();
+        }
+    }
+    match Json::from_str(&contents) {
+        Ok(Json::Object(values)) => values,
+        _ => {
+            // keep it for inspection rather than overwrite it on the next flush
+            let aside = path.with_extension("json.corrupt");
+            warn!("storage: {:?} is corrupt; moving it to {:?}", path, aside);
+            let _ = fs::rename(path, &aside);
+            BTreeMap::new()
+        }
+    }
+}
+
+impl Inner {
+    fn path(&self, namespace: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{}.json", file_stem(namespace))))
+    }
+
+    fn with_table<F, T>(&self, namespace: &str, func: F) -> T where F: FnOnce(&mut Table) -> T {
+        let mut tables = self.tables.lock().unwrap();
+        if !tables.contains_key(namespace) {
+            let values = match self.path(namespace) {
+                Some(path) => read_table(&path),
+                None => BTreeMap::new(),
+            };
+            tables.insert(namespace.to_string(), Table { values: values, dirty: false, generation: 0 });
+        }
+        func(tables.get_mut(namespace).unwrap())
+    }
+
+    /// Copies of the tables changed since they were last written, with
+    /// their generations; only `only`'s, if given.
+    fn dirty_tables(&self, only: Option<&str>) -> Vec<(String, u64, BTreeMap<String, Json>)> {
+        let tables = self.tables.lock().unwrap();
+        tables.iter()
+            .filter(|&(namespace, table)| table.dirty && only.map(|only| only == &namespace[..]).unwrap_or(true))
+            .map(|(namespace, table)| (namespace.clone(), table.generation, table.values.clone()))
+            .collect()
+    }
+
+    /// Writes out the changed tables, or only `only`.  A table which fails
+    /// to write is kept for the next flush, and the first failure is
+    /// returned once the others are written.
+    fn flush(&self, only: Option<&str>) -> io::Result<()> {
+        let _writing = self.writing.lock().unwrap();
+        let mut result = Ok(());
+        for (namespace, generation, values) in self.dirty_tables(only).into_iter() {
+            if let Some(path) = self.path(&namespace) {
+                let contents = Json::Object(values).pretty().to_string();
+                if let Err(err) = write_atomic(&path, contents.as_bytes()) {
+                    if result.is_ok() {
+                        result = Err(err);
+                    }
+                    continue;
+                }
+            }
+            self.mark_written(&namespace, generation);
+        }
+        result
+    }
+
+    /// Marks a table as written, unless it has changed since the copy of
+    /// generation `generation` was taken.
+    fn mark_written(&self, namespace: &str, generation: u64) {
+        let mut tables = self.tables.lock().unwrap();
+        if let Some(table) = tables.get_mut(namespace) {
+            if table.generation == generation {
+                table.dirty = false;
+            }
+        }
+    }
+}
+
+impl Storage {
+    /// Storage kept under `dir`, which is created if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Storage> {
+        try!(fs::create_dir_all(dir.as_ref()));
+        Ok(Storage {
+            inner: Arc::new(Inner {
+                dir: Some(dir.as_ref().to_path_buf()),
+                tables: Mutex::new(HashMap::new()),
+                writing: Mutex::new(()),
+            }),
+        })
+    }
+
+    /// Storage which is never written to disk.
+    pub fn in_memory() -> Storage {
+        Storage {
+            inner: Arc::new(Inner {
+                dir: None,
+                tables: Mutex::new(HashMap::new()),
+                writing: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn namespace(&self, name: &str) -> Namespace {
+        Namespace {
+            name: name.to_string(),
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Writes out every namespace changed since the last flush.  A
+    /// namespace which fails to write is kept for the next flush, and the
+    /// first failure is returned once the others are written.
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.flush(None)
+    }
+
+    /// Flushes every `interval`, from a background thread.
+    pub fn start_snapshots(&self, interval: Duration) {
+        let storage = self.clone();
+        let spawned = thread::Builder::new().name("storage-snapshot".to_string()).spawn(move || {
+            loop {
+                thread::sleep(interval);
+                if let Err(err) = storage.flush() {
+                    warn!("storage: snapshot failed: {:?}", err);
+                }
+            }
+        });
+        if let Err(err) = spawned {
+            warn!("storage: failed to spawn snapshot thread: {:?}", err);
+        }
+    }
+}
+
+impl Namespace {
+    pub fn get<T: Decodable>(&self, key: &str) -> Option<T> {
+        let value = match self.inner.with_table(&self.name, |table| table.values.get(key).cloned()) {
+            Some(value) => value,
+            None => return None,
+        };
+        match Decodable::decode(&mut json::Decoder::new(value)) {
+            Ok(decoded) => Some(decoded),
+            Err(err) => {
+                warn!("storage: {}/{} did not decode: {:?}", self.name, key, err);
+                None
+            }
+        }
+    }
+
+    pub fn set<T: Encodable>(&self, key: &str, value: &T) {
+        let mut batch = Batch::new();
+        batch.set(key, value);
+        self.apply(batch);
+    }
+
+    /// Makes every change in `batch` at once, so that no flush writes
+    /// some of them without the others.
+    pub fn apply(&self, batch: Batch) {
+        if batch.values.is_empty() {
+            return;
+        }
+        let name = &self.name;
+        self.inner.with_table(name, |table| {
+            for (key, encoded) in batch.values.into_iter() {
+                match encoded {
+                    Ok(encoded) => { table.values.insert(key, encoded); },
+                    Err(err) => warn!("storage: {}/{} did not encode: {:?}", name, key, err),
+                }
+            }
+            table.changed();
+        });
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.inner.with_table(&self.name, |table| {
+            if table.values.remove(key).is_some() {
+                table.changed();
+            }
+        });
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.inner.with_table(&self.name, |table| table.values.keys().cloned().collect())
+    }
+
+    /// Writes out this namespace now, if it has changed, rather than at
+    /// the next snapshot.
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.flush(Some(&self.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{Storage, file_stem};
+
+    #[derive(RustcEncodable, RustcDecodable, Debug, PartialEq)]
+    struct Stats {
+        games: u32,
+        nick: String,
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = env::temp_dir().join(format!("ircbot-storage-test-{}", ::time::precise_time_ns()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let storage = Storage::open(&dir).unwrap();
+        let greed = storage.namespace("greed");
+        let radio = storage.namespace("r/a/dio");
+        greed.set("alice", &Stats { games: 3, nick: "Alice".to_string() });
+        greed.set("bob", &1u64);
+        greed.remove("bob");
+        radio.set("alice", &7u64);
+        storage.namespace("r_a_dio").set("alice", &8u64);
+        storage.flush().unwrap();
+        assert!(dir.join("r_2fa_2fdio.json").exists());
+        assert!(dir.join("r_5fa_5fdio.json").exists());
+
+        let reopened = Storage::open(&dir).unwrap();
+        let greed = reopened.namespace("greed");
+        assert_eq!(greed.get::<Stats>("alice"), Some(Stats { games: 3, nick: "Alice".to_string() }));
+        assert_eq!(greed.get::<u64>("bob"), None);
+        assert_eq!(greed.get::<u64>("alice"), None);
+        assert_eq!(greed.keys(), vec!["alice".to_string()]);
+        assert_eq!(reopened.namespace("r/a/dio").get::<u64>("alice"), Some(7));
+        assert_eq!(reopened.namespace("r_a_dio").get::<u64>("alice"), Some(8));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_stem() {
+        assert_eq!(file_stem("greed"), "greed");
+        assert_eq!(file_stem("*ledger"), "_2aledger");
+        assert_eq!(file_stem("a_b"), "a_5fb");
+        assert_eq!(file_stem("é"), "_c3_a9");
+    }
+
+    #[test]
+    fn test_flush_failure() {
+        let dir = env::temp_dir().join(format!("ircbot-storage-test-{}", ::time::precise_time_ns()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let storage = Storage::open(&dir).unwrap();
+        // a directory where the file should go cannot be replaced
+        fs::create_dir_all(dir.join("broken.json").join("x")).unwrap();
+        for name in ["a", "broken", "z"].iter() {
+            storage.namespace(name).set("key", &1u64);
+        }
+        assert!(storage.flush().is_err());
+        assert!(dir.join("a.json").exists());
+        assert!(dir.join("z.json").exists());
+
+        // the failed namespace is still dirty, and is written once it can be
+        fs::remove_dir_all(dir.join("broken.json")).unwrap();
+        storage.flush().unwrap();
+        assert!(dir.join("broken.json").is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_changed_while_flushing() {
+        let storage = Storage::in_memory();
+        let greed = storage.namespace("greed");
+        greed.set("alice", &1u64);
+
+        // a change made between copying a table and writing it out
+        // leaves the table dirty for the next flush
+        let copies = storage.inner.dirty_tables(None);
+        assert_eq!(copies.len(), 1);
+        greed.set("alice", &2u64);
+        storage.inner.mark_written(&copies[0].0, copies[0].1);
+        assert_eq!(storage.inner.dirty_tables(None).len(), 1);
+
+        storage.flush().unwrap();
+        assert!(storage.inner.dirty_tables(None).is_empty());
+        assert_eq!(greed.get::<u64>("alice"), Some(2));
+    }
+}
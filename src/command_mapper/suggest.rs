@@ -0,0 +1,71 @@
+use std::cmp::min;
+
+/// How far a command name may be from an input to be suggested for it.
+/// Anything looser fires on ordinary chatter which happens to start with
+/// a command prefix.
+const MAX_DISTANCE: usize = 1;
+
+/// Levenshtein distance between two strings, counted in chars.
+pub fn edit_distance(left: &str, right: &str) -> usize {
+    let right: Vec<char> = right.chars().collect();
+    let mut prev_row: Vec<usize> = (0..right.len() + 1).collect();
+    let mut cur_row: Vec<usize> = vec![0; right.len() + 1];
+
+    for (left_idx, left_ch) in left.chars().enumerate() {
+        cur_row[0] = left_idx + 1;
+        for (right_idx, &right_ch) in right.iter().enumerate() {
+            let substitution = if left_ch == right_ch { 0 } else { 1 };
+            cur_row[right_idx + 1] = min(
+                prev_row[right_idx] + substitution,
+                min(prev_row[right_idx + 1] + 1, cur_row[right_idx] + 1));
+        }
+        ::std::mem::swap(&mut prev_row, &mut cur_row);
+    }
+    prev_row[right.len()]
+}
+
+/// Finds the candidate closest to `input`, if any is a single edit away.
+/// Ties go to the earliest candidate.
+pub fn closest<'a, I>(input: &str, candidates: I) -> Option<&'a str>
+    where I: Iterator<Item=&'a str>
+{
+    let mut best: Option<(usize, &'a str)> = None;
+    for candidate in candidates {
+        let distance = edit_distance(input, candidate);
+        if MAX_DISTANCE < distance {
+            continue;
+        }
+        best = match best {
+            Some((best_distance, _)) if best_distance <= distance => best,
+            _ => Some((distance, candidate)),
+        };
+    }
+    best.map(|(_, candidate)| candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, closest};
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("seen", "seen"), 0);
+        assert_eq!(edit_distance("sen", "seen"), 1);
+        assert_eq!(edit_distance("seen", ""), 4);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("greed-stats", "greed"), 6);
+    }
+
+    #[test]
+    fn test_closest() {
+        let commands = ["help", "seen", "greed", "greed-stats", "deer"];
+        assert_eq!(closest("sen", commands.iter().map(|x| *x)), Some("seen"));
+        assert_eq!(closest("gred", commands.iter().map(|x| *x)), Some("greed"));
+        assert_eq!(closest("greedstats", commands.iter().map(|x| *x)), Some("greed-stats"));
+        assert_eq!(closest("greedstat", commands.iter().map(|x| *x)), None);
+        assert_eq!(closest("hello", commands.iter().map(|x| *x)), None);
+        assert_eq!(closest("deed", commands.iter().map(|x| *x)), Some("deer"));
+        assert_eq!(closest("xyzzy", commands.iter().map(|x| *x)), None);
+    }
+}
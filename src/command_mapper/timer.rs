@@ -0,0 +1,73 @@
+use std::sync::{Arc, Mutex};
+
+use time::Timespec;
+
+/// Identifies a timer to the plugin which scheduled it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerToken(pub u64);
+
+/// A plugin's pending timers.  The plugin schedules them through the
+/// handle from `IrcBotConfigurator::timers`, and the container calls
+/// `RustBotPlugin::on_timer` once each is due.  Timers are checked
+/// every bot tick, so they fire up to half a second late.
+#[derive(Clone)]
+pub struct Timers {
+    // sorted by deadline
+    pending: Arc<Mutex<Vec<(Timespec, TimerToken)>>>,
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers { pending: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// Schedules `token` for `at`, replacing any timer it already has.
+    pub fn schedule(&self, at: Timespec, token: TimerToken) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|&(_, tok)| tok != token);
+        let idx = match pending.binary_search(&(at, token)) {
+            Ok(idx) | Err(idx) => idx,
+        };
+        pending.insert(idx, (at, token));
+    }
+
+    /// Returns whether `token` was pending.
+    pub fn cancel(&self, token: TimerToken) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        pending.retain(|&(_, tok)| tok != token);
+        pending.len() < before
+    }
+
+    /// Removes and returns the timers due at `now`, earliest first.
+    pub fn take_due(&self, now: Timespec) -> Vec<TimerToken> {
+        let mut pending = self.pending.lock().unwrap();
+        let due = pending.iter().take_while(|&&(at, _)| at <= now).count();
+        pending.drain(..due).map(|(_, token)| token).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+
+    use super::{Timers, TimerToken};
+
+    #[test]
+    fn test_take_due() {
+        let timers = Timers::new();
+        timers.schedule(Timespec::new(30, 0), TimerToken(3));
+        timers.schedule(Timespec::new(10, 0), TimerToken(1));
+        timers.schedule(Timespec::new(20, 0), TimerToken(2));
+        timers.schedule(Timespec::new(40, 0), TimerToken(4));
+        // rescheduling moves the timer
+        timers.schedule(Timespec::new(5, 0), TimerToken(4));
+        assert!(timers.cancel(TimerToken(2)));
+        assert!(!timers.cancel(TimerToken(2)));
+
+        assert_eq!(timers.take_due(Timespec::new(0, 0)), vec![]);
+        assert_eq!(timers.take_due(Timespec::new(10, 0)), vec![TimerToken(4), TimerToken(1)]);
+        assert_eq!(timers.take_due(Timespec::new(100, 0)), vec![TimerToken(3)]);
+        assert_eq!(timers.take_due(Timespec::new(100, 0)), vec![]);
+    }
+}
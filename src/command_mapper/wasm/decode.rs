@@ -0,0 +1,682 @@
+//! Decodes the binary format of WebAssembly modules.
+//!
+//! Function bodies are decoded into `Instr`s with the targets of blocks
+//! resolved, ready for `exec` to run.  Modules are not type-checked;
+//! `exec` traps, rather than misbehaving, on code which would not have
+//! validated.
+
+const MAGIC: &'static [u8] = b"\0asm";
+const VERSION: u32 = 1;
+
+/// Function bodies may declare no more locals than this.
+const MAX_LOCALS: u32 = 50000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The module ends in the middle of something
+    Truncated,
+    Malformed(&'static str),
+    /// Valid, but uses a feature the runtime lacks
+    Unsupported(&'static str),
+}
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ValueType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValueType>,
+    pub results: Vec<ValueType>,
+}
+
+#[derive(Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub type_idx: u32,
+}
+
+#[derive(Debug)]
+pub struct Function {
+    pub type_idx: u32,
+    /// The types of the locals after the parameters
+    pub locals: Vec<ValueType>,
+    pub code: Vec<Instr>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConstExpr {
+    Value(u64),
+    GetGlobal(u32),
+}
+
+#[derive(Debug)]
+pub struct Global {
+    pub mutable: bool,
+    pub init: ConstExpr,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportKind {
+    Func,
+    Table,
+    Memory,
+    Global,
+}
+
+#[derive(Debug)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+    pub index: u32,
+}
+
+#[derive(Debug)]
+pub struct Element {
+    pub offset: ConstExpr,
+    pub funcs: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct Data {
+    pub offset: ConstExpr,
+    pub bytes: Vec<u8>,
+}
+
+/// An instruction, with the immediates it needs.  Values of every type
+/// are kept as `u64`s: `i32`s zero-extended, floats as their bits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instr {
+    Unreachable,
+    Nop,
+    /// The number of results, and where the matching `End` is
+    Block(usize, usize),
+    Loop(usize),
+    /// The number of results, and where the matching `Else`, if any,
+    /// and `End` are
+    If(usize, Option<usize>, usize),
+    /// Where the matching `End` is
+    Else(usize),
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>, u32),
+    Return,
+    Call(u32),
+    /// Through the table, checking the callee has this type
+    CallIndirect(u32),
+    Drop,
+    Select,
+    GetLocal(u32),
+    SetLocal(u32),
+    TeeLocal(u32),
+    GetGlobal(u32),
+    SetGlobal(u32),
+    /// The opcode, and the constant offset
+    Load(u8, u32),
+    Store(u8, u32),
+    CurrentMemory,
+    GrowMemory,
+    MemoryCopy,
+    MemoryFill,
+    Const(u64),
+    /// One of the comparison, arithmetic and conversion opcodes, which
+    /// take no immediates
+    Numeric(u8),
+    /// A saturating float-to-int conversion, by its `0xfc` sub-opcode
+    TruncSat(u8),
+}
+
+#[derive(Debug, Default)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub functions: Vec<Function>,
+    pub table: Option<Limits>,
+    pub memory: Option<Limits>,
+    pub globals: Vec<Global>,
+    pub exports: Vec<Export>,
+    pub start: Option<u32>,
+    pub elements: Vec<Element>,
+    pub data: Vec<Data>,
+}
+
+impl Module {
+    /// The index of the function exported as `name`.
+    pub fn exported_func(&self, name: &str) -> Option<u32> {
+        self.exports.iter()
+            .find(|export| export.kind == ExportKind::Func && export.name == name)
+            .map(|export| export.index)
+    }
+
+    /// The type of the function at `func_idx`, counting imports first.
+    pub fn func_type(&self, func_idx: u32) -> Option<&FuncType> {
+        let func_idx = func_idx as usize;
+        let type_idx = if func_idx < self.imports.len() {
+            self.imports[func_idx].type_idx
+        } else {
+            match self.functions.get(func_idx - self.imports.len()) {
+                Some(function) => function.type_idx,
+                None => return None,
+            }
+        };
+        self.types.get(type_idx as usize)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes: bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.len() <= self.pos
+    }
+
+    fn byte(&mut self) -> DecodeResult<u8> {
+        match self.bytes.get(self.pos) {
+            Some(&byte) => {
+                self.pos += 1;
+                Ok(byte)
+            },
+            None => Err(DecodeError::Truncated),
+        }
+    }
+
+    fn take(&mut self, len: usize) -> DecodeResult<&'a [u8]> {
+        if self.bytes.len() - self.pos < len {
+            return Err(DecodeError::Truncated);
+        }
+        let taken = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(taken)
+    }
+
+    fn fixed(&mut self, len: usize) -> DecodeResult<u64> {
+        let bytes = try!(self.take(len));
+        Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn leb(&mut self, bits: u32, signed: bool) -> DecodeResult<u64> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = try!(self.byte());
+            if shift >= bits {
+                return Err(DecodeError::Malformed("integer too long"));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if signed && shift < 64 && byte & 0x40 != 0 {
+                    value |= !0 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn u32(&mut self) -> DecodeResult<u32> {
+        let value = try!(self.leb(32, false));
+        if value > ::std::u32::MAX as u64 {
+            return Err(DecodeError::Malformed("integer too large"));
+        }
+        Ok(value as u32)
+    }
+
+    fn i32(&mut self) -> DecodeResult<i32> {
+        let value = try!(self.leb(32, true)) as i64;
+        if value < ::std::i32::MIN as i64 || value > ::std::i32::MAX as i64 {
+            return Err(DecodeError::Malformed("integer too large"));
+        }
+        Ok(value as i32)
+    }
+
+    fn i64(&mut self) -> DecodeResult<i64> {
+        Ok(try!(self.leb(64, true)) as i64)
+    }
+
+    fn len(&mut self) -> DecodeResult<usize> {
+        let len = try!(self.u32()) as usize;
+        // every item takes at least a byte, so this bounds allocations
+        if self.bytes.len() - self.pos < len {
+            return Err(DecodeError::Truncated);
+        }
+        Ok(len)
+    }
+
+    fn name(&mut self) -> DecodeResult<String> {
+        let len = try!(self.len());
+        let bytes = try!(self.take(len));
+        match ::std::str::from_utf8(bytes) {
+            Ok(name) => Ok(name.to_string()),
+            Err(_) => Err(DecodeError::Malformed("name is not UTF-8")),
+        }
+    }
+
+    fn value_type(&mut self) -> DecodeResult<ValueType> {
+        match try!(self.byte()) {
+            0x7f => Ok(ValueType::I32),
+            0x7e => Ok(ValueType::I64),
+            0x7d => Ok(ValueType::F32),
+            0x7c => Ok(ValueType::F64),
+            _ => Err(DecodeError::Unsupported("value type")),
+        }
+    }
+
+    fn limits(&mut self) -> DecodeResult<Limits> {
+        match try!(self.byte()) {
+            0x00 => Ok(Limits { min: try!(self.u32()), max: None }),
+            0x01 => {
+                let min = try!(self.u32());
+                let max = try!(self.u32());
+                if max < min {
+                    return Err(DecodeError::Malformed("limits maximum below minimum"));
+                }
+                Ok(Limits { min: min, max: Some(max) })
+            },
+            _ => Err(DecodeError::Unsupported("shared memory")),
+        }
+    }
+
+    fn const_expr(&mut self) -> DecodeResult<ConstExpr> {
+        let expr = match try!(self.byte()) {
+            0x41 => ConstExpr::Value(try!(self.i32()) as u32 as u64),
+            0x42 => ConstExpr::Value(try!(self.i64()) as u64),
+            0x43 => ConstExpr::Value(try!(self.fixed(4))),
+            0x44 => ConstExpr::Value(try!(self.fixed(8))),
+            0x23 => ConstExpr::GetGlobal(try!(self.u32())),
+            _ => return Err(DecodeError::Unsupported("constant expression")),
+        };
+        if try!(self.byte()) != 0x0b {
+            return Err(DecodeError::Unsupported("constant expression"));
+        }
+        Ok(expr)
+    }
+
+    fn block_arity(&mut self) -> DecodeResult<usize> {
+        match try!(self.byte()) {
+            0x40 => Ok(0),
+            0x7f | 0x7e | 0x7d | 0x7c => Ok(1),
+            _ => Err(DecodeError::Unsupported("multi-value block")),
+        }
+    }
+}
+
+/// Decodes a function body's instructions, up to and including the
+/// final `End`.
+fn decode_code(reader: &mut Reader) -> DecodeResult<Vec<Instr>> {
+    let mut code = Vec::new();
+    // where the blocks around the next instruction start
+    let mut open: Vec<usize> = Vec::new();
+
+    loop {
+        let here = code.len();
+        let opcode = try!(reader.byte());
+        let instr = match opcode {
+            0x00 => Instr::Unreachable,
+            0x01 => Instr::Nop,
+            0x02 | 0x03 | 0x04 => {
+                let arity = try!(reader.block_arity());
+                open.push(here);
+                match opcode {
+                    0x02 => Instr::Block(arity, 0),
+                    0x03 => Instr::Loop(arity),
+                    _ => Instr::If(arity, None, 0),
+                }
+            },
+            0x05 => {
+                let start = match open.last() {
+                    Some(&start) => start,
+                    None => return Err(DecodeError::Malformed("else outside if")),
+                };
+                match code[start] {
+                    Instr::If(_, ref mut else_idx @ None, _) => *else_idx = Some(here),
+                    _ => return Err(DecodeError::Malformed("else outside if")),
+                }
+                Instr::Else(0)
+            },
+            0x0b => {
+                let start = match open.pop() {
+                    Some(start) => start,
+                    None => {
+                        code.push(Instr::End);
+                        return Ok(code);
+                    }
+                };
+                let else_idx = match code[start] {
+                    Instr::Block(_, ref mut end) => { *end = here; None },
+                    Instr::If(_, else_idx, ref mut end) => { *end = here; else_idx },
+                    _ => None,
+                };
+                if let Some(else_idx) = else_idx {
+                    code[else_idx] = Instr::Else(here);
+                }
+                Instr::End
+            },
+            0x0c => Instr::Br(try!(reader.u32())),
+            0x0d => Instr::BrIf(try!(reader.u32())),
+            0x0e => {
+                let count = try!(reader.len());
+                let mut targets = Vec::with_capacity(count);
+                for _ in 0..count {
+                    targets.push(try!(reader.u32()));
+                }
+                Instr::BrTable(targets, try!(reader.u32()))
+            },
+            0x0f => Instr::Return,
+            0x10 => Instr::Call(try!(reader.u32())),
+            0x11 => {
+                let type_idx = try!(reader.u32());
+                if try!(reader.u32()) != 0 {
+                    return Err(DecodeError::Unsupported("multiple tables"));
+                }
+                Instr::CallIndirect(type_idx)
+            },
+            0x1a => Instr::Drop,
+            0x1b => Instr::Select,
+            0x1c => {
+                // typed select; the types don't matter here
+                let count = try!(reader.len());
+                for _ in 0..count {
+                    try!(reader.value_type());
+                }
+                Instr::Select
+            },
+            0x20 => Instr::GetLocal(try!(reader.u32())),
+            0x21 => Instr::SetLocal(try!(reader.u32())),
+            0x22 => Instr::TeeLocal(try!(reader.u32())),
+            0x23 => Instr::GetGlobal(try!(reader.u32())),
+            0x24 => Instr::SetGlobal(try!(reader.u32())),
+            0x28...0x3e => {
+                // alignment is only a hint
+                try!(reader.u32());
+                let offset = try!(reader.u32());
+                if opcode <= 0x35 {
+                    Instr::Load(opcode, offset)
+                } else {
+                    Instr::Store(opcode, offset)
+                }
+            },
+            0x3f | 0x40 => {
+                if try!(reader.byte()) != 0 {
+                    return Err(DecodeError::Unsupported("multiple memories"));
+                }
+                if opcode == 0x3f { Instr::CurrentMemory } else { Instr::GrowMemory }
+            },
+            0x41 => Instr::Const(try!(reader.i32()) as u32 as u64),
+            0x42 => Instr::Const(try!(reader.i64()) as u64),
+            0x43 => Instr::Const(try!(reader.fixed(4))),
+            0x44 => Instr::Const(try!(reader.fixed(8))),
+            0x45...0xc4 => Instr::Numeric(opcode),
+            0xfc => match try!(reader.u32()) {
+                sub @ 0...7 => Instr::TruncSat(sub as u8),
+                10 => {
+                    if try!(reader.byte()) != 0 || try!(reader.byte()) != 0 {
+                        return Err(DecodeError::Unsupported("multiple memories"));
+                    }
+                    Instr::MemoryCopy
+                },
+                11 => {
+                    if try!(reader.byte()) != 0 {
+                        return Err(DecodeError::Unsupported("multiple memories"));
+                    }
+                    Instr::MemoryFill
+                },
+                _ => return Err(DecodeError::Unsupported("instruction")),
+            },
+            _ => return Err(DecodeError::Unsupported("instruction")),
+        };
+        code.push(instr);
+    }
+}
+
+fn decode_body(reader: &mut Reader, type_idx: u32) -> DecodeResult<Function> {
+    let mut locals = Vec::new();
+    let mut total: u32 = 0;
+    for _ in 0..try!(reader.len()) {
+        let count = try!(reader.u32());
+        let value_type = try!(reader.value_type());
+        total = total.saturating_add(count);
+        if MAX_LOCALS < total {
+            return Err(DecodeError::Unsupported("too many locals"));
+        }
+        for _ in 0..count {
+            locals.push(value_type);
+        }
+    }
+    let code = try!(decode_code(reader));
+    if !reader.is_empty() {
+        return Err(DecodeError::Malformed("function body continues past its end"));
+    }
+    Ok(Function { type_idx: type_idx, locals: locals, code: code })
+}
+
+fn decode_section(module: &mut Module, func_types: &mut Vec<u32>, id: u8, reader: &mut Reader) -> DecodeResult<()> {
+    match id {
+        1 => for _ in 0..try!(reader.len()) {
+            if try!(reader.byte()) != 0x60 {
+                return Err(DecodeError::Malformed("function type"));
+            }
+            let mut params = Vec::new();
+            for _ in 0..try!(reader.len()) {
+                params.push(try!(reader.value_type()));
+            }
+            let mut results = Vec::new();
+            for _ in 0..try!(reader.len()) {
+                results.push(try!(reader.value_type()));
+            }
+            if results.len() > 1 {
+                return Err(DecodeError::Unsupported("multiple results"));
+            }
+            module.types.push(FuncType { params: params, results: results });
+        },
+        2 => for _ in 0..try!(reader.len()) {
+            let module_name = try!(reader.name());
+            let name = try!(reader.name());
+            if try!(reader.byte()) != 0x00 {
+                return Err(DecodeError::Unsupported("importing anything but functions"));
+            }
+            let type_idx = try!(reader.u32());
+            module.imports.push(Import { module: module_name, name: name, type_idx: type_idx });
+        },
+        3 => for _ in 0..try!(reader.len()) {
+            func_types.push(try!(reader.u32()));
+        },
+        4 => for _ in 0..try!(reader.len()) {
+            if try!(reader.byte()) != 0x70 || module.table.is_some() {
+                return Err(DecodeError::Unsupported("tables other than one of functions"));
+            }
+            module.table = Some(try!(reader.limits()));
+        },
+        5 => for _ in 0..try!(reader.len()) {
+            if module.memory.is_some() {
+                return Err(DecodeError::Unsupported("multiple memories"));
+            }
+            module.memory = Some(try!(reader.limits()));
+        },
+        6 => for _ in 0..try!(reader.len()) {
+            try!(reader.value_type());
+            let mutable = match try!(reader.byte()) {
+                0 => false,
+                1 => true,
+                _ => return Err(DecodeError::Malformed("global mutability")),
+            };
+            let init = try!(reader.const_expr());
+            module.globals.push(Global { mutable: mutable, init: init });
+        },
+        7 => for _ in 0..try!(reader.len()) {
+            let name = try!(reader.name());
+            let kind = match try!(reader.byte()) {
+                0 => ExportKind::Func,
+                1 => ExportKind::Table,
+                2 => ExportKind::Memory,
+                3 => ExportKind::Global,
+                _ => return Err(DecodeError::Malformed("export kind")),
+            };
+            let index = try!(reader.u32());
+            module.exports.push(Export { name: name, kind: kind, index: index });
+        },
+        8 => module.start = Some(try!(reader.u32())),
+        9 => for _ in 0..try!(reader.len()) {
+            if try!(reader.u32()) != 0 {
+                return Err(DecodeError::Unsupported("element segment kind"));
+            }
+            let offset = try!(reader.const_expr());
+            let mut funcs = Vec::new();
+            for _ in 0..try!(reader.len()) {
+                funcs.push(try!(reader.u32()));
+            }
+            module.elements.push(Element { offset: offset, funcs: funcs });
+        },
+        10 => {
+            let count = try!(reader.len());
+            if count != func_types.len() {
+                return Err(DecodeError::Malformed("function and code sections disagree"));
+            }
+            for idx in 0..count {
+                let size = try!(reader.len());
+                let mut body = Reader::new(try!(reader.take(size)));
+                module.functions.push(try!(decode_body(&mut body, func_types[idx])));
+            }
+        },
+        11 => for _ in 0..try!(reader.len()) {
+            if try!(reader.u32()) != 0 {
+                return Err(DecodeError::Unsupported("data segment kind"));
+            }
+            let offset = try!(reader.const_expr());
+            let len = try!(reader.len());
+            let bytes = try!(reader.take(len)).to_vec();
+            module.data.push(Data { offset: offset, bytes: bytes });
+        },
+        // the data count, which only matters to validation
+        12 => { try!(reader.u32()); },
+        _ => return Err(DecodeError::Malformed("unknown section")),
+    }
+    if !reader.is_empty() {
+        return Err(DecodeError::Malformed("section continues past its end"));
+    }
+    Ok(())
+}
+
+pub fn decode(bytes: &[u8]) -> DecodeResult<Module> {
+    let mut reader = Reader::new(bytes);
+    if try!(reader.take(4).map_err(|_| DecodeError::BadMagic)) != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    let version = try!(reader.fixed(4)) as u32;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let mut module: Module = Default::default();
+    let mut func_types = Vec::new();
+    while !reader.is_empty() {
+        let id = try!(reader.byte());
+        let size = try!(reader.len());
+        let mut section = Reader::new(try!(reader.take(size)));
+        if id == 0 {
+            // custom sections carry names and debug information
+            continue;
+        }
+        try!(decode_section(&mut module, &mut func_types, id, &mut section));
+    }
+    if module.functions.len() != func_types.len() {
+        return Err(DecodeError::Malformed("function and code sections disagree"));
+    }
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, DecodeError, Instr, ValueType};
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, contents.len() as u8];
+        section.extend_from_slice(contents);
+        section
+    }
+
+    fn module(sections: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        for section in sections.iter() {
+            bytes.extend_from_slice(section);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_decode() {
+        let bytes = module(&[
+            // (i32) -> i32
+            section(1, &[1, 0x60, 1, 0x7f, 1, 0x7f]),
+            section(2, &[1, 3, b'e', b'n', b'v', 1, b'f', 0, 0]),
+            section(3, &[1, 0]),
+            section(5, &[1, 1, 1, 2]),
+            section(7, &[1, 3, b'r', b'u', b'n', 0, 1]),
+            // block (if (get_local 0) (then i32.const -1) (else i32.const 2)) end
+            section(10, &[1, 17, 1, 1, 0x7e,
+                0x02, 0x7f, 0x20, 0x00, 0x04, 0x7f, 0x41, 0x7f, 0x05, 0x41, 0x02, 0x0b, 0x0b, 0x0b]),
+            section(0, b"\x04name"),
+        ]);
+        let module = decode(&bytes).unwrap();
+        assert_eq!(module.types[0].params, vec![ValueType::I32]);
+        assert_eq!(module.imports[0].module, "env");
+        assert_eq!(module.imports[0].name, "f");
+        assert_eq!(module.memory.unwrap().max, Some(2));
+        assert_eq!(module.exported_func("run"), Some(1));
+        assert_eq!(module.func_type(1).map(|ty| ty.results.len()), Some(1));
+        assert_eq!(module.functions[0].locals, vec![ValueType::I64]);
+        assert_eq!(module.functions[0].code, vec![
+            Instr::Block(1, 7),
+            Instr::GetLocal(0),
+            Instr::If(1, Some(4), 6),
+            Instr::Const(0xffffffff),
+            Instr::Else(6),
+            Instr::Const(2),
+            Instr::End,
+            Instr::End,
+            Instr::End,
+        ]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b"\0as").unwrap_err(), DecodeError::BadMagic);
+        assert_eq!(decode(b"\0asm\x02\0\0\0").unwrap_err(), DecodeError::UnsupportedVersion(2));
+        assert_eq!(decode(&module(&[vec![1, 9, 1]])).unwrap_err(), DecodeError::Truncated);
+        assert_eq!(decode(&module(&[section(1, &[1, 0x60, 0, 2, 0x7f, 0x7f])])).unwrap_err(),
+            DecodeError::Unsupported("multiple results"));
+        // a body with no final end
+        assert_eq!(decode(&module(&[section(1, &[1, 0x60, 0, 0]), section(3, &[1, 0]),
+            section(10, &[1, 2, 0, 0x01])])).unwrap_err(), DecodeError::Truncated);
+        assert_eq!(decode(&module(&[section(3, &[1, 0])])).unwrap_err(),
+            DecodeError::Malformed("function and code sections disagree"));
+        assert_eq!(decode(&module(&[section(1, &[1, 0x60, 0, 0]), section(3, &[1, 0]),
+            section(10, &[1, 3, 0, 0x05, 0x0b])])).unwrap_err(),
+            DecodeError::Malformed("else outside if"));
+        assert_eq!(decode(&module(&[section(1, &[1, 0x60, 0, 0]), section(3, &[1, 0]),
+            section(10, &[1, 6, 1, 0xff, 0xff, 0x03, 0x7f, 0x0b])])).unwrap_err(),
+            DecodeError::Unsupported("too many locals"));
+    }
+}
@@ -0,0 +1,1040 @@
+//! Runs decoded WebAssembly modules, within limits on the instructions
+//! each call may execute and the memory the module may use.
+
+use std::mem;
+use std::sync::Arc;
+
+use super::decode::{ConstExpr, Instr, Module};
+
+pub const PAGE_SIZE: usize = 65536;
+
+/// Calls nest no deeper than this.
+const MAX_CALL_DEPTH: usize = 1000;
+
+/// The value stack holds no more than this.
+const MAX_STACK: usize = 100000;
+
+/// Tables hold no more entries than this.
+const MAX_TABLE_SIZE: usize = 65536;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Trap {
+    Unreachable,
+    /// The call ran more instructions than it was given fuel for
+    OutOfFuel,
+    MemoryOutOfBounds,
+    DivideByZero,
+    IntegerOverflow,
+    InvalidConversion,
+    StackOverflow,
+    UndefinedElement,
+    IndirectCallMismatch,
+    /// The module did something which would not have validated
+    Invalid(&'static str),
+    /// A host function refused the call
+    Host(String),
+}
+
+pub type ExecResult<T> = Result<T, Trap>;
+
+/// The module's linear memory.
+pub struct Memory {
+    bytes: Vec<u8>,
+    max_pages: u32,
+}
+
+impl Memory {
+    pub fn pages(&self) -> u32 {
+        (self.bytes.len() / PAGE_SIZE) as u32
+    }
+
+    /// Grows by `delta` pages, returning the previous size, unless that
+    /// would pass the maximum.
+    pub fn grow(&mut self, delta: u32) -> Option<u32> {
+        let pages = self.pages();
+        if self.max_pages - pages < delta {
+            return None;
+        }
+        let new_len = (pages + delta) as usize * PAGE_SIZE;
+        self.bytes.resize(new_len, 0);
+        Some(pages)
+    }
+
+    fn start(&self, addr: u32, offset: u32, len: usize) -> ExecResult<usize> {
+        let start = addr as u64 + offset as u64;
+        if (self.bytes.len() as u64) < start + len as u64 {
+            return Err(Trap::MemoryOutOfBounds);
+        }
+        Ok(start as usize)
+    }
+
+    pub fn read(&self, addr: u32, len: u32) -> ExecResult<&[u8]> {
+        let start = try!(self.start(addr, 0, len as usize));
+        Ok(&self.bytes[start..start + len as usize])
+    }
+
+    pub fn write(&mut self, addr: u32, data: &[u8]) -> ExecResult<()> {
+        let start = try!(self.start(addr, 0, data.len()));
+        self.bytes[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn load(&self, addr: u32, offset: u32, len: usize) -> ExecResult<u64> {
+        let start = try!(self.start(addr, offset, len));
+        let bytes = &self.bytes[start..start + len];
+        Ok(bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn store(&mut self, addr: u32, offset: u32, len: usize, value: u64) -> ExecResult<()> {
+        let start = try!(self.start(addr, offset, len));
+        for idx in 0..len {
+            self.bytes[start + idx] = (value >> (8 * idx)) as u8;
+        }
+        Ok(())
+    }
+}
+
+/// Provides the functions a module imports.
+pub trait Host {
+    /// Calls the `import`th imported function.
+    fn call(&mut self, import: usize, args: &[u64], memory: &mut Memory) -> ExecResult<Option<u64>>;
+}
+
+struct Frame {
+    // among the functions the module defines
+    func: usize,
+    pc: usize,
+    locals: Vec<u64>,
+    arity: usize,
+    stack_base: usize,
+    labels_base: usize,
+}
+
+struct Label {
+    // where to continue after branching to the label
+    cont: usize,
+    is_loop: bool,
+    arity: usize,
+    height: usize,
+}
+
+fn pop(stack: &mut Vec<u64>) -> ExecResult<u64> {
+    stack.pop().ok_or(Trap::Invalid("value stack underflow"))
+}
+
+fn pop_n(stack: &mut Vec<u64>, count: usize) -> ExecResult<Vec<u64>> {
+    if stack.len() < count {
+        return Err(Trap::Invalid("value stack underflow"));
+    }
+    let at = stack.len() - count;
+    Ok(stack.split_off(at))
+}
+
+/// Leaves the top `arity` values on the stack at `height`.
+fn unwind(stack: &mut Vec<u64>, height: usize, arity: usize) -> ExecResult<()> {
+    let results = try!(pop_n(stack, arity));
+    if stack.len() < height {
+        return Err(Trap::Invalid("value stack underflow"));
+    }
+    stack.truncate(height);
+    stack.extend(results.into_iter());
+    Ok(())
+}
+
+/// Where a segment starts in the table or memory.
+fn const_offset(expr: ConstExpr, globals: &[u64]) -> Result<usize, String> {
+    match expr {
+        ConstExpr::Value(value) => Ok(value as u32 as usize),
+        ConstExpr::GetGlobal(idx) => match globals.get(idx as usize) {
+            Some(&value) => Ok(value as u32 as usize),
+            None => Err(format!("offset from unknown global {}", idx)),
+        },
+    }
+}
+
+/// An instantiated module.
+pub struct Instance {
+    module: Arc<Module>,
+    memory: Memory,
+    globals: Vec<u64>,
+    table: Vec<Option<u32>>,
+}
+
+impl Instance {
+    /// Sets up the memory, globals and table of `module`, which may use
+    /// no more than `max_pages` pages of memory.  The start function is
+    /// left for `start`.
+    pub fn new(module: Module, max_pages: u32) -> Result<Instance, String> {
+        let memory_limits = module.memory;
+        let max_pages = match memory_limits.and_then(|limits| limits.max) {
+            Some(max) if max < max_pages => max,
+            _ => max_pages,
+        };
+        let min_pages = memory_limits.map(|limits| limits.min).unwrap_or(0);
+        if max_pages < min_pages {
+            return Err(format!("module needs {} pages of memory, but may have {}", min_pages, max_pages));
+        }
+
+        let mut globals = Vec::new();
+        for global in module.globals.iter() {
+            let value = match global.init {
+                ConstExpr::Value(value) => value,
+                ConstExpr::GetGlobal(idx) => match globals.get(idx as usize) {
+                    Some(&value) => value,
+                    None => return Err(format!("global initialised from unknown global {}", idx)),
+                },
+            };
+            globals.push(value);
+        }
+        let func_count = module.imports.len() + module.functions.len();
+        let table_size = module.table.map(|limits| limits.min as usize).unwrap_or(0);
+        if MAX_TABLE_SIZE < table_size {
+            return Err(format!("module needs a table of {} entries, but may have {}",
+                table_size, MAX_TABLE_SIZE));
+        }
+        let mut table = vec![None; table_size];
+        for element in module.elements.iter() {
+            let start = try!(const_offset(element.offset, &globals));
+            if table.len() < start + element.funcs.len() {
+                return Err("element segment does not fit in the table".to_string());
+            }
+            for (idx, &func) in element.funcs.iter().enumerate() {
+                if func_count <= func as usize {
+                    return Err(format!("element segment names unknown function {}", func));
+                }
+                table[start + idx] = Some(func);
+            }
+        }
+
+        let mut memory = Memory {
+            bytes: vec![0; min_pages as usize * PAGE_SIZE],
+            max_pages: max_pages,
+        };
+        for data in module.data.iter() {
+            let start = try!(const_offset(data.offset, &globals));
+            if memory.write(start as u32, &data.bytes).is_err() {
+                return Err("data segment does not fit in memory".to_string());
+            }
+        }
+
+        Ok(Instance {
+            module: Arc::new(module),
+            memory: memory,
+            globals: globals,
+            table: table,
+        })
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Runs the module's start function, if it has one.
+    pub fn start<H: Host>(&mut self, host: &mut H, fuel: u64) -> ExecResult<()> {
+        match self.module.start {
+            Some(func) => self.call(host, func, &[], fuel).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls the function exported as `name`; `None` if there is none.
+    pub fn invoke<H: Host>(&mut self, host: &mut H, name: &str, args: &[u64], fuel: u64)
+                           -> Option<ExecResult<Option<u64>>> {
+        self.module.exported_func(name).map(|func| self.call(host, func, args, fuel))
+    }
+
+    /// Calls the function at `func_idx`, running at most `fuel`
+    /// instructions.
+    pub fn call<H: Host>(&mut self, host: &mut H, func_idx: u32, args: &[u64], fuel: u64)
+                         -> ExecResult<Option<u64>> {
+        let module = self.module.clone();
+        let mut fuel = fuel;
+        let mut stack: Vec<u64> = args.to_vec();
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut labels: Vec<Label> = Vec::new();
+
+        if (func_idx as usize) < module.imports.len() {
+            return self.call_host(host, func_idx as usize, &mut stack).map(|_| stack.pop());
+        }
+        try!(self.enter(func_idx, &mut stack, &mut frames, &labels));
+
+        loop {
+            if fuel == 0 {
+                return Err(Trap::OutOfFuel);
+            }
+            fuel -= 1;
+            if MAX_STACK < stack.len() {
+                return Err(Trap::StackOverflow);
+            }
+
+            let frame_idx = frames.len() - 1;
+            let code = &module.functions[frames[frame_idx].func].code;
+            let pc = frames[frame_idx].pc;
+            let instr = match code.get(pc) {
+                Some(instr) => instr,
+                None => return Err(Trap::Invalid("ran off the end of a function")),
+            };
+            frames[frame_idx].pc = pc + 1;
+
+            // a branch: how many labels out, or `None` to return
+            let mut branch: Option<Option<u32>> = None;
+            match *instr {
+                Instr::Unreachable => return Err(Trap::Unreachable),
+                Instr::Nop => (),
+                Instr::Block(arity, end) => labels.push(Label {
+                    cont: end + 1,
+                    is_loop: false,
+                    arity: arity,
+                    height: stack.len(),
+                }),
+                Instr::Loop(arity) => labels.push(Label {
+                    cont: pc + 1,
+                    is_loop: true,
+                    arity: arity,
+                    height: stack.len(),
+                }),
+                Instr::If(arity, else_idx, end) => {
+                    let cond = try!(pop(&mut stack)) as u32;
+                    if cond != 0 || else_idx.is_some() {
+                        labels.push(Label {
+                            cont: end + 1,
+                            is_loop: false,
+                            arity: arity,
+                            height: stack.len(),
+                        });
+                    }
+                    if cond == 0 {
+                        frames[frame_idx].pc = match else_idx {
+                            Some(else_idx) => else_idx + 1,
+                            None => end + 1,
+                        };
+                    }
+                },
+                Instr::Else(end) => frames[frame_idx].pc = end,
+                Instr::End => {
+                    if labels.len() == frames[frame_idx].labels_base {
+                        branch = Some(None);
+                    } else {
+                        let label = labels.pop().unwrap();
+                        try!(unwind(&mut stack, label.height, label.arity));
+                    }
+                },
+                Instr::Br(depth) => branch = Some(Some(depth)),
+                Instr::BrIf(depth) => {
+                    if try!(pop(&mut stack)) as u32 != 0 {
+                        branch = Some(Some(depth));
+                    }
+                },
+                Instr::BrTable(ref targets, default) => {
+                    let idx = try!(pop(&mut stack)) as u32 as usize;
+                    branch = Some(Some(targets.get(idx).cloned().unwrap_or(default)));
+                },
+                Instr::Return => branch = Some(None),
+                Instr::Call(func) => {
+                    if (func as usize) < module.imports.len() {
+                        try!(self.call_host(host, func as usize, &mut stack));
+                    } else {
+                        try!(self.enter(func, &mut stack, &mut frames, &labels));
+                    }
+                },
+                Instr::CallIndirect(type_idx) => {
+                    let idx = try!(pop(&mut stack)) as u32 as usize;
+                    let func = match self.table.get(idx) {
+                        Some(&Some(func)) => func,
+                        _ => return Err(Trap::UndefinedElement),
+                    };
+                    if module.types.get(type_idx as usize) != module.func_type(func) {
+                        return Err(Trap::IndirectCallMismatch);
+                    }
+                    if (func as usize) < module.imports.len() {
+                        try!(self.call_host(host, func as usize, &mut stack));
+                    } else {
+                        try!(self.enter(func, &mut stack, &mut frames, &labels));
+                    }
+                },
+                Instr::Drop => { try!(pop(&mut stack)); },
+                Instr::Select => {
+                    let cond = try!(pop(&mut stack)) as u32;
+                    let second = try!(pop(&mut stack));
+                    let first = try!(pop(&mut stack));
+                    stack.push(if cond != 0 { first } else { second });
+                },
+                Instr::GetLocal(idx) => {
+                    let value = match frames[frame_idx].locals.get(idx as usize) {
+                        Some(&value) => value,
+                        None => return Err(Trap::Invalid("unknown local")),
+                    };
+                    stack.push(value);
+                },
+                Instr::SetLocal(idx) | Instr::TeeLocal(idx) => {
+                    let value = try!(pop(&mut stack));
+                    match frames[frame_idx].locals.get_mut(idx as usize) {
+                        Some(local) => *local = value,
+                        None => return Err(Trap::Invalid("unknown local")),
+                    }
+                    if let Instr::TeeLocal(_) = *instr {
+                        stack.push(value);
+                    }
+                },
+                Instr::GetGlobal(idx) => {
+                    let value = match self.globals.get(idx as usize) {
+                        Some(&value) => value,
+                        None => return Err(Trap::Invalid("unknown global")),
+                    };
+                    stack.push(value);
+                },
+                Instr::SetGlobal(idx) => {
+                    let value = try!(pop(&mut stack));
+                    match module.globals.get(idx as usize) {
+                        Some(global) if global.mutable => self.globals[idx as usize] = value,
+                        _ => return Err(Trap::Invalid("setting an unknown or immutable global")),
+                    }
+                },
+                Instr::Load(opcode, offset) => {
+                    let addr = try!(pop(&mut stack)) as u32;
+                    let value = try!(load(&self.memory, opcode, addr, offset));
+                    stack.push(value);
+                },
+                Instr::Store(opcode, offset) => {
+                    let value = try!(pop(&mut stack));
+                    let addr = try!(pop(&mut stack)) as u32;
+                    let len = match opcode {
+                        0x36 | 0x38 | 0x3e => 4,
+                        0x37 | 0x39 => 8,
+                        0x3a | 0x3c => 1,
+                        _ => 2,
+                    };
+                    try!(self.memory.store(addr, offset, len, value));
+                },
+                Instr::CurrentMemory => stack.push(self.memory.pages() as u64),
+                Instr::GrowMemory => {
+                    let delta = try!(pop(&mut stack)) as u32;
+                    let previous = self.memory.grow(delta).unwrap_or(!0);
+                    stack.push(previous as u64);
+                },
+                Instr::MemoryCopy => {
+                    let len = try!(pop(&mut stack)) as u32;
+                    let src = try!(pop(&mut stack)) as u32;
+                    let dst = try!(pop(&mut stack)) as u32;
+                    fuel = fuel.saturating_sub(len as u64 / 64);
+                    let bytes = try!(self.memory.read(src, len)).to_vec();
+                    try!(self.memory.write(dst, &bytes));
+                },
+                Instr::MemoryFill => {
+                    let len = try!(pop(&mut stack)) as u32;
+                    let value = try!(pop(&mut stack)) as u8;
+                    let dst = try!(pop(&mut stack)) as u32;
+                    fuel = fuel.saturating_sub(len as u64 / 64);
+                    try!(self.memory.write(dst, &vec![value; len as usize]));
+                },
+                Instr::Const(value) => stack.push(value),
+                Instr::Numeric(opcode) => try!(numeric(opcode, &mut stack)),
+                Instr::TruncSat(sub) => {
+                    let value = try!(pop(&mut stack));
+                    stack.push(trunc_sat(sub, value));
+                },
+            }
+
+            match branch {
+                None => (),
+                Some(Some(depth)) if (depth as usize) < labels.len() - frames[frame_idx].labels_base => {
+                    let target = labels.len() - 1 - depth as usize;
+                    let (cont, is_loop, arity, height) = {
+                        let label = &labels[target];
+                        (label.cont, label.is_loop, label.arity, label.height)
+                    };
+                    // branching to a loop starts it again, taking no values
+                    try!(unwind(&mut stack, height, if is_loop { 0 } else { arity }));
+                    labels.truncate(if is_loop { target + 1 } else { target });
+                    frames[frame_idx].pc = cont;
+                },
+                Some(Some(depth)) if depth as usize != labels.len() - frames[frame_idx].labels_base => {
+                    return Err(Trap::Invalid("branch to an unknown label"));
+                },
+                // branching out of the function body returns
+                Some(_) => {
+                    let frame = frames.pop().unwrap();
+                    try!(unwind(&mut stack, frame.stack_base, frame.arity));
+                    labels.truncate(frame.labels_base);
+                    if frames.is_empty() {
+                        return match frame.arity {
+                            0 => Ok(None),
+                            _ => Ok(stack.pop()),
+                        };
+                    }
+                },
+            }
+        }
+    }
+
+    /// Pushes a frame for a call to a function the module defines,
+    /// taking its arguments from the stack.
+    fn enter(&self, func_idx: u32, stack: &mut Vec<u64>, frames: &mut Vec<Frame>, labels: &Vec<Label>)
+             -> ExecResult<()> {
+        if MAX_CALL_DEPTH <= frames.len() {
+            return Err(Trap::StackOverflow);
+        }
+        let func = func_idx as usize - self.module.imports.len();
+        let (params, arity) = match self.module.func_type(func_idx) {
+            Some(func_type) => (func_type.params.len(), func_type.results.len()),
+            None => return Err(Trap::Invalid("call to an unknown function")),
+        };
+        let mut locals = try!(pop_n(stack, params));
+        locals.extend(self.module.functions[func].locals.iter().map(|_| 0));
+        frames.push(Frame {
+            func: func,
+            pc: 0,
+            locals: locals,
+            arity: arity,
+            stack_base: stack.len(),
+            labels_base: labels.len(),
+        });
+        Ok(())
+    }
+
+    fn call_host<H: Host>(&mut self, host: &mut H, import: usize, stack: &mut Vec<u64>) -> ExecResult<()> {
+        let (params, arity) = match self.module.func_type(import as u32) {
+            Some(func_type) => (func_type.params.len(), func_type.results.len()),
+            None => return Err(Trap::Invalid("call to an unknown function")),
+        };
+        let args = try!(pop_n(stack, params));
+        match try!(host.call(import, &args, &mut self.memory)) {
+            Some(result) if arity == 1 => stack.push(result),
+            None if arity == 0 => (),
+            _ => return Err(Trap::Host("host function returned the wrong number of values".to_string())),
+        }
+        Ok(())
+    }
+}
+
+fn load(memory: &Memory, opcode: u8, addr: u32, offset: u32) -> ExecResult<u64> {
+    let len = match opcode {
+        0x28 | 0x2a | 0x34 | 0x35 => 4,
+        0x29 | 0x2b => 8,
+        0x2c | 0x2d | 0x30 | 0x31 => 1,
+        _ => 2,
+    };
+    let raw = try!(memory.load(addr, offset, len));
+    Ok(match opcode {
+        0x2c => raw as u8 as i8 as i32 as u32 as u64,
+        0x2e => raw as u16 as i16 as i32 as u32 as u64,
+        0x30 => raw as u8 as i8 as i64 as u64,
+        0x32 => raw as u16 as i16 as i64 as u64,
+        0x34 => raw as u32 as i32 as i64 as u64,
+        _ => raw,
+    })
+}
+
+fn f32_of(value: u64) -> f32 {
+    unsafe { mem::transmute::<u32, f32>(value as u32) }
+}
+
+fn f64_of(value: u64) -> f64 {
+    unsafe { mem::transmute::<u64, f64>(value) }
+}
+
+fn of_f32(value: f32) -> u64 {
+    unsafe { mem::transmute::<f32, u32>(value) as u64 }
+}
+
+fn of_f64(value: f64) -> u64 {
+    unsafe { mem::transmute::<f64, u64>(value) }
+}
+
+fn of_bool(value: bool) -> u64 {
+    if value { 1 } else { 0 }
+}
+
+macro_rules! float_ops {
+    ($nearest:ident, $min:ident, $max:ident, $ty:ty) => {
+        /// Rounds half-way cases to even.
+        fn $nearest(x: $ty) -> $ty {
+            if (x - x.trunc()).abs() == 0.5 {
+                2.0 * (x / 2.0).round()
+            } else {
+                x.round()
+            }
+        }
+
+        fn $min(a: $ty, b: $ty) -> $ty {
+            if a.is_nan() || b.is_nan() {
+                a + b
+            } else if a == b {
+                // -0 is less than +0
+                if a.is_sign_negative() { a } else { b }
+            } else if a < b { a } else { b }
+        }
+
+        fn $max(a: $ty, b: $ty) -> $ty {
+            if a.is_nan() || b.is_nan() {
+                a + b
+            } else if a == b {
+                if a.is_sign_positive() { a } else { b }
+            } else if a > b { a } else { b }
+        }
+    }
+}
+
+float_ops!(nearest_f32, min_f32, max_f32, f32);
+float_ops!(nearest_f64, min_f64, max_f64, f64);
+
+/// Truncates towards zero, trapping if the result does not fit in
+/// `[min, max)`.
+fn trunc(x: f64, min: f64, max: f64) -> ExecResult<f64> {
+    if x.is_nan() {
+        return Err(Trap::InvalidConversion);
+    }
+    let truncated = x.trunc();
+    if truncated < min || max <= truncated {
+        return Err(Trap::IntegerOverflow);
+    }
+    Ok(truncated)
+}
+
+const I32_RANGE: (f64, f64) = (-2147483648.0, 2147483648.0);
+const U32_RANGE: (f64, f64) = (0.0, 4294967296.0);
+const I64_RANGE: (f64, f64) = (-9223372036854775808.0, 9223372036854775808.0);
+const U64_RANGE: (f64, f64) = (0.0, 18446744073709551616.0);
+
+/// A saturating conversion, with `sub` the `0xfc` sub-opcode.
+fn trunc_sat(sub: u8, value: u64) -> u64 {
+    let x = if sub & 2 == 0 { f32_of(value) as f64 } else { f64_of(value) };
+    let (min, max) = match sub {
+        0 | 2 => I32_RANGE,
+        1 | 3 => U32_RANGE,
+        4 | 6 => I64_RANGE,
+        _ => U64_RANGE,
+    };
+    if x.is_nan() {
+        return 0;
+    }
+    let clamped = if x < min { min } else if max <= x { max } else { x.trunc() };
+    match sub {
+        0 | 2 if clamped == max => ::std::i32::MAX as u32 as u64,
+        0 | 2 => clamped as i32 as u32 as u64,
+        1 | 3 if clamped == max => ::std::u32::MAX as u64,
+        1 | 3 => clamped as u32 as u64,
+        4 | 6 if clamped == max => ::std::i64::MAX as u64,
+        4 | 6 => clamped as i64 as u64,
+        _ if clamped == max => ::std::u64::MAX,
+        _ => clamped as u64,
+    }
+}
+
+fn numeric(opcode: u8, stack: &mut Vec<u64>) -> ExecResult<()> {
+    let result = match opcode {
+        0x45 => of_bool(try!(pop(stack)) as u32 == 0),
+        0x46...0x4f => {
+            let b = try!(pop(stack)) as u32;
+            let a = try!(pop(stack)) as u32;
+            let (sa, sb) = (a as i32, b as i32);
+            of_bool(match opcode {
+                0x46 => a == b,
+                0x47 => a != b,
+                0x48 => sa < sb,
+                0x49 => a < b,
+                0x4a => sa > sb,
+                0x4b => a > b,
+                0x4c => sa <= sb,
+                0x4d => a <= b,
+                0x4e => sa >= sb,
+                _ => a >= b,
+            })
+        },
+        0x50 => of_bool(try!(pop(stack)) == 0),
+        0x51...0x5a => {
+            let b = try!(pop(stack));
+            let a = try!(pop(stack));
+            let (sa, sb) = (a as i64, b as i64);
+            of_bool(match opcode {
+                0x51 => a == b,
+                0x52 => a != b,
+                0x53 => sa < sb,
+                0x54 => a < b,
+                0x55 => sa > sb,
+                0x56 => a > b,
+                0x57 => sa <= sb,
+                0x58 => a <= b,
+                0x59 => sa >= sb,
+                _ => a >= b,
+            })
+        },
+        0x5b...0x60 => {
+            let b = f32_of(try!(pop(stack)));
+            let a = f32_of(try!(pop(stack)));
+            of_bool(match opcode {
+                0x5b => a == b,
+                0x5c => a != b,
+                0x5d => a < b,
+                0x5e => a > b,
+                0x5f => a <= b,
+                _ => a >= b,
+            })
+        },
+        0x61...0x66 => {
+            let b = f64_of(try!(pop(stack)));
+            let a = f64_of(try!(pop(stack)));
+            of_bool(match opcode {
+                0x61 => a == b,
+                0x62 => a != b,
+                0x63 => a < b,
+                0x64 => a > b,
+                0x65 => a <= b,
+                _ => a >= b,
+            })
+        },
+        0x67...0x69 => {
+            let a = try!(pop(stack)) as u32;
+            (match opcode {
+                0x67 => a.leading_zeros(),
+                0x68 => a.trailing_zeros(),
+                _ => a.count_ones(),
+            }) as u64
+        },
+        0x6a...0x78 => {
+            let b = try!(pop(stack)) as u32;
+            let a = try!(pop(stack)) as u32;
+            let (sa, sb) = (a as i32, b as i32);
+            (match opcode {
+                0x6a => a.wrapping_add(b),
+                0x6b => a.wrapping_sub(b),
+                0x6c => a.wrapping_mul(b),
+                0x6d...0x70 if b == 0 => return Err(Trap::DivideByZero),
+                0x6d if sa == ::std::i32::MIN && sb == -1 => return Err(Trap::IntegerOverflow),
+                0x6d => (sa / sb) as u32,
+                0x6e => a / b,
+                0x6f => sa.wrapping_rem(sb) as u32,
+                0x70 => a % b,
+                0x71 => a & b,
+                0x72 => a | b,
+                0x73 => a ^ b,
+                0x74 => a.wrapping_shl(b),
+                0x75 => sa.wrapping_shr(b) as u32,
+                0x76 => a.wrapping_shr(b),
+                0x77 => a.rotate_left(b % 32),
+                _ => a.rotate_right(b % 32),
+            }) as u64
+        },
+        0x79...0x7b => {
+            let a = try!(pop(stack));
+            (match opcode {
+                0x79 => a.leading_zeros(),
+                0x7a => a.trailing_zeros(),
+                _ => a.count_ones(),
+            }) as u64
+        },
+        0x7c...0x8a => {
+            let b = try!(pop(stack));
+            let a = try!(pop(stack));
+            let (sa, sb) = (a as i64, b as i64);
+            match opcode {
+                0x7c => a.wrapping_add(b),
+                0x7d => a.wrapping_sub(b),
+                0x7e => a.wrapping_mul(b),
+                0x7f...0x82 if b == 0 => return Err(Trap::DivideByZero),
+                0x7f if sa == ::std::i64::MIN && sb == -1 => return Err(Trap::IntegerOverflow),
+                0x7f => (sa / sb) as u64,
+                0x80 => a / b,
+                0x81 => sa.wrapping_rem(sb) as u64,
+                0x82 => a % b,
+                0x83 => a & b,
+                0x84 => a | b,
+                0x85 => a ^ b,
+                0x86 => a.wrapping_shl(b as u32),
+                0x87 => sa.wrapping_shr(b as u32) as u64,
+                0x88 => a.wrapping_shr(b as u32),
+                0x89 => a.rotate_left((b % 64) as u32),
+                _ => a.rotate_right((b % 64) as u32),
+            }
+        },
+        0x8b...0x91 => {
+            let bits = try!(pop(stack)) as u32;
+            let a = f32_of(bits as u64);
+            match opcode {
+                0x8b => (bits & 0x7fffffff) as u64,
+                0x8c => (bits ^ 0x80000000) as u64,
+                0x8d => of_f32(a.ceil()),
+                0x8e => of_f32(a.floor()),
+                0x8f => of_f32(a.trunc()),
+                0x90 => of_f32(nearest_f32(a)),
+                _ => of_f32(a.sqrt()),
+            }
+        },
+        0x92...0x98 => {
+            let b_bits = try!(pop(stack)) as u32;
+            let a_bits = try!(pop(stack)) as u32;
+            let (a, b) = (f32_of(a_bits as u64), f32_of(b_bits as u64));
+            match opcode {
+                0x92 => of_f32(a + b),
+                0x93 => of_f32(a - b),
+                0x94 => of_f32(a * b),
+                0x95 => of_f32(a / b),
+                0x96 => of_f32(min_f32(a, b)),
+                0x97 => of_f32(max_f32(a, b)),
+                _ => ((a_bits & 0x7fffffff) | (b_bits & 0x80000000)) as u64,
+            }
+        },
+        0x99...0x9f => {
+            let bits = try!(pop(stack));
+            let a = f64_of(bits);
+            match opcode {
+                0x99 => bits & 0x7fffffffffffffff,
+                0x9a => bits ^ 0x8000000000000000,
+                0x9b => of_f64(a.ceil()),
+                0x9c => of_f64(a.floor()),
+                0x9d => of_f64(a.trunc()),
+                0x9e => of_f64(nearest_f64(a)),
+                _ => of_f64(a.sqrt()),
+            }
+        },
+        0xa0...0xa6 => {
+            let b_bits = try!(pop(stack));
+            let a_bits = try!(pop(stack));
+            let (a, b) = (f64_of(a_bits), f64_of(b_bits));
+            match opcode {
+                0xa0 => of_f64(a + b),
+                0xa1 => of_f64(a - b),
+                0xa2 => of_f64(a * b),
+                0xa3 => of_f64(a / b),
+                0xa4 => of_f64(min_f64(a, b)),
+                0xa5 => of_f64(max_f64(a, b)),
+                _ => (a_bits & 0x7fffffffffffffff) | (b_bits & 0x8000000000000000),
+            }
+        },
+        0xa7...0xc4 => {
+            let a = try!(pop(stack));
+            let (f32_in, f64_in) = (f32_of(a) as f64, f64_of(a));
+            match opcode {
+                0xa7 => a as u32 as u64,
+                0xa8 => try!(trunc(f32_in, I32_RANGE.0, I32_RANGE.1)) as i32 as u32 as u64,
+                0xa9 => try!(trunc(f32_in, U32_RANGE.0, U32_RANGE.1)) as u32 as u64,
+                0xaa => try!(trunc(f64_in, I32_RANGE.0, I32_RANGE.1)) as i32 as u32 as u64,
+                0xab => try!(trunc(f64_in, U32_RANGE.0, U32_RANGE.1)) as u32 as u64,
+                0xac => a as u32 as i32 as i64 as u64,
+                0xad => a as u32 as u64,
+                0xae => try!(trunc(f32_in, I64_RANGE.0, I64_RANGE.1)) as i64 as u64,
+                0xaf => try!(trunc(f32_in, U64_RANGE.0, U64_RANGE.1)) as u64,
+                0xb0 => try!(trunc(f64_in, I64_RANGE.0, I64_RANGE.1)) as i64 as u64,
+                0xb1 => try!(trunc(f64_in, U64_RANGE.0, U64_RANGE.1)) as u64,
+                0xb2 => of_f32(a as u32 as i32 as f32),
+                0xb3 => of_f32(a as u32 as f32),
+                0xb4 => of_f32(a as i64 as f32),
+                0xb5 => of_f32(a as f32),
+                0xb6 => of_f32(f64_in as f32),
+                0xb7 => of_f64(a as u32 as i32 as f64),
+                0xb8 => of_f64(a as u32 as f64),
+                0xb9 => of_f64(a as i64 as f64),
+                0xba => of_f64(a as f64),
+                0xbb => of_f64(f32_of(a) as f64),
+                // reinterpretations: the bits are already right
+                0xbc => a as u32 as u64,
+                0xbd...0xbf => a,
+                0xc0 => a as u8 as i8 as i32 as u32 as u64,
+                0xc1 => a as u16 as i16 as i32 as u32 as u64,
+                0xc2 => a as u8 as i8 as i64 as u64,
+                0xc3 => a as u16 as i16 as i64 as u64,
+                _ => a as u32 as i32 as i64 as u64,
+            }
+        },
+        _ => return Err(Trap::Invalid("unknown instruction")),
+    };
+    stack.push(result);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Instance, Host, Memory, Trap, ExecResult, PAGE_SIZE};
+    use super::{nearest_f64, min_f64, trunc_sat, numeric, of_f64, of_f32};
+    use super::super::decode::decode;
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, contents.len() as u8];
+        section.extend_from_slice(contents);
+        section
+    }
+
+    /// A module of `(i32, i32) -> i32` functions, exported as `f0`, `f1`
+    /// and so on, importing `env.log` as function 0.
+    fn module(bodies: &[&[u8]], extra: &[Vec<u8>]) -> Instance {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        // (i32, i32) -> i32, and (i32) -> ()
+        bytes.extend(section(1, &[2, 0x60, 2, 0x7f, 0x7f, 1, 0x7f, 0x60, 1, 0x7f, 0]));
+        bytes.extend(section(2, &[1, 3, b'e', b'n', b'v', 3, b'l', b'o', b'g', 0, 1]));
+        let mut funcs = vec![bodies.len() as u8];
+        let mut exports = vec![bodies.len() as u8];
+        let mut code = vec![bodies.len() as u8];
+        for (idx, body) in bodies.iter().enumerate() {
+            funcs.push(0);
+            exports.extend_from_slice(&[2, b'f', b'0' + idx as u8, 0, idx as u8 + 1]);
+            // one i32 local
+            code.push(body.len() as u8 + 3);
+            code.extend_from_slice(&[1, 1, 0x7f]);
+            code.extend_from_slice(body);
+        }
+        bytes.extend(section(3, &funcs));
+        // a page of memory, growable to two
+        bytes.extend(section(5, &[1, 1, 1, 2]));
+        bytes.extend(section(7, &exports));
+        bytes.extend(section(10, &code));
+        for extra in extra.iter() {
+            bytes.extend_from_slice(extra);
+        }
+        Instance::new(decode(&bytes).unwrap(), 16).unwrap()
+    }
+
+    struct Log(Vec<u64>);
+
+    impl Host for Log {
+        fn call(&mut self, import: usize, args: &[u64], _: &mut Memory) -> ExecResult<Option<u64>> {
+            assert_eq!(import, 0);
+            if args[0] == 666 {
+                return Err(Trap::Host("no".to_string()));
+            }
+            self.0.push(args[0]);
+            Ok(None)
+        }
+    }
+
+    fn run(instance: &mut Instance, name: &str, a: u32, b: u32) -> ExecResult<Option<u64>> {
+        instance.invoke(&mut Log(Vec::new()), name, &[a as u64, b as u64], 10000).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let mut instance = module(&[
+            // a + b
+            &[0x20, 0, 0x20, 1, 0x6a, 0x0b],
+            // a / b, signed
+            &[0x20, 0, 0x20, 1, 0x6d, 0x0b],
+            // (i32) (f64.convert_s a) / 2.0, truncated
+            &[0x20, 0, 0xb7, 0x44, 0, 0, 0, 0, 0, 0, 0, 0x40, 0xa3, 0xaa, 0x0b],
+        ], &[]);
+        assert_eq!(run(&mut instance, "f0", 2, 3), Ok(Some(5)));
+        assert_eq!(run(&mut instance, "f0", !0, 1), Ok(Some(0)));
+        assert_eq!(run(&mut instance, "f1", -7i32 as u32, 2), Ok(Some(-3i32 as u32 as u64)));
+        assert_eq!(run(&mut instance, "f1", 1, 0), Err(Trap::DivideByZero));
+        assert_eq!(run(&mut instance, "f1", 0x80000000, !0), Err(Trap::IntegerOverflow));
+        assert_eq!(run(&mut instance, "f2", -9i32 as u32, 0), Ok(Some(-4i32 as u32 as u64)));
+        assert!(instance.invoke(&mut Log(Vec::new()), "missing", &[], 100).is_none());
+    }
+
+    #[test]
+    fn test_control() {
+        let mut instance = module(&[
+            // sum of 1..=a: loop (local2 += a; a -= 1; br_if 0 a) local2
+            &[0x03, 0x40,
+              0x20, 2, 0x20, 0, 0x6a, 0x21, 2,
+              0x20, 0, 0x41, 1, 0x6b, 0x22, 0,
+              0x0d, 0, 0x0b,
+              0x20, 2, 0x0b],
+            // if a { b } else { call f0(b, 0) }, returning early from the then
+            &[0x20, 0, 0x04, 0x7f, 0x20, 1, 0x0f, 0x05, 0x20, 1, 0x41, 0, 0x10, 1, 0x0b, 0x0b],
+            // br_table on a: block 0 returns 10, block 1 returns 20, default 30
+            &[0x02, 0x40, 0x02, 0x40, 0x02, 0x40,
+              0x20, 0, 0x0e, 2, 0, 1, 2,
+              0x0b, 0x41, 10, 0x0f,
+              0x0b, 0x41, 20, 0x0f,
+              0x0b, 0x41, 30, 0x0b],
+            // log(a) then loop forever
+            &[0x20, 0, 0x10, 0, 0x03, 0x40, 0x0c, 0, 0x0b, 0x00, 0x0b],
+        ], &[]);
+        assert_eq!(run(&mut instance, "f0", 4, 0), Ok(Some(10)));
+        assert_eq!(run(&mut instance, "f1", 1, 7), Ok(Some(7)));
+        assert_eq!(run(&mut instance, "f1", 0, 7), Ok(Some(28)));
+        assert_eq!(run(&mut instance, "f2", 0, 0), Ok(Some(10)));
+        assert_eq!(run(&mut instance, "f2", 1, 0), Ok(Some(20)));
+        assert_eq!(run(&mut instance, "f2", 9, 0), Ok(Some(30)));
+
+        let mut log = Log(Vec::new());
+        assert_eq!(instance.invoke(&mut log, "f3", &[5, 0], 1000).unwrap(), Err(Trap::OutOfFuel));
+        assert_eq!(log.0, vec![5]);
+        assert_eq!(instance.invoke(&mut log, "f3", &[666, 0], 1000).unwrap(), Err(Trap::Host("no".to_string())));
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        // f0 calls itself forever
+        let mut instance = module(&[&[0x20, 0, 0x20, 1, 0x10, 1, 0x0b]], &[]);
+        assert_eq!(run(&mut instance, "f0", 0, 0), Err(Trap::StackOverflow));
+    }
+
+    #[test]
+    fn test_memory() {
+        let data = section(11, &[1, 0, 0x41, 8, 0x0b, 4, 0x78, 0x56, 0x34, 0x12]);
+        let mut instance = module(&[
+            // i32.load offset=a
+            &[0x20, 0, 0x28, 2, 0, 0x0b],
+            // i32.store8 b at a, then load8_s it back
+            &[0x20, 0, 0x20, 1, 0x3a, 0, 0, 0x20, 0, 0x2c, 0, 0, 0x0b],
+            // memory.grow a
+            &[0x20, 0, 0x40, 0, 0x0b],
+        ], &[data]);
+        assert_eq!(run(&mut instance, "f0", 8, 0), Ok(Some(0x12345678)));
+        assert_eq!(run(&mut instance, "f0", PAGE_SIZE as u32 - 2, 0), Err(Trap::MemoryOutOfBounds));
+        assert_eq!(run(&mut instance, "f1", 100, 0xff), Ok(Some(!0 as u32 as u64)));
+        assert_eq!(instance.memory().read(100, 1), Ok(&[0xff][..]));
+        // the module allows two pages
+        assert_eq!(run(&mut instance, "f2", 2, 0), Ok(Some(!0 as u32 as u64)));
+        assert_eq!(run(&mut instance, "f2", 1, 0), Ok(Some(1)));
+        assert_eq!(instance.memory().pages(), 2);
+    }
+
+    #[test]
+    fn test_call_indirect() {
+        // a table of f0 and the import; f1 calls table[a] as (i32, i32) -> i32
+        let table = section(4, &[1, 0x70, 0, 2]);
+        let elements = section(9, &[1, 0, 0x41, 0, 0x0b, 2, 1, 0]);
+        let mut instance = module(&[
+            &[0x20, 0, 0x20, 1, 0x6c, 0x0b],
+            &[0x41, 6, 0x41, 7, 0x20, 0, 0x11, 0, 0, 0x0b],
+        ], &[table, elements]);
+        // sections out of order still decode; the table comes from the
+        // module regardless
+        assert_eq!(run(&mut instance, "f1", 0, 0), Ok(Some(42)));
+        assert_eq!(run(&mut instance, "f1", 1, 0), Err(Trap::IndirectCallMismatch));
+        assert_eq!(run(&mut instance, "f1", 2, 0), Err(Trap::UndefinedElement));
+
+        // a table of 100000 entries is refused rather than allocated
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        bytes.extend(section(4, &[1, 0x70, 0, 0xa0, 0x8d, 0x06]));
+        assert!(Instance::new(decode(&bytes).unwrap(), 16).is_err());
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(nearest_f64(2.5), 2.0);
+        assert_eq!(nearest_f64(-3.5), -4.0);
+        assert!(nearest_f64(-0.5).is_sign_negative());
+        assert!(min_f64(0.0, -0.0).is_sign_negative());
+        assert!(min_f64(1.0, ::std::f64::NAN).is_nan());
+        assert_eq!(trunc_sat(2, of_f64(-1e10)), 0x80000000);
+        assert_eq!(trunc_sat(3, of_f64(-1.5)), 0);
+        assert_eq!(trunc_sat(5, of_f32(::std::f32::INFINITY)), !0);
+        assert_eq!(trunc_sat(0, of_f32(::std::f32::NAN)), 0);
+
+        // i32.trunc_f64_u of 2^32, then of just below it
+        let mut stack = vec![of_f64(4294967296.0)];
+        assert_eq!(numeric(0xab, &mut stack), Err(Trap::IntegerOverflow));
+        let mut stack = vec![of_f64(4294967295.9)];
+        assert_eq!(numeric(0xab, &mut stack), Ok(()));
+        assert_eq!(stack, vec![0xffffffff]);
+        // f32.copysign
+        let mut stack = vec![of_f32(2.0), of_f32(-0.0)];
+        assert_eq!(numeric(0x98, &mut stack), Ok(()));
+        assert_eq!(stack, vec![of_f32(-2.0)]);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let bytes = b"\0asm\x01\0\0\0\x05\x03\x01\x00\x04";
+        let module = decode(bytes).unwrap();
+        assert!(Instance::new(module, 2).is_err());
+        let module = decode(bytes).unwrap();
+        assert!(Instance::new(module, 4).is_ok());
+    }
+}
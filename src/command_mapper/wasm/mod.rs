@@ -0,0 +1,685 @@
+//! Plugins compiled to WebAssembly, run by an interpreter inside the bot.
+//!
+//! The interpreter, in `decode` and `exec`, is our own: wasmi and wasmtime
+//! need a far newer compiler than the nightly this bot builds with.  It
+//! covers the MVP instructions, sign extension and saturating truncation,
+//! one memory and one table, with limits on fuel, memory, table size and
+//! call depth, and nothing more.
+//!
+//! A module exports its memory as `memory`, and a `manifest` function
+//! taking nothing and returning an `i64`: the address of a JSON document
+//! in its memory, shifted left 32 bits, or'd with its length.
+//!
+//! ```text
+//! {"name": "echo", "formats": [{"format": "echo {*text}", "description": "Repeat something"}]}
+//! ```
+//!
+//! The plugin is registered as `wasm:` and its name, `wasm:echo` here,
+//! and its storage and configuration go under that name, so a module
+//! cannot take those of a built-in plugin.
+//!
+//! Commands matching a format are dispatched by calling `dispatch` with
+//! the index of the format, as an `i32`.  Timers call `on_timer` with
+//! their token, as an `i64`.  Modules may import these functions from
+//! `ircbot`, with strings passed as an address and a length:
+//!
+//! ```text
+//! reply(text, len)                        reply to the command
+//! reply_notice(text, len)                 reply by notice
+//! say(target, len, text, len)             message a channel or nick
+//! set_timer(delay_secs: i64, token: i64)  call on_timer after a delay
+//! cancel_timer(token: i64) -> i32         1 if the timer was pending
+//! kv_get(key, len, buf, cap) -> i32       the plugin's storage
+//! kv_set(key, len, value, len)
+//! kv_remove(key, len)
+//! arg(name, len, buf, cap) -> i32         an argument of the command
+//! source_nick(buf, cap) -> i32            who sent the command
+//! current_nick(buf, cap) -> i32           the bot's nick
+//! reply_target(buf, cap) -> i32           where replies go
+//! channel_nicks(chan, len, buf, cap) -> i32   space-separated
+//! ```
+//!
+//! Functions filling a buffer copy as much as fits and return the full
+//! length, or -1 if there is nothing to copy.  A module which uses too
+//! much fuel or memory, or misuses the host functions, traps; the trap is
+//! logged and nothing the call asked to send is sent.  Its memory and
+//! storage keep whatever it changed before trapping.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::str;
+use std::sync::Arc;
+
+use mio::Sender;
+use rustc_serialize::json::Json;
+
+use irc::{IrcMsg, IrcMsgBuf, server};
+use irc::legacy::{FrozenState, User};
+
+use super::{
+    send_privmsg,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Namespace,
+    Replier,
+    RustBotPlugin,
+    TimerToken,
+    Timers,
+    Token,
+};
+use self::decode::{decode, Module, ValueType};
+use self::exec::{Instance, Host, Memory, Trap, ExecResult};
+
+mod decode;
+mod exec;
+
+/// Instructions one invocation may run, by default.
+const DEFAULT_FUEL: u64 = 5000000;
+
+/// Pages of 64KiB a module may use, by default.
+const DEFAULT_MAX_MEMORY_PAGES: u32 = 160;
+
+/// Messages one invocation may send.
+const MAX_MESSAGES: usize = 10;
+
+const MAX_TEXT_LEN: u32 = 450;
+const MAX_KEY_LEN: u32 = 256;
+const MAX_VALUE_LEN: u32 = 65536;
+
+/// Timers may be set no further ahead than this.
+const MAX_TIMER_SECS: i64 = 366 * 86400;
+
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct WasmPluginConfig {
+    pub path: String,
+    /// Instructions one invocation may run
+    pub fuel: Option<u64>,
+    /// Pages of 64KiB the module may use
+    pub max_memory_pages: Option<u32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum HostFunc {
+    Reply,
+    ReplyNotice,
+    Say,
+    SetTimer,
+    CancelTimer,
+    KvGet,
+    KvSet,
+    KvRemove,
+    Arg,
+    SourceNick,
+    CurrentNick,
+    ReplyTarget,
+    ChannelNicks,
+}
+
+const I32: ValueType = ValueType::I32;
+const I64: ValueType = ValueType::I64;
+
+/// The functions modules may import, with their parameters and result.
+const HOST_FUNCS: &'static [(&'static str, HostFunc, &'static [ValueType], Option<ValueType>)] = &[
+    ("reply", HostFunc::Reply, &[I32, I32], None),
+    ("reply_notice", HostFunc::ReplyNotice, &[I32, I32], None),
+    ("say", HostFunc::Say, &[I32, I32, I32, I32], None),
+    ("set_timer", HostFunc::SetTimer, &[I64, I64], None),
+    ("cancel_timer", HostFunc::CancelTimer, &[I64], Some(I32)),
+    ("kv_get", HostFunc::KvGet, &[I32, I32, I32, I32], Some(I32)),
+    ("kv_set", HostFunc::KvSet, &[I32, I32, I32, I32], None),
+    ("kv_remove", HostFunc::KvRemove, &[I32, I32], None),
+    ("arg", HostFunc::Arg, &[I32, I32, I32, I32], Some(I32)),
+    ("source_nick", HostFunc::SourceNick, &[I32, I32], Some(I32)),
+    ("current_nick", HostFunc::CurrentNick, &[I32, I32], Some(I32)),
+    ("reply_target", HostFunc::ReplyTarget, &[I32, I32], Some(I32)),
+    ("channel_nicks", HostFunc::ChannelNicks, &[I32, I32, I32, I32], Some(I32)),
+];
+
+/// Finds the host function behind each of the module's imports.
+fn resolve_imports(module: &Module) -> Result<Vec<HostFunc>, String> {
+    let mut resolved = Vec::new();
+    for import in module.imports.iter() {
+        let found = HOST_FUNCS.iter().find(|&&(name, _, _, _)| name == import.name);
+        let (func, params, result) = match found {
+            Some(&(_, func, params, result)) if import.module == "ircbot" => (func, params, result),
+            _ => return Err(format!("unknown import {}.{}", import.module, import.name)),
+        };
+        let matches = match module.types.get(import.type_idx as usize) {
+            Some(func_type) => &func_type.params[..] == params && func_type.results.first() == result.as_ref(),
+            None => false,
+        };
+        if !matches {
+            return Err(format!("import {} has the wrong type", import.name));
+        }
+        resolved.push(func);
+    }
+    Ok(resolved)
+}
+
+/// Reads a manifest, returning the plugin's name and its formats with
+/// their descriptions.
+fn parse_manifest(text: &str) -> Result<(String, Vec<(String, String)>), String> {
+    let manifest = match Json::from_str(text) {
+        Ok(manifest) => manifest,
+        Err(err) => return Err(format!("bad manifest {:?}: {:?}", text, err)),
+    };
+    let name = match manifest.find("name").and_then(|x| x.as_string()) {
+        Some(name) if name.len() > 0 && !name.starts_with('*') => name.to_string(),
+        _ => return Err(format!("manifest has no usable name: {:?}", text)),
+    };
+    let mut formats = Vec::new();
+    for declared in manifest.find("formats").and_then(|x| x.as_array()).unwrap_or(&Vec::new()).iter() {
+        let format = match declared.find("format").and_then(|x| x.as_string()) {
+            Some(format) => format,
+            None => return Err(format!("manifest format without a format: {:?}", declared)),
+        };
+        let description = declared.find("description").and_then(|x| x.as_string()).unwrap_or("");
+        formats.push((format.to_string(), description.to_string()));
+    }
+    Ok((name, formats))
+}
+
+fn host_error(message: &str) -> Trap {
+    Trap::Host(message.to_string())
+}
+
+/// Reads a string from the module's memory, no longer than `max_len`.
+fn read_str(memory: &Memory, ptr: u32, len: u32, max_len: u32) -> ExecResult<String> {
+    if max_len < len {
+        return Err(host_error("string too long"));
+    }
+    match str::from_utf8(try!(memory.read(ptr, len))) {
+        Ok(string) => Ok(string.to_string()),
+        Err(_) => Err(host_error("string is not UTF-8")),
+    }
+}
+
+/// Reads text to send, which must fit on one line.
+fn read_text(memory: &Memory, ptr: u32, len: u32) -> ExecResult<String> {
+    let text = try!(read_str(memory, ptr, len, MAX_TEXT_LEN));
+    if text.contains(|ch: char| ch == '\r' || ch == '\n' || ch == '\0') {
+        return Err(host_error("text contains a line break"));
+    }
+    Ok(text)
+}
+
+/// Copies as much of `value` as fits into the buffer at `ptr`, returning
+/// its full length, or -1 if there is no value.
+fn fill(memory: &mut Memory, ptr: u32, cap: u32, value: Option<&str>) -> ExecResult<Option<u64>> {
+    let value = match value {
+        Some(value) => value.as_bytes(),
+        None => return Ok(Some(-1i32 as u32 as u64)),
+    };
+    let copied = ::std::cmp::min(value.len(), cap as usize);
+    try!(memory.write(ptr, &value[..copied]));
+    Ok(Some(value.len() as u32 as u64))
+}
+
+fn channel_nicks(state: &FrozenState, channel: &str) -> Option<String> {
+    let channel_id = match state.identify_channel(channel) {
+        Some(channel_id) => channel_id,
+        None => return None,
+    };
+    state.resolve_channel(channel_id).map(|channel| {
+        let nicks: Vec<String> = channel.get_users().iter()
+            .filter_map(|user_id| state.resolve_user(user_id.clone()))
+            .map(|user: &User| user.get_nick().to_string())
+            .collect();
+        nicks.join(" ")
+    })
+}
+
+/// Something a module asked to send, sent once it returns.
+#[derive(Debug, PartialEq, Eq)]
+enum Effect {
+    Reply(String),
+    Notice(String),
+    Say(String, String),
+}
+
+/// What a module may read about the command being dispatched.
+struct CommandState {
+    args: BTreeMap<String, String>,
+    source_nick: String,
+    current_nick: String,
+    reply_target: String,
+    state: Option<Arc<FrozenState>>,
+}
+
+/// What a module may see and do during one invocation.
+struct Context<'a> {
+    imports: &'a [HostFunc],
+    storage: Option<&'a Namespace>,
+    timers: Option<&'a Timers>,
+    command: Option<&'a CommandState>,
+    effects: Vec<Effect>,
+}
+
+impl<'a> Context<'a> {
+    fn send(&mut self, effect: Effect) -> ExecResult<Option<u64>> {
+        if MAX_MESSAGES <= self.effects.len() {
+            return Err(host_error("too many messages"));
+        }
+        self.effects.push(effect);
+        Ok(None)
+    }
+
+    fn storage(&self) -> ExecResult<&'a Namespace> {
+        self.storage.ok_or(host_error("storage is not available while loading"))
+    }
+
+    fn timers(&self) -> ExecResult<&'a Timers> {
+        self.timers.ok_or(host_error("timers are not available while loading"))
+    }
+
+    fn command(&self) -> ExecResult<&'a CommandState> {
+        self.command.ok_or(host_error("no command is being dispatched"))
+    }
+}
+
+impl<'a> Host for Context<'a> {
+    fn call(&mut self, import: usize, args: &[u64], memory: &mut Memory) -> ExecResult<Option<u64>> {
+        let arg = |idx: usize| args[idx] as u32;
+        match self.imports[import] {
+            HostFunc::Reply => {
+                try!(self.command());
+                let text = try!(read_text(memory, arg(0), arg(1)));
+                self.send(Effect::Reply(text))
+            },
+            HostFunc::ReplyNotice => {
+                try!(self.command());
+                let text = try!(read_text(memory, arg(0), arg(1)));
+                self.send(Effect::Notice(text))
+            },
+            HostFunc::Say => {
+                let target = try!(read_text(memory, arg(0), arg(1)));
+                if target.len() == 0 || target.contains(' ') {
+                    return Err(host_error("bad target"));
+                }
+                let text = try!(read_text(memory, arg(2), arg(3)));
+                self.send(Effect::Say(target, text))
+            },
+            HostFunc::SetTimer => {
+                let delay = ::std::cmp::min(::std::cmp::max(args[0] as i64, 0), MAX_TIMER_SECS);
+                let at = ::time::get_time() + ::time::Duration::seconds(delay);
+                try!(self.timers()).schedule(at, TimerToken(args[1]));
+                Ok(None)
+            },
+            HostFunc::CancelTimer => {
+                let cancelled = try!(self.timers()).cancel(TimerToken(args[0]));
+                Ok(Some(if cancelled { 1 } else { 0 }))
+            },
+            HostFunc::KvGet => {
+                let key = try!(read_str(memory, arg(0), arg(1), MAX_KEY_LEN));
+                let value = try!(self.storage()).get::<String>(&key);
+                fill(memory, arg(2), arg(3), value.as_ref().map(|x| &x[..]))
+            },
+            HostFunc::KvSet => {
+                let key = try!(read_str(memory, arg(0), arg(1), MAX_KEY_LEN));
+                let value = try!(read_str(memory, arg(2), arg(3), MAX_VALUE_LEN));
+                try!(self.storage()).set(&key, &value);
+                Ok(None)
+            },
+            HostFunc::KvRemove => {
+                let key = try!(read_str(memory, arg(0), arg(1), MAX_KEY_LEN));
+                try!(self.storage()).remove(&key);
+                Ok(None)
+            },
+            HostFunc::Arg => {
+                let name = try!(read_str(memory, arg(0), arg(1), MAX_KEY_LEN));
+                let value = try!(self.command()).args.get(&name);
+                fill(memory, arg(2), arg(3), value.map(|x| &x[..]))
+            },
+            HostFunc::SourceNick => {
+                let command = self.command.map(|command| &command.source_nick[..]);
+                fill(memory, arg(0), arg(1), command)
+            },
+            HostFunc::CurrentNick => {
+                let command = self.command.map(|command| &command.current_nick[..]);
+                fill(memory, arg(0), arg(1), command)
+            },
+            HostFunc::ReplyTarget => {
+                let command = self.command.map(|command| &command.reply_target[..]);
+                fill(memory, arg(0), arg(1), command)
+            },
+            HostFunc::ChannelNicks => {
+                let channel = try!(read_str(memory, arg(0), arg(1), MAX_TEXT_LEN));
+                let nicks = match try!(self.command()).state {
+                    Some(ref state) => channel_nicks(state, &channel),
+                    None => None,
+                };
+                fill(memory, arg(2), arg(3), nicks.as_ref().map(|x| &x[..]))
+            },
+        }
+    }
+}
+
+pub struct WasmPlugin {
+    name: String,
+    formats: Vec<(String, String)>,
+    instance: Instance,
+    imports: Vec<HostFunc>,
+    fuel: u64,
+    storage: Option<Namespace>,
+    timers: Option<Timers>,
+    raw_tx: Option<Sender<IrcMsgBuf>>,
+}
+
+impl WasmPlugin {
+    /// Loads the module at `config.path`, running its start function and
+    /// reading its manifest.
+    pub fn load(config: &WasmPluginConfig) -> Result<WasmPlugin, String> {
+        let mut bytes = Vec::new();
+        if let Err(err) = File::open(&config.path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+            return Err(format!("failed to read {}: {:?}", config.path, err));
+        }
+        WasmPlugin::from_bytes(&bytes,
+            config.fuel.unwrap_or(DEFAULT_FUEL),
+            config.max_memory_pages.unwrap_or(DEFAULT_MAX_MEMORY_PAGES))
+    }
+
+    fn from_bytes(bytes: &[u8], fuel: u64, max_pages: u32) -> Result<WasmPlugin, String> {
+        let module = try!(decode(bytes).map_err(|err| format!("bad module: {:?}", err)));
+        let imports = try!(resolve_imports(&module));
+        let mut instance = try!(Instance::new(module, max_pages));
+
+        let packed = {
+            let mut context = Context {
+                imports: &imports,
+                storage: None,
+                timers: None,
+                command: None,
+                effects: Vec::new(),
+            };
+            if let Err(trap) = instance.start(&mut context, fuel) {
+                return Err(format!("start trapped: {:?}", trap));
+            }
+            match instance.invoke(&mut context, "manifest", &[], fuel) {
+                Some(Ok(Some(packed))) => packed,
+                Some(Ok(None)) => return Err("manifest returned nothing".to_string()),
+                Some(Err(trap)) => return Err(format!("manifest trapped: {:?}", trap)),
+                None => return Err("no manifest export".to_string()),
+            }
+        };
+        let text = match instance.memory().read((packed >> 32) as u32, packed as u32) {
+            Ok(text) => String::from_utf8_lossy(text).into_owned(),
+            Err(_) => return Err("manifest is outside memory".to_string()),
+        };
+        let (name, formats) = try!(parse_manifest(&text));
+        if formats.len() > 0 && instance.module().exported_func("dispatch").is_none() {
+            return Err("manifest declares formats but there is no dispatch export".to_string());
+        }
+
+        Ok(WasmPlugin {
+            name: format!("wasm:{}", name),
+            formats: formats,
+            instance: instance,
+            imports: imports,
+            fuel: fuel,
+            storage: None,
+            timers: None,
+            raw_tx: None,
+        })
+    }
+
+    /// Calls an export, returning what it asked to send, or nothing if it
+    /// trapped.
+    fn invoke(&mut self, export: &str, args: &[u64], command: Option<&CommandState>) -> Vec<Effect> {
+        let mut context = Context {
+            imports: &self.imports,
+            storage: self.storage.as_ref(),
+            timers: self.timers.as_ref(),
+            command: command,
+            effects: Vec::new(),
+        };
+        match self.instance.invoke(&mut context, export, args, self.fuel) {
+            Some(Ok(_)) => context.effects,
+            Some(Err(trap)) => {
+                warn!("{}: {} trapped: {:?}", self.name, export, trap);
+                Vec::new()
+            },
+            None => {
+                warn!("{}: no {} export", self.name, export);
+                Vec::new()
+            },
+        }
+    }
+
+    fn say(&self, target: &str, text: &str) {
+        match self.raw_tx {
+            Some(ref raw_tx) => send_privmsg(raw_tx, target, text),
+            None => warn!("{}: dropping message to {} sent before connecting", self.name, target),
+        }
+    }
+}
+
+impl RustBotPlugin for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        self.storage = Some(conf.storage());
+        self.timers = Some(conf.timers());
+        for (idx, &(ref format, ref description)) in self.formats.iter().enumerate() {
+            match Format::from_str(format) {
+                Ok(parsed) => conf.map_format(Token(idx as u64), parsed, description),
+                Err(err) => warn!("{}: bad format {:?}: {:?}", self.name, format, err),
+            }
+        }
+    }
+
+    fn on_message(&mut self, replier: &mut Replier, _: &IrcMsg) {
+        if self.raw_tx.is_none() {
+            self.raw_tx = Some(replier.0.clone());
+        }
+    }
+
+    fn on_timer(&mut self, replier: &mut Replier, token: TimerToken) {
+        for effect in self.invoke("on_timer", &[token.0], None).into_iter() {
+            if let Effect::Say(target, text) = effect {
+                send_privmsg(&replier.0, &target, &text);
+            }
+        }
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let source_nick = match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(privmsg) => privmsg.source_nick().to_string(),
+            Err(_) => return,
+        };
+        let command = CommandState {
+            args: m.command().args(),
+            source_nick: source_nick,
+            current_nick: m.current_nick().to_string(),
+            reply_target: m.reply_target.clone(),
+            state: Some(m.get_state()),
+        };
+        let effects = self.invoke("dispatch", &[m.command().token.0 as u32 as u64], Some(&command));
+        for effect in effects.into_iter() {
+            match effect {
+                Effect::Reply(text) => m.reply(&text),
+                Effect::Notice(text) => m.reply_notice(&text),
+                Effect::Say(target, text) => self.say(&target, &text),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{WasmPlugin, CommandState, Effect, parse_manifest};
+    use super::super::{Storage, Timers};
+
+    fn leb(mut value: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id];
+        section.extend(leb(contents.len()));
+        section.extend_from_slice(contents);
+        section
+    }
+
+    fn name(name: &str) -> Vec<u8> {
+        let mut bytes = leb(name.len());
+        bytes.extend_from_slice(name.as_bytes());
+        bytes
+    }
+
+    const MANIFEST: &'static str = concat!(
+        r#"{"name": "echo", "formats": ["#,
+        r#"{"format": "echo {*text}", "description": "Repeat something"}, "#,
+        r#"{"format": "spin"}]}"#);
+
+    /// A module which, for format 0, stores and replies its `text`
+    /// argument, and for format 1 loops forever.  Format 2 does as 0 does,
+    /// then traps.  On a timer, it says what it stored to `#x`.
+    fn echo_module() -> Vec<u8> {
+        let mut bytes = b"\0asm\x01\0\0\0".to_vec();
+        bytes.extend(section(1, &[6,
+            // reply
+            0x60, 2, 0x7f, 0x7f, 0,
+            // arg, kv_get
+            0x60, 4, 0x7f, 0x7f, 0x7f, 0x7f, 1, 0x7f,
+            // kv_set, say
+            0x60, 4, 0x7f, 0x7f, 0x7f, 0x7f, 0,
+            // manifest
+            0x60, 0, 1, 0x7e,
+            // dispatch, on_timer
+            0x60, 1, 0x7f, 0, 0x60, 1, 0x7e, 0]));
+        let mut imports = vec![5];
+        for &(func, type_idx) in [("reply", 0), ("arg", 1), ("kv_set", 2), ("kv_get", 1), ("say", 2)].iter() {
+            imports.extend(name("ircbot"));
+            imports.extend(name(func));
+            imports.extend_from_slice(&[0, type_idx]);
+        }
+        bytes.extend(section(2, &imports));
+        bytes.extend(section(3, &[3, 3, 4, 5]));
+        bytes.extend(section(5, &[1, 0, 1]));
+
+        let mut exports = vec![4];
+        for &(export, kind, idx) in [("memory", 2, 0), ("manifest", 0, 5), ("dispatch", 0, 6), ("on_timer", 0, 7)].iter() {
+            exports.extend(name(export));
+            exports.extend_from_slice(&[kind, idx]);
+        }
+        bytes.extend(section(7, &exports));
+
+        let mut code = vec![3];
+        // manifest: its address, 0, and its length, in two bytes
+        let manifest = [0, 0x42, MANIFEST.len() as u8 | 0x80, (MANIFEST.len() >> 7) as u8, 0x0b];
+        code.extend(leb(manifest.len()));
+        code.extend_from_slice(&manifest);
+        let dispatch = [1, 1, 0x7f,
+            // if format == 1 { loop { br 0 } }
+            0x20, 0, 0x41, 1, 0x46, 0x04, 0x40, 0x03, 0x40, 0x0c, 0, 0x0b, 0x0b,
+            // len = arg("text", 1000, 100)
+            0x41, 0xf0, 0x03, 0x41, 4, 0x41, 0xe8, 0x07, 0x41, 60, 0x10, 1, 0x21, 1,
+            // kv_set("text", 1000, len)
+            0x41, 0xf0, 0x03, 0x41, 4, 0x41, 0xe8, 0x07, 0x20, 1, 0x10, 2,
+            // reply(1000, len)
+            0x41, 0xe8, 0x07, 0x20, 1, 0x10, 0,
+            // if format == 2 { unreachable }
+            0x20, 0, 0x41, 2, 0x46, 0x04, 0x40, 0x00, 0x0b,
+            0x0b];
+        code.extend(leb(dispatch.len()));
+        code.extend_from_slice(&dispatch);
+        let on_timer = [1, 1, 0x7f,
+            // len = kv_get("text", 1000, 100)
+            0x41, 0xf0, 0x03, 0x41, 4, 0x41, 0xe8, 0x07, 0x41, 60, 0x10, 3, 0x21, 1,
+            // say("#x", 1000, len)
+            0x41, 0xf4, 0x03, 0x41, 2, 0x41, 0xe8, 0x07, 0x20, 1, 0x10, 4,
+            0x0b];
+        code.extend(leb(on_timer.len()));
+        code.extend_from_slice(&on_timer);
+        bytes.extend(section(10, &code));
+
+        // the manifest at 0, "text" at 496 and "#x" at 500
+        let mut data = vec![2, 0, 0x41, 0, 0x0b];
+        data.extend(name(MANIFEST));
+        data.extend_from_slice(&[0, 0x41, 0xf0, 0x03, 0x0b, 6]);
+        data.extend_from_slice(b"text#x");
+        bytes.extend(section(11, &data));
+        bytes
+    }
+
+    fn command(text: &str) -> CommandState {
+        let mut args = BTreeMap::new();
+        args.insert("text".to_string(), text.to_string());
+        CommandState {
+            args: args,
+            source_nick: "alice".to_string(),
+            current_nick: "bot".to_string(),
+            reply_target: "#x".to_string(),
+            state: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let (name, formats) = parse_manifest(MANIFEST).unwrap();
+        assert_eq!(name, "echo");
+        assert_eq!(formats, vec![
+            ("echo {*text}".to_string(), "Repeat something".to_string()),
+            ("spin".to_string(), "".to_string()),
+        ]);
+        assert!(parse_manifest(r#"{"formats": []}"#).is_err());
+        assert!(parse_manifest(r#"{"name": "*ledger"}"#).is_err());
+        assert!(parse_manifest(r#"{"name": "x", "formats": [{}]}"#).is_err());
+        assert!(parse_manifest("not json").is_err());
+    }
+
+    #[test]
+    fn test_plugin() {
+        let mut plugin = WasmPlugin::from_bytes(&echo_module(), 10000, 4).unwrap();
+        assert_eq!(plugin.name, "wasm:echo");
+        assert_eq!(plugin.formats.len(), 2);
+
+        let storage = Storage::in_memory();
+        plugin.storage = Some(storage.namespace("wasm:echo"));
+        plugin.timers = Some(Timers::new());
+
+        assert_eq!(plugin.invoke("dispatch", &[0], Some(&command("hello"))),
+            vec![Effect::Reply("hello".to_string())]);
+        assert_eq!(storage.namespace("wasm:echo").get::<String>("text"), Some("hello".to_string()));
+        assert_eq!(plugin.invoke("on_timer", &[1], None),
+            vec![Effect::Say("#x".to_string(), "hello".to_string())]);
+        // the loop runs out of fuel, and the module carries on afterwards
+        assert_eq!(plugin.invoke("dispatch", &[1], Some(&command("x"))), vec![]);
+        // replies may not span lines
+        assert_eq!(plugin.invoke("dispatch", &[0], Some(&command("a\r\nQUIT"))), vec![]);
+        // replying needs a command to reply to
+        assert_eq!(plugin.invoke("dispatch", &[0], None), vec![]);
+        // a trap discards the reply, but not what was stored
+        assert_eq!(plugin.invoke("dispatch", &[2], Some(&command("lost"))), vec![]);
+        assert_eq!(storage.namespace("wasm:echo").get::<String>("text"), Some("lost".to_string()));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let mut bytes = echo_module();
+        assert!(WasmPlugin::from_bytes(&bytes, 10000, 0).is_err());
+        // the manifest runs out of fuel
+        assert!(WasmPlugin::from_bytes(&bytes, 1, 4).is_err());
+        assert!(WasmPlugin::from_bytes(&bytes[..40], 10000, 4).is_err());
+        // import "ircbot.zzzzz" rather than "ircbot.reply"
+        let at = bytes.windows(5).position(|x| x == b"reply").unwrap();
+        bytes[at..at + 5].copy_from_slice(b"zzzzz");
+        let err = WasmPlugin::from_bytes(&bytes, 10000, 4).err().unwrap();
+        assert!(err.contains("unknown import"), "{}", err);
+    }
+}
@@ -0,0 +1,366 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver};
+use std::sync::mpsc::{TrySendError, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::CommandMapperDispatch;
+
+/// Handles requests on a `WorkerPool` thread.  Each worker thread has
+/// its own instance, made by the factory given to `WorkerPool::start`.
+pub trait Worker<T>: 'static {
+    fn handle(&mut self, request: T);
+
+    /// Called instead of `handle` for a request which waited in the
+    /// queue for longer than the pool's timeout.  A request which takes
+    /// longer than that to handle is given up on without a call.
+    fn expired(&mut self, _request: T) {}
+}
+
+pub struct WorkerConfig {
+    workers: usize,
+    queue_size: usize,
+    timeout: Option<Duration>,
+}
+
+impl WorkerConfig {
+    /// One worker, a queue of ten requests and no timeout.
+    pub fn new() -> WorkerConfig {
+        WorkerConfig {
+            workers: 1,
+            queue_size: 10,
+            timeout: None,
+        }
+    }
+
+    pub fn workers(mut self, workers: usize) -> WorkerConfig {
+        assert!(workers > 0, "a WorkerPool needs at least one worker");
+        self.workers = workers;
+        self
+    }
+
+    pub fn queue_size(mut self, queue_size: usize) -> WorkerConfig {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Limits how long a request may wait in the queue, and how long a
+    /// worker may spend on one before it is abandoned and replaced.
+    pub fn timeout(mut self, timeout: Duration) -> WorkerConfig {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SubmitError {
+    /// Every worker is busy and the queue is full
+    QueueFull,
+    /// Every worker thread has exited
+    Unavailable,
+}
+
+struct Job<T> {
+    enqueued: Instant,
+    request: T,
+}
+
+/// A worker thread, as the watchdog sees it.
+struct Slot {
+    // when the worker started on its current request, if it has one
+    busy_since: Option<Instant>,
+    // dropped once the worker has exited or been abandoned
+    running: Option<Sender<()>>,
+}
+
+/// A bounded queue of requests, handled by a fixed number of threads.
+/// A worker which panics is logged and replaced with a fresh one from
+/// the factory, so one bad request does not take down the service.
+/// With a timeout, a watchdog also replaces workers stuck on a request;
+/// the stuck thread exits once it gets unstuck.
+pub struct WorkerPool<T: Send + 'static> {
+    name: String,
+    sender: SyncSender<Job<T>>,
+    // disconnected once every worker thread has exited
+    running: Receiver<()>,
+    // dropping this stops the watchdog
+    watchdog: Option<Sender<()>>,
+}
+
+fn worker_loop<T, W, F>(name: &str, rx: &Mutex<Receiver<Job<T>>>, timeout: Option<Duration>,
+                        factory: &F, slot: &Mutex<Slot>)
+    where W: Worker<T>, F: Fn() -> W
+{
+    let mut worker = factory();
+    loop {
+        let job = match rx.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        slot.lock().unwrap().busy_since = Some(Instant::now());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            match timeout {
+                Some(timeout) if timeout < job.enqueued.elapsed() => worker.expired(job.request),
+                _ => worker.handle(job.request),
+            }
+        }));
+        {
+            let mut state = slot.lock().unwrap();
+            state.busy_since = None;
+            if state.running.is_none() {
+                // the watchdog has started a replacement
+                return;
+            }
+        }
+        if result.is_err() {
+            warn!("{}: worker panicked; restarting it", name);
+            worker = factory();
+        }
+    }
+}
+
+fn spawn_worker<T, W, F>(name: &str, worker_idx: usize, rx: &Arc<Mutex<Receiver<Job<T>>>>,
+                         timeout: Option<Duration>, factory: &Arc<F>, running: Sender<()>)
+                         -> Option<Arc<Mutex<Slot>>>
+    where T: Send + 'static, W: Worker<T>, F: Fn() -> W + Send + Sync + 'static
+{
+    let slot = Arc::new(Mutex::new(Slot { busy_since: None, running: Some(running) }));
+    let thread_name = format!("plugin-{}-{}", name, worker_idx);
+    let pool_name = name.to_string();
+    let rx = rx.clone();
+    let factory = factory.clone();
+    let thread_slot = slot.clone();
+    let spawned = thread::Builder::new().name(thread_name).spawn(move || {
+        worker_loop(&pool_name, &rx, timeout, &*factory, &thread_slot);
+        thread_slot.lock().unwrap().running.take();
+    });
+    match spawned {
+        Ok(_) => Some(slot),
+        Err(err) => {
+            warn!("{}: failed to spawn worker: {:?}", name, err);
+            None
+        }
+    }
+}
+
+/// Abandons workers which have been busy with one request for longer
+/// than `timeout`, starting a replacement for each, until `stop` is
+/// dropped.
+fn watchdog<T, W, F>(name: &str, rx: Arc<Mutex<Receiver<Job<T>>>>, timeout: Duration, factory: Arc<F>,
+                     running: Sender<()>, stop: Receiver<()>, mut slots: Vec<Arc<Mutex<Slot>>>)
+    where T: Send + 'static, W: Worker<T>, F: Fn() -> W + Send + Sync + 'static
+{
+    let mut next_idx = slots.len();
+    loop {
+        match stop.recv_timeout(timeout / 4) {
+            Err(RecvTimeoutError::Timeout) => (),
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let mut stuck = 0;
+        for slot in slots.iter() {
+            let mut state = slot.lock().unwrap();
+            let overdue = match state.busy_since {
+                Some(since) => timeout < since.elapsed(),
+                None => false,
+            };
+            if overdue && state.running.take().is_some() {
+                warn!("{}: worker busy for over {:?}; replacing it", name, timeout);
+                stuck += 1;
+            }
+        }
+        slots.retain(|slot| slot.lock().unwrap().running.is_some());
+        for _ in 0..stuck {
+            let spawned = spawn_worker(name, next_idx, &rx, Some(timeout), &factory, running.clone());
+            slots.extend(spawned.into_iter());
+            next_idx += 1;
+        }
+    }
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn start<W, F>(name: &str, config: WorkerConfig, factory: F) -> WorkerPool<T>
+        where W: Worker<T>, F: Fn() -> W + Send + Sync + 'static
+    {
+        let (tx, rx) = sync_channel(config.queue_size);
+        let rx = Arc::new(Mutex::new(rx));
+        let factory = Arc::new(factory);
+        let (running_tx, running_rx) = channel::<()>();
+
+        let mut slots = Vec::new();
+        for worker_idx in 0..config.workers {
+            let spawned = spawn_worker(name, worker_idx, &rx, config.timeout, &factory, running_tx.clone());
+            slots.extend(spawned.into_iter());
+        }
+
+        let mut watchdog_tx = None;
+        if let Some(timeout) = config.timeout {
+            let (stop_tx, stop_rx) = channel::<()>();
+            let pool_name = name.to_string();
+            let spawned = thread::Builder::new().name(format!("plugin-{}-watchdog", name)).spawn(move || {
+                watchdog(&pool_name, rx, timeout, factory, running_tx, stop_rx, slots);
+            });
+            match spawned {
+                Ok(_) => watchdog_tx = Some(stop_tx),
+                Err(err) => warn!("{}: failed to spawn watchdog: {:?}", name, err),
+            }
+        }
+
+        WorkerPool {
+            name: name.to_string(),
+            sender: tx,
+            running: running_rx,
+            watchdog: watchdog_tx,
+        }
+    }
+
+    /// Stops accepting requests and waits for the workers to finish the
+    /// queued ones, until `deadline`.  Returns whether they finished.
+    pub fn shutdown(self, deadline: Instant) -> bool {
+        let WorkerPool { name, sender, running, watchdog } = self;
+        drop(sender);
+        drop(watchdog);
+
+        let now = Instant::now();
+        let timeout = if now < deadline { deadline - now } else { Duration::from_millis(0) };
+        match running.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Disconnected) => true,
+            Ok(()) | Err(RecvTimeoutError::Timeout) => {
+                warn!("{}: workers did not finish before the deadline", name);
+                false
+            }
+        }
+    }
+
+    /// Queue a request without blocking.
+    pub fn submit(&self, request: T) -> Result<(), SubmitError> {
+        let job = Job { enqueued: Instant::now(), request: request };
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(SubmitError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(SubmitError::Unavailable),
+        }
+    }
+
+    /// Queue a request, blocking while the queue is full.
+    pub fn submit_wait(&self, request: T) -> Result<(), SubmitError> {
+        let job = Job { enqueued: Instant::now(), request: request };
+        self.sender.send(job).map_err(|_| SubmitError::Unavailable)
+    }
+}
+
+impl WorkerPool<CommandMapperDispatch> {
+    /// Queue a command, replying to the user if it could not be queued.
+    pub fn dispatch(&self, m: &CommandMapperDispatch) {
+        match self.submit(m.clone()) {
+            Ok(()) => (),
+            Err(SubmitError::QueueFull) => {
+                m.reply(&format!("Service ``{}'' is busy; try again later", self.name));
+            },
+            Err(SubmitError::Unavailable) => {
+                m.reply(&format!("Service ``{}'' unavailable", self.name));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{Worker, WorkerPool, WorkerConfig, SubmitError};
+
+    struct Echo(Sender<u32>);
+
+    impl Worker<u32> for Echo {
+        fn handle(&mut self, request: u32) {
+            if request == 0 {
+                panic!("zero");
+            }
+            if request == 100 {
+                thread::sleep(Duration::from_millis(50));
+            }
+            if request == 200 {
+                thread::sleep(Duration::from_millis(300));
+            }
+            self.0.send(request).unwrap();
+        }
+
+        fn expired(&mut self, request: u32) {
+            self.0.send(1000 + request).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_restart_on_panic() {
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let pool = WorkerPool::start("echo", WorkerConfig::new(),
+            move || Echo(tx.lock().unwrap().clone()));
+        pool.submit_wait(1).unwrap();
+        pool.submit_wait(0).unwrap();
+        pool.submit_wait(2).unwrap();
+        assert_eq!(rx.recv().unwrap(), 1);
+        assert_eq!(rx.recv().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_queue_full_and_timeout() {
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let config = WorkerConfig::new().queue_size(2).timeout(Duration::from_millis(20));
+        let pool = WorkerPool::start("echo", config, move || Echo(tx.lock().unwrap().clone()));
+
+        pool.submit(100).unwrap();
+        thread::sleep(Duration::from_millis(10));
+        // the worker is busy with 100, so two requests fit in the queue
+        pool.submit(100).unwrap();
+        pool.submit(3).unwrap();
+        assert_eq!(pool.submit(4), Err(SubmitError::QueueFull));
+
+        // each 100 outlasts the timeout, so the watchdog starts a worker
+        // for the second one and then for 3, which has waited too long
+        let mut replies = vec![rx.recv().unwrap(), rx.recv().unwrap(), rx.recv().unwrap()];
+        replies.sort();
+        assert_eq!(replies, vec![100, 100, 1003]);
+    }
+
+    #[test]
+    fn test_replace_stuck_worker() {
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let config = WorkerConfig::new().timeout(Duration::from_millis(100));
+        let pool = WorkerPool::start("echo", config, move || Echo(tx.lock().unwrap().clone()));
+
+        pool.submit(200).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        pool.submit(6).unwrap();
+        assert_eq!(rx.recv().unwrap(), 6);
+        pool.submit(7).unwrap();
+        assert_eq!(rx.recv().unwrap(), 7);
+        assert_eq!(rx.recv().unwrap(), 200);
+        assert!(pool.shutdown(Instant::now() + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn test_shutdown_drains_queue() {
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let pool = WorkerPool::start("echo", WorkerConfig::new(), move || Echo(tx.lock().unwrap().clone()));
+        pool.submit(100).unwrap();
+        pool.submit(5).unwrap();
+        assert!(pool.shutdown(Instant::now() + Duration::from_millis(1000)));
+        assert_eq!(rx.iter().collect::<Vec<_>>(), vec![100, 5]);
+
+        let (tx, _rx) = channel();
+        let tx = Mutex::new(tx);
+        let pool = WorkerPool::start("echo", WorkerConfig::new(), move || Echo(tx.lock().unwrap().clone()));
+        pool.submit(100).unwrap();
+        assert!(!pool.shutdown(Instant::now() + Duration::from_millis(10)));
+    }
+}
@@ -3,6 +3,7 @@
 
 #![feature(plugin)]
 #![plugin(phf_macros)]
+#![cfg_attr(test, feature(test))]
 
 #[macro_use] extern crate log;
 
@@ -16,20 +17,25 @@ extern crate url;
 extern crate mio;
 extern crate bytes;
 extern crate phf;
+extern crate libc;
+
+#[cfg(test)] extern crate test;
 
 use std::io::Read;
 use std::fs::File;
 use std::env::args_os;
 
 use botcore::BotConfig;
+use command_mapper::PluginConfig;
 
 mod botcore;
 mod plugins;
 mod command_mapper;
 mod irc_mio;
+mod signal;
 mod utils;
 
-fn parse_appconfig() -> Option<BotConfig> {
+fn parse_appconfig() -> Option<(BotConfig, PluginConfig)> {
     let args = args_os().collect::<Vec<_>>();
 
     let filename = match args.as_slice() {
@@ -48,26 +54,33 @@ fn parse_appconfig() -> Option<BotConfig> {
     }
 
     let mut parser = toml::Parser::new(&buf);
-    let table = match parser.parse() {
+    let (table, plugin_config) = match parser.parse() {
         Some(table) => {
             let core_key = "core".to_string();
-            match table.get(&core_key) {
+            let core = match table.get(&core_key) {
                 Some(value) => value.clone(),
                 None => panic!("failed to parse in some way.")
-            }
+            };
+            // `[plugins.<name>]` tables, handed to each plugin as is
+            let plugin_config = match table.get("plugins") {
+                Some(&toml::Value::Table(ref plugins)) => plugins.clone(),
+                Some(_) => panic!("plugins must be a table"),
+                None => PluginConfig::new(),
+            };
+            (core, plugin_config)
         }
         None => panic!("failed to parse in some way.")
     };
-    toml::decode::<BotConfig>(table)
+    toml::decode::<BotConfig>(table).map(|conf| (conf, plugin_config))
 }
 
 
 fn main() {
-    let appconfig = match parse_appconfig() {
+    let (appconfig, plugin_config) = match parse_appconfig() {
         Some(config) => config,
         None => panic!("bad config")
     };
 
     println!("starting");
-    botcore::run_loop(&appconfig).ok().expect("main loop failed");
+    botcore::run_loop(&appconfig, plugin_config).ok().expect("main loop failed");
 }
@@ -1,13 +1,14 @@
 use std::fmt;
 use std::convert::From;
-use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
 use std::io::{self, Read};
+use std::time::Duration as StdDuration;
+use std::time::Instant;
 
 use rustc_serialize::json::{self, DecoderError};
 use hyper;
 use time::{Timespec, get_time, Duration, SteadyTime};
 
-use irc::{IrcMsg, IrcMsgBuf};
+use irc::IrcMsg;
 
 use utils::formatting::duration_to_string;
 use command_mapper::{
@@ -16,6 +17,9 @@ use command_mapper::{
     IrcBotConfigurator,
     Format,
     Token,
+    Worker,
+    WorkerPool,
+    WorkerConfig,
 };
 
 static UPCOMING_URL: &'static str = "http://anime.yshi.org/api/calendar/upcoming/100";
@@ -97,13 +101,13 @@ fn get_upcoming() -> Result<Vec<Upcoming>, ApiFailure> {
 }
 
 pub struct AnimeCalendarPlugin {
-    sender: Option<SyncSender<(CommandMapperDispatch, IrcMsgBuf)>>
+    pool: Option<WorkerPool<CommandMapperDispatch>>
 }
 
 
 impl AnimeCalendarPlugin {
     pub fn new() -> AnimeCalendarPlugin {
-        AnimeCalendarPlugin { sender: None }
+        AnimeCalendarPlugin { pool: None }
     }
 
     pub fn get_plugin_name() -> &'static str {
@@ -221,49 +225,54 @@ impl AniCalInternal {
             Err(ref err) => m.reply(&format!("err: {:?}", err)),
         }
     }
+}
 
-    fn start(&mut self, rx: Receiver<(CommandMapperDispatch, IrcMsgBuf)>) {
-        for (m, _) in rx.iter() {
-            let command_phrase = m.command();
-            match command_phrase.token {
-                CMD_UPCOMING => {
-                    let search = command_phrase.get::<String>(&"search");
-                    self.handle_upcoming(&m, maybe_str_ref(&search))
-                }
-                _ => ()
+impl Worker<CommandMapperDispatch> for AniCalInternal {
+    fn handle(&mut self, m: CommandMapperDispatch) {
+        let command_phrase = m.command();
+        match command_phrase.token {
+            CMD_UPCOMING => {
+                let search = command_phrase.get::<String>(&"search");
+                self.handle_upcoming(&m, maybe_str_ref(&search))
             }
+            _ => ()
         }
     }
+
+    fn expired(&mut self, m: CommandMapperDispatch) {
+        m.reply("Service ``animecalendar'' timed out");
+    }
 }
 
 impl RustBotPlugin for AnimeCalendarPlugin {
+    fn name(&self) -> &str {
+        AnimeCalendarPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_UPCOMING, Format::from_str("upcoming").unwrap());
-        conf.map_format(CMD_UPCOMING, Format::from_str("upcoming {*search}").unwrap());
-        conf.map_format(CMD_UPCOMING, Format::from_str("showtime").unwrap());
-        conf.map_format(CMD_UPCOMING, Format::from_str("showtime {*search}").unwrap());
+        conf.map_format(CMD_UPCOMING, Format::from_str("upcoming {*search?}").unwrap(),
+            "List upcoming anime episodes, optionally filtered by title");
+        conf.map_format(CMD_UPCOMING, Format::from_str("showtime {*search?}").unwrap(),
+            "List upcoming anime episodes, optionally filtered by title");
     }
 
     fn start(&mut self) {
         info!("started AnimeCalendarPlugin");
-        let (tx, rx) = sync_channel(10);
-        self.sender = Some(tx);
-
-        let _ = ::std::thread::Builder::new().name("plugin-animecalendar".to_string()).spawn(move || {
-            info!("AniCalInternal started.");
-            AniCalInternal::new().start(rx);
-        });
+        // a single worker, so that every request shares the cache
+        let config = WorkerConfig::new().timeout(StdDuration::from_secs(30));
+        self.pool = Some(WorkerPool::start("animecalendar", config, AniCalInternal::new));
     }
 
-    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, message: &IrcMsg) {
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _message: &IrcMsg) {
         info!("dispatching AnimeCalendarPlugin command");
-        match self.sender {
-            Some(ref sender) => {
-                if let Err(err) = sender.send((m.clone(), message.to_owned())) {
-                    m.reply(&format!("Service ``animecalendar'' unavailable: {:?}", err));
-                }
-            }
-            None => ()
-        };
+        if let Some(ref pool) = self.pool {
+            pool.dispatch(m);
+        }
+    }
+
+    fn stop(&mut self, deadline: Instant) {
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown(deadline);
+        }
     }
 }
@@ -25,8 +25,13 @@ impl AsciiArtPlugin {
 }
 
 impl RustBotPlugin for AsciiArtPlugin {
+    fn name(&self) -> &str {
+        AsciiArtPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_DUCK, Format::from_str("duck").ok().unwrap());
+        conf.map_format(CMD_DUCK, Format::from_str("duck").ok().unwrap(),
+            "Draw a duck");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _: &IrcMsg) {
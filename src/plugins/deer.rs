@@ -11,6 +11,7 @@ use command_mapper::{
     CommandMapperDispatch,
     IrcBotConfigurator,
     Format,
+    Namespace,
     Token,
 };
 
@@ -95,15 +96,29 @@ fn render_deer(format: &str) -> Vec<String> {
 pub struct DeerPlugin {
     lines_sent: u64,
     throttle_map: HashMap<(UserId, MessageEndpoint), Timespec>,
+    storage: Option<Namespace>,
 }
 
 impl RustBotPlugin for DeerPlugin {
+    fn name(&self) -> &str {
+        DeerPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_DEER, Format::from_str("deer").unwrap());
-        conf.map_format(CMD_REED, Format::from_str("reed").unwrap());
-        conf.map_format(CMD_DEERMAN, Format::from_str("deerman").unwrap());
-        conf.map_format(CMD_NAMREED, Format::from_str("namreed").unwrap());
-        conf.map_format(CMD_DEER_STATS, Format::from_str("deer-stats").unwrap());
+        let storage = conf.storage();
+        self.lines_sent = storage.get("lines_sent").unwrap_or(0);
+        self.storage = Some(storage);
+
+        conf.map_format(CMD_DEER, Format::from_str("deer").unwrap(),
+            "Draw a deer");
+        conf.map_format(CMD_REED, Format::from_str("reed").unwrap(),
+            "Draw a deer facing the other way");
+        conf.map_format(CMD_DEERMAN, Format::from_str("deerman").unwrap(),
+            "Draw a deerman");
+        conf.map_format(CMD_NAMREED, Format::from_str("namreed").unwrap(),
+            "Draw a deerman facing the other way");
+        conf.map_format(CMD_DEER_STATS, Format::from_str("deer-stats").unwrap(),
+            "Show how many lines of deer have been drawn");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _msg: &IrcMsg) {
@@ -119,6 +134,7 @@ impl DeerPlugin {
         DeerPlugin {
             lines_sent: 0,
             throttle_map: HashMap::new(),
+            storage: None,
         }
     }
 
@@ -154,6 +170,9 @@ impl DeerPlugin {
                     m.reply(&deer_line);
                     self.lines_sent += 1;
                 }
+                if let Some(ref storage) = self.storage {
+                    storage.set("lines_sent", &self.lines_sent);
+                }
                 self.throttle_bump(source, m.target.clone());
             },
             DeerCommandType::DeerStats => {
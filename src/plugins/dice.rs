@@ -0,0 +1,430 @@
+use irc::{IrcMsg, server};
+use rand::{Rng, thread_rng};
+
+use command_mapper::{
+    RustBotPlugin,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Token,
+};
+
+const CMD_ROLL: Token = Token(0);
+
+static MAX_EXPRESSIONS: usize = 5;
+
+static MAX_TERMS: usize = 10;
+
+/// Per term, before any explode
+static MAX_DICE: u32 = 100;
+
+static MAX_SIDES: u32 = 1000;
+
+static MAX_CONSTANT: i64 = 1000000;
+
+/// How many dice explosions may add to a term
+static MAX_EXPLOSIONS: usize = 100;
+
+/// Terms of more dice than this are summed without showing each die
+static MAX_DICE_SHOWN: usize = 30;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    Empty,
+    /// Not something like `2d6`, `d%` or `3`
+    BadTerm(String),
+    /// A modifier which isn't `!`, `k`, `kh`, `kl`, `d`, `dh` or `dl`
+    BadModifier(String),
+    TooManyExpressions,
+    TooManyTerms,
+    TooManyDice,
+    TooManySides,
+    ConstantTooLarge,
+    /// Keeping or dropping more dice than are rolled, or keeping none
+    BadKeep,
+    /// One-sided dice would explode forever
+    ExplodingOneSided,
+}
+
+fn describe_parse_error(err: &ParseError) -> String {
+    match *err {
+        ParseError::Empty => "Roll what? Try ``roll 3d6+2''".to_string(),
+        ParseError::BadTerm(ref term) =>
+            format!("I don't understand {:?}; dice look like 2d6, d20 or d%", term),
+        ParseError::BadModifier(ref modifier) =>
+            format!("I don't understand {:?}; try ! to explode, kh3 or dl1", modifier),
+        ParseError::TooManyExpressions => format!("At most {} rolls at once", MAX_EXPRESSIONS),
+        ParseError::TooManyTerms => format!("At most {} terms per roll", MAX_TERMS),
+        ParseError::TooManyDice => format!("At most {} dice per term", MAX_DICE),
+        ParseError::TooManySides => format!("At most {} sides per die", MAX_SIDES),
+        ParseError::ConstantTooLarge => format!("Numbers are limited to {}", MAX_CONSTANT),
+        ParseError::BadKeep => "Keep at least one of the dice rolled".to_string(),
+        ParseError::ExplodingOneSided => "One-sided dice can't explode".to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Keep {
+    All,
+    Highest(u32),
+    Lowest(u32),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Dice {
+    count: u32,
+    sides: u32,
+    explode: bool,
+    keep: Keep,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Term {
+    Dice(Dice),
+    Constant(i64),
+}
+
+/// Terms, each added if `true` and subtracted if `false`.
+#[derive(Debug, PartialEq, Eq)]
+struct Expression {
+    source: String,
+    terms: Vec<(bool, Term)>,
+}
+
+#[derive(Debug)]
+struct DieRoll {
+    value: u32,
+    // rolled its maximum, and so added another die
+    exploded: bool,
+    kept: bool,
+}
+
+/// Splits `number` off the start of `text`.
+fn take_number(text: &str) -> (Option<u32>, &str) {
+    let end = text.find(|ch: char| !ch.is_digit(10)).unwrap_or(text.len());
+    // anything too long to parse is well over every limit
+    let number = if end == 0 { None } else { Some(text[..end].parse().unwrap_or(u32::max_value())) };
+    (number, &text[end..])
+}
+
+fn parse_term(text: &str) -> Result<Term, ParseError> {
+    if text.chars().all(|ch| ch.is_digit(10)) {
+        return match text.parse::<i64>() {
+            Ok(value) if value <= MAX_CONSTANT => Ok(Term::Constant(value)),
+            _ => Err(ParseError::ConstantTooLarge),
+        };
+    }
+
+    let (count, rest) = take_number(text);
+    if !rest.starts_with('d') {
+        return Err(ParseError::BadTerm(text.to_string()));
+    }
+    let (sides, mut rest) = if rest[1..].starts_with('%') {
+        (Some(100), &rest[2..])
+    } else {
+        take_number(&rest[1..])
+    };
+    let count = count.unwrap_or(1);
+    let sides = match sides {
+        Some(sides) if sides > 0 => sides,
+        _ => return Err(ParseError::BadTerm(text.to_string())),
+    };
+    if count == 0 || MAX_DICE < count {
+        return Err(ParseError::TooManyDice);
+    }
+    if MAX_SIDES < sides {
+        return Err(ParseError::TooManySides);
+    }
+
+    let mut dice = Dice { count: count, sides: sides, explode: false, keep: Keep::All };
+    while !rest.is_empty() {
+        if rest.starts_with('!') {
+            if sides == 1 {
+                return Err(ParseError::ExplodingOneSided);
+            }
+            dice.explode = true;
+            rest = &rest[1..];
+            continue;
+        }
+        let (keep_highest, after) = if rest.starts_with("kh") {
+            (true, &rest[2..])
+        } else if rest.starts_with("kl") {
+            (false, &rest[2..])
+        } else if rest.starts_with("k") {
+            (true, &rest[1..])
+        } else if rest.starts_with("dh") {
+            (false, &rest[2..])
+        } else if rest.starts_with("dl") {
+            (true, &rest[2..])
+        } else if rest.starts_with("d") {
+            (true, &rest[1..])
+        } else {
+            return Err(ParseError::BadModifier(rest.to_string()));
+        };
+        let dropping = rest.starts_with("d");
+        let (number, after) = take_number(after);
+        let number = match number {
+            Some(number) => number,
+            None => return Err(ParseError::BadModifier(rest.to_string())),
+        };
+        if count < number || (dropping && count == number) || (!dropping && number == 0) {
+            return Err(ParseError::BadKeep);
+        }
+        let kept = if dropping { count - number } else { number };
+        dice.keep = if keep_highest { Keep::Highest(kept) } else { Keep::Lowest(kept) };
+        rest = after;
+    }
+    Ok(Term::Dice(dice))
+}
+
+fn parse_expression(text: &str) -> Result<Expression, ParseError> {
+    let mut terms = Vec::new();
+    let mut add = true;
+    let mut start = 0;
+    let text_lower = text.to_lowercase();
+    for (idx, ch) in text_lower.char_indices().chain(Some((text_lower.len(), '+')).into_iter()) {
+        if ch != '+' && ch != '-' {
+            continue;
+        }
+        let term = &text_lower[start..idx];
+        if term.is_empty() {
+            // a leading sign
+            if idx != 0 {
+                return Err(ParseError::BadTerm(text.to_string()));
+            }
+        } else {
+            terms.push((add, try!(parse_term(term))));
+        }
+        add = ch == '+';
+        start = idx + 1;
+    }
+    if terms.is_empty() {
+        return Err(ParseError::BadTerm(text.to_string()));
+    }
+    if MAX_TERMS < terms.len() {
+        return Err(ParseError::TooManyTerms);
+    }
+    Ok(Expression { source: text.to_string(), terms: terms })
+}
+
+/// Parses whitespace- or comma-separated expressions; whitespace around
+/// `+` and `-` joins terms rather than separating expressions.
+fn parse_expressions(text: &str) -> Result<Vec<Expression>, ParseError> {
+    let mut sources: Vec<String> = Vec::new();
+    for part in text.split(',') {
+        let mut joining = true;
+        let first = sources.len();
+        for word in part.split_whitespace() {
+            let starts_op = word.starts_with('+') || word.starts_with('-');
+            if first < sources.len() && (joining || starts_op) {
+                sources.last_mut().unwrap().push_str(word);
+            } else {
+                sources.push(word.to_string());
+            }
+            joining = word.ends_with('+') || word.ends_with('-');
+        }
+    }
+    if sources.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    if MAX_EXPRESSIONS < sources.len() {
+        return Err(ParseError::TooManyExpressions);
+    }
+    let mut expressions = Vec::new();
+    for source in sources.iter() {
+        expressions.push(try!(parse_expression(source)));
+    }
+    Ok(expressions)
+}
+
+impl Dice {
+    /// Rolls with `roll`, which gives a value from 1 to the sides it is passed.
+    fn roll<F>(&self, roll: &mut F) -> Vec<DieRoll> where F: FnMut(u32) -> u32 {
+        let mut rolls = Vec::new();
+        let mut explosions = 0;
+        for _ in 0..self.count {
+            loop {
+                let value = roll(self.sides);
+                let exploded = self.explode && value == self.sides && explosions < MAX_EXPLOSIONS;
+                rolls.push(DieRoll { value: value, exploded: exploded, kept: true });
+                if !exploded {
+                    break;
+                }
+                explosions += 1;
+            }
+        }
+
+        let (keep, highest) = match self.keep {
+            Keep::All => return rolls,
+            Keep::Highest(keep) => (keep as usize, true),
+            Keep::Lowest(keep) => (keep as usize, false),
+        };
+        let mut order: Vec<usize> = (0..rolls.len()).collect();
+        order.sort_by(|&a, &b| if highest {
+            rolls[b].value.cmp(&rolls[a].value)
+        } else {
+            rolls[a].value.cmp(&rolls[b].value)
+        });
+        for &idx in order[keep..].iter() {
+            rolls[idx].kept = false;
+        }
+        rolls
+    }
+}
+
+fn format_rolls(rolls: &[DieRoll]) -> String {
+    if MAX_DICE_SHOWN < rolls.len() {
+        return format!("[{} dice]", rolls.len());
+    }
+    let shown: Vec<String> = rolls.iter().map(|die| {
+        let value = if die.exploded { format!("{}!", die.value) } else { die.value.to_string() };
+        if die.kept { value } else { format!("({})", value) }
+    }).collect();
+    format!("[{}]", shown.join(", "))
+}
+
+impl Expression {
+    /// The total and its breakdown.
+    fn roll<F>(&self, roll: &mut F) -> (i64, String) where F: FnMut(u32) -> u32 {
+        let mut total = 0;
+        let mut breakdown = String::new();
+        for (idx, &(add, ref term)) in self.terms.iter().enumerate() {
+            let (value, shown) = match *term {
+                Term::Constant(value) => (value, value.to_string()),
+                Term::Dice(ref dice) => {
+                    let rolls = dice.roll(roll);
+                    let value = rolls.iter()
+                        .filter(|die| die.kept)
+                        .fold(0, |sum, die| sum + die.value as i64);
+                    (value, format_rolls(&rolls))
+                },
+            };
+            if add {
+                total += value;
+                if idx != 0 {
+                    breakdown.push_str(" + ");
+                }
+            } else {
+                total -= value;
+                breakdown.push_str(if idx == 0 { "-" } else { " - " });
+            }
+            breakdown.push_str(&shown);
+        }
+        (total, breakdown)
+    }
+}
+
+pub struct DicePlugin;
+
+impl DicePlugin {
+    pub fn new() -> DicePlugin {
+        DicePlugin
+    }
+
+    pub fn get_plugin_name() -> &'static str {
+        "dice"
+    }
+}
+
+enum DiceCommandType {
+    Roll(String),
+}
+
+fn parse_command(m: &CommandMapperDispatch) -> Option<DiceCommandType> {
+    let command_phrase = m.command();
+    match command_phrase.token {
+        CMD_ROLL => command_phrase.get("expr").map(DiceCommandType::Roll),
+        _ => None
+    }
+}
+
+impl RustBotPlugin for DicePlugin {
+    fn name(&self) -> &str {
+        DicePlugin::get_plugin_name()
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        conf.map_format(CMD_ROLL, Format::from_str("roll {*expr}").unwrap(),
+            "Roll dice, such as 3d6+2, 4d6kh3, d% or 2d10! to explode on a 10");
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let privmsg;
+        match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(p) => privmsg = p,
+            Err(_) => return,
+        }
+        let source_nick = privmsg.source_nick();
+
+        match parse_command(m) {
+            Some(DiceCommandType::Roll(ref expr)) => {
+                let expressions = match parse_expressions(expr) {
+                    Ok(expressions) => expressions,
+                    Err(err) => {
+                        m.reply(&format!("{}: {}", source_nick, describe_parse_error(&err)));
+                        return;
+                    }
+                };
+                let mut rng = thread_rng();
+                let mut roll = |sides: u32| rng.gen_range(1, sides + 1);
+                let results: Vec<String> = expressions.iter().map(|expression| {
+                    let (total, breakdown) = expression.roll(&mut roll);
+                    format!("{}: {} = {}", expression.source, breakdown, total)
+                }).collect();
+                m.reply(&format!("{}: {}", source_nick, results.join("; ")));
+            },
+            None => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_term, parse_expressions, Dice, Keep, Term, ParseError};
+
+    fn dice(count: u32, sides: u32, explode: bool, keep: Keep) -> Term {
+        Term::Dice(Dice { count: count, sides: sides, explode: explode, keep: keep })
+    }
+
+    #[test]
+    fn test_parse_term() {
+        assert_eq!(parse_term("3d6"), Ok(dice(3, 6, false, Keep::All)));
+        assert_eq!(parse_term("d%"), Ok(dice(1, 100, false, Keep::All)));
+        assert_eq!(parse_term("4d6kh3"), Ok(dice(4, 6, false, Keep::Highest(3))));
+        assert_eq!(parse_term("4d6d1"), Ok(dice(4, 6, false, Keep::Highest(3))));
+        assert_eq!(parse_term("2d20kl1"), Ok(dice(2, 20, false, Keep::Lowest(1))));
+        assert_eq!(parse_term("5d10!dh2"), Ok(dice(5, 10, true, Keep::Lowest(3))));
+        assert_eq!(parse_term("12"), Ok(Term::Constant(12)));
+
+        assert_eq!(parse_term("d"), Err(ParseError::BadTerm("d".to_string())));
+        assert_eq!(parse_term("3x6"), Err(ParseError::BadTerm("3x6".to_string())));
+        assert_eq!(parse_term("3d6q"), Err(ParseError::BadModifier("q".to_string())));
+        assert_eq!(parse_term("1000d6"), Err(ParseError::TooManyDice));
+        assert_eq!(parse_term("99999999999d6"), Err(ParseError::TooManyDice));
+        assert_eq!(parse_term("d100000"), Err(ParseError::TooManySides));
+        assert_eq!(parse_term("99999999"), Err(ParseError::ConstantTooLarge));
+        assert_eq!(parse_term("2d6kh3"), Err(ParseError::BadKeep));
+        assert_eq!(parse_term("2d6d2"), Err(ParseError::BadKeep));
+        assert_eq!(parse_term("3d1!"), Err(ParseError::ExplodingOneSided));
+    }
+
+    #[test]
+    fn test_parse_expressions() {
+        let sources = |text: &str| parse_expressions(text).unwrap().into_iter()
+            .map(|expression| expression.source).collect::<Vec<_>>();
+        assert_eq!(sources("3d6+2 d20"), vec!["3d6+2", "d20"]);
+        assert_eq!(sources("3d6 + 2, d20 -1"), vec!["3d6+2", "d20-1"]);
+        assert_eq!(parse_expressions(" , "), Err(ParseError::Empty));
+        assert_eq!(parse_expressions("d4 d4 d4 d4 d4 d4"), Err(ParseError::TooManyExpressions));
+        assert_eq!(parse_expressions("d6++2"), Err(ParseError::BadTerm("d6++2".to_string())));
+    }
+
+    #[test]
+    fn test_roll() {
+        let expression = parse_expressions("-1+4d6kh3+2d6!").unwrap().pop().unwrap();
+        let mut values = vec![3, 1, 6, 5, 6, 2, 4].into_iter();
+        let mut roll = |_: u32| values.next().unwrap();
+        assert_eq!(expression.roll(&mut roll),
+            (-1 + 3 + 6 + 5 + 6 + 2 + 4, "-1 + [3, (1), 6, 5] + [6!, 2, 4]".to_string()));
+    }
+}
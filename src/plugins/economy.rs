@@ -0,0 +1,379 @@
+use time::{get_time, Duration, Timespec};
+
+use irc::{IrcMsg, server};
+
+use utils::formatting::duration_to_string;
+use command_mapper::{
+    RustBotPlugin,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Namespace,
+    Token,
+    Ledger,
+    LedgerError,
+    Transfer,
+    MINT,
+};
+
+const CMD_BALANCE: Token = Token(0);
+const CMD_GIVE: Token = Token(1);
+const CMD_DAILY: Token = Token(2);
+const CMD_STATEMENT: Token = Token(3);
+
+static STATEMENT_LENGTH: usize = 5;
+
+static GIVE_WINDOW_SECS: i64 = 86400;
+
+/// `[plugins.economy]` in the configuration file.
+#[derive(RustcDecodable, Default)]
+struct EconomyConfig {
+    daily_allowance: Option<u64>,
+    daily_interval_secs: Option<i64>,
+    // how much one account may give away per day
+    give_limit: Option<u64>,
+    // how long an account must have existed before it may give
+    min_account_age_secs: Option<i64>,
+}
+
+enum EconomyCommandType {
+    Balance(Option<String>),
+    Give(String, u64),
+    Daily,
+    Statement,
+}
+
+fn parse_command(m: &CommandMapperDispatch) -> Option<EconomyCommandType> {
+    let command_phrase = m.command();
+    match command_phrase.token {
+        CMD_BALANCE => Some(EconomyCommandType::Balance(command_phrase.get("nick"))),
+        CMD_GIVE => match (command_phrase.get("nick"), command_phrase.get("amount")) {
+            (Some(nick), Some(amount)) => Some(EconomyCommandType::Give(nick, amount)),
+            _ => None,
+        },
+        CMD_DAILY => Some(EconomyCommandType::Daily),
+        CMD_STATEMENT => Some(EconomyCommandType::Statement),
+        _ => None,
+    }
+}
+
+fn format_transfer(transfer: &Transfer, account: &str, now: Timespec) -> String {
+    let ago = duration_to_string(now - Timespec::new(transfer.when, 0));
+    if transfer.to.to_lowercase() == account.to_lowercase() {
+        format!("#{} +{} from {} ({}) {} ago", transfer.id, transfer.amount,
+            transfer.from, transfer.reason, ago)
+    } else {
+        format!("#{} -{} to {} ({}) {} ago", transfer.id, transfer.amount,
+            transfer.to, transfer.reason, ago)
+    }
+}
+
+/// Why points were not handed out or given.
+#[derive(Debug, PartialEq)]
+enum Refusal {
+    // the seconds until the next allowance
+    TooSoon(i64),
+    ToSelf,
+    ToBot,
+    ZeroAmount,
+    NoAccount,
+    TooNew,
+    // how much more may be given today
+    OverLimit(u64),
+    InsufficientFunds(u64),
+    Failed(LedgerError),
+}
+
+/// Balances, allowances and the give limit are kept by services account,
+/// so changing nick neither earns more points nor reaches anyone else's.
+/// Users who are not identified may only look at balances.
+pub struct EconomyPlugin {
+    ledger: Option<Ledger>,
+    // the time of each account's last allowance under `daily:<account>`,
+    // and what it gave away recently as `(when, amount)`s under
+    // `given:<account>`, accounts lowercased
+    storage: Option<Namespace>,
+    daily_allowance: u64,
+    daily_interval_secs: i64,
+    give_limit: u64,
+    min_account_age_secs: i64,
+}
+
+impl EconomyPlugin {
+    pub fn new() -> EconomyPlugin {
+        EconomyPlugin {
+            ledger: None,
+            storage: None,
+            daily_allowance: 100,
+            daily_interval_secs: 86400,
+            give_limit: 500,
+            min_account_age_secs: 86400,
+        }
+    }
+
+    pub fn get_plugin_name() -> &'static str {
+        "economy"
+    }
+
+    /// Hands `account` its allowance, returning its new balance.
+    fn daily(&self, account: &str, now: i64) -> Result<u64, Refusal> {
+        let (ledger, storage) = match (self.ledger.as_ref(), self.storage.as_ref()) {
+            (Some(ledger), Some(storage)) => (ledger, storage),
+            _ => return Err(Refusal::NoAccount),
+        };
+        let key = format!("daily:{}", account.to_lowercase());
+        if let Some(last) = storage.get::<i64>(&key) {
+            if now < last + self.daily_interval_secs {
+                return Err(Refusal::TooSoon(last + self.daily_interval_secs - now));
+            }
+        }
+        match ledger.transfer(MINT, account, self.daily_allowance, "daily allowance") {
+            Ok(_) => {
+                storage.set(&key, &now);
+                Ok(ledger.balance(account))
+            },
+            Err(err) => Err(Refusal::Failed(err)),
+        }
+    }
+
+    /// Moves `amount` points from account `from` to account `to`, within
+    /// the limits on giving.
+    fn give(&self, from: &str, to: &str, amount: u64, now: i64) -> Result<Transfer, Refusal> {
+        let (ledger, storage) = match (self.ledger.as_ref(), self.storage.as_ref()) {
+            (Some(ledger), Some(storage)) => (ledger, storage),
+            _ => return Err(Refusal::NoAccount),
+        };
+        if to.to_lowercase() == from.to_lowercase() {
+            return Err(Refusal::ToSelf);
+        }
+        if to.starts_with('*') {
+            return Err(Refusal::ToBot);
+        }
+        if amount == 0 {
+            return Err(Refusal::ZeroAmount);
+        }
+        // also guards against typos in the nick
+        if !ledger.has_account(to) {
+            return Err(Refusal::NoAccount);
+        }
+        match ledger.opened(from) {
+            Some(opened) if opened + self.min_account_age_secs <= now => (),
+            _ => return Err(Refusal::TooNew),
+        }
+        let given_key = format!("given:{}", from.to_lowercase());
+        let mut given: Vec<(i64, u64)> = storage.get(&given_key).unwrap_or(Vec::new());
+        given.retain(|&(when, _)| now < when + GIVE_WINDOW_SECS);
+        let given_today = given.iter().fold(0, |sum, &(_, given_amount)| sum + given_amount);
+        if self.give_limit < given_today.saturating_add(amount) {
+            return Err(Refusal::OverLimit(self.give_limit.saturating_sub(given_today)));
+        }
+
+        match ledger.transfer(from, to, amount, "gift") {
+            Ok(transfer) => {
+                given.push((now, amount));
+                storage.set(&given_key, &given);
+                Ok(transfer)
+            },
+            Err(LedgerError::InsufficientFunds { balance, .. }) => Err(Refusal::InsufficientFunds(balance)),
+            Err(err) => Err(Refusal::Failed(err)),
+        }
+    }
+
+    fn dispatch_cmd_balance(&mut self, m: &CommandMapperDispatch, source_nick: &str, nick: Option<&str>) {
+        let ledger = match self.ledger {
+            Some(ref ledger) => ledger,
+            None => return,
+        };
+        match nick {
+            Some(nick) => match m.account_of(nick) {
+                Some(account) => m.reply(&format!("{} has {} points", nick, ledger.balance(&account))),
+                None => m.reply(&format!("{}: {} isn't identified to services", source_nick, nick)),
+            },
+            None => match m.source_account() {
+                Some(account) => m.reply(&format!("{}: you have {} points",
+                    source_nick, ledger.balance(account))),
+                None => m.reply(&format!("{}: identify to services to have points", source_nick)),
+            },
+        }
+    }
+
+    fn dispatch_cmd_daily(&mut self, m: &CommandMapperDispatch, source_nick: &str, account: &str) {
+        match self.daily(account, get_time().sec) {
+            Ok(balance) => m.reply(&format!("{}: here are {} points; you now have {}",
+                source_nick, self.daily_allowance, balance)),
+            Err(Refusal::TooSoon(secs)) => m.reply(&format!("{}: your next allowance is in {}",
+                source_nick, duration_to_string(Duration::seconds(secs)))),
+            Err(err) => warn!("economy: daily allowance for {} failed: {:?}", account, err),
+        }
+    }
+
+    fn dispatch_cmd_give(&mut self, m: &CommandMapperDispatch, source_nick: &str, account: &str,
+                         nick: &str, amount: u64) {
+        if nick.starts_with('*') || nick.to_lowercase() == m.current_nick().to_lowercase() {
+            m.reply(&format!("{}: keep them", source_nick));
+            return;
+        }
+        let to = match m.account_of(nick) {
+            Some(to) => to,
+            None => {
+                m.reply(&format!("{}: {} isn't identified to services", source_nick, nick));
+                return;
+            },
+        };
+        let reply = match self.give(account, &to, amount, get_time().sec) {
+            Ok(transfer) => format!("gave {} points to {} (#{})", amount, nick, transfer.id),
+            Err(Refusal::ToSelf) => "you already have them".to_string(),
+            Err(Refusal::ToBot) => "keep them".to_string(),
+            Err(Refusal::ZeroAmount) => "give at least one point".to_string(),
+            Err(Refusal::NoAccount) =>
+                format!("{} has no points account yet; they can open one with ``daily''", nick),
+            Err(Refusal::TooNew) => format!("accounts must be {} old to give points",
+                duration_to_string(Duration::seconds(self.min_account_age_secs))),
+            Err(Refusal::OverLimit(left)) => format!("you may give {} more points today", left),
+            Err(Refusal::InsufficientFunds(balance)) => format!("you only have {} points", balance),
+            Err(err) => {
+                warn!("economy: {} giving {} to {} failed: {:?}", account, amount, to, err);
+                return;
+            },
+        };
+        m.reply(&format!("{}: {}", source_nick, reply));
+    }
+
+    fn dispatch_cmd_statement(&mut self, m: &CommandMapperDispatch, source_nick: &str, account: &str) {
+        let ledger = match self.ledger {
+            Some(ref ledger) => ledger,
+            None => return,
+        };
+        let transfers = ledger.entries_for(account, STATEMENT_LENGTH);
+        if transfers.is_empty() {
+            m.reply(&format!("{}: no points have moved in or out of your account", source_nick));
+            return;
+        }
+        let now = get_time();
+        let lines: Vec<String> = transfers.iter()
+            .map(|transfer| format_transfer(transfer, account, now))
+            .collect();
+        m.reply(&format!("{}: {}", source_nick, lines.join("; ")));
+    }
+}
+
+impl RustBotPlugin for EconomyPlugin {
+    fn name(&self) -> &str {
+        EconomyPlugin::get_plugin_name()
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        self.ledger = Some(conf.ledger());
+        self.storage = Some(conf.storage());
+        let config: EconomyConfig = conf.config().unwrap_or_else(Default::default);
+        self.daily_allowance = config.daily_allowance.unwrap_or(self.daily_allowance);
+        self.daily_interval_secs = config.daily_interval_secs.unwrap_or(self.daily_interval_secs);
+        self.give_limit = config.give_limit.unwrap_or(self.give_limit);
+        self.min_account_age_secs = config.min_account_age_secs.unwrap_or(self.min_account_age_secs);
+
+        conf.map_format(CMD_BALANCE, Format::from_str("balance").unwrap(),
+            "Show how many points you have");
+        conf.map_format(CMD_BALANCE, Format::from_str("balance {nick:s}").unwrap(),
+            "Show how many points a nick has");
+        conf.map_format(CMD_GIVE, Format::from_str("give {nick:s} {amount:d}").unwrap(),
+            "Give some of your points to a nick");
+        conf.map_format(CMD_DAILY, Format::from_str("daily").unwrap(),
+            "Collect your daily allowance of points");
+        conf.map_format(CMD_STATEMENT, Format::from_str("statement").unwrap(),
+            "Show the latest points moved in or out of your account");
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let privmsg;
+        match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(p) => privmsg = p,
+            Err(_) => return,
+        }
+        let source_nick = privmsg.source_nick();
+
+        let command = match parse_command(m) {
+            Some(command) => command,
+            None => return,
+        };
+        // anyone may look at balances, but only the identified have points
+        if let EconomyCommandType::Balance(ref nick) = command {
+            self.dispatch_cmd_balance(m, source_nick, nick.as_ref().map(|nick| &nick[..]));
+            return;
+        }
+        let account = match m.source_account() {
+            Some(account) => account,
+            None => {
+                m.reply(&format!("{}: identify to services to have points", source_nick));
+                return;
+            },
+        };
+
+        match command {
+            EconomyCommandType::Balance(_) => (),
+            EconomyCommandType::Give(ref nick, amount) =>
+                self.dispatch_cmd_give(m, source_nick, account, nick, amount),
+            EconomyCommandType::Daily => self.dispatch_cmd_daily(m, source_nick, account),
+            EconomyCommandType::Statement => self.dispatch_cmd_statement(m, source_nick, account),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::get_time;
+
+    use command_mapper::{Ledger, Storage, MINT};
+    use super::{EconomyPlugin, Refusal};
+
+    fn plugin() -> EconomyPlugin {
+        let storage = Storage::in_memory();
+        let mut plugin = EconomyPlugin::new();
+        plugin.ledger = Some(Ledger::new(storage.namespace("*ledger")));
+        plugin.storage = Some(storage.namespace("economy"));
+        plugin
+    }
+
+    #[test]
+    fn test_daily() {
+        let plugin = plugin();
+        let now = get_time().sec;
+        assert_eq!(plugin.daily("alice", now), Ok(100));
+        assert_eq!(plugin.daily("ALICE", now + 3600), Err(Refusal::TooSoon(86400 - 3600)));
+        assert_eq!(plugin.daily("alice", now + 86400), Ok(200));
+        assert_eq!(plugin.daily("bob", now + 3600), Ok(100));
+    }
+
+    #[test]
+    fn test_give() {
+        let mut plugin = plugin();
+        plugin.give_limit = 200;
+        let now = get_time().sec;
+        plugin.daily("alice", now).unwrap();
+        plugin.daily("bob", now).unwrap();
+        assert_eq!(plugin.give("alice", "bob", 10, now), Err(Refusal::TooNew));
+
+        let later = now + 86400;
+        plugin.daily("alice", later).unwrap();
+        assert_eq!(plugin.give("alice", "ALICE", 10, later), Err(Refusal::ToSelf));
+        assert_eq!(plugin.give("alice", MINT, 10, later), Err(Refusal::ToBot));
+        assert_eq!(plugin.give("alice", "*greed", 10, later), Err(Refusal::ToBot));
+        assert_eq!(plugin.give("alice", "bob", 0, later), Err(Refusal::ZeroAmount));
+        assert_eq!(plugin.give("alice", "carol", 10, later), Err(Refusal::NoAccount));
+        assert_eq!(plugin.give("alice", "bob", 201, later), Err(Refusal::OverLimit(200)));
+        assert_eq!(plugin.give("bob", "alice", 150, later), Err(Refusal::InsufficientFunds(100)));
+
+        let transfer = plugin.give("alice", "bob", 150, later).unwrap();
+        assert_eq!((&transfer.from[..], &transfer.to[..], transfer.amount), ("alice", "bob", 150));
+        assert_eq!(plugin.give("alice", "bob", 51, later + 60), Err(Refusal::OverLimit(50)));
+        assert!(plugin.give("alice", "bob", 50, later + 60).is_ok());
+
+        // a day on, only the last gift still counts against the limit
+        plugin.daily("alice", later + 86400).unwrap();
+        assert_eq!(plugin.give("alice", "bob", 151, later + 86400), Err(Refusal::OverLimit(150)));
+        assert!(plugin.give("alice", "bob", 100, later + 86400).is_ok());
+
+        let ledger = plugin.ledger.as_ref().unwrap();
+        assert_eq!(ledger.balance("alice"), 0);
+        assert_eq!(ledger.balance("bob"), 400);
+    }
+}
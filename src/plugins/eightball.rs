@@ -59,11 +59,15 @@ fn parse_command<'a>(m: &CommandMapperDispatch) -> Option<EightBallCommandType>
 
 
 impl RustBotPlugin for EightBallPlugin {
+    fn name(&self) -> &str {
+        EightBallPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_8BALL, Format::from_str("8ball {*query}").ok().unwrap());
-        conf.map_format(CMD_8BALL, Format::from_str("8ball").ok().unwrap());
-        conf.map_format(CMD_8BALL, Format::from_str("\u{1f3b1} {*query}").ok().unwrap());
-        conf.map_format(CMD_8BALL, Format::from_str("\u{1f3b1}").ok().unwrap());
+        conf.map_format(CMD_8BALL, Format::from_str("8ball {*query?}").ok().unwrap(),
+            "Ask the magic 8-ball a question");
+        conf.map_format(CMD_8BALL, Format::from_str("\u{1f3b1} {*query?}").ok().unwrap(),
+            "Ask the magic 8-ball a question");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
@@ -25,6 +25,10 @@ fn apply_bitflips(input: &mut [u8]) {
 }
 
 impl RustBotPlugin for FetwgrkifgPlugin {
+    fn name(&self) -> &str {
+        FetwgrkifgPlugin::get_plugin_name()
+    }
+
     fn on_message(&mut self, replier: &mut Replier, msg: &IrcMsg) {
         if let Ok(privmsg) = msg.as_tymsg::<&ser2::Privmsg>() {
             if privmsg.get_target().starts_with(b"#") && rand::random::<f64>() < 0.0003 {
@@ -0,0 +1,1021 @@
+use std::collections::HashMap;
+use std::default::Default;
+use std::cmp::Ordering;
+use std::fmt;
+
+use time::{get_time, Duration, Timespec};
+
+use ::rand::thread_rng;
+
+use irc::legacy::{
+    ChannelId,
+    User,
+    UserId,
+};
+
+use irc::{IrcMsg, client as cli2, server};
+use irc::legacy::MessageEndpoint::{
+    KnownChannel,
+    KnownUser,
+};
+
+use utils::formatting::duration_to_string;
+use command_mapper::{
+    RustBotPlugin,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Namespace,
+    Token,
+    Replier,
+    Timers,
+    TimerToken,
+    Ledger,
+    LedgerError,
+};
+
+use self::rules::{Rules, GreedConfig, RollResult, Turn};
+
+mod rules;
+
+const CMD_GREED: Token = Token(0);
+const CMD_GREED_STATS: Token = Token(1);
+const CMD_GREED_TOP: Token = Token(2);
+const CMD_GREED_TOP_GLOBAL: Token = Token(3);
+const CMD_GREED_HISTORY: Token = Token(4);
+const CMD_GREED_VS: Token = Token(5);
+const CMD_GREED_CHALLENGE: Token = Token(6);
+const CMD_GREED_ROUND: Token = Token(7);
+
+pub struct GreedPlugin {
+    games: HashMap<ChannelId, Game>,
+    timers: Option<Timers>,
+    // also the token of each game's timer
+    next_game_id: u64,
+    // `UserStats` under `stats:<scope>:<player>`, `GameRecord`s under
+    // `history:<player>` and `HeadToHead` under `h2h:<player>:<player>`,
+    // where the scope is `global` or a channel and players are keyed as
+    // `PlayerKey` says.
+    storage: Option<Namespace>,
+    ledger: Option<Ledger>,
+    rules: Rules,
+}
+
+enum GreedCommandType {
+    Greed(u64),
+    GreedChallenge(String, u64),
+    GreedRound(u64),
+    GreedStats,
+    GreedTop(Scope, u64),
+    GreedHistory(String),
+    GreedVs(Option<String>, String),
+}
+
+struct GreedPlayResult {
+    user_id: UserId,
+    user_nick: String,
+    // the services account stakes are taken from and paid to, if the
+    // player was identified
+    account: Option<String>,
+    score: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GameKind {
+    /// Settled by the next player to roll
+    Duel,
+    /// Everyone who rolls before the timer fires plays; the highest wins
+    Round,
+}
+
+struct Game {
+    id: u64,
+    kind: GameKind,
+    channel: String,
+    // for a challenge, the only nick who may take the game up
+    challenged: Option<String>,
+    // in order of play
+    players: Vec<GreedPlayResult>,
+    // points each player puts in the pot, held by `GREED_POT` meanwhile
+    stake: u64,
+}
+
+fn parse_command<'a>(m: &CommandMapperDispatch) -> Option<GreedCommandType> {
+    let command_phrase = m.command();
+    match command_phrase.token {
+        CMD_GREED => Some(GreedCommandType::Greed(command_phrase.get("stake").unwrap_or(0))),
+        CMD_GREED_CHALLENGE => command_phrase.get::<String>("nick").map(|nick| {
+            let nick = nick.trim_left_matches('@').to_string();
+            GreedCommandType::GreedChallenge(nick, command_phrase.get("stake").unwrap_or(0))
+        }),
+        CMD_GREED_ROUND => Some(GreedCommandType::GreedRound(command_phrase.get("stake").unwrap_or(0))),
+        CMD_GREED_STATS => Some(GreedCommandType::GreedStats),
+        CMD_GREED_TOP => command_phrase.get("count")
+            .map(|count| GreedCommandType::GreedTop(Scope::Channel, count)),
+        CMD_GREED_TOP_GLOBAL => command_phrase.get("count")
+            .map(|count| GreedCommandType::GreedTop(Scope::Global, count)),
+        CMD_GREED_HISTORY => command_phrase.get("nick").map(GreedCommandType::GreedHistory),
+        CMD_GREED_VS => command_phrase.get("nick")
+            .map(|nick| GreedCommandType::GreedVs(command_phrase.get("other"), nick)),
+        _ => None
+    }
+}
+
+enum Scope {
+    Channel,
+    Global,
+}
+
+static GLOBAL_SCOPE: &'static str = "global";
+
+static MAX_TOP_COUNT: u64 = 10;
+
+static MAX_HISTORY_KEPT: usize = 10;
+
+static HISTORY_SHOWN: usize = 5;
+
+/// The ledger account holding the stakes of open games
+static GREED_POT: &'static str = "*greed";
+
+/// The ledger note listing the stakes in the pot, as (game, account,
+/// stake), so they can be returned if the bot restarts before the games
+/// are settled.  It is changed by the same transactions as the pot.
+static HELD_STAKES: &'static str = "greed:stakes";
+
+static MAX_STAKE: u64 = 1000;
+
+#[derive(RustcDecodable, RustcEncodable)]
+struct UserStats {
+    // the nick as last seen, for display; missing from older records
+    nick: Option<String>,
+    games: u32,
+    wins: u32,
+    score_sum: i32,
+    opponent_score_sum: i32
+}
+
+impl Default for UserStats {
+    fn default() -> UserStats {
+        UserStats {
+            nick: None,
+            games: 0,
+            wins: 0,
+            score_sum: 0,
+            opponent_score_sum: 0,
+        }
+    }
+}
+
+impl UserStats {
+    fn points(&self) -> i32 {
+        self.score_sum - self.opponent_score_sum
+    }
+}
+
+impl fmt::Display for UserStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{} wins over {} games; points: {}",
+            self.wins, self.games, self.points())
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+struct GameRecord {
+    when: i64,
+    channel: String,
+    opponent: String,
+    score: i32,
+    opponent_score: i32,
+}
+
+impl GameRecord {
+    fn format(&self, now: Timespec) -> String {
+        let outcome = match self.score.cmp(&self.opponent_score) {
+            Ordering::Greater => "beat",
+            Ordering::Equal => "tied with",
+            Ordering::Less => "lost to",
+        };
+        format!("{} {} {}-{} in {} {} ago", outcome, self.opponent,
+            self.score, self.opponent_score, self.channel,
+            duration_to_string(now - Timespec::new(self.when, 0)))
+    }
+}
+
+/// Results between two players, from the point of view of the one whose
+/// key sorts first.
+#[derive(RustcDecodable, RustcEncodable, Default)]
+struct HeadToHead {
+    wins: u32,
+    losses: u32,
+    ties: u32,
+}
+
+/// The accounts of those of `players` who staked; in a game with a stake
+/// that is all of them.
+fn stakers<'a>(players: &[&'a GreedPlayResult]) -> Vec<&'a str> {
+    players.iter()
+        .filter_map(|player| player.account.as_ref().map(|account| &account[..]))
+        .collect()
+}
+
+/// Whom records are kept for: the services account if the player was
+/// identified, so that they follow nick changes, or else the lowercased
+/// nick.  Accounts are keyed as `@<account>`, which no nick can be.  The
+/// nick is kept for display.
+struct PlayerKey {
+    key: String,
+    nick: String,
+}
+
+impl PlayerKey {
+    fn new(nick: &str, account: Option<&str>) -> PlayerKey {
+        let key = match account {
+            Some(account) => format!("@{}", account.to_lowercase()),
+            None => nick.to_lowercase(),
+        };
+        PlayerKey { key: key, nick: nick.to_string() }
+    }
+}
+
+fn h2h_key(player: &str, other: &str) -> (String, bool) {
+    if player <= other {
+        (format!("h2h:{}:{}", player, other), false)
+    } else {
+        (format!("h2h:{}:{}", other, player), true)
+    }
+}
+
+
+impl GreedPlugin {
+    pub fn new() -> GreedPlugin {
+        GreedPlugin {
+            games: HashMap::new(),
+            timers: None,
+            next_game_id: 1,
+            storage: None,
+            ledger: None,
+            rules: Rules::classic(),
+        }
+    }
+
+    pub fn get_plugin_name() -> &'static str {
+        "greed"
+    }
+
+    /// Moves stats kept under bare nicks, before scopes, to the global scope.
+    fn migrate_storage(&self) {
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => return,
+        };
+        for key in storage.keys().into_iter() {
+            if key.contains(':') {
+                continue;
+            }
+            if let Some(stats) = storage.get::<UserStats>(&key) {
+                storage.set(&format!("stats:{}:{}", GLOBAL_SCOPE, key), &stats);
+            }
+            storage.remove(&key);
+        }
+    }
+
+    fn get_userstats(&self, scope: &str, player: &str) -> Option<UserStats> {
+        let key = format!("stats:{}:{}", scope.to_lowercase(), player);
+        self.storage.as_ref().and_then(|storage| storage.get(&key))
+    }
+
+    /// How `nick` is keyed, by their account if they are identified.
+    fn player_key(&self, m: &CommandMapperDispatch, nick: &str) -> PlayerKey {
+        PlayerKey::new(nick, m.account_of(nick).as_ref().map(|account| &account[..]))
+    }
+
+    fn dispatch_cmd_greed_stats(&mut self, m: &CommandMapperDispatch, msg: &server::Privmsg) {
+        let player = PlayerKey::new(msg.source_nick(), m.source_account());
+        m.reply(match self.get_userstats(GLOBAL_SCOPE, &player.key) {
+            Some(stats) => format!("{}: {}", msg.source_nick(), stats),
+            None => format!("{}: You haven't played any games yet", msg.source_nick())
+        }.as_ref());
+    }
+
+    fn add_userstats_roll(&mut self, scope: &str, player: &PlayerKey, win: bool, self_score: i32, opp_score: i32) {
+        let mut cur_user: UserStats = self.get_userstats(scope, &player.key).unwrap_or_else(Default::default);
+        cur_user.nick = Some(player.nick.clone());
+        cur_user.games += 1;
+        cur_user.wins += if win { 1 } else { 0 };
+        cur_user.score_sum += self_score;
+        cur_user.opponent_score_sum += opp_score;
+        if let Some(ref storage) = self.storage {
+            let key = format!("stats:{}:{}", scope.to_lowercase(), player.key);
+            storage.set(&key, &cur_user);
+        }
+    }
+
+    fn add_history(&mut self, channel: &str, player: &PlayerKey, opponent: &PlayerKey, score: i32, opp_score: i32) {
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => return,
+        };
+        let key = format!("history:{}", player.key);
+        let mut history: Vec<GameRecord> = storage.get(&key).unwrap_or(Vec::new());
+        history.push(GameRecord {
+            when: get_time().sec,
+            channel: channel.to_string(),
+            opponent: opponent.nick.clone(),
+            score: score,
+            opponent_score: opp_score,
+        });
+        if MAX_HISTORY_KEPT < history.len() {
+            let excess = history.len() - MAX_HISTORY_KEPT;
+            history.drain(..excess);
+        }
+        storage.set(&key, &history);
+    }
+
+    fn get_head_to_head(&self, player: &str, other: &str) -> HeadToHead {
+        let (key, swapped) = h2h_key(player, other);
+        let h2h: HeadToHead = self.storage.as_ref()
+            .and_then(|storage| storage.get(&key))
+            .unwrap_or_else(Default::default);
+        if swapped {
+            HeadToHead { wins: h2h.losses, losses: h2h.wins, ties: h2h.ties }
+        } else {
+            h2h
+        }
+    }
+
+    fn add_head_to_head(&mut self, player: &str, other: &str, result: Ordering) {
+        let (key, swapped) = h2h_key(player, other);
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => return,
+        };
+        let mut h2h: HeadToHead = storage.get(&key).unwrap_or_else(Default::default);
+        match (result, swapped) {
+            (Ordering::Equal, _) => h2h.ties += 1,
+            (Ordering::Greater, false) | (Ordering::Less, true) => h2h.wins += 1,
+            (Ordering::Less, false) | (Ordering::Greater, true) => h2h.losses += 1,
+        }
+        storage.set(&key, &h2h);
+    }
+
+    fn play_turn(&self) -> Turn {
+        let mut rng = thread_rng();
+        Turn::play(&self.rules, |count| RollResult::roll(&mut rng, count))
+    }
+
+    /// Records a finished game everywhere it counts.
+    fn record_game(&mut self, channel: &str, player: &PlayerKey, score: i32,
+                   opponent: &PlayerKey, opp_score: i32) {
+        let result = self.rules.compare(score, opp_score);
+        for scope in [GLOBAL_SCOPE, channel].iter() {
+            self.add_userstats_roll(scope, player, result == Ordering::Greater, score, opp_score);
+            self.add_userstats_roll(scope, opponent, result == Ordering::Less, opp_score, score);
+        }
+        self.add_history(channel, player, opponent, score, opp_score);
+        self.add_history(channel, opponent, player, opp_score, score);
+        self.add_head_to_head(&player.key, &opponent.key, result);
+    }
+
+    fn dispatch_cmd_greed_top(&mut self, m: &CommandMapperDispatch, msg: &server::Privmsg,
+                              scope: Scope, count: u64) {
+        let scope = match scope {
+            Scope::Channel if msg.get_target().starts_with(b"#") =>
+                String::from_utf8_lossy(msg.get_target()).to_lowercase(),
+            _ => GLOBAL_SCOPE.to_string(),
+        };
+        let count = ::std::cmp::min(::std::cmp::max(count, 1), MAX_TOP_COUNT) as usize;
+        let ranking = self.leaderboard(&scope, count);
+        if ranking.is_empty() {
+            m.reply(&format!("Nobody has played greed in {} yet", scope));
+            return;
+        }
+        m.reply(&format!("Greed leaders in {}: {}", scope, ranking.join(", ")));
+    }
+
+    /// The first `count` players in `scope`, by wins and then points.
+    fn leaderboard(&self, scope: &str, count: usize) -> Vec<String> {
+        let storage = match self.storage {
+            Some(ref storage) => storage,
+            None => return Vec::new(),
+        };
+        let prefix = format!("stats:{}:", scope);
+        let mut stats: Vec<(String, UserStats)> = storage.keys().into_iter()
+            .filter(|key| key.starts_with(&prefix))
+            .filter_map(|key| {
+                let player = key[prefix.len()..].to_string();
+                storage.get(&key).map(|stats| (player, stats))
+            })
+            .collect();
+        stats.sort_by(|&(_, ref a), &(_, ref b)| {
+            match b.wins.cmp(&a.wins) {
+                Ordering::Equal => b.points().cmp(&a.points()),
+                unequal => unequal,
+            }
+        });
+        stats.iter().take(count).enumerate()
+            .map(|(idx, &(ref player, ref stats))| {
+                format!("{}. {} ({} wins, {} points)", idx + 1,
+                    stats.nick.as_ref().unwrap_or(player), stats.wins, stats.points())
+            })
+            .collect()
+    }
+
+    fn dispatch_cmd_greed_history(&mut self, m: &CommandMapperDispatch, nick: &str) {
+        let player = self.player_key(m, nick);
+        let history: Vec<GameRecord> = self.storage.as_ref()
+            .and_then(|storage| storage.get(&format!("history:{}", player.key)))
+            .unwrap_or(Vec::new());
+        if history.is_empty() {
+            m.reply(&format!("{} hasn't played any games yet", nick));
+            return;
+        }
+        let now = get_time();
+        let games: Vec<String> = history.iter().rev().take(HISTORY_SHOWN)
+            .map(|game| game.format(now))
+            .collect();
+        m.reply(&format!("{}: {}", nick, games.join("; ")));
+    }
+
+    fn dispatch_cmd_greed_vs(&mut self, m: &CommandMapperDispatch, player: &PlayerKey, other: &str) {
+        let other = self.player_key(m, other);
+        let h2h = self.get_head_to_head(&player.key, &other.key);
+        if h2h.wins + h2h.losses + h2h.ties == 0 {
+            m.reply(&format!("{} and {} haven't played each other yet", player.nick, other.nick));
+            return;
+        }
+        m.reply(&format!("{} vs {}: {} wins, {} losses, {} ties",
+            player.nick, other.nick, h2h.wins, h2h.losses, h2h.ties));
+    }
+
+    /// Returns the stakes of games left open when the bot stopped.  Each
+    /// is tried once.
+    fn return_held_stakes(&self) {
+        let ledger = match self.ledger {
+            Some(ref ledger) => ledger,
+            None => return,
+        };
+        let held: Vec<(u64, String, u64)> = ledger.note(HELD_STAKES).unwrap_or(Vec::new());
+        if held.is_empty() {
+            return;
+        }
+        for (idx, &(_, ref account, stake)) in held.iter().enumerate() {
+            let rest = held[idx + 1..].to_vec();
+            let result = ledger.transaction(|tx| {
+                try!(tx.transfer(GREED_POT, account, stake, "greed stake returned"));
+                tx.set_note(HELD_STAKES, &rest);
+                Ok(())
+            });
+            if let Err(err) = result {
+                warn!("greed: failed to return {}'s stake of {}: {:?}", account, stake, err);
+            }
+        }
+        let cleared = ledger.transaction(|tx| {
+            tx.set_note(HELD_STAKES, &Vec::<(u64, String, u64)>::new());
+            Ok(())
+        });
+        if let Err(err) = cleared {
+            warn!("greed: failed to clear the held stakes: {:?}", err);
+        }
+    }
+
+    /// Puts `account`'s stake in game `game` in the pot.
+    fn take_stake(&self, game: u64, account: &str, stake: u64, channel: &str) -> Result<(), LedgerError> {
+        let ledger = match self.ledger {
+            Some(ref ledger) => ledger,
+            None => return Err(LedgerError::InsufficientFunds { account: account.to_string(), balance: 0 }),
+        };
+        ledger.transaction(|tx| {
+            try!(tx.transfer(account, GREED_POT, stake, &format!("greed stake in {}", channel)));
+            let mut held: Vec<(u64, String, u64)> = ledger.note(HELD_STAKES).unwrap_or(Vec::new());
+            held.push((game, account.to_string(), stake));
+            tx.set_note(HELD_STAKES, &held);
+            Ok(())
+        })
+    }
+
+    /// Shares `pot` points from `GREED_POT` evenly among `accounts`,
+    /// settling the stakes held for game `game`.
+    fn pay_out(&self, game: u64, accounts: &[&str], pot: u64, reason: &str) {
+        let ledger = match self.ledger {
+            Some(ref ledger) => ledger,
+            None => return,
+        };
+        if pot == 0 || accounts.is_empty() {
+            return;
+        }
+        let share = pot / accounts.len() as u64;
+        // the remainder goes to whoever rolled first
+        let first_share = share + pot % accounts.len() as u64;
+        let result = ledger.transaction(|tx| {
+            for (idx, account) in accounts.iter().enumerate() {
+                let amount = if idx == 0 { first_share } else { share };
+                if amount > 0 {
+                    try!(tx.transfer(GREED_POT, account, amount, reason));
+                }
+            }
+            let held: Vec<(u64, String, u64)> = ledger.note(HELD_STAKES).unwrap_or(Vec::new());
+            let held: Vec<(u64, String, u64)> = held.into_iter()
+                .filter(|&(held_game, _, _)| held_game != game)
+                .collect();
+            tx.set_note(HELD_STAKES, &held);
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("greed: failed to pay out {} to {:?}: {:?}", pot, accounts, err);
+        }
+    }
+
+    /// Opens a game in the channel with the sender's turn as its first.
+    fn open_game(&mut self, m: &CommandMapperDispatch, player: GreedPlayResult, channel_id: ChannelId,
+                 channel: &str, kind: GameKind, challenged: Option<String>, stake: u64) {
+        let id = self.next_game_id;
+        self.next_game_id += 1;
+        let secs = match kind {
+            GameKind::Duel => self.rules.open_secs,
+            GameKind::Round => self.rules.round_secs,
+        };
+        if let Some(ref timers) = self.timers {
+            timers.schedule(get_time() + Duration::seconds(secs), TimerToken(id));
+        }
+        let staking = if stake > 0 {
+            format!(", staking {} points", stake)
+        } else {
+            String::new()
+        };
+        match (kind, challenged.as_ref()) {
+            (GameKind::Duel, Some(nick)) => m.reply(&format!(
+                "{}: {} challenges you{}; say greed within {} to take it up",
+                nick, player.user_nick, staking, duration_to_string(Duration::seconds(secs)))),
+            (GameKind::Duel, None) if stake > 0 => m.reply(&format!(
+                "{} plays for {} points; say greed within {} to take it up",
+                player.user_nick, stake, duration_to_string(Duration::seconds(secs)))),
+            (GameKind::Duel, None) => (),
+            (GameKind::Round, _) => m.reply(&format!(
+                "{} starts a round of greed{}; say greed within {} to join",
+                player.user_nick, staking, duration_to_string(Duration::seconds(secs)))),
+        }
+        self.games.insert(channel_id, Game {
+            id: id,
+            kind: kind,
+            channel: channel.to_string(),
+            challenged: challenged,
+            players: vec![player],
+            stake: stake,
+        });
+    }
+
+    fn dispatch_cmd_greed(&mut self, m: &CommandMapperDispatch, msg: &server::Privmsg,
+                          kind: GameKind, challenged: Option<String>, stake: u64) {
+        let (user_id, channel_id) = match (m.source.clone(), m.target.clone()) {
+            (KnownUser(uid), KnownChannel(cid)) => (uid, cid),
+            _ => return
+        };
+
+        let source_nick = msg.source_nick();
+        let channel = String::from_utf8_lossy(msg.get_target()).into_owned();
+
+        if let Some(ref nick) = challenged {
+            if nick.to_lowercase() == source_nick.to_lowercase() {
+                m.reply(&format!("{}: you can't challenge yourself", source_nick));
+                return;
+            }
+            if nick.to_lowercase() == m.current_nick().to_lowercase() {
+                m.reply(&format!("{}: I only keep the score", source_nick));
+                return;
+            }
+        }
+        if MAX_STAKE < stake {
+            m.reply(&format!("{}: stakes are limited to {} points", source_nick, MAX_STAKE));
+            return;
+        }
+        let account = m.source_account().map(|account| account.to_string());
+
+        let (open_kind, game_id, stake) = match self.games.get(&channel_id) {
+            None => (None, self.next_game_id, stake),
+            Some(game) => {
+                if kind == GameKind::Round || challenged.is_some() || stake != 0 {
+                    m.reply(&format!("{}: there's already a game of greed open here; say greed to join it",
+                        source_nick));
+                    return;
+                }
+                if game.players.iter().any(|player| player.user_id == user_id) {
+                    m.reply(&match game.kind {
+                        GameKind::Duel => format!("You can't go twice in a row, {}", source_nick),
+                        GameKind::Round => format!("{}: you've already rolled this round", source_nick),
+                    });
+                    return;
+                }
+                if let Some(ref nick) = game.challenged {
+                    if nick.to_lowercase() != source_nick.to_lowercase() {
+                        m.reply(&format!("{}: that game is {}'s challenge", source_nick, nick));
+                        return;
+                    }
+                }
+                (Some(game.kind), game.id, game.stake)
+            },
+        };
+        if stake > 0 {
+            let account = match account {
+                Some(ref account) => account,
+                None => {
+                    m.reply(&format!("{}: identify to services to play for points", source_nick));
+                    return;
+                },
+            };
+            match self.take_stake(game_id, account, stake, &channel) {
+                Ok(()) => (),
+                Err(LedgerError::InsufficientFunds { balance, .. }) => {
+                    m.reply(&format!("{}: the stake is {} points and you have {}",
+                        source_nick, stake, balance));
+                    return;
+                },
+                Err(err) => {
+                    warn!("greed: failed to take {}'s stake of {}: {:?}", account, stake, err);
+                    return;
+                },
+            }
+        }
+
+        let turn = self.play_turn();
+        m.reply(&format!("{}: {}", source_nick, turn.format(&self.rules)));
+        let player = GreedPlayResult {
+            user_id: user_id,
+            user_nick: source_nick.to_string(),
+            account: account,
+            score: turn.score,
+        };
+
+        match open_kind {
+            None => self.open_game(m, player, channel_id, &channel, kind, challenged, stake),
+            Some(GameKind::Round) => {
+                if let Some(game) = self.games.get_mut(&channel_id) {
+                    game.players.push(player);
+                }
+            },
+            Some(GameKind::Duel) => {
+                let game = self.games.remove(&channel_id).unwrap();
+                if let Some(ref timers) = self.timers {
+                    timers.cancel(TimerToken(game.id));
+                }
+                let prev_play = game.players.into_iter().next().unwrap();
+                self.finish_duel(m, &channel, game.id, player, prev_play, game.stake);
+            },
+        }
+    }
+
+    fn finish_duel(&mut self, m: &CommandMapperDispatch, channel: &str, game: u64,
+                   cur_play: GreedPlayResult, prev_play: GreedPlayResult, stake: u64) {
+        let source_nick = &cur_play.user_nick[..];
+        let prev_play_nick_now = m.get_state().resolve_user(prev_play.user_id)
+            .and_then(|user: &User| Some(user.get_nick().to_string()));
+        let prev_play_nick = prev_play_nick_now.clone()
+            .unwrap_or_else(|| format!("{} (deceased)", prev_play.user_nick));
+        // records follow the account, or else the nick the previous
+        // player has now, if still around; winnings go to the account the
+        // stake came from
+        let prev_play_nick_now = prev_play_nick_now.unwrap_or(prev_play.user_nick.clone());
+        let prev_play_score = prev_play.score;
+        let cur_play_score = cur_play.score;
+        let cmp_result = self.rules.compare(prev_play_score, cur_play_score);
+        let score_diff = (prev_play_score - cur_play_score).abs();
+        let response = match cmp_result {
+             Ordering::Less => format!("{} wins {} points from {}!",
+                source_nick, score_diff, prev_play_nick),
+             Ordering::Equal if prev_play_score == cur_play_score =>
+                format!("{} and {} tie.", source_nick, prev_play_nick),
+             Ordering::Equal => format!("{} and {} tie; a win takes {} points more.",
+                source_nick, prev_play_nick, self.rules.win_margin),
+             Ordering::Greater => format!("{} wins {} points from {}!",
+                prev_play_nick, score_diff, source_nick),
+        };
+        m.reply(&response);
+        if stake > 0 {
+            let pot = 2 * stake;
+            match cmp_result {
+                Ordering::Less => {
+                    self.pay_out(game, &stakers(&[&cur_play]), pot, "greed winnings");
+                    m.reply(&format!("{} collects the {}-point pot", source_nick, pot));
+                },
+                Ordering::Equal => {
+                    self.pay_out(game, &stakers(&[&prev_play, &cur_play]), pot, "greed stake returned");
+                    m.reply("Stakes returned");
+                },
+                Ordering::Greater => {
+                    self.pay_out(game, &stakers(&[&prev_play]), pot, "greed winnings");
+                    m.reply(&format!("{} collects the {}-point pot", prev_play_nick_now, pot));
+                },
+            }
+        }
+        let cur_key = PlayerKey::new(source_nick, cur_play.account.as_ref().map(|x| &x[..]));
+        let prev_key = PlayerKey::new(&prev_play_nick_now, prev_play.account.as_ref().map(|x| &x[..]));
+        self.record_game(channel, &cur_key, cur_play_score, &prev_key, prev_play_score);
+    }
+
+    /// Settles a round, returning the announcement.  The winner takes the
+    /// points each loser scored short of them, and the stakes if any.
+    fn finish_round(&mut self, game: Game) -> String {
+        let mut players = game.players;
+        if players.len() < 2 {
+            if game.stake > 0 {
+                self.pay_out(game.id, &stakers(&[&players[0]]), game.stake, "greed stake returned");
+            }
+            return format!("Nobody joined {}'s round of greed", players[0].user_nick);
+        }
+        // stable, so ties go to whoever rolled first
+        players.sort_by(|a, b| b.score.cmp(&a.score));
+
+        let results: Vec<String> = players.iter()
+            .map(|player| format!("{} {}", player.user_nick, player.score))
+            .collect();
+        let top_score = players[0].score;
+        let leaders: Vec<&GreedPlayResult> = players.iter()
+            .take_while(|player| self.rules.compare(player.score, top_score) == Ordering::Equal)
+            .collect();
+        let leader_nicks: Vec<&str> = leaders.iter().map(|player| &player.user_nick[..]).collect();
+        let stakes = game.stake * players.len() as u64;
+        let mut response = if leaders.len() == 1 {
+            let pot = players[1..].iter().fold(0, |pot, player| pot + top_score - player.score);
+            format!("{} wins the round and a pot of {} points! ({})",
+                leader_nicks[0], pot, results.join(", "))
+        } else {
+            format!("{} tie for the round. ({})", leader_nicks.join(" and "), results.join(", "))
+        };
+        if stakes > 0 {
+            self.pay_out(game.id, &stakers(&leaders), stakes, "greed winnings");
+            response.push_str(&format!(" The {} points staked go to {}.",
+                stakes, leader_nicks.join(" and ")));
+        }
+
+        let winner = PlayerKey::new(&players[0].user_nick, players[0].account.as_ref().map(|x| &x[..]));
+        for player in players[1..].iter() {
+            let loser = PlayerKey::new(&player.user_nick, player.account.as_ref().map(|x| &x[..]));
+            self.record_game(&game.channel, &winner, top_score, &loser, player.score);
+        }
+        response
+    }
+}
+
+impl RustBotPlugin for GreedPlugin {
+    fn name(&self) -> &str {
+        GreedPlugin::get_plugin_name()
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        self.storage = Some(conf.storage());
+        self.timers = Some(conf.timers());
+        self.ledger = Some(conf.ledger());
+        self.migrate_storage();
+        self.return_held_stakes();
+        self.rules = match conf.config::<GreedConfig>() {
+            Some(config) => Rules::from_config(config).unwrap_or_else(|err| {
+                warn!("greed: {}; using the classic rules", err);
+                Rules::classic()
+            }),
+            None => Rules::classic(),
+        };
+        conf.map_format(CMD_GREED, Format::from_str("greed").unwrap(),
+            &format!("Roll {} dice against the previous roller in the channel", self.rules.dice));
+        conf.map_format(CMD_GREED_CHALLENGE, Format::from_str("greed {nick:s}").unwrap(),
+            "Roll, leaving a game only the given nick may take up");
+        conf.map_format(CMD_GREED_ROUND, Format::from_str("greed round").unwrap(),
+            "Roll, starting a round which anyone may join until it closes; the highest wins");
+        conf.map_format(CMD_GREED, Format::from_str("greed --bet {stake:d}").unwrap(),
+            "Roll, staking points from your balance on the game");
+        conf.map_format(CMD_GREED_CHALLENGE, Format::from_str("greed --bet {stake:d} {nick:s}").unwrap(),
+            "Challenge a nick, staking points from your balance");
+        conf.map_format(CMD_GREED_ROUND, Format::from_str("greed round --bet {stake:d}").unwrap(),
+            "Start a round in which every player stakes points; the highest takes them all");
+        conf.map_format(CMD_GREED_STATS, Format::from_str("greed-stats").unwrap(),
+            "Show your greed record");
+        conf.map_format(CMD_GREED_TOP, Format::from_str("greed-top {count:d=5}").unwrap(),
+            "Show the channel's greed leaderboard");
+        conf.map_format(CMD_GREED_TOP_GLOBAL, Format::from_str("greed-top global {count:d=5}").unwrap(),
+            "Show the greed leaderboard across all channels");
+        conf.map_format(CMD_GREED_HISTORY, Format::from_str("greed-history {nick:s}").unwrap(),
+            "Show a player's recent greed games");
+        conf.map_format(CMD_GREED_VS, Format::from_str("greed-vs {nick:s}").unwrap(),
+            "Show your head-to-head greed record against a player");
+        conf.map_format(CMD_GREED_VS, Format::from_str("greed-vs {other:s} {nick:s}").unwrap(),
+            "Show the head-to-head greed record between two players");
+    }
+    
+    fn on_timer(&mut self, replier: &mut Replier, token: TimerToken) {
+        let TimerToken(id) = token;
+        let channel_id = match self.games.iter().find(|&(_, game)| game.id == id) {
+            Some((channel_id, _)) => channel_id.clone(),
+            None => return,
+        };
+        let game = self.games.remove(&channel_id).unwrap();
+        let channel = game.channel.clone();
+        let stake = game.stake;
+        let body = match (game.kind, game.challenged.clone()) {
+            (GameKind::Duel, challenged) => {
+                self.pay_out(game.id, &stakers(&[&game.players[0]]), stake, "greed stake returned");
+                let nick = &game.players[0].user_nick[..];
+                match challenged {
+                    Some(challenged) => format!("{} didn't take up {}'s challenge in time",
+                        challenged, nick),
+                    None => format!("Nobody took up {}'s game of greed in time", nick),
+                }
+            },
+            (GameKind::Round, _) => self.finish_round(game),
+        };
+        let privmsg = cli2::PrivmsgBuf::new(channel.as_bytes(), body.as_bytes()).unwrap();
+        if replier.reply(&privmsg).is_err() {
+            warn!("greed: failed to announce the end of the game in {}", channel);
+        }
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let privmsg;
+        match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(p) => privmsg = p,
+            Err(_) => return,
+        }
+
+        match parse_command(m) {
+            Some(GreedCommandType::Greed(stake)) =>
+                self.dispatch_cmd_greed(m, privmsg, GameKind::Duel, None, stake),
+            Some(GreedCommandType::GreedChallenge(nick, stake)) =>
+                self.dispatch_cmd_greed(m, privmsg, GameKind::Duel, Some(nick), stake),
+            Some(GreedCommandType::GreedRound(stake)) =>
+                self.dispatch_cmd_greed(m, privmsg, GameKind::Round, None, stake),
+            Some(GreedCommandType::GreedStats) => self.dispatch_cmd_greed_stats(m, privmsg),
+            Some(GreedCommandType::GreedTop(scope, count)) =>
+                self.dispatch_cmd_greed_top(m, privmsg, scope, count),
+            Some(GreedCommandType::GreedHistory(ref nick)) => self.dispatch_cmd_greed_history(m, nick),
+            Some(GreedCommandType::GreedVs(ref other, ref nick)) => {
+                let first = match *other {
+                    Some(ref other) => self.player_key(m, other),
+                    None => PlayerKey::new(privmsg.source_nick(), m.source_account()),
+                };
+                self.dispatch_cmd_greed_vs(m, &first, nick)
+            },
+            None => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use command_mapper::{Ledger, LedgerError, Storage, MINT};
+    use super::{GreedPlugin, GameRecord, PlayerKey, UserStats, GLOBAL_SCOPE, GREED_POT, HELD_STAKES};
+
+    fn plugin() -> GreedPlugin {
+        let storage = Storage::in_memory();
+        let mut plugin = GreedPlugin::new();
+        plugin.ledger = Some(Ledger::new(storage.namespace("*ledger")));
+        plugin.storage = Some(storage.namespace("greed"));
+        plugin
+    }
+
+    #[test]
+    fn test_stakes() {
+        let plugin = plugin();
+        let ledger = plugin.ledger.clone().unwrap();
+        ledger.transfer(MINT, "alice", 100, "daily").unwrap();
+        ledger.transfer(MINT, "bob", 100, "daily").unwrap();
+        ledger.transfer(MINT, "carol", 20, "daily").unwrap();
+
+        assert_eq!(plugin.take_stake(1, "alice", 30, "#x"), Ok(()));
+        assert_eq!(plugin.take_stake(1, "bob", 30, "#x"), Ok(()));
+        assert_eq!(plugin.take_stake(1, "carol", 30, "#x"),
+            Err(LedgerError::InsufficientFunds { account: "carol".to_string(), balance: 20 }));
+        assert_eq!(plugin.take_stake(2, "alice", 5, "#y"), Ok(()));
+        assert_eq!(ledger.balance("alice"), 65);
+        assert_eq!(ledger.balance("carol"), 20);
+        assert_eq!(ledger.balance(GREED_POT), 65);
+        assert_eq!(ledger.note::<Vec<(u64, String, u64)>>(HELD_STAKES).unwrap().len(), 3);
+
+        // paying out settles that game's stakes, and only those
+        plugin.pay_out(1, &["bob"], 60, "greed winnings");
+        assert_eq!(ledger.balance("bob"), 130);
+        assert_eq!(ledger.balance(GREED_POT), 5);
+        assert_eq!(ledger.note::<Vec<(u64, String, u64)>>(HELD_STAKES),
+            Some(vec![(2, "alice".to_string(), 5)]));
+    }
+
+    #[test]
+    fn test_tied_pot() {
+        let plugin = plugin();
+        let ledger = plugin.ledger.clone().unwrap();
+        for nick in ["alice", "bob", "carol"].iter() {
+            ledger.transfer(MINT, nick, 10, "daily").unwrap();
+        }
+        ledger.transaction(|tx| {
+            try!(tx.transfer("alice", GREED_POT, 9, "greed stake in #x"));
+            try!(tx.transfer("bob", GREED_POT, 9, "greed stake in #x"));
+            tx.transfer("carol", GREED_POT, 9, "greed stake in #x")
+        }).unwrap();
+
+        // the remainder goes to whoever rolled first
+        plugin.pay_out(1, &["carol", "alice"], 27, "greed winnings");
+        assert_eq!(ledger.balance("carol"), 15);
+        assert_eq!(ledger.balance("alice"), 14);
+        assert_eq!(ledger.balance("bob"), 1);
+        assert_eq!(ledger.balance(GREED_POT), 0);
+
+        // more than the pot holds pays nobody
+        plugin.pay_out(1, &["carol", "alice"], 2, "greed winnings");
+        assert_eq!(ledger.balance("carol"), 15);
+        assert_eq!(ledger.balance("alice"), 14);
+    }
+
+    #[test]
+    fn test_return_held_stakes() {
+        let plugin = plugin();
+        let ledger = plugin.ledger.clone().unwrap();
+        ledger.transfer(MINT, "alice", 50, "daily").unwrap();
+        ledger.transfer(MINT, "bob", 50, "daily").unwrap();
+        plugin.take_stake(1, "alice", 20, "#x").unwrap();
+        plugin.take_stake(1, "bob", 20, "#x").unwrap();
+
+        // as after a restart with the game still open
+        plugin.return_held_stakes();
+        assert_eq!(ledger.balance("alice"), 50);
+        assert_eq!(ledger.balance("bob"), 50);
+        assert_eq!(ledger.balance(GREED_POT), 0);
+        assert_eq!(ledger.note::<Vec<(u64, String, u64)>>(HELD_STAKES), Some(vec![]));
+
+        // returned only once
+        plugin.return_held_stakes();
+        assert_eq!(ledger.balance("alice"), 50);
+    }
+
+    #[test]
+    fn test_record_game() {
+        let mut plugin = plugin();
+        let alice = PlayerKey::new("Alice", Some("alice"));
+        let bob = PlayerKey::new("Bob", None);
+        plugin.record_game("#x", &alice, 500, &bob, 200);
+
+        // the same account under another nick keeps the same record
+        let alice = PlayerKey::new("alice_away", Some("ALICE"));
+        plugin.record_game("#y", &alice, 100, &bob, 400);
+
+        let stats = plugin.get_userstats(GLOBAL_SCOPE, "@alice").unwrap();
+        assert_eq!((stats.games, stats.wins, stats.points()), (2, 1, 0));
+        assert_eq!(stats.nick, Some("alice_away".to_string()));
+        let stats = plugin.get_userstats("#x", "bob").unwrap();
+        assert_eq!((stats.games, stats.wins, stats.points()), (1, 0, -300));
+        assert!(plugin.get_userstats("#x", "alice").is_none());
+
+        let storage = plugin.storage.clone().unwrap();
+        let history: Vec<GameRecord> = storage.get("history:bob").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].opponent, "Alice");
+        assert_eq!(history[1].opponent, "alice_away");
+        assert_eq!((history[1].score, history[1].opponent_score), (400, 100));
+    }
+
+    #[test]
+    fn test_leaderboard() {
+        let mut plugin = plugin();
+        let storage = plugin.storage.clone().unwrap();
+        let players = [("carol", 1, 50), ("alice", 3, -10), ("bob", 3, 20)];
+        for &(nick, wins, points) in players.iter() {
+            storage.set(&format!("stats:#x:{}", nick), &UserStats {
+                nick: Some(nick.to_string()),
+                games: 5,
+                wins: wins,
+                score_sum: points,
+                opponent_score_sum: 0,
+            });
+        }
+        // by wins, then points
+        assert_eq!(plugin.leaderboard("#x", 10), vec![
+            "1. bob (3 wins, 20 points)".to_string(),
+            "2. alice (3 wins, -10 points)".to_string(),
+            "3. carol (1 wins, 50 points)".to_string(),
+        ]);
+        assert_eq!(plugin.leaderboard("#x", 1).len(), 1);
+        assert!(plugin.leaderboard("#y", 10).is_empty());
+
+        // the nick shown is the one last played under, not the key
+        plugin.record_game("#z", &PlayerKey::new("Dave", Some("dave")), 300,
+            &PlayerKey::new("Erin", None), 100);
+        assert_eq!(plugin.leaderboard("#z", 10), vec![
+            "1. Dave (1 wins, 200 points)".to_string(),
+            "2. Erin (0 wins, -200 points)".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn test_head_to_head() {
+        let mut plugin = plugin();
+        let zed = PlayerKey::new("Zed", None);
+        let amy = PlayerKey::new("Amy", Some("amy"));
+        // zed sorts after @amy, so the record is kept from amy's side
+        plugin.record_game("#x", &zed, 500, &amy, 100);
+        plugin.record_game("#x", &zed, 500, &amy, 100);
+        plugin.record_game("#x", &amy, 500, &zed, 100);
+        plugin.record_game("#x", &amy, 300, &zed, 300);
+
+        let h2h = plugin.get_head_to_head("zed", "@amy");
+        assert_eq!((h2h.wins, h2h.losses, h2h.ties), (2, 1, 1));
+        let h2h = plugin.get_head_to_head("@amy", "zed");
+        assert_eq!((h2h.wins, h2h.losses, h2h.ties), (1, 2, 1));
+        let h2h = plugin.get_head_to_head("zed", "amy");
+        assert_eq!((h2h.wins, h2h.losses, h2h.ties), (0, 0, 0));
+    }
+}
@@ -0,0 +1,384 @@
+use std::cmp::Ordering;
+
+use ::rand::Rng;
+use ::rand::distributions::{Sample, Range};
+
+static SCORING_TABLE: [(&'static [u8], i32); 28] = [
+    (&[1, 2, 3, 4, 5, 6], 1200),
+    (&[2, 2, 3, 3, 4, 4],  800),
+    (&[1, 1, 1, 1, 1, 1], 8000),
+    (&[1, 1, 1, 1, 1],    4000),
+    (&[1, 1, 1, 1],       2000),
+    (&[1, 1, 1],          1000),
+    (&[1],                 100),
+    (&[2, 2, 2, 2, 2, 2], 1600),
+    (&[2, 2, 2, 2, 2],     800),
+    (&[2, 2, 2, 2],        400),
+    (&[2, 2, 2],           200),
+    (&[3, 3, 3, 3, 3, 3], 2400),
+    (&[3, 3, 3, 3, 3],    1200),
+    (&[3, 3, 3, 3],        600),
+    (&[3, 3, 3],           300),
+    (&[4, 4, 4, 4, 4, 4], 3200),
+    (&[4, 4, 4, 4, 4],    1600),
+    (&[4, 4, 4, 4],        800),
+    (&[4, 4, 4],           400),
+    (&[5, 5, 5, 5, 5, 5], 4000),
+    (&[5, 5, 5, 5, 5],    2000),
+    (&[5, 5, 5, 5],       1000),
+    (&[5, 5, 5],           500),
+    (&[5],                  50),
+    (&[6, 6, 6, 6, 6, 6], 4800),
+    (&[6, 6, 6, 6, 6],    2400),
+    (&[6, 6, 6, 6],       1200),
+    (&[6, 6, 6],           600),
+];
+
+static MAX_DICE: usize = 12;
+
+/// Dice values which score together, such as three 1s.
+#[derive(RustcDecodable, Clone, Debug, PartialEq, Eq)]
+pub struct ScoreRec {
+    pub dice: Vec<u8>,
+    pub points: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    /// One throw of all the dice
+    Classic,
+    /// Dice which did not score are re-rolled, up to `rerolls` times.  A
+    /// re-roll which scores nothing loses the whole turn.
+    Farkle,
+}
+
+/// `[plugins.greed]` in the configuration file.  Every key is optional;
+/// `variant` picks the defaults which the others override.
+#[derive(RustcDecodable, Default)]
+pub struct GreedConfig {
+    pub variant: Option<String>,
+    pub dice: Option<usize>,
+    pub rerolls: Option<u32>,
+    pub scoring: Option<Vec<ScoreRec>>,
+    pub three_pair_bonus: Option<i32>,
+    pub min_score: Option<i32>,
+    pub win_margin: Option<i32>,
+    pub open_secs: Option<i64>,
+    pub round_secs: Option<i64>,
+}
+
+pub struct Rules {
+    pub variant: Variant,
+    pub dice: usize,
+    pub rerolls: u32,
+    // tried in order at each position of the sorted roll
+    pub scoring: Vec<ScoreRec>,
+    // for any three pairs, instead of what they would otherwise score
+    pub three_pair_bonus: Option<i32>,
+    // turns scoring less than this score nothing
+    pub min_score: i32,
+    // a win by fewer points than this is a tie
+    pub win_margin: i32,
+    // an open game nobody takes up is cancelled after this long
+    pub open_secs: i64,
+    // how long a round stays open for players to join
+    pub round_secs: i64,
+}
+
+impl Rules {
+    pub fn classic() -> Rules {
+        Rules {
+            variant: Variant::Classic,
+            dice: 6,
+            rerolls: 0,
+            scoring: SCORING_TABLE.iter()
+                .map(|&(dice, points)| ScoreRec { dice: dice.to_vec(), points: points })
+                .collect(),
+            three_pair_bonus: None,
+            min_score: 0,
+            win_margin: 0,
+            open_secs: 300,
+            round_secs: 60,
+        }
+    }
+
+    pub fn farkle() -> Rules {
+        Rules {
+            variant: Variant::Farkle,
+            rerolls: 2,
+            three_pair_bonus: Some(1500),
+            .. Rules::classic()
+        }
+    }
+
+    pub fn from_config(config: GreedConfig) -> Result<Rules, String> {
+        let mut rules = match config.variant.as_ref().map(|variant| &variant[..]) {
+            None | Some("classic") => Rules::classic(),
+            Some("farkle") => Rules::farkle(),
+            Some(other) => return Err(format!("unknown variant {:?}", other)),
+        };
+        if let Some(dice) = config.dice {
+            if dice == 0 || MAX_DICE < dice {
+                return Err(format!("dice must be between 1 and {}", MAX_DICE));
+            }
+            rules.dice = dice;
+        }
+        if let Some(rerolls) = config.rerolls {
+            rules.rerolls = rerolls;
+        }
+        if let Some(mut scoring) = config.scoring {
+            for rec in scoring.iter_mut() {
+                if rec.dice.is_empty() || rec.dice.iter().any(|&val| val < 1 || 6 < val) {
+                    return Err(format!("bad scoring dice {:?}", rec.dice));
+                }
+                rec.dice.sort();
+            }
+            rules.scoring = scoring;
+        }
+        if config.three_pair_bonus.is_some() {
+            rules.three_pair_bonus = config.three_pair_bonus;
+        }
+        if let Some(min_score) = config.min_score {
+            rules.min_score = min_score;
+        }
+        if let Some(win_margin) = config.win_margin {
+            rules.win_margin = win_margin;
+        }
+        if let Some(open_secs) = config.open_secs {
+            if open_secs <= 0 {
+                return Err("open_secs must be positive".to_string());
+            }
+            rules.open_secs = open_secs;
+        }
+        if let Some(round_secs) = config.round_secs {
+            if round_secs <= 0 {
+                return Err("round_secs must be positive".to_string());
+            }
+            rules.round_secs = round_secs;
+        }
+        Ok(rules)
+    }
+
+    /// How a turn scoring `score` fares against one scoring `other`.
+    pub fn compare(&self, score: i32, other: i32) -> Ordering {
+        if (score - other).abs() < self.win_margin {
+            return Ordering::Equal;
+        }
+        score.cmp(&other)
+    }
+}
+
+/// The dice from one throw, sorted.
+#[derive(Debug)]
+pub struct RollResult(Vec<u8>);
+
+fn format_dice(dice: &[u8]) -> String {
+    let dice: Vec<String> = dice.iter().map(|val| val.to_string()).collect();
+    dice.join(", ")
+}
+
+impl RollResult {
+    pub fn new(mut dice: Vec<u8>) -> RollResult {
+        dice.sort();
+        RollResult(dice)
+    }
+
+    pub fn roll<R: Rng>(rng: &mut R, count: usize) -> RollResult {
+        let mut between = Range::new(1u8, 7u8);
+        RollResult::new((0..count).map(|_| between.sample(rng)).collect())
+    }
+
+    fn is_three_pairs(&self) -> bool {
+        let RollResult(ref roll) = *self;
+        roll.len() == 6
+            && roll[0] == roll[1] && roll[2] == roll[3] && roll[4] == roll[5]
+            && roll[1] != roll[2] && roll[3] != roll[4]
+    }
+
+    pub fn get_scores(&self, rules: &Rules) -> Vec<ScoreRec> {
+        let RollResult(ref roll) = *self;
+        if let Some(bonus) = rules.three_pair_bonus {
+            if self.is_three_pairs() {
+                return vec![ScoreRec { dice: roll.clone(), points: bonus }];
+            }
+        }
+
+        let mut idx = 0;
+        let mut score_comps = Vec::new();
+        while idx < roll.len() {
+            let mut idx_incr = 1;
+            for score_rec in rules.scoring.iter() {
+                if roll[idx..].starts_with(&score_rec.dice) {
+                    idx_incr = score_rec.dice.len();
+                    score_comps.push(score_rec.clone());
+                    break;
+                }
+            }
+            idx += idx_incr;
+        }
+        score_comps
+    }
+
+    pub fn total_score(&self, rules: &Rules) -> i32 {
+        self.get_scores(rules).iter().map(|rec| rec.points).fold(0, |acc, points| acc + points)
+    }
+
+    /// How many of the dice scored.
+    fn scoring_dice(&self, rules: &Rules) -> usize {
+        self.get_scores(rules).iter().map(|rec| rec.dice.len()).fold(0, |acc, len| acc + len)
+    }
+
+    fn format(&self, rules: &Rules) -> String {
+        let RollResult(ref roll) = *self;
+        let scores: Vec<String> = self.get_scores(rules).iter()
+            .map(|rec| format!("[{} => {}]", format_dice(&rec.dice), rec.points))
+            .collect();
+        format!("[{}] => [{}]", format_dice(roll), scores.join(", "))
+    }
+}
+
+/// One player's go: a throw, and under `Variant::Farkle` any re-rolls.
+pub struct Turn {
+    throws: Vec<RollResult>,
+    pub score: i32,
+    bust: bool,
+}
+
+impl Turn {
+    /// Plays a turn, getting each throw of `n` dice from `throw(n)`.
+    pub fn play<F>(rules: &Rules, mut throw: F) -> Turn where F: FnMut(usize) -> RollResult {
+        let first = throw(rules.dice);
+        let mut score = first.total_score(rules);
+        let mut remaining = rules.dice - first.scoring_dice(rules);
+        let mut throws = vec![first];
+        let mut bust = false;
+
+        if rules.variant == Variant::Farkle && 0 < score {
+            for _ in 0..rules.rerolls {
+                if remaining == 0 {
+                    break;
+                }
+                let again = throw(remaining);
+                let points = again.total_score(rules);
+                remaining -= again.scoring_dice(rules);
+                throws.push(again);
+                if points == 0 {
+                    bust = true;
+                    score = 0;
+                    break;
+                }
+                score += points;
+            }
+        }
+        if score < rules.min_score {
+            score = 0;
+        }
+        Turn { throws: throws, score: score, bust: bust }
+    }
+
+    pub fn format(&self, rules: &Rules) -> String {
+        let throws: Vec<String> = self.throws.iter().map(|throw| throw.format(rules)).collect();
+        let mut out = throws.join(" then re-rolled ");
+        if self.bust {
+            out.push_str(", bust!");
+        } else if self.score == 0 && 0 < rules.min_score {
+            out.push_str(&format!(", under the minimum of {}", rules.min_score));
+        }
+        out.push_str(&format!(" for {} points", self.score));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::{Rules, GreedConfig, RollResult, ScoreRec, Turn};
+
+    fn rec(dice: &[u8], points: i32) -> ScoreRec {
+        ScoreRec { dice: dice.to_vec(), points: points }
+    }
+
+    #[test]
+    fn test_classic_scores() {
+        let rules = Rules::classic();
+        let roll = RollResult::new(vec![5, 1, 3, 1, 2, 1]);
+        assert_eq!(roll.get_scores(&rules), vec![rec(&[1, 1, 1], 1000), rec(&[5], 50)]);
+        assert_eq!(roll.total_score(&rules), 1050);
+
+        let straight = RollResult::new(vec![6, 5, 4, 3, 2, 1]);
+        assert_eq!(straight.get_scores(&rules), vec![rec(&[1, 2, 3, 4, 5, 6], 1200)]);
+
+        let pairs = RollResult::new(vec![2, 2, 3, 3, 4, 4]);
+        assert_eq!(pairs.total_score(&rules), 800);
+        let other_pairs = RollResult::new(vec![2, 2, 4, 4, 6, 6]);
+        assert_eq!(other_pairs.get_scores(&rules), vec![]);
+    }
+
+    #[test]
+    fn test_three_pair_bonus() {
+        let rules = Rules::farkle();
+        let other_pairs = RollResult::new(vec![6, 2, 4, 4, 6, 2]);
+        assert_eq!(other_pairs.get_scores(&rules), vec![rec(&[2, 2, 4, 4, 6, 6], 1500)]);
+        let pairs = RollResult::new(vec![2, 2, 3, 3, 4, 4]);
+        assert_eq!(pairs.total_score(&rules), 1500);
+        // four of a kind is not two pairs
+        let four = RollResult::new(vec![2, 2, 2, 2, 6, 6]);
+        assert_eq!(four.get_scores(&rules), vec![rec(&[2, 2, 2, 2], 400)]);
+    }
+
+    #[test]
+    fn test_configured_scores() {
+        let rules = Rules::from_config(GreedConfig {
+            dice: Some(3),
+            scoring: Some(vec![rec(&[6, 6, 6], 1000), rec(&[6], 60)]),
+            min_score: Some(100),
+            win_margin: Some(50),
+            .. GreedConfig::default()
+        }).unwrap();
+        assert_eq!(rules.dice, 3);
+        assert_eq!(RollResult::new(vec![6, 1, 6]).total_score(&rules), 120);
+        assert_eq!(RollResult::new(vec![6, 6, 6]).total_score(&rules), 1000);
+        assert_eq!(RollResult::new(vec![1, 1, 1]).total_score(&rules), 0);
+
+        let low = Turn::play(&rules, |_| RollResult::new(vec![6, 1, 2]));
+        assert_eq!(low.score, 0);
+        assert_eq!(rules.compare(1000, 960), Ordering::Equal);
+        assert_eq!(rules.compare(1000, 950), Ordering::Greater);
+
+        assert!(Rules::from_config(GreedConfig {
+            variant: Some("poker".to_string()), .. GreedConfig::default()
+        }).is_err());
+        assert!(Rules::from_config(GreedConfig { dice: Some(0), .. GreedConfig::default() }).is_err());
+        assert!(Rules::from_config(GreedConfig {
+            scoring: Some(vec![rec(&[7], 10)]), .. GreedConfig::default()
+        }).is_err());
+    }
+
+    #[test]
+    fn test_farkle_rerolls() {
+        let mut rules = Rules::farkle();
+        let throws = || {
+            let mut throws = vec![
+                vec![1, 2, 3, 4, 6, 6],
+                vec![5, 2, 3, 4, 4],
+                vec![2, 3, 4, 6],
+            ].into_iter();
+            move |count: usize| {
+                let throw = throws.next().unwrap();
+                assert_eq!(throw.len(), count);
+                RollResult::new(throw)
+            }
+        };
+
+        let turn = Turn::play(&rules, throws());
+        assert_eq!((turn.score, turn.bust, turn.throws.len()), (0, true, 3));
+
+        rules.rerolls = 1;
+        let turn = Turn::play(&rules, throws());
+        assert_eq!((turn.score, turn.bust, turn.throws.len()), (150, false, 2));
+
+        let classic = Turn::play(&Rules::classic(), throws());
+        assert_eq!((classic.score, classic.throws.len()), (100, 1));
+    }
+}
@@ -38,11 +38,19 @@ fn parse_command<'a>(m: &CommandMapperDispatch) -> Result<u8, ()> {
 
 
 impl RustBotPlugin for IrcColorsPlugin {
+    fn name(&self) -> &str {
+        IrcColorsPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_IRC_COLORS, Format::from_str("irc-colors").ok().unwrap());
-        conf.map_format(CMD_IRC_COLORS, Format::from_str("irc-colours").ok().unwrap());
-        conf.map_format(CMD_IRC_COLORS_MORE, Format::from_str("irc-colors more").ok().unwrap());
-        conf.map_format(CMD_IRC_COLORS_MORE, Format::from_str("irc-colours more").ok().unwrap());
+        conf.map_format(CMD_IRC_COLORS, Format::from_str("irc-colors").ok().unwrap(),
+            "Show the 16 standard IRC colours");
+        conf.map_format(CMD_IRC_COLORS, Format::from_str("irc-colours").ok().unwrap(),
+            "Show the 16 standard IRC colours");
+        conf.map_format(CMD_IRC_COLORS_MORE, Format::from_str("irc-colors more").ok().unwrap(),
+            "Show all 100 extended IRC colours");
+        conf.map_format(CMD_IRC_COLORS_MORE, Format::from_str("irc-colours more").ok().unwrap(),
+            "Show all 100 extended IRC colours");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _: &IrcMsg) {
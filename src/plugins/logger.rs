@@ -1,36 +1,71 @@
 use std::io::{self, Write};
 use std::fs::File;
-use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::time::Instant;
 
 use time::{get_time, now_utc};
 use irc::{IrcMsg, IrcMsgBuf};
 
-use command_mapper::{Replier, RustBotPlugin};
+use command_mapper::{
+    Replier,
+    RustBotPlugin,
+    Worker,
+    WorkerPool,
+    WorkerConfig,
+};
 
-fn logger_loop(rx: Receiver<IrcMsgBuf>) -> Result<(), io::Error> {
-    let logfile = format!("logs/{}.irclog", now_utc().rfc3339());
-    let mut log = try!(File::create(&logfile));
-    for msg in rx.iter() {
-        let timespec = get_time();
-        let mut ms_time = 0;
-        ms_time += 1000 * timespec.sec as u64;
-        ms_time += timespec.nsec as u64 / 1_000_000;
-        let timefmt = format!("{} ", ms_time);
-        try!(log.write_all(timefmt.as_bytes()));
-        try!(log.write_all(msg.as_bytes()));
-        try!(log.write_all(b"\r\n"));
+struct LogWriter {
+    log: Option<File>,
+}
+
+impl LogWriter {
+    fn new() -> LogWriter {
+        let logfile = format!("logs/{}.irclog", now_utc().rfc3339());
+        let log = match File::create(&logfile) {
+            Ok(log) => Some(log),
+            Err(err) => {
+                info!("Error opening {}: {:?}", logfile, err);
+                None
+            }
+        };
+        LogWriter { log: log }
     }
+}
+
+fn write_line(log: &mut File, msg: &IrcMsgBuf) -> Result<(), io::Error> {
+    let timespec = get_time();
+    let mut ms_time = 0;
+    ms_time += 1000 * timespec.sec as u64;
+    ms_time += timespec.nsec as u64 / 1_000_000;
+    let timefmt = format!("{} ", ms_time);
+    try!(log.write_all(timefmt.as_bytes()));
+    try!(log.write_all(msg.as_bytes()));
+    try!(log.write_all(b"\r\n"));
     Ok(())
 }
 
+impl Worker<IrcMsgBuf> for LogWriter {
+    fn handle(&mut self, msg: IrcMsgBuf) {
+        let mut failed = false;
+        if let Some(ref mut log) = self.log {
+            if let Err(err) = write_line(log, &msg) {
+                info!("Error in logger: {:?}", err);
+                failed = true;
+            }
+        }
+        if failed {
+            self.log = None;
+        }
+    }
+}
+
 
 pub struct LoggerPlugin {
-    sender: Option<SyncSender<IrcMsgBuf>>
+    pool: Option<WorkerPool<IrcMsgBuf>>
 }
 
 impl LoggerPlugin {
     pub fn new() -> LoggerPlugin {
-        LoggerPlugin { sender: None }
+        LoggerPlugin { pool: None }
     }
 
     pub fn get_plugin_name() -> &'static str {
@@ -39,26 +74,31 @@ impl LoggerPlugin {
 }
 
 impl RustBotPlugin for LoggerPlugin {
+    fn name(&self) -> &str {
+        LoggerPlugin::get_plugin_name()
+    }
+
     fn start(&mut self) {
-        let (tx, rx) = sync_channel(10);
-        let _ = ::std::thread::Builder::new().name("plugin-logger".to_string()).spawn(move || {
-            if let Err(err) = logger_loop(rx) {
-                info!("Error in logger loop: {:?}", err);
-            }
-        });
-        self.sender = Some(tx);
+        // one writer, so lines stay in order
+        self.pool = Some(WorkerPool::start("logger", WorkerConfig::new(), LogWriter::new));
     }
 
     fn on_message(&mut self, _: &mut Replier, msg: &IrcMsg) {
         let mut disable_self = false;
-        if let Some(ref sender) = self.sender {
-            if let Err(err) = sender.send(msg.to_owned()) {
+        if let Some(ref pool) = self.pool {
+            if let Err(err) = pool.submit_wait(msg.to_owned()) {
                 info!("Logger service gone: {:?}", err);
                 disable_self = true;
             }
         }
         if disable_self {
-            self.sender = None;
+            self.pool = None;
+        }
+    }
+
+    fn stop(&mut self, deadline: Instant) {
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown(deadline);
         }
     }
 }
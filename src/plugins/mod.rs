@@ -13,6 +13,10 @@ pub use self::unicode_names::UnicodeNamePlugin;
 pub use self::eightball::EightBallPlugin;
 pub use self::pick::PickPlugin;
 pub use self::irc_colors::IrcColorsPlugin;
+pub use self::tell::TellPlugin;
+pub use self::remind::RemindPlugin;
+pub use self::economy::EconomyPlugin;
+pub use self::dice::DicePlugin;
 
 mod deer;
 mod greed;
@@ -28,4 +32,8 @@ mod animecalendar;
 mod unicode_names;
 mod eightball;
 mod pick;
-mod irc_colors;
\ No newline at end of file
+mod irc_colors;
+mod tell;
+mod remind;
+mod economy;
+mod dice;
\ No newline at end of file
@@ -1,4 +1,6 @@
 use irc::IrcMsg;
+use irc::legacy::User;
+use irc::legacy::MessageEndpoint::KnownChannel;
 use rand::{Rng, thread_rng};
 
 use command_mapper::{
@@ -9,8 +11,12 @@ use command_mapper::{
     Token,
 };
 
-const CMD_PICK_BARE: Token = Token(0);
-const CMD_PICK: Token = Token(1);
+const CMD_PICK: Token = Token(0);
+const CMD_PICK_MANY: Token = Token(1);
+const CMD_SHUFFLE: Token = Token(2);
+const CMD_PICK_NICK: Token = Token(3);
+
+static MAX_WEIGHT: u32 = 1000;
 
 pub struct PickPlugin;
 
@@ -24,42 +30,259 @@ impl PickPlugin {
     }
 }
 
+#[derive(Debug, PartialEq, Eq)]
+struct Choice {
+    text: String,
+    weight: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    NoOptions,
+    UnterminatedQuote,
+    WeightTooLarge,
+    /// Every option has a weight of zero
+    NothingToPick,
+}
+
+fn describe_parse_error(err: &ParseError) -> String {
+    match *err {
+        ParseError::NoOptions => "Pick from what? Separate the options with commas".to_string(),
+        ParseError::UnterminatedQuote => "There's a quote missing".to_string(),
+        ParseError::WeightTooLarge => format!("Weights are limited to {}", MAX_WEIGHT),
+        ParseError::NothingToPick => "Every option has a weight of zero".to_string(),
+    }
+}
+
+/// Takes a weight off the end of `text` if the colon at `colon` starts one.
+fn finish_choice(text: &str, colon: Option<usize>) -> Result<Choice, ParseError> {
+    if let Some(colon) = colon {
+        let weight = text[colon + 1..].trim();
+        if !weight.is_empty() && weight.chars().all(|ch| ch.is_digit(10)) {
+            return match weight.parse() {
+                Ok(weight) if weight <= MAX_WEIGHT => Ok(Choice {
+                    text: text[..colon].trim().to_string(),
+                    weight: weight,
+                }),
+                _ => Err(ParseError::WeightTooLarge),
+            };
+        }
+    }
+    Ok(Choice { text: text.trim().to_string(), weight: 1 })
+}
+
+/// Parses comma-separated options, each optionally followed by `:<weight>`.
+/// Commas and colons lose their meaning in double quotes, in single
+/// quotes around a whole option, or after a backslash; an apostrophe
+/// anywhere else is just an apostrophe.
+fn parse_options(input: &str) -> Result<Vec<Choice>, ParseError> {
+    let mut choices = Vec::new();
+    let mut current = String::new();
+    // where the last colon outside quotes is in `current`
+    let mut colon = None;
+    let mut chars = input.chars();
+    loop {
+        let next = chars.next();
+        match next {
+            None | Some(',') => {
+                let choice = try!(finish_choice(&current, colon));
+                if !choice.text.is_empty() {
+                    choices.push(choice);
+                }
+                if next.is_none() {
+                    break;
+                }
+                current.clear();
+                colon = None;
+            },
+            Some(quote) if quote == '"' || (quote == '\'' && current.trim().is_empty()) => loop {
+                match chars.next() {
+                    None => return Err(ParseError::UnterminatedQuote),
+                    Some(ch) if ch == quote => break,
+                    Some('\\') if quote == '"' => match chars.next() {
+                        Some(ch) => current.push(ch),
+                        None => return Err(ParseError::UnterminatedQuote),
+                    },
+                    Some(ch) => current.push(ch),
+                }
+            },
+            Some('\\') => current.push(chars.next().unwrap_or('\\')),
+            Some(':') => {
+                colon = Some(current.len());
+                current.push(':');
+            },
+            Some(ch) => current.push(ch),
+        }
+    }
+    if choices.is_empty() {
+        return Err(ParseError::NoOptions);
+    }
+    if choices.iter().all(|choice| choice.weight == 0) {
+        return Err(ParseError::NothingToPick);
+    }
+    Ok(choices)
+}
+
+/// Picks up to `count` choices by weight, without replacement.
+fn pick_weighted<R: Rng>(rng: &mut R, mut choices: Vec<Choice>, count: usize) -> Vec<String> {
+    choices.retain(|choice| choice.weight > 0);
+    let mut picked = Vec::new();
+    while picked.len() < count && !choices.is_empty() {
+        let total = choices.iter().fold(0, |sum, choice| sum + choice.weight as u64);
+        let mut target = rng.gen_range(0, total);
+        let mut idx = 0;
+        while choices[idx].weight as u64 <= target {
+            target -= choices[idx].weight as u64;
+            idx += 1;
+        }
+        picked.push(choices.remove(idx).text);
+    }
+    picked
+}
+
 enum PickCommandType {
     PickBare,
-    Pick(String),
+    Pick(usize, String),
+    Shuffle(String),
+    PickNick,
 }
 
 fn parse_command<'a>(m: &CommandMapperDispatch) -> Option<PickCommandType> {
     let command_phrase = m.command();
     match command_phrase.token {
-        CMD_PICK_BARE => Some(PickCommandType::PickBare),
         CMD_PICK => {
             match command_phrase.get::<String>(&"rest") {
-                Some(rest) => Some(PickCommandType::Pick(rest)),
-                None => None
+                Some(rest) => Some(PickCommandType::Pick(1, rest)),
+                None => Some(PickCommandType::PickBare)
             }
         },
+        CMD_PICK_MANY => match (command_phrase.get::<u64>("count"), command_phrase.get("rest")) {
+            (Some(count), Some(rest)) => Some(PickCommandType::Pick(count as usize, rest)),
+            _ => None,
+        },
+        CMD_SHUFFLE => command_phrase.get("rest").map(PickCommandType::Shuffle),
+        CMD_PICK_NICK => Some(PickCommandType::PickNick),
         _ => None
     }
 }
 
+impl PickPlugin {
+    fn dispatch_cmd_pick(&mut self, m: &CommandMapperDispatch, count: usize, options: &str) {
+        if count == 0 {
+            m.reply("Picked nothing");
+            return;
+        }
+        match parse_options(options) {
+            Ok(choices) => m.reply(&pick_weighted(&mut thread_rng(), choices, count).join(", ")),
+            Err(err) => m.reply(&describe_parse_error(&err)),
+        }
+    }
+
+    fn dispatch_cmd_shuffle(&mut self, m: &CommandMapperDispatch, options: &str) {
+        let choices = match parse_options(options) {
+            Ok(choices) => choices,
+            Err(err) => {
+                m.reply(&describe_parse_error(&err));
+                return;
+            }
+        };
+        // weights don't matter when everything is kept
+        let mut texts: Vec<String> = choices.into_iter().map(|choice| choice.text).collect();
+        thread_rng().shuffle(&mut texts);
+        m.reply(&texts.join(", "));
+    }
+
+    fn dispatch_cmd_pick_nick(&mut self, m: &CommandMapperDispatch) {
+        let channel_id = match m.target {
+            KnownChannel(ref channel_id) => channel_id.clone(),
+            _ => {
+                m.reply("I can only pick nicks in a channel");
+                return;
+            }
+        };
+        let state = m.get_state();
+        let self_nick = m.current_nick();
+        let nicks: Vec<String> = match state.resolve_channel(channel_id) {
+            Some(channel) => channel.get_users().iter()
+                .filter_map(|user_id| state.resolve_user(user_id.clone()))
+                .map(|user: &User| user.get_nick().to_string())
+                .filter(|nick| *nick != self_nick)
+                .collect(),
+            None => Vec::new(),
+        };
+        match thread_rng().choose(&nicks) {
+            Some(nick) => m.reply(nick),
+            None => m.reply("There's nobody here but me"),
+        }
+    }
+}
 
 impl RustBotPlugin for PickPlugin {
+    fn name(&self) -> &str {
+        PickPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_PICK_BARE, Format::from_str("pick").ok().unwrap());
-        conf.map_format(CMD_PICK, Format::from_str("pick {*rest}").ok().unwrap());
+        conf.map_format(CMD_PICK, Format::from_str("pick {*rest?}").ok().unwrap(),
+            "Pick one of a comma-separated list of options, such as ``a, \"b, c\", d:3''");
+        conf.map_format(CMD_PICK_MANY, Format::from_str("pick -n {count:d} {*rest}").unwrap(),
+            "Pick several different options from a comma-separated list");
+        conf.map_format(CMD_SHUFFLE, Format::from_str("shuffle {*rest}").unwrap(),
+            "Shuffle a comma-separated list");
+        conf.map_format(CMD_PICK_NICK, Format::from_str("pick nick").unwrap(),
+            "Pick someone in the channel");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _: &IrcMsg) {
         match parse_command(m) {
             Some(PickCommandType::PickBare) => m.reply("pock"),
-            Some(PickCommandType::Pick(options)) => {
-                let mut rng = thread_rng();
-                let options: Vec<&str> = options.split(",").map(|x| x.trim()).collect();
-                let picked = rng.choose(&options).unwrap();
-                m.reply(picked);
-            },
+            Some(PickCommandType::Pick(count, ref options)) => self.dispatch_cmd_pick(m, count, options),
+            Some(PickCommandType::Shuffle(ref options)) => self.dispatch_cmd_shuffle(m, options),
+            Some(PickCommandType::PickNick) => self.dispatch_cmd_pick_nick(m),
             None => return
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::XorShiftRng;
+
+    use super::{parse_options, pick_weighted, Choice, ParseError};
+
+    fn choice(text: &str, weight: u32) -> Choice {
+        Choice { text: text.to_string(), weight: weight }
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(parse_options("a, b ,c"), Ok(vec![choice("a", 1), choice("b", 1), choice("c", 1)]));
+        assert_eq!(parse_options("\"tea, milk\", 'coffee: black', rum\\, neat"),
+            Ok(vec![choice("tea, milk", 1), choice("coffee: black", 1), choice("rum, neat", 1)]));
+        assert_eq!(parse_options("a:3, b : 1, c:0, d:e"),
+            Ok(vec![choice("a", 3), choice("b", 1), choice("c", 0), choice("d:e", 1)]));
+        assert_eq!(parse_options("\"10:30\", 11\\:00"),
+            Ok(vec![choice("10:30", 1), choice("11:00", 1)]));
+        assert_eq!(parse_options(" , ,"), Err(ParseError::NoOptions));
+        assert_eq!(parse_options(""), Err(ParseError::NoOptions));
+        assert_eq!(parse_options("a, \"b"), Err(ParseError::UnterminatedQuote));
+        assert_eq!(parse_options("don't, do"), Ok(vec![choice("don't", 1), choice("do", 1)]));
+        assert_eq!(parse_options("I'll go, I won't"), Ok(vec![choice("I'll go", 1), choice("I won't", 1)]));
+        assert_eq!(parse_options("a, 'b"), Err(ParseError::UnterminatedQuote));
+        assert_eq!(parse_options("a:5000"), Err(ParseError::WeightTooLarge));
+        assert_eq!(parse_options("a:0, b:0"), Err(ParseError::NothingToPick));
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let mut rng = XorShiftRng::new_unseeded();
+        for _ in 0..100 {
+            let choices = vec![choice("a", 3), choice("b", 0), choice("c", 1), choice("d", 1)];
+            let mut picked = pick_weighted(&mut rng, choices, 5);
+            picked.sort();
+            assert_eq!(picked, vec!["a", "c", "d"]);
+        }
+        let choices = vec![choice("a", 1), choice("b", 1)];
+        assert_eq!(pick_weighted(&mut rng, choices, 1).len(), 1);
+    }
+}
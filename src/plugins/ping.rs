@@ -35,8 +35,13 @@ fn parse_command<'a>(m: &CommandMapperDispatch) -> Option<PingCommandType> {
 
 
 impl RustBotPlugin for PingPlugin {
+    fn name(&self) -> &str {
+        PingPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_PING, Format::from_str("ping").ok().unwrap());
+        conf.map_format(CMD_PING, Format::from_str("ping").ok().unwrap(),
+            "Check that the bot is responding");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _: &IrcMsg) {
@@ -1,6 +1,6 @@
 use std::io::{self, Read};
 use std::convert::From;
-use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::time::{Duration, Instant};
 
 use hyper;
 use rustc_serialize::json::{self, DecoderError};
@@ -13,6 +13,9 @@ use command_mapper::{
     IrcBotConfigurator,
     Format,
     Token,
+    Worker,
+    WorkerPool,
+    WorkerConfig,
 };
 
 
@@ -102,33 +105,30 @@ impl RadioInternalState {
             }
         }
     }
-    fn start(&mut self, rx: Receiver<EventType>) {
-        for event in rx.iter() {
-            match event {
-                EventType::Dispatch(dispatch) => {
-                    match dispatch.command().token {
-                        CMD_DJ => self.handle_dj(&dispatch),
-                        _ => ()
-                    }
-                }
-            }
+}
+
+impl Worker<CommandMapperDispatch> for RadioInternalState {
+    fn handle(&mut self, m: CommandMapperDispatch) {
+        match m.command().token {
+            CMD_DJ => self.handle_dj(&m),
+            _ => ()
         }
     }
+
+    fn expired(&mut self, m: CommandMapperDispatch) {
+        m.reply("Service ``r-a-dio'' timed out");
+    }
 }
 
 pub struct RadioPlugin {
-    sender: Option<SyncSender<EventType>>,
+    pool: Option<WorkerPool<CommandMapperDispatch>>,
 }
 
-pub enum EventType {
-    Dispatch(CommandMapperDispatch),
-} 
-
 
 impl RadioPlugin {
     pub fn new() -> RadioPlugin {
         RadioPlugin {
-            sender: None
+            pool: None
         }
     }
 
@@ -139,28 +139,29 @@ impl RadioPlugin {
 
 
 impl RustBotPlugin for RadioPlugin {
+    fn name(&self) -> &str {
+        RadioPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_DJ, Format::from_str("dj").unwrap());
+        conf.map_format(CMD_DJ, Format::from_str("dj").unwrap(),
+            "Show the current r/a/dio DJ and song");
     }
 
     fn start(&mut self) {
-        let (tx, rx) = sync_channel(10);
-        let _ = ::std::thread::Builder::new().name("plugin-radio".to_string()).spawn(move || {
-            let mut internal_state = RadioInternalState::new();
-            internal_state.start(rx);
-        });
-
-        self.sender = Some(tx);
+        let config = WorkerConfig::new().workers(2).timeout(Duration::from_secs(30));
+        self.pool = Some(WorkerPool::start("r-a-dio", config, RadioInternalState::new));
     }
-    
+
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _message: &IrcMsg) {
-        match self.sender {
-            Some(ref sender) => {
-                if let Err(err) = sender.send(EventType::Dispatch(m.clone())) {
-                    m.reply(&format!("Service ``r-a-dio'' unavailable: {:?}", err));
-                }
-            },
-            None => ()
-        };
+        if let Some(ref pool) = self.pool {
+            pool.dispatch(m);
+        }
+    }
+
+    fn stop(&mut self, deadline: Instant) {
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown(deadline);
+        }
     }
 }
@@ -0,0 +1,539 @@
+use time::{get_time, Duration, Timespec};
+
+use irc::{IrcMsg, client as cli2, server};
+
+use utils::formatting::duration_to_string;
+use command_mapper::{
+    RustBotPlugin,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Namespace,
+    Token,
+    Replier,
+    Timers,
+    TimerToken,
+};
+
+const CMD_REMIND: Token = Token(0);
+const CMD_REMINDERS: Token = Token(1);
+const CMD_REMINDERS_CANCEL: Token = Token(2);
+const CMD_TIMEZONE: Token = Token(3);
+const CMD_TIMEZONE_SET: Token = Token(4);
+
+static MAX_PENDING_PER_USER: usize = 10;
+
+static MAX_DELAY_DAYS: i64 = 366;
+
+/// Reminders which fire later than this, say while the bot was down,
+/// say how late they are.
+static LATE_AFTER_SECS: i64 = 60;
+
+#[derive(Debug, PartialEq, Eq)]
+enum ParseError {
+    /// Neither `in <duration>` nor `at <time>`
+    MissingWhen,
+    BadDuration,
+    BadTime,
+    BadTimezone,
+    TooFar,
+    MissingMessage,
+}
+
+fn describe_parse_error(err: &ParseError) -> String {
+    match *err {
+        ParseError::MissingWhen => "Say when: ``in 2h30m ...'' or ``at 14:00 ...''".to_string(),
+        ParseError::BadDuration => "Durations look like 2h30m, 90s or 1d".to_string(),
+        ParseError::BadTime => "Times look like 14:00 or 9:30".to_string(),
+        ParseError::BadTimezone => "Timezones look like UTC, UTC+2 or -05:30".to_string(),
+        ParseError::TooFar => format!("Reminders can be at most {} days away", MAX_DELAY_DAYS),
+        ParseError::MissingMessage => "What should I remind about?".to_string(),
+    }
+}
+
+/// The length of one of `word`'s units, if it is one.
+fn unit_seconds(word: &str) -> Option<i64> {
+    match word {
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(60),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(3600),
+        "d" | "day" | "days" => Some(86400),
+        "w" | "week" | "weeks" => Some(7 * 86400),
+        _ => None,
+    }
+}
+
+/// Parses compact durations such as `2h30m`, in seconds.
+fn parse_compact_duration(word: &str) -> Option<i64> {
+    let mut total: i64 = 0;
+    let mut rest = word;
+    while !rest.is_empty() {
+        let digits = rest.find(|ch: char| !ch.is_digit(10)).unwrap_or(rest.len());
+        let units = rest[digits..].find(|ch: char| ch.is_digit(10)).map(|idx| idx + digits)
+            .unwrap_or(rest.len());
+        let number: i64 = match rest[..digits].parse() {
+            Ok(number) => number,
+            Err(_) => return None,
+        };
+        let unit = match unit_seconds(&rest[digits..units]) {
+            Some(unit) => unit,
+            None => return None,
+        };
+        total = total.saturating_add(number.saturating_mul(unit));
+        rest = &rest[units..];
+    }
+    Some(total)
+}
+
+/// Parses a duration from the start of `words`, such as `2h30m` or
+/// `2 hours 30 minutes`.  Returns the seconds and the number of words used.
+fn parse_duration(words: &[&str]) -> Option<(i64, usize)> {
+    let mut total: i64 = 0;
+    let mut used = 0;
+    while used < words.len() {
+        let word = words[used].to_lowercase();
+        let next_unit = words.get(used + 1).and_then(|unit| unit_seconds(&unit.to_lowercase()));
+        // no sign: a negative duration would be in the past
+        let is_number = word.chars().all(|ch| ch.is_digit(10));
+        match (word.parse::<i64>(), next_unit) {
+            (Ok(number), Some(unit)) if is_number => {
+                total = total.saturating_add(number.saturating_mul(unit));
+                used += 2;
+                continue;
+            },
+            _ => (),
+        }
+        match parse_compact_duration(&word) {
+            Some(seconds) if !word.is_empty() => {
+                total = total.saturating_add(seconds);
+                used += 1;
+            },
+            _ => break,
+        }
+    }
+    if used == 0 { None } else { Some((total, used)) }
+}
+
+/// Parses a UTC offset, in minutes: `UTC`, `UTC+2`, `GMT-5:30`, `+0200`.
+fn parse_offset(text: &str) -> Option<i32> {
+    let upper = text.to_uppercase();
+    let rest = if upper.starts_with("UTC") || upper.starts_with("GMT") {
+        &upper[3..]
+    } else if upper == "Z" {
+        ""
+    } else {
+        &upper[..]
+    };
+    if rest.is_empty() {
+        return Some(0);
+    }
+
+    let (sign, rest) = match rest.chars().next() {
+        Some('+') => (1, &rest[1..]),
+        Some('-') => (-1, &rest[1..]),
+        _ => return None,
+    };
+    // only ASCII from here on, so that slicing stays on char boundaries;
+    // a second sign is not an offset either
+    if !rest.chars().all(|ch| ch.is_digit(10) || ch == ':') {
+        return None;
+    }
+    let (hours, minutes) = match rest.find(':') {
+        Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+        None if rest.len() == 4 => (&rest[..2], &rest[2..]),
+        None => (rest, "0"),
+    };
+    match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+        (Ok(hours), Ok(minutes)) if hours <= 14 && minutes < 60 =>
+            Some(sign * (hours * 60 + minutes)),
+        _ => None,
+    }
+}
+
+fn format_offset(offset: i32) -> String {
+    if offset == 0 {
+        return "UTC".to_string();
+    }
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!("UTC{}{:02}:{:02}", sign, offset.abs() / 60, offset.abs() % 60)
+}
+
+/// Parses `HH:MM`, in minutes after midnight.
+fn parse_time_of_day(text: &str) -> Option<i64> {
+    let idx = match text.find(':') {
+        Some(idx) => idx,
+        None => return None,
+    };
+    if !text.chars().all(|ch| ch.is_digit(10) || ch == ':') {
+        return None;
+    }
+    match (text[..idx].parse::<i64>(), text[idx + 1..].parse::<i64>()) {
+        (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 && idx + 3 == text.len() =>
+            Some(hours * 60 + minutes),
+        _ => None,
+    }
+}
+
+/// The next `minute_of_day` in the timezone `offset` minutes from UTC.
+fn next_time_of_day(now: Timespec, minute_of_day: i64, offset: i32) -> Timespec {
+    let local = now.sec + offset as i64 * 60;
+    let midnight = local - ((local % 86400) + 86400) % 86400;
+    let mut at = midnight + minute_of_day * 60;
+    if at <= local {
+        at += 86400;
+    }
+    Timespec::new(at - offset as i64 * 60, 0)
+}
+
+/// Parses `in <duration> [to] <message>` or `at <time> [timezone] [to]
+/// <message>`, where times are in the timezone `offset` unless given.
+fn parse_reminder(spec: &str, now: Timespec, offset: i32) -> Result<(Timespec, String), ParseError> {
+    let words: Vec<&str> = spec.split_whitespace().collect();
+    let (at, used) = match words.first().map(|word| word.to_lowercase()) {
+        Some(ref word) if word == "in" => match parse_duration(&words[1..]) {
+            Some((seconds, _)) if MAX_DELAY_DAYS * 86400 < seconds => return Err(ParseError::TooFar),
+            Some((seconds, used)) => (now + Duration::seconds(seconds), used + 1),
+            None => return Err(ParseError::BadDuration),
+        },
+        Some(ref word) if word == "at" => {
+            let minute_of_day = match words.get(1).and_then(|time| parse_time_of_day(time)) {
+                Some(minute_of_day) => minute_of_day,
+                None => return Err(ParseError::BadTime),
+            };
+            let (offset, used) = match words.get(2) {
+                Some(zone) if zone.chars().any(|ch| ch.is_digit(10)) || zone.len() <= 3 => {
+                    match parse_offset(zone) {
+                        Some(offset) => (offset, 3),
+                        None if zone.starts_with('+') || zone.starts_with('-') => {
+                            return Err(ParseError::BadTimezone);
+                        },
+                        None => (offset, 2),
+                    }
+                },
+                _ => (offset, 2),
+            };
+            (next_time_of_day(now, minute_of_day, offset), used)
+        },
+        _ => return Err(ParseError::MissingWhen),
+    };
+    if Duration::days(MAX_DELAY_DAYS) < at - now {
+        return Err(ParseError::TooFar);
+    }
+
+    let mut message = &words[used..];
+    if message.first().map(|word| word.to_lowercase() == "to").unwrap_or(false) {
+        message = &message[1..];
+    }
+    if message.is_empty() {
+        return Err(ParseError::MissingMessage);
+    }
+    Ok((at, message.join(" ")))
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+struct Reminder {
+    id: u64,
+    creator: String,
+    // a nick or channel
+    target: String,
+    message: String,
+    at: i64,
+}
+
+impl Reminder {
+    fn format(&self, now: Timespec) -> String {
+        let mut out = if self.target.starts_with('#') || self.target == self.creator {
+            format!("{}: reminder: {}", self.creator, self.message)
+        } else {
+            format!("{}: {} asked me to remind you: {}", self.target, self.creator, self.message)
+        };
+        let late = now - Timespec::new(self.at, 0);
+        if Duration::seconds(LATE_AFTER_SECS) < late {
+            out.push_str(&format!(" (late by {})", duration_to_string(late)));
+        }
+        out
+    }
+}
+
+enum RemindCommandType {
+    Remind(String, String),
+    Reminders,
+    RemindersCancel(u64),
+    Timezone,
+    TimezoneSet(String),
+}
+
+fn parse_command(m: &CommandMapperDispatch) -> Option<RemindCommandType> {
+    let command_phrase = m.command();
+    match command_phrase.token {
+        CMD_REMIND => match (command_phrase.get("who"), command_phrase.get("spec")) {
+            (Some(who), Some(spec)) => Some(RemindCommandType::Remind(who, spec)),
+            _ => None,
+        },
+        CMD_REMINDERS => Some(RemindCommandType::Reminders),
+        CMD_REMINDERS_CANCEL => command_phrase.get("id").map(RemindCommandType::RemindersCancel),
+        CMD_TIMEZONE => Some(RemindCommandType::Timezone),
+        CMD_TIMEZONE_SET => command_phrase.get("zone").map(RemindCommandType::TimezoneSet),
+        _ => None,
+    }
+}
+
+pub struct RemindPlugin {
+    storage: Option<Namespace>,
+    timers: Option<Timers>,
+    next_id: u64,
+    pending: Vec<Reminder>,
+}
+
+impl RemindPlugin {
+    pub fn new() -> RemindPlugin {
+        RemindPlugin {
+            storage: None,
+            timers: None,
+            next_id: 1,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn get_plugin_name() -> &'static str {
+        "remind"
+    }
+
+    fn save(&self) {
+        if let Some(ref storage) = self.storage {
+            storage.set("next_id", &self.next_id);
+            storage.set("pending", &self.pending);
+        }
+    }
+
+    fn get_offset(&self, nick: &str) -> i32 {
+        self.storage.as_ref()
+            .and_then(|storage| storage.get(&format!("tz:{}", nick.to_lowercase())))
+            .unwrap_or(0)
+    }
+
+    fn schedule(&self, reminder: &Reminder) {
+        if let Some(ref timers) = self.timers {
+            timers.schedule(Timespec::new(reminder.at, 0), TimerToken(reminder.id));
+        }
+    }
+
+    fn dispatch_cmd_remind(&mut self, m: &CommandMapperDispatch, source_nick: &str,
+                           who: &str, spec: &str) {
+        let target = if who.to_lowercase() == "me" { source_nick } else { who };
+        let pending = self.pending.iter()
+            .filter(|rem| rem.creator.to_lowercase() == source_nick.to_lowercase())
+            .count();
+        if MAX_PENDING_PER_USER <= pending {
+            m.reply(&format!("{}: you already have {} reminders pending; see ``reminders''",
+                source_nick, pending));
+            return;
+        }
+
+        let now = get_time();
+        let (at, message) = match parse_reminder(spec, now, self.get_offset(source_nick)) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                m.reply(&format!("{}: {}", source_nick, describe_parse_error(&err)));
+                return;
+            }
+        };
+
+        let reminder = Reminder {
+            id: self.next_id,
+            creator: source_nick.to_string(),
+            target: target.to_string(),
+            message: message,
+            at: at.sec,
+        };
+        self.next_id += 1;
+        self.schedule(&reminder);
+        m.reply(&format!("{}: I'll remind {} in {} (#{})",
+            source_nick, if target == source_nick { "you" } else { target },
+            duration_to_string(at - now), reminder.id));
+        self.pending.push(reminder);
+        self.save();
+    }
+
+    fn dispatch_cmd_reminders(&mut self, m: &CommandMapperDispatch, source_nick: &str) {
+        let now = get_time();
+        let mut any = false;
+        for reminder in self.pending.iter() {
+            if reminder.creator.to_lowercase() != source_nick.to_lowercase() {
+                continue;
+            }
+            any = true;
+            m.reply(&format!("#{} for {} in {}: {}", reminder.id, reminder.target,
+                duration_to_string(Timespec::new(reminder.at, 0) - now), reminder.message));
+        }
+        if !any {
+            m.reply(&format!("{}: you have no reminders pending", source_nick));
+        }
+    }
+
+    fn dispatch_cmd_reminders_cancel(&mut self, m: &CommandMapperDispatch, source_nick: &str, id: u64) {
+        let position = self.pending.iter().position(|rem| {
+            rem.id == id && rem.creator.to_lowercase() == source_nick.to_lowercase()
+        });
+        match position {
+            Some(position) => {
+                self.pending.remove(position);
+                if let Some(ref timers) = self.timers {
+                    timers.cancel(TimerToken(id));
+                }
+                self.save();
+                m.reply(&format!("{}: cancelled reminder #{}", source_nick, id));
+            },
+            None => m.reply(&format!("{}: you have no reminder #{}", source_nick, id)),
+        }
+    }
+
+    fn dispatch_cmd_timezone(&mut self, m: &CommandMapperDispatch, source_nick: &str,
+                             zone: Option<&str>) {
+        let offset = match zone {
+            Some(zone) => match parse_offset(zone) {
+                Some(offset) => offset,
+                None => {
+                    m.reply(&format!("{}: {}", source_nick, describe_parse_error(&ParseError::BadTimezone)));
+                    return;
+                }
+            },
+            None => {
+                let offset = self.get_offset(source_nick);
+                m.reply(&format!("{}: your timezone is {}", source_nick, format_offset(offset)));
+                return;
+            }
+        };
+        if let Some(ref storage) = self.storage {
+            storage.set(&format!("tz:{}", source_nick.to_lowercase()), &offset);
+        }
+        m.reply(&format!("{}: your timezone is now {}", source_nick, format_offset(offset)));
+    }
+}
+
+impl RustBotPlugin for RemindPlugin {
+    fn name(&self) -> &str {
+        RemindPlugin::get_plugin_name()
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        let storage = conf.storage();
+        self.next_id = storage.get("next_id").unwrap_or(1);
+        self.pending = storage.get("pending").unwrap_or(Vec::new());
+        self.storage = Some(storage);
+        // reminders missed while the bot was down fire on the first tick
+        self.timers = Some(conf.timers());
+        for reminder in self.pending.iter() {
+            self.schedule(reminder);
+        }
+
+        conf.map_format(CMD_REMIND, Format::from_str("remind {who:s} {*spec}").unwrap(),
+            "Set a reminder for yourself (me), a nick or a channel: ``in 2h30m to ...'' or ``at 14:00 UTC ...''");
+        conf.map_format(CMD_REMINDERS, Format::from_str("reminders").unwrap(),
+            "List your pending reminders");
+        conf.map_format(CMD_REMINDERS_CANCEL, Format::from_str("reminders cancel {id:d}").unwrap(),
+            "Cancel one of your reminders");
+        conf.map_format(CMD_TIMEZONE, Format::from_str("timezone").unwrap(),
+            "Show the timezone your reminder times are in");
+        conf.map_format(CMD_TIMEZONE_SET, Format::from_str("timezone {zone:s}").unwrap(),
+            "Set the timezone your reminder times are in, as an offset such as UTC+2");
+    }
+
+    fn on_timer(&mut self, replier: &mut Replier, token: TimerToken) {
+        let TimerToken(id) = token;
+        let position = match self.pending.iter().position(|rem| rem.id == id) {
+            Some(position) => position,
+            None => return,
+        };
+        let reminder = self.pending.remove(position);
+        self.save();
+
+        let body = reminder.format(get_time());
+        let privmsg = cli2::PrivmsgBuf::new(reminder.target.as_bytes(), body.as_bytes()).unwrap();
+        if replier.reply(&privmsg).is_err() {
+            warn!("remind: failed to deliver reminder {} to {}", id, reminder.target);
+        }
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let privmsg;
+        match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(p) => privmsg = p,
+            Err(_) => return,
+        }
+        let source_nick = privmsg.source_nick();
+
+        match parse_command(m) {
+            Some(RemindCommandType::Remind(ref who, ref spec)) =>
+                self.dispatch_cmd_remind(m, source_nick, who, spec),
+            Some(RemindCommandType::Reminders) => self.dispatch_cmd_reminders(m, source_nick),
+            Some(RemindCommandType::RemindersCancel(id)) =>
+                self.dispatch_cmd_reminders_cancel(m, source_nick, id),
+            Some(RemindCommandType::Timezone) => self.dispatch_cmd_timezone(m, source_nick, None),
+            Some(RemindCommandType::TimezoneSet(ref zone)) =>
+                self.dispatch_cmd_timezone(m, source_nick, Some(zone)),
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+
+    use super::{parse_duration, parse_offset, parse_reminder, ParseError};
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration(&["2h30m", "to"]), Some((9000, 1)));
+        assert_eq!(parse_duration(&["2", "hours", "30", "minutes", "to"]), Some((9000, 4)));
+        assert_eq!(parse_duration(&["1d", "1", "hour"]), Some((90000, 3)));
+        assert_eq!(parse_duration(&["to", "deploy"]), None);
+        assert_eq!(parse_duration(&["5", "apples"]), None);
+    }
+
+    #[test]
+    fn test_parse_offset() {
+        assert_eq!(parse_offset("UTC"), Some(0));
+        assert_eq!(parse_offset("utc+2"), Some(120));
+        assert_eq!(parse_offset("GMT-5:30"), Some(-330));
+        assert_eq!(parse_offset("+0200"), Some(120));
+        assert_eq!(parse_offset("-08:00"), Some(-480));
+        assert_eq!(parse_offset("Europe/Paris"), None);
+        assert_eq!(parse_offset("+25"), None);
+        assert_eq!(parse_offset("+aé1"), None);
+        assert_eq!(parse_offset("utc+é"), None);
+        assert_eq!(parse_offset("+-999"), None);
+        assert_eq!(parse_offset("+1:-5"), None);
+        assert_eq!(parse_offset("+:30"), None);
+    }
+
+    #[test]
+    fn test_parse_reminder() {
+        // 1970-01-02 10:00 UTC
+        let now = Timespec::new(86400 + 10 * 3600, 0);
+
+        assert_eq!(parse_reminder("in 2h30m to deploy", now, 0),
+            Ok((Timespec::new(now.sec + 9000, 0), "deploy".to_string())));
+        assert_eq!(parse_reminder("at 14:00 UTC standup", now, 0),
+            Ok((Timespec::new(86400 + 14 * 3600, 0), "standup".to_string())));
+        // 14:00 at UTC+2 is 12:00 UTC
+        assert_eq!(parse_reminder("at 14:00 standup", now, 120),
+            Ok((Timespec::new(86400 + 12 * 3600, 0), "standup".to_string())));
+        // already past today, so tomorrow
+        assert_eq!(parse_reminder("at 9:00 UTC-1 to standup", now, 0),
+            Ok((Timespec::new(2 * 86400 + 10 * 3600, 0), "standup".to_string())));
+
+        assert_eq!(parse_reminder("tomorrow standup", now, 0), Err(ParseError::MissingWhen));
+        assert_eq!(parse_reminder("in soon standup", now, 0), Err(ParseError::BadDuration));
+        assert_eq!(parse_reminder("at noon standup", now, 0), Err(ParseError::BadTime));
+        assert_eq!(parse_reminder("in 2h", now, 0), Err(ParseError::MissingMessage));
+        assert_eq!(parse_reminder("in 400d to wait", now, 0), Err(ParseError::TooFar));
+        assert_eq!(parse_reminder("in 9999999999999w to wait", now, 0), Err(ParseError::TooFar));
+        assert_eq!(parse_reminder("in -9999999999999 weeks to wait", now, 0), Err(ParseError::BadDuration));
+        assert_eq!(parse_reminder("in -5 minutes to wait", now, 0), Err(ParseError::BadDuration));
+        assert_eq!(parse_reminder("in +5 minutes to wait", now, 0), Err(ParseError::BadDuration));
+        assert_eq!(parse_reminder("at -1:00 standup", now, 0), Err(ParseError::BadTime));
+        assert_eq!(parse_reminder("at 10:+5 standup", now, 0), Err(ParseError::BadTime));
+    }
+}
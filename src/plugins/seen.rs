@@ -1,64 +1,230 @@
+use std::str;
 use std::collections::BTreeMap;
 
 use time::{get_time, Timespec};
 
 use irc::{IrcMsg, server};
+use irc::legacy::FrozenState;
 
 use utils::formatting::duration_to_string;
+use utils::glob::glob_match;
 use command_mapper::{
     RustBotPlugin,
     CommandMapperDispatch,
     IrcBotConfigurator,
     Format,
+    Quoting,
     Token,
     Replier,
+    Namespace,
 };
 
 const CMD_SEEN: Token = Token(0);
 
 static MAX_USER_RECORDS_KEPT: usize = 5;
 
-enum Message {
-    Privmsg(server::PrivmsgBuf),
-    Quit(server::QuitBuf),
+// The most matches a wildcard query replies with
+static MAX_QUERY_RESULTS: usize = 3;
+
+// Message bodies and reasons are `None` unless they were valid UTF-8
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+enum Activity {
+    Privmsg { channel: String, body: Option<String> },
+    Action { channel: String, body: Option<String> },
+    Notice { channel: String, body: Option<String> },
+    Join { channel: String },
+    Part { channel: String, reason: Option<String> },
+    Kick { channel: String, by: String, reason: Option<String> },
+    Quit { reason: Option<String> },
+    NickFrom { nick: String },
+    NickTo { nick: String },
 }
 
+impl Activity {
+    fn get_channel(&self) -> Option<&str> {
+        match *self {
+            Activity::Privmsg { ref channel, .. } |
+            Activity::Action { ref channel, .. } |
+            Activity::Notice { ref channel, .. } |
+            Activity::Join { ref channel } |
+            Activity::Part { ref channel, .. } |
+            Activity::Kick { ref channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+
+    fn is_speech(&self) -> bool {
+        match *self {
+            Activity::Privmsg { .. } | Activity::Action { .. } | Activity::Notice { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
 pub struct SeenRecord {
-    when: Timespec,
-    message: Message,
+    when: i64,
+    activity: Activity,
 }
 
 impl SeenRecord {
-    fn new_privmsg(when: Timespec, message: server::PrivmsgBuf) -> SeenRecord {
+    fn new(when: Timespec, activity: Activity) -> SeenRecord {
         SeenRecord {
-            when: when,
-            message: Message::Privmsg(message)
+            when: when.sec,
+            activity: activity,
         }
     }
 
-    fn new_quit(when: Timespec, message: server::QuitBuf) -> SeenRecord {
-        SeenRecord {
-            when: when,
-            message: Message::Quit(message)
+    fn when(&self) -> Timespec {
+        Timespec::new(self.when, 0)
+    }
+}
+
+#[derive(RustcDecodable, RustcEncodable, Debug)]
+pub struct SeenUser {
+    nick: String,
+    // `user@host`, from the last message the user sent
+    hostmask: Option<String>,
+    // oldest first
+    records: Vec<SeenRecord>,
+}
+
+impl SeenUser {
+    fn new(nick: &str) -> SeenUser {
+        SeenUser {
+            nick: nick.to_string(),
+            hostmask: None,
+            records: Vec::new(),
+        }
+    }
+
+    /// The most recent record, in `channel` if given.
+    fn latest_in(&self, channel: Option<&str>) -> Option<&SeenRecord> {
+        self.records.iter().rev().find(|rec| match channel {
+            Some(channel) => rec.activity.get_channel()
+                .map(|ch| ch.to_lowercase() == channel.to_lowercase())
+                .unwrap_or(false),
+            None => true,
+        })
+    }
+}
+
+/// What wildcard queries are matched against, without decoding records
+struct IndexEntry {
+    nick: String,
+    hostmask: Option<String>,
+}
+
+impl IndexEntry {
+    fn matches(&self, pattern: &str) -> bool {
+        if pattern.contains('!') || pattern.contains('@') {
+            match self.hostmask {
+                Some(ref hostmask) => glob_match(pattern, &format!("{}!{}", self.nick, hostmask)),
+                None => false,
+            }
+        } else {
+            glob_match(pattern, &self.nick)
         }
     }
 }
 
 pub struct SeenPlugin {
-    map: BTreeMap<String, Vec<SeenRecord>>,
+    // `SeenUser` by lowercased nick
+    map: Option<Namespace>,
+    index: BTreeMap<String, IndexEntry>,
+    // nick and `user@host` from the prefix of the message being handled
+    last_prefix: Option<(String, String)>,
 }
 
 
 impl SeenPlugin {
     pub fn new() -> SeenPlugin {
         SeenPlugin {
-            map: BTreeMap::new()
+            map: None,
+            index: BTreeMap::new(),
+            last_prefix: None,
         }
     }
 
     pub fn get_plugin_name() -> &'static str {
         "seen"
     }
+
+    fn load_index(&mut self) {
+        let map = match self.map {
+            Some(ref map) => map.clone(),
+            None => return,
+        };
+        for key in map.keys().into_iter() {
+            if let Some(user) = map.get::<SeenUser>(&key) {
+                self.index.insert(key, IndexEntry { nick: user.nick, hostmask: user.hostmask });
+            }
+        }
+    }
+
+    fn get_user(&self, nick: &str) -> Option<SeenUser> {
+        self.map.as_ref().and_then(|map| map.get(&nick.to_lowercase()))
+    }
+
+    fn set_user(&mut self, user: &SeenUser) {
+        let key = user.nick.to_lowercase();
+        if let Some(ref map) = self.map {
+            map.set(&key, user);
+        }
+        self.index.insert(key, IndexEntry {
+            nick: user.nick.clone(),
+            hostmask: user.hostmask.clone(),
+        });
+    }
+
+    fn add_record(&mut self, nick: &str, activity: Activity) {
+        let mut user = self.get_user(nick).unwrap_or_else(|| SeenUser::new(nick));
+        user.nick = nick.to_string();
+        if let Some((ref prefix_nick, ref hostmask)) = self.last_prefix {
+            if prefix_nick == nick {
+                user.hostmask = Some(hostmask.clone());
+            }
+        }
+        user.records.push(SeenRecord::new(get_time(), activity));
+        trim_vec(&mut user.records);
+        self.set_user(&user);
+    }
+
+    /// Copies the history of `old_nick` to `new_nick`, so that it carries
+    /// over to the new nick, and records the change under both.
+    fn change_nick(&mut self, old_nick: &str, new_nick: &str) {
+        let activity = Activity::NickFrom { nick: old_nick.to_string() };
+        if old_nick.to_lowercase() == new_nick.to_lowercase() {
+            self.add_record(new_nick, activity);
+            return;
+        }
+
+        let old_user = self.get_user(old_nick);
+        let mut user = self.get_user(new_nick).unwrap_or_else(|| SeenUser::new(new_nick));
+        if let Some(old_user) = old_user {
+            user.records.extend(old_user.records.into_iter());
+            user.records.sort_by_key(|rec| rec.when);
+            if user.hostmask.is_none() {
+                user.hostmask = old_user.hostmask;
+            }
+        }
+        user.records.push(SeenRecord::new(get_time(), activity));
+        trim_vec(&mut user.records);
+        self.set_user(&user);
+
+        self.add_record(old_nick, Activity::NickTo { nick: new_nick.to_string() });
+    }
+
+    /// Users matching a wildcard `pattern`, most recently seen first.
+    fn query(&self, pattern: &str, channel: Option<&str>) -> Vec<SeenUser> {
+        let mut users: Vec<SeenUser> = self.index.iter()
+            .filter(|&(_, entry)| entry.matches(pattern))
+            .filter_map(|(_, entry)| self.get_user(&entry.nick))
+            .filter(|user| user.latest_in(channel).is_some())
+            .collect();
+        users.sort_by_key(|user| -user.latest_in(channel).unwrap().when);
+        users
+    }
 }
 
 
@@ -72,78 +238,183 @@ fn trim_vec<T>(vec: &mut Vec<T>) {
     vec.extend(before.into_iter().skip(excess_elem));
 }
 
-enum SeenCommandType {
-    Seen(String)
-}
-
-fn format_activity(nick: &str, records: &Vec<SeenRecord>) -> String {
-    use std::str;
-    use std::fmt::Write;
-
-    let mut priv_msg: Option<(Timespec, Option<String>)> = None;
-    let mut quit_msg: Option<Timespec> = None;
-    
-    for record in records.iter().rev() {
-        match record.message {
-            Message::Privmsg(ref pmsg) => {
-                let said_what = str::from_utf8(pmsg.get_body_raw())
-                    .ok().map(ToOwned::to_owned);
-                priv_msg = Some((record.when, said_what));
-            }
-            Message::Quit(_) => quit_msg = Some(record.when),
-        }
+fn to_string(bytes: &[u8]) -> Option<String> {
+    str::from_utf8(bytes).ok().map(ToOwned::to_owned)
+}
+
+fn to_string_lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+fn is_wildcard(query: &str) -> bool {
+    query.contains(|ch: char| ch == '*' || ch == '?' || ch == '!' || ch == '@')
+}
+
+/// Splits the nick and `user@host` out of the prefix of a raw message.
+fn get_prefix(raw: &[u8]) -> Option<(String, String)> {
+    if !raw.starts_with(b":") {
+        return None;
     }
+    let prefix = match raw[1..].split(|&ch| ch == b' ').next() {
+        Some(prefix) => prefix,
+        None => return None,
+    };
+    let bang = match prefix.iter().position(|&ch| ch == b'!') {
+        Some(bang) => bang,
+        None => return None,
+    };
+    Some((to_string_lossy(&prefix[..bang]), to_string_lossy(&prefix[bang + 1..])))
+}
 
-    let now = get_time();
+fn is_channel(target: &[u8]) -> bool {
+    // FIXME: Hacky is-channel
+    target.starts_with(b"#")
+}
 
-    let mut out = String::new();
-    match priv_msg {
-        Some((when, Some(ref said_what))) => {
-            write!(&mut out, "{} said ``{}'' {} ago",
-                nick, said_what, duration_to_string(now - when)).unwrap();
-        },
-        Some((when, None)) => {
-            write!(&mut out, "{} said something I dare not repeat {} ago",
-                nick, duration_to_string(now - when)).unwrap();
-        },
-        None => (),
+/// Splits a CTCP ACTION (`/me`) out of a message body.
+fn get_action(body: &[u8]) -> Option<&[u8]> {
+    const PREFIX: &'static [u8] = b"\x01ACTION ";
+    if !body.starts_with(PREFIX) {
+        return None;
     }
-    match (quit_msg, priv_msg.is_some()) {
-        (Some(when), true) => {
-            write!(&mut out, " before quitting {} later", duration_to_string(now - when)).unwrap();
-        },
-        (Some(when), false) => {
-            write!(&mut out, "{} quit {} ago", nick, duration_to_string(now - when)).unwrap();
-        },
-        (None, true) => (),
-        (None, false) => {
-            write!(&mut out, "Sorry, I am very confused about {}", nick).unwrap();
-        }
+    let action = &body[PREFIX.len()..];
+    Some(match action.last() {
+        Some(&b'\x01') => &action[..action.len() - 1],
+        _ => action,
+    })
+}
+
+enum SeenCommandType {
+    Seen(String, Option<String>)
+}
+
+fn format_reason(reason: &Option<String>) -> String {
+    match *reason {
+        Some(ref reason) if !reason.is_empty() => format!(" ({})", reason),
+        _ => String::new(),
+    }
+}
+
+fn format_record(nick: &str, record: &SeenRecord, now: Timespec) -> String {
+    let ago = duration_to_string(now - record.when());
+    match record.activity {
+        Activity::Privmsg { ref channel, body: Some(ref body) } =>
+            format!("{} said ``{}'' in {} {} ago", nick, body, channel, ago),
+        Activity::Action { ref channel, body: Some(ref body) } =>
+            format!("{} was last seen in {} {} ago: * {} {}", nick, channel, ago, nick, body),
+        Activity::Notice { ref channel, body: Some(ref body) } =>
+            format!("{} noticed ``{}'' in {} {} ago", nick, body, channel, ago),
+        Activity::Privmsg { ref channel, body: None } |
+        Activity::Action { ref channel, body: None } |
+        Activity::Notice { ref channel, body: None } =>
+            format!("{} said something I dare not repeat in {} {} ago", nick, channel, ago),
+        Activity::Join { ref channel } =>
+            format!("{} joined {} {} ago", nick, channel, ago),
+        Activity::Part { ref channel, ref reason } =>
+            format!("{} left {} {} ago{}", nick, channel, ago, format_reason(reason)),
+        Activity::Kick { ref channel, ref by, ref reason } =>
+            format!("{} was kicked from {} by {} {} ago{}", nick, channel, by, ago, format_reason(reason)),
+        Activity::Quit { ref reason } =>
+            format!("{} quit {} ago{}", nick, ago, format_reason(reason)),
+        Activity::NickFrom { nick: ref old_nick } =>
+            format!("{} changed nick from {} {} ago", nick, old_nick, ago),
+        Activity::NickTo { nick: ref new_nick } =>
+            format!("{} changed nick to {} {} ago", nick, new_nick, ago),
     }
+}
 
+fn format_activity(nick: &str, records: &Vec<SeenRecord>, now: Timespec) -> String {
+    let latest = match records.last() {
+        Some(latest) => latest,
+        None => return format!("Sorry, I am very confused about {}", nick),
+    };
+
+    let mut out = format_record(nick, latest, now);
+    if !latest.activity.is_speech() {
+        if let Some(spoke) = records.iter().rev().find(|rec| rec.activity.is_speech()) {
+            out.push_str("; before that, ");
+            out.push_str(&format_record(nick, spoke, now));
+        }
+    }
     out
 }
 
 
 impl RustBotPlugin for SeenPlugin {
+    fn name(&self) -> &str {
+        SeenPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_SEEN, Format::from_str("seen {nick:s}").unwrap());
+        self.map = Some(conf.storage());
+        self.load_index();
+        conf.map_format(CMD_SEEN, Format::from_str("seen {nick:s}").unwrap()
+            .with_quoting(Quoting::Shell),
+            "Show when a nick was last active; accepts wildcards and nick!user@host masks");
+        conf.map_format(CMD_SEEN, Format::from_str("seen --channel {channel:s} {nick:s}").unwrap()
+            .with_quoting(Quoting::Shell),
+            "Show when a nick was last active in a channel");
     }
 
     fn on_message(&mut self, _: &mut Replier, msg: &IrcMsg) {
+        // the typed hooks for this message run next, and pick this up
+        self.last_prefix = get_prefix(msg.as_bytes());
+
         if let Ok(privmsg) = msg.as_tymsg::<&server::Privmsg>() {
-            // FIXME: dedup this code? source_nick could be on IrcMsg
-            let source = privmsg.source_nick().to_owned();
-            let records: &mut Vec<SeenRecord> = self.map.entry(source).or_insert(Vec::new());
-            records.push(SeenRecord::new_privmsg(get_time(), privmsg.to_owned()));
-            trim_vec(records);
+            // messages sent to the bot itself are not ours to repeat
+            if !is_channel(privmsg.get_target()) {
+                return;
+            }
+            let channel = to_string_lossy(privmsg.get_target());
+            let activity = match get_action(privmsg.get_body_raw()) {
+                Some(action) => Activity::Action { channel: channel, body: to_string(action) },
+                None => Activity::Privmsg { channel: channel, body: to_string(privmsg.get_body_raw()) },
+            };
+            self.add_record(privmsg.source_nick(), activity);
         }
-        if let Ok(quitmsg) = msg.as_tymsg::<&server::Quit>() {
-            let source = quitmsg.source_nick().to_owned();
-            let records: &mut Vec<SeenRecord> = self.map.entry(source).or_insert(Vec::new());
-            records.push(SeenRecord::new_quit(get_time(), quitmsg.to_owned()));
-            trim_vec(records);
+    }
+
+    fn on_notice(&mut self, _: &mut Replier, _: &FrozenState, msg: &server::Notice) {
+        if !is_channel(msg.get_target()) {
+            return;
         }
+        self.add_record(msg.source_nick(), Activity::Notice {
+            channel: to_string_lossy(msg.get_target()),
+            body: to_string(msg.get_body_raw()),
+        });
+    }
+
+    fn on_join(&mut self, _: &mut Replier, _: &FrozenState, msg: &server::Join) {
+        self.add_record(msg.source_nick(), Activity::Join {
+            channel: to_string_lossy(msg.get_channel()),
+        });
+    }
+
+    fn on_part(&mut self, _: &mut Replier, _: &FrozenState, msg: &server::Part) {
+        self.add_record(msg.source_nick(), Activity::Part {
+            channel: to_string_lossy(msg.get_channel()),
+            reason: msg.get_reason().and_then(to_string),
+        });
+    }
+
+    fn on_kick(&mut self, _: &mut Replier, _: &FrozenState, msg: &server::Kick) {
+        let kicked = to_string_lossy(msg.get_target());
+        self.add_record(&kicked, Activity::Kick {
+            channel: to_string_lossy(msg.get_channel()),
+            by: msg.source_nick().to_string(),
+            reason: msg.get_reason().and_then(to_string),
+        });
+    }
+
+    fn on_quit(&mut self, _: &mut Replier, _: &FrozenState, msg: &server::Quit) {
+        self.add_record(msg.source_nick(), Activity::Quit {
+            reason: msg.get_reason().and_then(to_string),
+        });
+    }
+
+    fn on_nick(&mut self, _: &mut Replier, _: &FrozenState, msg: &server::Nick) {
+        let new_nick = to_string_lossy(msg.get_new_nick());
+        self.change_nick(msg.source_nick(), &new_nick);
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
@@ -153,8 +424,7 @@ impl RustBotPlugin for SeenPlugin {
             Err(_) => return,
         }
 
-        // FIXME: Hacky is-channel
-        if !privmsg.get_target().starts_with(b"#") {
+        if !is_channel(privmsg.get_target()) {
             return
         }
         let source_nick = privmsg.source_nick();
@@ -163,14 +433,30 @@ impl RustBotPlugin for SeenPlugin {
 
         let parsed_command = match command_phrase.token {
             CMD_SEEN => match command_phrase.get("nick") {
-                Some(nick) => Some(SeenCommandType::Seen(nick)),
+                Some(nick) => Some(SeenCommandType::Seen(nick, command_phrase.get("channel"))),
                 None => None
             },
             _ => None
         };
 
         match parsed_command {
-            Some(SeenCommandType::Seen(ref target_nick)) => {
+            Some(SeenCommandType::Seen(ref pattern, ref channel)) if is_wildcard(pattern) => {
+                let channel = channel.as_ref().map(|ch| &ch[..]);
+                let users = self.query(pattern, channel);
+                if users.is_empty() {
+                    m.reply(&format!("Nobody matching {} has been seen", pattern));
+                    return;
+                }
+                let now = get_time();
+                for user in users.iter().take(MAX_QUERY_RESULTS) {
+                    m.reply(&format_record(&user.nick, user.latest_in(channel).unwrap(), now));
+                }
+                if MAX_QUERY_RESULTS < users.len() {
+                    m.reply(&format!("... and {} more matching {}",
+                        users.len() - MAX_QUERY_RESULTS, pattern));
+                }
+            },
+            Some(SeenCommandType::Seen(ref target_nick, ref channel)) => {
                 if source_nick == target_nick {
                     m.reply(&format!("Looking for yourself, {}?", source_nick));
                     return;
@@ -180,16 +466,73 @@ impl RustBotPlugin for SeenPlugin {
                     m.reply(&format!("You found me, {}!", source_nick));
                     return;
                 }
-                let activity = match self.map.get(target_nick) {
+                let user = match self.get_user(target_nick) {
                     Some(val) => val,
                     None => {
                         m.reply(&format!("{} is unknown", target_nick));
                         return
                     }
                 };
-                m.reply(&format_activity(target_nick, activity));
+                match *channel {
+                    Some(ref channel) => match user.latest_in(Some(&channel[..])) {
+                        Some(record) => m.reply(&format_record(&user.nick, record, get_time())),
+                        None => m.reply(&format!("{} has not been seen in {}", user.nick, channel)),
+                    },
+                    None => m.reply(&format_activity(&user.nick, &user.records, get_time())),
+                }
             },
             None => return
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use time::Timespec;
+
+    use super::{get_action, get_prefix, format_activity, Activity, SeenRecord};
+
+    #[test]
+    fn test_get_action() {
+        assert_eq!(get_action(b"\x01ACTION waves\x01"), Some(&b"waves"[..]));
+        assert_eq!(get_action(b"\x01ACTION waves"), Some(&b"waves"[..]));
+        assert_eq!(get_action(b"waves"), None);
+    }
+
+    #[test]
+    fn test_get_prefix() {
+        assert_eq!(get_prefix(b":nick!~user@host.example PRIVMSG #x :hi"),
+            Some(("nick".to_string(), "~user@host.example".to_string())));
+        assert_eq!(get_prefix(b":irc.example 001 nick :Welcome"), None);
+        assert_eq!(get_prefix(b"PING :irc.example"), None);
+    }
+
+    #[test]
+    fn test_format_activity() {
+        let now = Timespec::new(4 * 3600, 0);
+        let mut records = vec![
+            SeenRecord::new(Timespec::new(0, 0), Activity::Privmsg {
+                channel: "#x".to_string(),
+                body: Some("hello".to_string()),
+            }),
+            SeenRecord::new(Timespec::new(3600, 0), Activity::Kick {
+                channel: "#x".to_string(),
+                by: "y".to_string(),
+                reason: Some("flood".to_string()),
+            }),
+        ];
+        assert_eq!(format_activity("foo", &records, now),
+            "foo was kicked from #x by y 3h ago (flood); \
+             before that, foo said ``hello'' in #x 4h ago");
+
+        records.push(SeenRecord::new(Timespec::new(7200, 0), Activity::NickFrom {
+            nick: "bar".to_string(),
+        }));
+        records.remove(1);
+        assert_eq!(format_activity("foo", &records, now),
+            "foo changed nick from bar 2h ago; before that, foo said ``hello'' in #x 4h ago");
+
+        assert_eq!(format_activity("foo", &Vec::new(), now),
+            "Sorry, I am very confused about foo");
+    }
+}
@@ -0,0 +1,396 @@
+use time::{get_time, Timespec};
+
+use irc::{IrcMsg, client as cli2, server};
+use irc::legacy::FrozenState;
+
+use utils::formatting::duration_to_string;
+use command_mapper::{
+    RustBotPlugin,
+    CommandMapperDispatch,
+    IrcBotConfigurator,
+    Format,
+    Quoting,
+    Namespace,
+    Token,
+    Replier,
+};
+
+const CMD_TELL: Token = Token(0);
+const CMD_TELL_NOTICE: Token = Token(1);
+const CMD_TELL_PRIVATE: Token = Token(2);
+const CMD_TELLS: Token = Token(3);
+const CMD_TELLS_CANCEL: Token = Token(4);
+
+/// How many undelivered messages one sender may leave
+static MAX_PENDING_PER_SENDER: usize = 5;
+
+static MAX_MESSAGE_LENGTH: usize = 300;
+
+/// How many messages are delivered at once; the rest wait for the
+/// recipient's next message.
+static MAX_DELIVERED_AT_ONCE: usize = 3;
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Copy, PartialEq, Eq, Debug)]
+enum Delivery {
+    /// Where the recipient next speaks or joins
+    Channel,
+    Notice,
+    Private,
+}
+
+#[derive(RustcDecodable, RustcEncodable, Clone, Debug)]
+struct Tell {
+    id: u64,
+    sender: String,
+    recipient: String,
+    message: String,
+    when: i64,
+    delivery: Delivery,
+}
+
+impl Tell {
+    fn is_for(&self, nick: &str) -> bool {
+        self.recipient.to_lowercase() == nick.to_lowercase()
+    }
+
+    fn is_from(&self, nick: &str) -> bool {
+        self.sender.to_lowercase() == nick.to_lowercase()
+    }
+
+    fn format(&self, now: Timespec) -> String {
+        format!("{}: {} asked me to tell you {} ago: {}",
+            self.recipient, self.sender,
+            duration_to_string(now - Timespec::new(self.when, 0)),
+            self.message)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TellError {
+    ToSelf,
+    TooLong,
+    /// The sender already has this many messages undelivered
+    QuotaReached(usize),
+}
+
+/// Where a message for `nick` goes, who has just spoken or joined in
+/// `channel`, or messaged the bot if it is `None`: the target, and
+/// whether to send a notice.
+fn destination<'a>(delivery: Delivery, nick: &'a [u8], channel: Option<&'a [u8]>) -> (&'a [u8], bool) {
+    match (delivery, channel) {
+        (Delivery::Channel, Some(channel)) => (channel, false),
+        (Delivery::Notice, _) => (nick, true),
+        (Delivery::Channel, None) | (Delivery::Private, _) => (nick, false),
+    }
+}
+
+enum TellCommandType {
+    Tell(Delivery, String, String),
+    Tells,
+    TellsCancel(u64),
+}
+
+fn parse_command(m: &CommandMapperDispatch) -> Option<TellCommandType> {
+    let command_phrase = m.command();
+    let delivery = match command_phrase.token {
+        CMD_TELL => Delivery::Channel,
+        CMD_TELL_NOTICE => Delivery::Notice,
+        CMD_TELL_PRIVATE => Delivery::Private,
+        CMD_TELLS => return Some(TellCommandType::Tells),
+        CMD_TELLS_CANCEL => return command_phrase.get("id").map(TellCommandType::TellsCancel),
+        _ => return None,
+    };
+    match (command_phrase.get("nick"), command_phrase.get("message")) {
+        (Some(nick), Some(message)) => Some(TellCommandType::Tell(delivery, nick, message)),
+        _ => None,
+    }
+}
+
+pub struct TellPlugin {
+    storage: Option<Namespace>,
+    next_id: u64,
+    // oldest first
+    pending: Vec<Tell>,
+}
+
+impl TellPlugin {
+    pub fn new() -> TellPlugin {
+        TellPlugin {
+            storage: None,
+            next_id: 1,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn get_plugin_name() -> &'static str {
+        "tell"
+    }
+
+    fn save(&self) {
+        if let Some(ref storage) = self.storage {
+            storage.set("next_id", &self.next_id);
+            storage.set("pending", &self.pending);
+        }
+    }
+
+    /// Takes up to `MAX_DELIVERED_AT_ONCE` messages for `nick`.
+    fn take_pending(&mut self, nick: &str) -> Vec<Tell> {
+        if !self.pending.iter().any(|tell| tell.is_for(nick)) {
+            return Vec::new();
+        }
+        let mut delivered = Vec::new();
+        let mut kept = Vec::new();
+        for tell in ::std::mem::replace(&mut self.pending, Vec::new()).into_iter() {
+            if tell.is_for(nick) && delivered.len() < MAX_DELIVERED_AT_ONCE {
+                delivered.push(tell);
+            } else {
+                kept.push(tell);
+            }
+        }
+        self.pending = kept;
+        self.save();
+        delivered
+    }
+
+    /// Stores a message, returning its id.
+    fn leave(&mut self, sender: &str, recipient: &str, message: &str,
+             delivery: Delivery, now: i64) -> Result<u64, TellError> {
+        if recipient.to_lowercase() == sender.to_lowercase() {
+            return Err(TellError::ToSelf);
+        }
+        if MAX_MESSAGE_LENGTH < message.chars().count() {
+            return Err(TellError::TooLong);
+        }
+        let sent = self.pending.iter().filter(|tell| tell.is_from(sender)).count();
+        if MAX_PENDING_PER_SENDER <= sent {
+            return Err(TellError::QuotaReached(sent));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.push(Tell {
+            id: id,
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            message: message.to_string(),
+            when: now,
+            delivery: delivery,
+        });
+        self.save();
+        Ok(id)
+    }
+
+    /// Removes `sender`'s undelivered message `id`.
+    fn cancel(&mut self, sender: &str, id: u64) -> Option<Tell> {
+        let position = self.pending.iter()
+            .position(|tell| tell.id == id && tell.is_from(sender));
+        position.map(|position| {
+            let tell = self.pending.remove(position);
+            self.save();
+            tell
+        })
+    }
+
+    /// Delivers messages for `nick`, who has just spoken or joined in
+    /// `channel`, or messaged the bot if it is `None`.
+    fn deliver(&mut self, replier: &mut Replier, nick: &str, channel: Option<&[u8]>) {
+        let now = get_time();
+        for tell in self.take_pending(nick).into_iter() {
+            let body = tell.format(now);
+            let result = match destination(tell.delivery, nick.as_bytes(), channel) {
+                (target, true) => {
+                    let notice = cli2::NoticeBuf::new(target, body.as_bytes()).unwrap();
+                    replier.reply(&notice)
+                },
+                (target, false) => {
+                    let privmsg = cli2::PrivmsgBuf::new(target, body.as_bytes()).unwrap();
+                    replier.reply(&privmsg)
+                },
+            };
+            if result.is_err() {
+                warn!("tell: failed to deliver message {} to {}", tell.id, nick);
+            }
+        }
+    }
+
+    fn dispatch_cmd_tell(&mut self, m: &CommandMapperDispatch, source_nick: &str,
+                         delivery: Delivery, nick: &str, message: &str) {
+        if nick.to_lowercase() == m.current_nick().to_lowercase() {
+            m.reply(&format!("{}: I'm right here", source_nick));
+            return;
+        }
+        match self.leave(source_nick, nick, message, delivery, get_time().sec) {
+            Ok(id) => m.reply(&format!("{}: I'll pass that on when {} is around (#{})",
+                source_nick, nick, id)),
+            Err(TellError::ToSelf) => m.reply(&format!("{}: tell yourself", source_nick)),
+            Err(TellError::TooLong) => m.reply(&format!("{}: messages are limited to {} characters",
+                source_nick, MAX_MESSAGE_LENGTH)),
+            Err(TellError::QuotaReached(sent)) => m.reply(&format!(
+                "{}: you already have {} undelivered messages; see ``tells''", source_nick, sent)),
+        }
+    }
+
+    fn dispatch_cmd_tells(&mut self, m: &CommandMapperDispatch, source_nick: &str) {
+        let now = get_time();
+        let mut any = false;
+        for tell in self.pending.iter().filter(|tell| tell.is_from(source_nick)) {
+            any = true;
+            // the listing may be in a channel
+            let message = match tell.delivery {
+                Delivery::Channel => &tell.message[..],
+                Delivery::Notice | Delivery::Private => "(private)",
+            };
+            m.reply(&format!("#{} to {}, {} ago: {}", tell.id, tell.recipient,
+                duration_to_string(now - Timespec::new(tell.when, 0)), message));
+        }
+        if !any {
+            m.reply(&format!("{}: you have no undelivered messages", source_nick));
+        }
+    }
+
+    fn dispatch_cmd_tells_cancel(&mut self, m: &CommandMapperDispatch, source_nick: &str, id: u64) {
+        match self.cancel(source_nick, id) {
+            Some(tell) => m.reply(&format!("{}: cancelled #{} to {}", source_nick, id, tell.recipient)),
+            None => m.reply(&format!("{}: you have no undelivered message #{}", source_nick, id)),
+        }
+    }
+}
+
+impl RustBotPlugin for TellPlugin {
+    fn name(&self) -> &str {
+        TellPlugin::get_plugin_name()
+    }
+
+    fn configure(&mut self, conf: &mut IrcBotConfigurator) {
+        let storage = conf.storage();
+        self.next_id = storage.get("next_id").unwrap_or(1);
+        self.pending = storage.get("pending").unwrap_or(Vec::new());
+        self.storage = Some(storage);
+
+        conf.map_format(CMD_TELL, Format::from_str("tell {nick:s} {*message}").unwrap()
+            .with_quoting(Quoting::Shell),
+            "Leave a message for a nick, delivered when they next speak or join");
+        conf.map_format(CMD_TELL_NOTICE, Format::from_str("tell --notice {nick:s} {*message}").unwrap()
+            .with_quoting(Quoting::Shell),
+            "Leave a message for a nick, delivered by notice");
+        conf.map_format(CMD_TELL_PRIVATE, Format::from_str("tell --private {nick:s} {*message}").unwrap()
+            .with_quoting(Quoting::Shell),
+            "Leave a message for a nick, delivered by private message");
+        conf.map_format(CMD_TELLS, Format::from_str("tells").unwrap(),
+            "List the messages you left which are not yet delivered");
+        conf.map_format(CMD_TELLS_CANCEL, Format::from_str("tells cancel {id:d}").unwrap(),
+            "Cancel an undelivered message");
+    }
+
+    fn on_message(&mut self, replier: &mut Replier, msg: &IrcMsg) {
+        if let Ok(privmsg) = msg.as_tymsg::<&server::Privmsg>() {
+            // FIXME: Hacky is-channel
+            let channel = if privmsg.get_target().starts_with(b"#") {
+                Some(privmsg.get_target())
+            } else {
+                None
+            };
+            self.deliver(replier, privmsg.source_nick(), channel);
+        }
+    }
+
+    fn on_join(&mut self, replier: &mut Replier, state: &FrozenState, msg: &server::Join) {
+        if msg.source_nick() == state.get_self_nick() {
+            return;
+        }
+        self.deliver(replier, msg.source_nick(), Some(msg.get_channel()));
+    }
+
+    fn on_nick(&mut self, replier: &mut Replier, _: &FrozenState, msg: &server::Nick) {
+        // a user coming back under the awaited nick is told privately;
+        // they may be in several channels, and have not spoken in any
+        let new_nick = String::from_utf8_lossy(msg.get_new_nick()).into_owned();
+        self.deliver(replier, &new_nick, None);
+    }
+
+    fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
+        let privmsg;
+        match msg.as_tymsg::<&server::Privmsg>() {
+            Ok(p) => privmsg = p,
+            Err(_) => return,
+        }
+        let source_nick = privmsg.source_nick();
+
+        match parse_command(m) {
+            Some(TellCommandType::Tell(delivery, ref nick, ref message)) =>
+                self.dispatch_cmd_tell(m, source_nick, delivery, nick, message),
+            Some(TellCommandType::Tells) => self.dispatch_cmd_tells(m, source_nick),
+            Some(TellCommandType::TellsCancel(id)) => self.dispatch_cmd_tells_cancel(m, source_nick, id),
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TellPlugin, TellError, Delivery, destination, MAX_MESSAGE_LENGTH};
+
+    fn ids(plugin: &mut TellPlugin, nick: &str) -> Vec<u64> {
+        plugin.take_pending(nick).iter().map(|tell| tell.id).collect()
+    }
+
+    #[test]
+    fn test_quota() {
+        let mut plugin = TellPlugin::new();
+        for idx in 0..5 {
+            let sender = if idx % 2 == 0 { "alice" } else { "Alice" };
+            assert_eq!(plugin.leave(sender, "bob", "hi", Delivery::Channel, 0), Ok(idx + 1));
+        }
+        assert_eq!(plugin.leave("ALICE", "carol", "hi", Delivery::Channel, 0), Err(TellError::QuotaReached(5)));
+        assert_eq!(plugin.leave("carol", "bob", "hi", Delivery::Channel, 0), Ok(6));
+
+        // delivering or cancelling one makes room for another
+        assert_eq!(plugin.cancel("alice", 3).map(|tell| tell.recipient), Some("bob".to_string()));
+        assert_eq!(plugin.leave("alice", "dave", "hi", Delivery::Channel, 0), Ok(7));
+        assert_eq!(plugin.leave("alice", "dave", "hi", Delivery::Channel, 0), Err(TellError::QuotaReached(5)));
+        assert_eq!(ids(&mut plugin, "dave"), vec![7]);
+        assert_eq!(plugin.leave("alice", "dave", "hi", Delivery::Channel, 0), Ok(8));
+    }
+
+    #[test]
+    fn test_leave_refused() {
+        let mut plugin = TellPlugin::new();
+        assert_eq!(plugin.leave("alice", "Alice", "hi", Delivery::Channel, 0), Err(TellError::ToSelf));
+        let long: String = (0..MAX_MESSAGE_LENGTH + 1).map(|_| 'x').collect();
+        assert_eq!(plugin.leave("alice", "bob", &long, Delivery::Channel, 0), Err(TellError::TooLong));
+        assert_eq!(plugin.leave("alice", "bob", &long[1..], Delivery::Channel, 0), Ok(1));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut plugin = TellPlugin::new();
+        plugin.leave("alice", "bob", "one", Delivery::Channel, 0).unwrap();
+        plugin.leave("alice", "bob", "two", Delivery::Private, 0).unwrap();
+        // only the sender may cancel
+        assert!(plugin.cancel("bob", 1).is_none());
+        assert!(plugin.cancel("alice", 9).is_none());
+        assert_eq!(plugin.cancel("ALICE", 1).map(|tell| tell.message), Some("one".to_string()));
+        assert!(plugin.cancel("alice", 1).is_none());
+        assert_eq!(ids(&mut plugin, "bob"), vec![2]);
+    }
+
+    #[test]
+    fn test_delivery() {
+        let mut plugin = TellPlugin::new();
+        for _ in 0..4 {
+            plugin.leave("alice", "Bob", "hi", Delivery::Channel, 0).unwrap();
+        }
+        plugin.leave("carol", "dave", "hi", Delivery::Channel, 0).unwrap();
+        // a few at a time, oldest first, whatever the nick's case
+        assert_eq!(ids(&mut plugin, "bob"), vec![1, 2, 3]);
+        assert_eq!(ids(&mut plugin, "BOB"), vec![4]);
+        assert_eq!(ids(&mut plugin, "bob"), vec![]);
+        assert_eq!(ids(&mut plugin, "dave"), vec![5]);
+
+        // where the recipient spoke or joined, unless asked otherwise
+        assert_eq!(destination(Delivery::Channel, b"bob", Some(b"#x")), (&b"#x"[..], false));
+        assert_eq!(destination(Delivery::Channel, b"bob", None), (&b"bob"[..], false));
+        assert_eq!(destination(Delivery::Notice, b"bob", Some(b"#x")), (&b"bob"[..], true));
+        assert_eq!(destination(Delivery::Private, b"bob", Some(b"#x")), (&b"bob"[..], false));
+    }
+}
@@ -36,8 +36,13 @@ impl UnicodeNamePlugin {
 }
 
 impl RustBotPlugin for UnicodeNamePlugin {
+    fn name(&self) -> &str {
+        UnicodeNamePlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_UNICODE_NAME, Format::from_str("u {*string}").unwrap());
+        conf.map_format(CMD_UNICODE_NAME, Format::from_str("u {*string}").unwrap(),
+            "Name each unicode character in a string");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
@@ -40,9 +40,15 @@ fn parse_command<'a>(m: &CommandMapperDispatch) -> Option<WhoAmICommandType> {
 
 
 impl RustBotPlugin for WhoAmIPlugin {
+    fn name(&self) -> &str {
+        WhoAmIPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, conf: &mut IrcBotConfigurator) {
-        conf.map_format(CMD_WHOAMI, Format::from_str("whoami").unwrap());
-        conf.map_format(CMD_WHEREAMI, Format::from_str("whereami").unwrap());
+        conf.map_format(CMD_WHOAMI, Format::from_str("whoami").unwrap(),
+            "Show who the bot thinks you are");
+        conf.map_format(CMD_WHEREAMI, Format::from_str("whereami").unwrap(),
+            "Show where the bot thinks you are");
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, msg: &IrcMsg) {
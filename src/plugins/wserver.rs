@@ -1,5 +1,5 @@
 use std::convert::From;
-use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
+use std::time::{Duration, Instant};
 
 use hyper;
 use hyper::header::Server;
@@ -12,6 +12,9 @@ use command_mapper::{
     IrcBotConfigurator,
     Format,
     Token,
+    Worker,
+    WorkerPool,
+    WorkerConfig,
 };
 
 const CMD_WSERVER: Token = Token(0);
@@ -69,26 +72,30 @@ impl WserverInternalState {
             }
         }
     }
+}
 
-    fn start(&mut self, rx: Receiver<CommandMapperDispatch>) {
-        for m in rx.iter() {
-            match m.command().token {
-                CMD_WSERVER => self.handle_wserver(&m),
-                _ => ()
-            }
+impl Worker<CommandMapperDispatch> for WserverInternalState {
+    fn handle(&mut self, m: CommandMapperDispatch) {
+        match m.command().token {
+            CMD_WSERVER => self.handle_wserver(&m),
+            _ => ()
         }
     }
+
+    fn expired(&mut self, m: CommandMapperDispatch) {
+        m.reply("Service ``wserver'' timed out");
+    }
 }
 
 pub struct WserverPlugin {
-    sender: Option<SyncSender<CommandMapperDispatch>>
+    pool: Option<WorkerPool<CommandMapperDispatch>>
 }
 
 
 impl WserverPlugin {
     pub fn new() -> WserverPlugin {
         WserverPlugin {
-            sender: None
+            pool: None
         }
     }
 
@@ -98,27 +105,29 @@ impl WserverPlugin {
 }
 
 impl RustBotPlugin for WserverPlugin {
+    fn name(&self) -> &str {
+        WserverPlugin::get_plugin_name()
+    }
+
     fn configure(&mut self, configurator: &mut IrcBotConfigurator) {
-        configurator.map_format(CMD_WSERVER, Format::from_str("wserver {host:s}").unwrap());
+        configurator.map_format(CMD_WSERVER, Format::from_str("wserver {host:s}").unwrap(),
+            "Show the web server software a host is running");
     }
 
     fn start(&mut self) {
-        let (tx, rx) = sync_channel(10);
-        let _ = ::std::thread::Builder::new().name("plugin-wserver".to_string()).spawn(move || {
-            let mut internal_state = WserverInternalState::new();
-            internal_state.start(rx);
-        });
-        self.sender = Some(tx);
+        let config = WorkerConfig::new().workers(2).timeout(Duration::from_secs(30));
+        self.pool = Some(WorkerPool::start("wserver", config, WserverInternalState::new));
     }
 
     fn dispatch_cmd(&mut self, m: &CommandMapperDispatch, _message: &IrcMsg) {
-        match self.sender {
-            Some(ref sender) => {
-                if let Err(err) = sender.send(m.clone()) {
-                    m.reply(&format!("Service ``wserver'' unavailable: {:?}", err));
-                }
-            }
-            None => ()
+        if let Some(ref pool) = self.pool {
+            pool.dispatch(m);
+        }
+    }
+
+    fn stop(&mut self, deadline: Instant) {
+        if let Some(pool) = self.pool.take() {
+            pool.shutdown(deadline);
         }
     }
 }
@@ -0,0 +1,26 @@
+//! Notices SIGINT and SIGTERM, so the bot can quit cleanly.
+
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use libc::{self, c_int, sighandler_t, SIGINT, SIGTERM, SIG_ERR};
+
+static SHUTDOWN_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn on_signal(_: c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs handlers for SIGINT and SIGTERM.
+pub fn install() {
+    for &signum in [SIGINT, SIGTERM].iter() {
+        let previous = unsafe { libc::signal(signum, on_signal as extern "C" fn(c_int) as sighandler_t) };
+        if previous == SIG_ERR {
+            warn!("failed to install a handler for signal {}", signum);
+        }
+    }
+}
+
+/// Whether SIGINT or SIGTERM has been received.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,45 @@
+//! Shell-style wildcards, as used in IRC masks.
+
+/// Matches `text` against `pattern`, where `*` matches any run of
+/// characters and `?` any one character.  Case-insensitive.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // the last `*` seen, and where in `text` it started matching
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            backtrack = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&ch| ch == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("foo*", "foobar"));
+        assert!(glob_match("foo*", "Foo"));
+        assert!(glob_match("*@host.example", "nick!~user@host.example"));
+        assert!(glob_match("f?o*r", "foobar"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("*@host.example", "nick!~user@other.example"));
+        assert!(!glob_match("foo", "foobar"));
+        assert!(!glob_match("foo?", "foo"));
+    }
+}
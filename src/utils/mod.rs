@@ -1 +1,2 @@
 pub mod formatting;
+pub mod glob;